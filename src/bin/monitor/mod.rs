@@ -10,4 +10,6 @@ mod imp;
 #[path = "other.rs"]
 mod imp;
 
+#[allow(unused_imports)] // Only ssserver supports reloading
+pub use self::imp::create_reload_monitor;
 pub use self::imp::create_signal_monitor;
@@ -1,4 +1,4 @@
-use futures;
+use futures::{self, stream, Stream};
 use std::io;
 
 pub async fn create_signal_monitor() -> io::Result<()> {
@@ -6,3 +6,9 @@ pub async fn create_signal_monitor() -> io::Result<()> {
     // Blocks forever
     futures::empty::<(), io::Error>().await
 }
+
+/// Reloading with signal is not supported, the stream never yields
+#[allow(dead_code)] // Only ssserver supports reloading
+pub fn create_reload_monitor() -> io::Result<impl Stream<Item = ()>> {
+    Ok(stream::pending())
+}
@@ -1,4 +1,7 @@
-use futures::future::{self, Either, FutureExt};
+use futures::{
+    future::{self, Either, FutureExt},
+    Stream,
+};
 use log::info;
 use std::io;
 use tokio::signal::unix::{signal, SignalKind};
@@ -17,3 +20,9 @@ pub async fn create_signal_monitor() -> io::Result<()> {
 
     Ok(())
 }
+
+/// Creates a stream of SIGHUP, which asks for reloading configuration
+#[allow(dead_code)] // Only ssserver supports reloading
+pub fn create_reload_monitor() -> io::Result<impl Stream<Item = ()>> {
+    signal(SignalKind::hangup())
+}
@@ -1,5 +1,7 @@
 use futures::{
     future::{self, Either, FutureExt},
+    stream,
+    Stream,
     StreamExt,
 };
 use log::info;
@@ -19,3 +21,9 @@ pub async fn create_signal_monitor() -> io::Result<()> {
 
     Ok(())
 }
+
+/// Reloading with signal is not supported, the stream never yields
+#[allow(dead_code)] // Only ssserver supports reloading
+pub fn create_reload_monitor() -> io::Result<impl Stream<Item = ()>> {
+    Ok(stream::pending())
+}
@@ -9,122 +9,91 @@ use std::{
     },
 };
 
-use bloomfilter::Bloom;
-use spin::Mutex;
-use tokio::runtime::Handle;
+use log::{debug, error, warn};
+use spin::RwLock;
+use tokio::{runtime::Handle, sync::watch};
 #[cfg(feature = "trust-dns")]
 use trust_dns_resolver::TokioAsyncResolver;
 
-use crate::config::{Config, ConfigType};
+use crate::config::{Config, ProxyConfig, ServerAddr, ServerConfig};
 #[cfg(feature = "trust-dns")]
 use crate::relay::dns_resolver::create_resolver;
-
-// Entries for server's bloom filter
-//
-// Borrowed from shadowsocks-libev's default value
-const BF_NUM_ENTRIES_FOR_SERVER: usize = 1_000_000;
-
-// Entries for client's bloom filter
-//
-// Borrowed from shadowsocks-libev's default value
-const BF_NUM_ENTRIES_FOR_CLIENT: usize = 10_000;
-
-// Error rate for server's bloom filter
-//
-// Borrowed from shadowsocks-libev's default value
-const BF_ERROR_RATE_FOR_SERVER: f64 = 1e-6;
-
-// Error rate for client's bloom filter
-//
-// Borrowed from shadowsocks-libev's default value
-const BF_ERROR_RATE_FOR_CLIENT: f64 = 1e-15;
-
-// A bloom filter borrowed from shadowsocks-libev's `ppbloom`
-//
-// It contains 2 bloom filters and each one holds 1/2 entries.
-// Use them as a ring buffer.
-struct PingPongBloom {
-    blooms: [Bloom<[u8]>; 2],
-    bloom_count: [usize; 2],
-    item_count: usize,
-    current: usize,
-}
-
-impl PingPongBloom {
-    fn new(ty: ConfigType) -> PingPongBloom {
-        let (mut item_count, fp_p) = if ty.is_local() {
-            (BF_NUM_ENTRIES_FOR_CLIENT, BF_ERROR_RATE_FOR_CLIENT)
-        } else {
-            (BF_NUM_ENTRIES_FOR_SERVER, BF_ERROR_RATE_FOR_SERVER)
-        };
-
-        item_count /= 2;
-
-        PingPongBloom {
-            blooms: [
-                Bloom::new_for_fp_rate(item_count, fp_p),
-                Bloom::new_for_fp_rate(item_count, fp_p),
-            ],
-            bloom_count: [0, 0],
-            item_count,
-            current: 0,
-        }
-    }
-
-    // Check if data in `buf` exist.
-    //
-    // Set into the current bloom filter if not exist.
-    //
-    // Return `true` if data exist in bloom filter.
-    fn check_and_set(&mut self, buf: &[u8]) -> bool {
-        for bloom in &self.blooms {
-            if bloom.check(buf) {
-                return true;
-            }
-        }
-
-        if self.bloom_count[self.current] >= self.item_count {
-            // Current bloom filter is full,
-            // Create a new one and use that one as current.
-
-            self.current = (self.current + 1) % 2;
-
-            self.bloom_count[self.current] = 0;
-            self.blooms[self.current].clear();
-        }
-
-        // Cannot be optimized by `check_and_set`
-        // Because we have to check every filters in `blooms` before `set`
-        self.blooms[self.current].set(buf);
-        self.bloom_count[self.current] += 1;
-
-        false
-    }
-}
+use crate::relay::{
+    access_log::AccessLog,
+    ip_set::IpSet,
+    limiter::{ConnectionLimiter, SharedConnectionLimiter},
+    metrics::Metrics,
+    replay::{ReplayFilter, SaltWindow, SessionWindows, SharedNonceFilter},
+    shaper::Bandwidth,
+    udprelay::associate::{SharedUdpAssociateClients, UdpAssociateClients},
+};
 
 /// Server's global running status
 ///
 /// Shared between UDP and TCP servers
 pub struct ServerState {
     #[cfg(feature = "trust-dns")]
-    dns_resolver: TokioAsyncResolver,
+    dns_resolver: RwLock<TokioAsyncResolver>,
+    forbidden_ip: RwLock<IpSet>,
     server_running: AtomicBool,
-    nonce_ppbloom: Mutex<PingPongBloom>,
+    server_draining: AtomicBool,
+    stopped_tx: watch::Sender<bool>,
+    stopped_rx: watch::Receiver<bool>,
+    replay_filter: ReplayFilter,
+    recent_salts: SaltWindow,
+    packet_windows: SessionWindows,
+    metrics: Metrics,
+    limiter: SharedConnectionLimiter,
+    bandwidth: Bandwidth,
+    access_log: Option<AccessLog>,
+    udp_associate_clients: SharedUdpAssociateClients,
 }
 
 impl ServerState {
     #[allow(unused_variables)]
     pub async fn new(config: &Config, rt: Handle) -> io::Result<SharedServerState> {
+        let (stopped_tx, stopped_rx) = watch::channel(false);
+
         let state = ServerState {
             #[cfg(feature = "trust-dns")]
-            dns_resolver: create_resolver(config.get_dns_config(), rt).await?,
+            dns_resolver: RwLock::new(create_resolver(config.get_dns_config(), rt).await?),
+            forbidden_ip: RwLock::new(config.forbidden_ip.clone()),
             server_running: AtomicBool::new(true),
-            nonce_ppbloom: Mutex::new(PingPongBloom::new(config.config_type)),
+            server_draining: AtomicBool::new(false),
+            stopped_tx,
+            stopped_rx,
+            replay_filter: ReplayFilter::new(config),
+            recent_salts: SaltWindow::new(),
+            packet_windows: SessionWindows::new(),
+            metrics: Metrics::new(),
+            limiter: ConnectionLimiter::new_shared(config),
+            bandwidth: Bandwidth::new(),
+            access_log: match config.access_log {
+                Some(ref path) => Some(AccessLog::open(path)?),
+                None => None,
+            },
+            udp_associate_clients: UdpAssociateClients::new_shared(),
         };
 
         Ok(Arc::new(state))
     }
 
+    /// Refreshes DNS resolver, forbidden IPs and connection limits with `config`
+    ///
+    /// Nothing will be changed if it fails to create the new resolver
+    pub async fn reload(&self, config: &Config) -> io::Result<()> {
+        #[cfg(feature = "trust-dns")]
+        {
+            let resolver = create_resolver(config.get_dns_config(), Handle::current()).await?;
+            *self.dns_resolver.write() = resolver;
+        }
+
+        *self.forbidden_ip.write() = config.forbidden_ip.clone();
+        self.limiter.reload(config);
+
+        Ok(())
+    }
+
     /// Check if the server is still in running state
     pub fn server_running(&self) -> bool {
         self.server_running.load(Ordering::Acquire)
@@ -132,21 +101,123 @@ impl ServerState {
 
     /// Stops the server, kills all detached running tasks
     pub fn server_stopped(&self) {
-        self.server_running.store(false, Ordering::Release)
+        self.server_running.store(false, Ordering::Release);
+        let _ = self.stopped_tx.broadcast(true);
+    }
+
+    /// Resolves after the server is stopped
+    pub async fn wait_stopped(&self) {
+        let mut stopped_rx = self.stopped_rx.clone();
+        while let Some(stopped) = stopped_rx.recv().await {
+            if stopped {
+                break;
+            }
+        }
+    }
+
+    /// Check if the server is draining, new clients won't be accepted
+    pub fn server_draining(&self) -> bool {
+        self.server_draining.load(Ordering::Acquire)
+    }
+
+    /// Starts draining, the running relays are kept until the server is stopped
+    pub fn start_draining(&self) {
+        self.server_draining.store(true, Ordering::Release)
     }
 
     /// Get the global shared resolver
     #[cfg(feature = "trust-dns")]
-    pub fn dns_resolver(&self) -> &TokioAsyncResolver {
-        &self.dns_resolver
+    pub fn dns_resolver(&self) -> TokioAsyncResolver {
+        self.dns_resolver.read().clone()
     }
 
-    /// Check if nonce exist or not
+    /// Check if IP is in forbidden list
+    pub fn check_forbidden_ip(&self, ip: &IpAddr) -> bool {
+        self.forbidden_ip.read().contains(ip)
+    }
+
+    /// Check if any IP is forbidden
+    pub fn has_forbidden_ip(&self) -> bool {
+        !self.forbidden_ip.read().is_empty()
+    }
+
+    /// Check if nonce of the server listening on `svr_addr` exist or not
     ///
     /// If not, set into the current bloom filter
-    pub fn check_nonce_and_set(&self, nonce: &[u8]) -> bool {
-        let mut ppbloom = self.nonce_ppbloom.lock();
-        ppbloom.check_and_set(nonce)
+    pub fn check_nonce_and_set(&self, svr_addr: &ServerAddr, nonce: &[u8]) -> bool {
+        self.replay_filter.filter(svr_addr).check_and_set(nonce)
+    }
+
+    /// Get the replay filter of the server listening on `svr_addr`
+    pub fn nonce_filter(&self, svr_addr: &ServerAddr) -> SharedNonceFilter {
+        self.replay_filter.filter(svr_addr)
+    }
+
+    /// Check if salt of an AEAD-2022 cipher is received recently
+    ///
+    /// If not, keep it until headers with the same timestamp are expired
+    pub fn check_recent_salt_and_set(&self, salt: &[u8]) -> bool {
+        self.recent_salts.check_and_set(salt)
+    }
+
+    /// Check if packet ID of an AEAD-2022 UDP session is received
+    ///
+    /// If not, set into the window of the session
+    pub fn check_packet_and_set(&self, session_id: u64, packet_id: u64) -> bool {
+        self.packet_windows.check_and_set(session_id, packet_id)
+    }
+
+    /// Loads replay filters from `replay_filter_path` in `config`, if it is set
+    ///
+    /// Filters are kept empty if it fails to load.
+    pub fn load_replay_filter(&self, config: &Config) {
+        if let Some(ref path) = config.replay_filter_path {
+            match self.replay_filter.load_from_file(path) {
+                Ok(..) => debug!("Loaded replay filter from {}", path),
+                Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+                    debug!("Replay filter {} doesn't exist, starts with an empty one", path)
+                }
+                Err(err) => warn!(
+                    "Failed to load replay filter from {}, starts with an empty one, {}",
+                    path, err
+                ),
+            }
+        }
+    }
+
+    /// Saves replay filters into `replay_filter_path` in `config`, if it is set
+    pub fn save_replay_filter(&self, config: &Config) {
+        if let Some(ref path) = config.replay_filter_path {
+            match self.replay_filter.save_to_file(path) {
+                Ok(..) => debug!("Saved replay filter into {}", path),
+                Err(err) => error!("Failed to save replay filter into {}, {}", path, err),
+            }
+        }
+    }
+
+    /// Get the global metrics
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    /// Get the limiter of concurrent connections
+    pub fn limiter(&self) -> &SharedConnectionLimiter {
+        &self.limiter
+    }
+
+    /// Get bandwidth limits of servers
+    pub fn bandwidth(&self) -> &Bandwidth {
+        &self.bandwidth
+    }
+
+    /// Get the access log, if it is enabled
+    pub fn access_log(&self) -> Option<&AccessLog> {
+        self.access_log.as_ref()
+    }
+
+    /// Get clients holding UDP ASSOCIATE sessions of the local SOCKS5 server
+    pub fn udp_associate_clients(&self) -> &SharedUdpAssociateClients {
+        &self.udp_associate_clients
     }
 }
 
@@ -192,7 +263,7 @@ impl Context {
 
     #[cfg(feature = "trust-dns")]
     /// Get the global shared resolver
-    pub fn dns_resolver(&self) -> &TokioAsyncResolver {
+    pub fn dns_resolver(&self) -> TokioAsyncResolver {
         self.server_state.dns_resolver()
     }
 
@@ -206,15 +277,72 @@ impl Context {
         self.server_state.server_stopped()
     }
 
+    /// Resolves after the server is stopped
+    pub async fn wait_stopped(&self) {
+        self.server_state.wait_stopped().await
+    }
+
+    /// Check if the server is draining, new clients won't be accepted
+    pub fn server_draining(&self) -> bool {
+        self.server_state.server_draining()
+    }
+
     /// Check if IP is in forbidden list
     pub fn check_forbidden_ip(&self, ip: &IpAddr) -> bool {
-        self.config.check_forbidden_ip(ip)
+        self.server_state.check_forbidden_ip(ip)
     }
 
-    /// Check if nonce exist or not
+    /// Check if any IP is forbidden
+    pub fn has_forbidden_ip(&self) -> bool {
+        self.server_state.has_forbidden_ip()
+    }
+
+    /// Check if nonce of the server listening on `svr_addr` exist or not
     ///
     /// If not, set into the current bloom filter
-    pub fn check_nonce_and_set(&self, nonce: &[u8]) -> bool {
-        self.server_state.check_nonce_and_set(nonce)
+    pub fn check_nonce_and_set(&self, svr_addr: &ServerAddr, nonce: &[u8]) -> bool {
+        self.server_state.check_nonce_and_set(svr_addr, nonce)
+    }
+
+    /// Get the replay filter of the server listening on `svr_addr`
+    pub fn nonce_filter(&self, svr_addr: &ServerAddr) -> SharedNonceFilter {
+        self.server_state.nonce_filter(svr_addr)
+    }
+
+    /// Check if packet ID of an AEAD-2022 UDP session is received
+    ///
+    /// If not, set into the window of the session
+    pub fn check_packet_and_set(&self, session_id: u64, packet_id: u64) -> bool {
+        self.server_state.check_packet_and_set(session_id, packet_id)
+    }
+
+    /// Get the global metrics
+    pub fn metrics(&self) -> &Metrics {
+        self.server_state.metrics()
+    }
+
+    /// Get the limiter of concurrent connections
+    pub fn limiter(&self) -> &SharedConnectionLimiter {
+        self.server_state.limiter()
+    }
+
+    /// Get bandwidth limits of servers
+    pub fn bandwidth(&self) -> &Bandwidth {
+        self.server_state.bandwidth()
+    }
+
+    /// Get the access log, if it is enabled
+    pub fn access_log(&self) -> Option<&AccessLog> {
+        self.server_state.access_log()
+    }
+
+    /// Get clients holding UDP ASSOCIATE sessions of the local SOCKS5 server
+    pub fn udp_associate_clients(&self) -> &SharedUdpAssociateClients {
+        self.server_state.udp_associate_clients()
+    }
+
+    /// Get the proxy for connecting to destinations of `svr_cfg`
+    pub fn outbound_proxy<'a>(&'a self, svr_cfg: &'a ServerConfig) -> Option<&'a ProxyConfig> {
+        svr_cfg.outbound_proxy().or(self.config.outbound_proxy.as_ref())
     }
 }
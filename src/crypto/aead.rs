@@ -42,12 +42,15 @@ pub type BoxAeadEncryptor = Box<dyn AeadEncryptor + Send + 'static>;
 
 /// Generate a specific AEAD cipher encryptor
 pub fn new_aead_encryptor(t: CipherType, key: &[u8], nonce: &[u8]) -> BoxAeadEncryptor {
-    assert!(t.category() == CipherCategory::Aead);
+    assert!(t.category() != CipherCategory::Stream);
 
     match t {
-        CipherType::Aes128Gcm | CipherType::Aes256Gcm | CipherType::ChaCha20IetfPoly1305 => {
-            Box::new(RingAeadCipher::new(t, key, nonce, true))
-        }
+        CipherType::Aes128Gcm
+        | CipherType::Aes256Gcm
+        | CipherType::ChaCha20IetfPoly1305
+        | CipherType::Aead2022Blake3Aes128Gcm
+        | CipherType::Aead2022Blake3Aes256Gcm
+        | CipherType::Aead2022Blake3ChaCha20Poly1305 => Box::new(RingAeadCipher::new(t, key, nonce, true)),
 
         #[cfg(feature = "sodium")]
         CipherType::XChaCha20IetfPoly1305 => Box::new(SodiumAeadCipher::new(t, key, nonce)),
@@ -61,12 +64,15 @@ pub fn new_aead_encryptor(t: CipherType, key: &[u8], nonce: &[u8]) -> BoxAeadEnc
 
 /// Generate a specific AEAD cipher decryptor
 pub fn new_aead_decryptor(t: CipherType, key: &[u8], nonce: &[u8]) -> BoxAeadDecryptor {
-    assert!(t.category() == CipherCategory::Aead);
+    assert!(t.category() != CipherCategory::Stream);
 
     match t {
-        CipherType::Aes128Gcm | CipherType::Aes256Gcm | CipherType::ChaCha20IetfPoly1305 => {
-            Box::new(RingAeadCipher::new(t, key, nonce, false))
-        }
+        CipherType::Aes128Gcm
+        | CipherType::Aes256Gcm
+        | CipherType::ChaCha20IetfPoly1305
+        | CipherType::Aead2022Blake3Aes128Gcm
+        | CipherType::Aead2022Blake3Aes256Gcm
+        | CipherType::Aead2022Blake3ChaCha20Poly1305 => Box::new(RingAeadCipher::new(t, key, nonce, false)),
 
         #[cfg(feature = "sodium")]
         CipherType::XChaCha20IetfPoly1305 => Box::new(SodiumAeadCipher::new(t, key, nonce)),
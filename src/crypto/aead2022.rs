@@ -0,0 +1,342 @@
+//! AEAD-2022 Ciphers
+//!
+//! AEAD-2022 ciphers are defined in [SIP022](https://github.com/shadowsocks/shadowsocks-org/issues/196).
+//! Session keys are derived with BLAKE3 instead of HKDF-SHA1, and UDP packets are identified by session IDs and
+//! packet IDs instead of salts.
+//!
+//! AES ciphers support extensible identity headers. Servers on the path are identified by their identity keys
+//! (iPSK), each one finds the key of the next hop or the user (uPSK) from its header:
+//!
+//! ```plain
+//! TCP: IdentityHeader = AES-ECB(BLAKE3_derive_key("shadowsocks 2022 identity subkey", iPSK || salt), BLAKE3(next PSK)[..16])
+//! UDP: IdentityHeader = AES-ECB(iPSK, BLAKE3(next PSK)[..16] ^ SeparateHeader)
+//! ```
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use aes::{
+    cipher::{generic_array::GenericArray, BlockDecrypt, BlockEncrypt, KeyInit},
+    Aes128,
+    Aes256,
+};
+use bytes::Bytes;
+use chacha20poly1305::{aead::AeadInPlace, XChaCha20Poly1305};
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_128_GCM, AES_256_GCM};
+
+use crate::crypto::cipher::{CipherResult, CipherType, Error};
+
+const SUBKEY_CONTEXT: &str = "shadowsocks 2022 session subkey";
+const IDENTITY_SUBKEY_CONTEXT: &str = "shadowsocks 2022 identity subkey";
+
+/// Length of identity headers
+pub const IDENTITY_HEADER_SIZE: usize = 16;
+
+/// Maximum difference between timestamps in headers and the local clock, in seconds
+pub const MAX_TIME_DIFF: u64 = 30;
+
+/// Length of the separate header of UDP packets encrypted by AES ciphers
+pub const UDP_SEPARATE_HEADER_SIZE: usize = 16;
+
+/// Length of the nonce of UDP packets encrypted by ChaCha20-Poly1305
+pub const UDP_XCHACHA20_NONCE_SIZE: usize = 24;
+
+/// Make session key
+///
+/// ```plain
+/// SK = BLAKE3_derive_key("shadowsocks 2022 session subkey", PSK || salt)
+/// ```
+///
+/// Salts of UDP packets are their 8 bytes session IDs.
+pub fn make_skey(key: &[u8], salt: &[u8]) -> Bytes {
+    derive_key(SUBKEY_CONTEXT, key, salt)
+}
+
+fn derive_key(context: &str, key: &[u8], salt: &[u8]) -> Bytes {
+    let mut hasher = blake3::Hasher::new_derive_key(context);
+    hasher.update(key);
+    hasher.update(salt);
+
+    let mut skey = vec![0u8; key.len()];
+    hasher.finalize_xof().fill(&mut skey);
+    Bytes::from(skey)
+}
+
+/// Identity of a key in identity headers, the first 16 bytes of its BLAKE3 hash
+pub fn key_hash(key: &[u8]) -> [u8; IDENTITY_HEADER_SIZE] {
+    let mut hash = [0u8; IDENTITY_HEADER_SIZE];
+    hash.copy_from_slice(&blake3::hash(key).as_bytes()[..IDENTITY_HEADER_SIZE]);
+    hash
+}
+
+/// Make identity headers of TCP requests with `salt`
+///
+/// `identity_keys` are keys of servers on the path, and `user_key` is identified by the last server.
+pub fn make_identity_headers(t: CipherType, identity_keys: &[Bytes], user_key: &[u8], salt: &[u8]) -> Vec<u8> {
+    let mut headers = Vec::with_capacity(identity_keys.len() * IDENTITY_HEADER_SIZE);
+    for (i, identity_key) in identity_keys.iter().enumerate() {
+        let next_key = identity_keys.get(i + 1).map_or(user_key, |k| &k[..]);
+
+        let mut header = key_hash(next_key);
+        let subkey = derive_key(IDENTITY_SUBKEY_CONTEXT, identity_key, salt);
+        encrypt_block(t, &subkey, &mut header);
+        headers.extend_from_slice(&header);
+    }
+    headers
+}
+
+/// Decrypt the identity header of a TCP request with `salt`, returns hash of the next key
+pub fn decrypt_identity_header(
+    t: CipherType,
+    identity_key: &[u8],
+    salt: &[u8],
+    header: &[u8],
+) -> [u8; IDENTITY_HEADER_SIZE] {
+    let mut hash = [0u8; IDENTITY_HEADER_SIZE];
+    hash.copy_from_slice(&header[..IDENTITY_HEADER_SIZE]);
+    let subkey = derive_key(IDENTITY_SUBKEY_CONTEXT, identity_key, salt);
+    decrypt_block(t, &subkey, &mut hash);
+    hash
+}
+
+/// Make identity headers of UDP packets with the plain separate header
+pub fn make_udp_identity_headers(
+    t: CipherType,
+    identity_keys: &[Bytes],
+    user_key: &[u8],
+    separate_header: &[u8],
+) -> Vec<u8> {
+    let mut headers = Vec::with_capacity(identity_keys.len() * IDENTITY_HEADER_SIZE);
+    for (i, identity_key) in identity_keys.iter().enumerate() {
+        let next_key = identity_keys.get(i + 1).map_or(user_key, |k| &k[..]);
+
+        let mut header = key_hash(next_key);
+        for (h, s) in header.iter_mut().zip(separate_header) {
+            *h ^= s;
+        }
+        encrypt_block(t, identity_key, &mut header);
+        headers.extend_from_slice(&header);
+    }
+    headers
+}
+
+/// Decrypt the identity header of a UDP packet with the plain separate header, returns hash of the next key
+pub fn decrypt_udp_identity_header(
+    t: CipherType,
+    identity_key: &[u8],
+    separate_header: &[u8],
+    header: &[u8],
+) -> [u8; IDENTITY_HEADER_SIZE] {
+    let mut hash = [0u8; IDENTITY_HEADER_SIZE];
+    hash.copy_from_slice(&header[..IDENTITY_HEADER_SIZE]);
+    decrypt_block(t, identity_key, &mut hash);
+    for (h, s) in hash.iter_mut().zip(separate_header) {
+        *h ^= s;
+    }
+    hash
+}
+
+/// Seconds since UNIX epoch
+pub fn unix_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Check if `timestamp` of a header is close enough to the local clock
+pub fn check_timestamp(timestamp: u64) -> bool {
+    let now = unix_timestamp();
+    now.max(timestamp) - now.min(timestamp) <= MAX_TIME_DIFF
+}
+
+/// Check if UDP packets of `t` start with an AES encrypted separate header, or an XChaCha20 nonce otherwise
+pub fn has_udp_separate_header(t: CipherType) -> bool {
+    match t {
+        CipherType::Aead2022Blake3Aes128Gcm | CipherType::Aead2022Blake3Aes256Gcm => true,
+        CipherType::Aead2022Blake3ChaCha20Poly1305 => false,
+        _ => panic!("{:?} is not an AEAD-2022 cipher", t),
+    }
+}
+
+/// Encrypt the separate header of UDP packets with the pre-shared `key`
+pub fn encrypt_udp_header(t: CipherType, key: &[u8], header: &mut [u8]) {
+    encrypt_block(t, key, &mut header[..UDP_SEPARATE_HEADER_SIZE])
+}
+
+/// Decrypt the separate header of UDP packets with the pre-shared `key`
+pub fn decrypt_udp_header(t: CipherType, key: &[u8], header: &mut [u8]) {
+    decrypt_block(t, key, &mut header[..UDP_SEPARATE_HEADER_SIZE])
+}
+
+fn encrypt_block(t: CipherType, key: &[u8], block: &mut [u8]) {
+    let block = GenericArray::from_mut_slice(block);
+    match t {
+        CipherType::Aead2022Blake3Aes128Gcm => Aes128::new_from_slice(key).unwrap().encrypt_block(block),
+        CipherType::Aead2022Blake3Aes256Gcm => Aes256::new_from_slice(key).unwrap().encrypt_block(block),
+        _ => panic!("{:?} doesn't support AES blocks", t),
+    }
+}
+
+fn decrypt_block(t: CipherType, key: &[u8], block: &mut [u8]) {
+    let block = GenericArray::from_mut_slice(block);
+    match t {
+        CipherType::Aead2022Blake3Aes128Gcm => Aes128::new_from_slice(key).unwrap().decrypt_block(block),
+        CipherType::Aead2022Blake3Aes256Gcm => Aes256::new_from_slice(key).unwrap().decrypt_block(block),
+        _ => panic!("{:?} doesn't support AES blocks", t),
+    }
+}
+
+/// Encrypt a UDP packet in `buf` and append the tag
+///
+/// AES ciphers take the session key and the last 12 bytes of the separate header as nonce, ChaCha20-Poly1305 takes
+/// the pre-shared key and a random 24 bytes nonce for XChaCha20-Poly1305.
+pub fn seal_udp(t: CipherType, key: &[u8], nonce: &[u8], buf: &mut Vec<u8>) {
+    match t {
+        CipherType::Aead2022Blake3Aes128Gcm | CipherType::Aead2022Blake3Aes256Gcm => {
+            let key = ring_key(t, key);
+            let nonce = Nonce::try_assume_unique_for_key(nonce).unwrap();
+            key.seal_in_place_append_tag(nonce, Aad::empty(), buf).unwrap();
+        }
+        CipherType::Aead2022Blake3ChaCha20Poly1305 => {
+            let cipher = XChaCha20Poly1305::new_from_slice(key).unwrap();
+            cipher
+                .encrypt_in_place(GenericArray::from_slice(nonce), b"", buf)
+                .unwrap();
+        }
+        _ => panic!("{:?} is not an AEAD-2022 cipher", t),
+    }
+}
+
+/// Decrypt a UDP packet in `buf` and remove the tag
+pub fn open_udp(t: CipherType, key: &[u8], nonce: &[u8], buf: &mut Vec<u8>) -> CipherResult<()> {
+    match t {
+        CipherType::Aead2022Blake3Aes128Gcm | CipherType::Aead2022Blake3Aes256Gcm => {
+            let key = ring_key(t, key);
+            let nonce = Nonce::try_assume_unique_for_key(nonce).map_err(|_| Error::AeadDecryptFailed)?;
+            let len = key
+                .open_in_place(nonce, Aad::empty(), buf)
+                .map_err(|_| Error::AeadDecryptFailed)?
+                .len();
+            buf.truncate(len);
+            Ok(())
+        }
+        CipherType::Aead2022Blake3ChaCha20Poly1305 => {
+            if nonce.len() != UDP_XCHACHA20_NONCE_SIZE {
+                return Err(Error::AeadDecryptFailed);
+            }
+
+            let cipher = XChaCha20Poly1305::new_from_slice(key).unwrap();
+            cipher
+                .decrypt_in_place(GenericArray::from_slice(nonce), b"", buf)
+                .map_err(|_| Error::AeadDecryptFailed)
+        }
+        _ => panic!("{:?} is not an AEAD-2022 cipher", t),
+    }
+}
+
+fn ring_key(t: CipherType, key: &[u8]) -> LessSafeKey {
+    let algorithm = match t {
+        CipherType::Aead2022Blake3Aes128Gcm => &AES_128_GCM,
+        _ => &AES_256_GCM,
+    };
+    LessSafeKey::new(UnboundKey::new(algorithm, key).unwrap())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn session_key() {
+        let key = [7u8; 32];
+        let skey = make_skey(&key, b"salt");
+        assert_eq!(skey.len(), 32);
+        assert_eq!(skey, make_skey(&key, b"salt"));
+        assert_ne!(skey, make_skey(&key, b"pepper"));
+
+        // Shorter keys are prefixes of the extendable output
+        assert_eq!(
+            &make_skey(&key[..16], b"salt")[..],
+            &make_skey_16(&key[..16], b"salt")[..]
+        );
+    }
+
+    fn make_skey_16(key: &[u8], salt: &[u8]) -> Vec<u8> {
+        let mut material = key.to_vec();
+        material.extend_from_slice(salt);
+        blake3::derive_key(SUBKEY_CONTEXT, &material)[..16].to_vec()
+    }
+
+    fn test_udp(t: CipherType) {
+        let key = vec![1u8; t.key_size()];
+        let nonce = if has_udp_separate_header(t) {
+            vec![2u8; 12]
+        } else {
+            vec![2u8; UDP_XCHACHA20_NONCE_SIZE]
+        };
+
+        let mut buf = b"message".to_vec();
+        seal_udp(t, &key, &nonce, &mut buf);
+        assert_eq!(buf.len(), 7 + t.tag_size());
+
+        let mut tampered = buf.clone();
+        tampered[0] ^= 1;
+        assert!(open_udp(t, &key, &nonce, &mut tampered).is_err());
+
+        open_udp(t, &key, &nonce, &mut buf).unwrap();
+        assert_eq!(&buf[..], b"message");
+    }
+
+    #[test]
+    fn udp_packet() {
+        test_udp(CipherType::Aead2022Blake3Aes128Gcm);
+        test_udp(CipherType::Aead2022Blake3Aes256Gcm);
+        test_udp(CipherType::Aead2022Blake3ChaCha20Poly1305);
+    }
+
+    #[test]
+    fn udp_separate_header() {
+        let t = CipherType::Aead2022Blake3Aes256Gcm;
+        let key = [3u8; 32];
+
+        let mut header = [0u8; UDP_SEPARATE_HEADER_SIZE];
+        header[7] = 1;
+        let origin = header;
+
+        encrypt_udp_header(t, &key, &mut header);
+        assert_ne!(header, origin);
+        decrypt_udp_header(t, &key, &mut header);
+        assert_eq!(header, origin);
+    }
+
+    #[test]
+    fn identity_headers() {
+        let t = CipherType::Aead2022Blake3Aes128Gcm;
+        let identity_keys = vec![Bytes::from(vec![1u8; 16]), Bytes::from(vec![2u8; 16])];
+        let user_key = [3u8; 16];
+        let salt = [4u8; 16];
+
+        // Each server finds the key of the next hop from its own header
+        let headers = make_identity_headers(t, &identity_keys, &user_key, &salt);
+        assert_eq!(headers.len(), 2 * IDENTITY_HEADER_SIZE);
+        assert_eq!(
+            decrypt_identity_header(t, &identity_keys[0], &salt, &headers[..16]),
+            key_hash(&identity_keys[1])
+        );
+        assert_eq!(
+            decrypt_identity_header(t, &identity_keys[1], &salt, &headers[16..]),
+            key_hash(&user_key)
+        );
+
+        let separate_header = [5u8; UDP_SEPARATE_HEADER_SIZE];
+        let headers = make_udp_identity_headers(t, &identity_keys, &user_key, &separate_header);
+        assert_eq!(
+            decrypt_udp_identity_header(t, &identity_keys[1], &separate_header, &headers[16..]),
+            key_hash(&user_key)
+        );
+        assert_ne!(
+            decrypt_udp_identity_header(t, &identity_keys[1], &[6u8; 16], &headers[16..]),
+            key_hash(&user_key)
+        );
+    }
+}
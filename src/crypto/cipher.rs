@@ -8,7 +8,10 @@ use std::{
     str::{self, FromStr},
 };
 
-use crate::crypto::digest::{self, Digest, DigestType};
+use crate::crypto::{
+    aead2022,
+    digest::{self, Digest, DigestType},
+};
 use bytes::{BufMut, Bytes, BytesMut};
 #[cfg(feature = "camellia-cfb")]
 use openssl::nid::Nid;
@@ -28,6 +31,7 @@ pub enum Error {
     IoError(io::Error),
     AeadDecryptFailed,
     SodiumError,
+    InvalidKey,
 }
 
 impl Debug for Error {
@@ -39,6 +43,7 @@ impl Debug for Error {
             Error::IoError(ref err) => write!(f, "{:?}", err),
             Error::AeadDecryptFailed => write!(f, "AEAD decrypt failed"),
             Error::SodiumError => write!(f, "Sodium error"),
+            Error::InvalidKey => write!(f, "InvalidKey"),
         }
     }
 }
@@ -52,6 +57,7 @@ impl Display for Error {
             Error::IoError(ref err) => write!(f, "{}", err),
             Error::AeadDecryptFailed => write!(f, "AeadDecryptFailed"),
             Error::SodiumError => write!(f, "Sodium error"),
+            Error::InvalidKey => write!(f, "InvalidKey"),
         }
     }
 }
@@ -65,6 +71,7 @@ impl From<Error> for io::Error {
             Error::IoError(err) => err,
             Error::AeadDecryptFailed => io::Error::new(io::ErrorKind::Other, "AEAD decrypt error"),
             Error::SodiumError => io::Error::new(io::ErrorKind::Other, "Sodium error"),
+            Error::InvalidKey => io::Error::new(io::ErrorKind::InvalidInput, "invalid key"),
         }
     }
 }
@@ -164,8 +171,12 @@ const CIPHER_CHACHA20_IETF_POLY1305: &str = "chacha20-ietf-poly1305";
 #[cfg(feature = "sodium")]
 const CIPHER_XCHACHA20_IETF_POLY1305: &str = "xchacha20-ietf-poly1305";
 
+const CIPHER_AEAD2022_BLAKE3_AES_128_GCM: &str = "2022-blake3-aes-128-gcm";
+const CIPHER_AEAD2022_BLAKE3_AES_256_GCM: &str = "2022-blake3-aes-256-gcm";
+const CIPHER_AEAD2022_BLAKE3_CHACHA20_POLY1305: &str = "2022-blake3-chacha20-poly1305";
+
 /// ShadowSocks cipher type
-#[derive(Clone, Debug, Copy)]
+#[derive(Clone, Debug, Copy, Eq, PartialEq)]
 pub enum CipherType {
     Table,
     Plain,
@@ -254,6 +265,10 @@ pub enum CipherType {
     Aes128PmacSiv,
     #[cfg(feature = "miscreant")]
     Aes256PmacSiv,
+
+    Aead2022Blake3Aes128Gcm,
+    Aead2022Blake3Aes256Gcm,
+    Aead2022Blake3ChaCha20Poly1305,
 }
 
 /// Category of ciphers
@@ -263,6 +278,8 @@ pub enum CipherCategory {
     Stream,
     /// AEAD ciphers is used in modern ShadowSocks protocol, which sends data in separate packets
     Aead,
+    /// AEAD ciphers of Shadowsocks 2022 (SIP022), which take base64 keys and send timestamped headers
+    Aead2022,
 }
 
 impl CipherType {
@@ -364,6 +381,10 @@ impl CipherType {
             CipherType::Aes128PmacSiv => 32,
             #[cfg(feature = "miscreant")]
             CipherType::Aes256PmacSiv => 64,
+
+            CipherType::Aead2022Blake3Aes128Gcm => AES_128_GCM.key_len(),
+            CipherType::Aead2022Blake3Aes256Gcm => AES_256_GCM.key_len(),
+            CipherType::Aead2022Blake3ChaCha20Poly1305 => CHACHA20_POLY1305.key_len(),
         }
     }
 
@@ -399,8 +420,56 @@ impl CipherType {
     }
 
     /// Extends key to match the required key length
+    ///
+    /// Keys of AEAD-2022 ciphers are decoded from base64 instead, see `check_key`. The last key is the user's key if
+    /// it is preceded by identity keys.
     pub fn bytes_to_key(self, key: &[u8]) -> Bytes {
-        self.classic_bytes_to_key(key)
+        match self.category() {
+            CipherCategory::Aead2022 => {
+                let user_key = key.rsplit(|&c| c == b':').next().unwrap_or_default();
+                base64::decode(user_key).map(Bytes::from).unwrap_or_default()
+            }
+            _ => self.classic_bytes_to_key(key),
+        }
+    }
+
+    /// Identity keys of servers on the path, in the order of hops
+    ///
+    /// AEAD-2022 passwords like `iPSK1:iPSK2:uPSK` have identity keys before the user's key, empty for others.
+    pub fn identity_keys(self, key: &[u8]) -> Vec<Bytes> {
+        if self.category() != CipherCategory::Aead2022 {
+            return Vec::new();
+        }
+
+        let mut keys = key
+            .split(|&c| c == b':')
+            .map(|k| base64::decode(k).map(Bytes::from).unwrap_or_default())
+            .collect::<Vec<_>>();
+        keys.pop();
+        keys
+    }
+
+    /// Checks if `key` could be used as the password of this cipher
+    ///
+    /// AEAD-2022 ciphers take base64 encoded keys of exactly `key_size` bytes, other ciphers take any password.
+    /// AES ciphers of AEAD-2022 could have identity keys before the user's key, separated by `:`.
+    pub fn check_key(self, key: &str) -> CipherResult<()> {
+        if self.category() != CipherCategory::Aead2022 {
+            return Ok(());
+        }
+
+        let keys = key.split(':').collect::<Vec<_>>();
+        if keys.len() > 1 && !aead2022::has_udp_separate_header(self) {
+            return Err(Error::InvalidKey);
+        }
+
+        for k in keys {
+            match base64::decode(k) {
+                Ok(ref k) if k.len() == self.key_size() => {}
+                _ => return Err(Error::InvalidKey),
+            }
+        }
+        Ok(())
     }
 
     /// Symmetric crypto initialize vector size
@@ -535,6 +604,10 @@ impl CipherType {
             CipherType::Aes128PmacSiv => 8,
             #[cfg(feature = "miscreant")]
             CipherType::Aes256PmacSiv => 8,
+
+            CipherType::Aead2022Blake3Aes128Gcm => AES_128_GCM.nonce_len(),
+            CipherType::Aead2022Blake3Aes256Gcm => AES_256_GCM.nonce_len(),
+            CipherType::Aead2022Blake3ChaCha20Poly1305 => CHACHA20_POLY1305.nonce_len(),
         }
     }
 
@@ -566,13 +639,17 @@ impl CipherType {
             #[cfg(feature = "miscreant")]
             CipherType::Aes128PmacSiv | CipherType::Aes256PmacSiv => CipherCategory::Aead,
 
+            CipherType::Aead2022Blake3Aes128Gcm
+            | CipherType::Aead2022Blake3Aes256Gcm
+            | CipherType::Aead2022Blake3ChaCha20Poly1305 => CipherCategory::Aead2022,
+
             _ => CipherCategory::Stream,
         }
     }
 
     /// Get tag size for AEAD Ciphers
     pub fn tag_size(self) -> usize {
-        assert!(self.category() == CipherCategory::Aead);
+        assert!(self.category() != CipherCategory::Stream);
 
         match self {
             CipherType::Aes128Gcm => AES_128_GCM.tag_len(),
@@ -584,13 +661,17 @@ impl CipherType {
             #[cfg(feature = "miscreant")]
             CipherType::Aes128PmacSiv | CipherType::Aes256PmacSiv => 16,
 
+            CipherType::Aead2022Blake3Aes128Gcm => AES_128_GCM.tag_len(),
+            CipherType::Aead2022Blake3Aes256Gcm => AES_256_GCM.tag_len(),
+            CipherType::Aead2022Blake3ChaCha20Poly1305 => CHACHA20_POLY1305.tag_len(),
+
             _ => panic!("Only support AEAD ciphers, found {:?}", self),
         }
     }
 
     /// Get nonce size for AEAD ciphers
     pub fn salt_size(self) -> usize {
-        assert!(self.category() == CipherCategory::Aead);
+        assert!(self.category() != CipherCategory::Stream);
         self.key_size()
     }
 
@@ -692,6 +773,10 @@ impl FromStr for CipherType {
             #[cfg(feature = "miscreant")]
             CIPHER_AES_256_PMAC_SIV => Ok(CipherType::Aes256PmacSiv),
 
+            CIPHER_AEAD2022_BLAKE3_AES_128_GCM => Ok(CipherType::Aead2022Blake3Aes128Gcm),
+            CIPHER_AEAD2022_BLAKE3_AES_256_GCM => Ok(CipherType::Aead2022Blake3Aes256Gcm),
+            CIPHER_AEAD2022_BLAKE3_CHACHA20_POLY1305 => Ok(CipherType::Aead2022Blake3ChaCha20Poly1305),
+
             _ => Err(Error::UnknownCipherType),
         }
     }
@@ -785,12 +870,18 @@ impl Display for CipherType {
             CipherType::Aes128PmacSiv => write!(f, "{}", CIPHER_AES_128_PMAC_SIV),
             #[cfg(feature = "miscreant")]
             CipherType::Aes256PmacSiv => write!(f, "{}", CIPHER_AES_256_PMAC_SIV),
+
+            CipherType::Aead2022Blake3Aes128Gcm => write!(f, "{}", CIPHER_AEAD2022_BLAKE3_AES_128_GCM),
+            CipherType::Aead2022Blake3Aes256Gcm => write!(f, "{}", CIPHER_AEAD2022_BLAKE3_AES_256_GCM),
+            CipherType::Aead2022Blake3ChaCha20Poly1305 => write!(f, "{}", CIPHER_AEAD2022_BLAKE3_CHACHA20_POLY1305),
         }
     }
 }
 
 #[cfg(test)]
 mod test_cipher {
+    use bytes::Bytes;
+
     use crate::crypto::{new_stream, CipherType, CryptoMode};
 
     #[test]
@@ -809,6 +900,38 @@ mod test_cipher {
         assert!(message.as_bytes() == &decrypted_msg[..]);
     }
 
+    #[test]
+    fn test_aead_2022_key() {
+        let ty = CipherType::Aead2022Blake3Aes128Gcm;
+        assert!(ty.check_key("AAECAwQFBgcICQoLDA0ODw==").is_ok());
+        assert!(ty.check_key("AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8=").is_err());
+        assert!(ty.check_key("password").is_err());
+        assert_eq!(
+            &ty.bytes_to_key(b"AAECAwQFBgcICQoLDA0ODw==")[..],
+            &(0u8..16).collect::<Vec<_>>()[..]
+        );
+        assert!(ty.identity_keys(b"AAECAwQFBgcICQoLDA0ODw==").is_empty());
+
+        // Identity keys of servers are followed by the user's key
+        let chain = "EBESExQVFhcYGRobHB0eHw==:AAECAwQFBgcICQoLDA0ODw==";
+        assert!(ty.check_key(chain).is_ok());
+        assert!(ty.check_key("EBESExQVFhcYGRobHB0eHw==:password").is_err());
+        assert_eq!(
+            &ty.bytes_to_key(chain.as_bytes())[..],
+            &(0u8..16).collect::<Vec<_>>()[..]
+        );
+        assert_eq!(
+            ty.identity_keys(chain.as_bytes()),
+            vec![Bytes::from((16u8..32).collect::<Vec<_>>())]
+        );
+
+        // ChaCha20-Poly1305 doesn't support identity headers
+        let ty = CipherType::Aead2022Blake3ChaCha20Poly1305;
+        let key = "AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8=";
+        assert!(ty.check_key(key).is_ok());
+        assert!(ty.check_key(&format!("{}:{}", key, key)).is_err());
+    }
+
     #[cfg(feature = "rc4")]
     #[test]
     fn test_rc4_md5_key_iv() {
@@ -19,7 +19,9 @@ use ring::{
 
 use crate::crypto::{
     aead::{increase_nonce, make_skey},
+    aead2022,
     cipher::Error,
+    CipherCategory,
     AeadDecryptor,
     AeadEncryptor,
     CipherResult,
@@ -28,7 +30,7 @@ use crate::crypto::{
 
 use byte_string::ByteStr;
 use bytes::{BufMut, BytesMut};
-use log::error;
+use log::debug;
 
 /// AEAD ciphers provided by Ring
 pub enum RingAeadCryptoVariant {
@@ -72,7 +74,10 @@ impl RingAeadCipher {
         // Nonce is 12 bytes
         assert_eq!(t.iv_size(), NONCE_LEN);
 
-        let skey = make_skey(t, key, salt);
+        let skey = match t.category() {
+            CipherCategory::Aead2022 => aead2022::make_skey(key, salt),
+            _ => make_skey(t, key, salt),
+        };
         let cipher = RingAeadCipher::new_variant(t, &skey, is_seal);
         RingAeadCipher { cipher, cipher_type: t }
     }
@@ -82,6 +87,9 @@ impl RingAeadCipher {
             CipherType::Aes128Gcm => RingAeadCipher::new_crypt(&AES_128_GCM, key, is_seal),
             CipherType::Aes256Gcm => RingAeadCipher::new_crypt(&AES_256_GCM, key, is_seal),
             CipherType::ChaCha20IetfPoly1305 => RingAeadCipher::new_crypt(&CHACHA20_POLY1305, key, is_seal),
+            CipherType::Aead2022Blake3Aes128Gcm => RingAeadCipher::new_crypt(&AES_128_GCM, key, is_seal),
+            CipherType::Aead2022Blake3Aes256Gcm => RingAeadCipher::new_crypt(&AES_256_GCM, key, is_seal),
+            CipherType::Aead2022Blake3ChaCha20Poly1305 => RingAeadCipher::new_crypt(&CHACHA20_POLY1305, key, is_seal),
             _ => panic!("unsupported cipher in ring {:?}", t),
         }
     }
@@ -134,7 +142,7 @@ impl AeadDecryptor for RingAeadCipher {
                     Ok(())
                 }
                 Err(..) => {
-                    error!(
+                    debug!(
                         "AEAD decrypt failed, input={:?}, tag={:?}, opening: {:?}",
                         ByteStr::new(&input[..output.len()]),
                         ByteStr::new(&input[output.len()..]),
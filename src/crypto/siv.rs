@@ -15,7 +15,7 @@ use crate::crypto::{
 
 use byte_string::ByteStr;
 use bytes::{BufMut, BytesMut};
-use log::error;
+use log::debug;
 
 /// AEAD ciphers provided by Miscreant
 pub enum MiscreantCryptoVariant {
@@ -121,7 +121,7 @@ impl AeadDecryptor for MiscreantCipher {
                 increase_nonce(&mut self.nonce);
             })
             .map_err(|_| {
-                error!(
+                debug!(
                     "AEAD decrypt failed, nonce={:?}, input={:?}, tag={:?}, err: decrypt failure",
                     ByteStr::new(&self.nonce),
                     ByteStr::new(&input[..input.len() - tag_size]),
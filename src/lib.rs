@@ -73,6 +73,27 @@
 //! let config = Config::load_from_file("shadowsocks.json", ConfigType::Server).unwrap();
 //! rt.block_on(run_server(config, rt_handle));
 //! ```
+//!
+//! Servers could also be added or removed while running with a `ServerRegistry`
+//!
+//! ```no_run
+//! use tokio::runtime::Runtime;
+//! use shadowsocks::{crypto::CipherType, run_server_with_registry, Config, ConfigType, ServerConfig, ServerRegistry};
+//!
+//! let mut rt = Runtime::new().expect("Failed to create runtime");
+//! let rt_handle = rt.handle().clone();
+//!
+//! let config = Config::load_from_file("shadowsocks.json", ConfigType::Server).unwrap();
+//! let registry = ServerRegistry::new();
+//! rt.spawn(run_server_with_registry(config, rt_handle, registry.clone()));
+//!
+//! let svr_cfg = ServerConfig::basic("0.0.0.0:8389".parse().unwrap(), "password".to_owned(), CipherType::Aes256Gcm);
+//! rt.block_on(registry.add_server(svr_cfg)).unwrap();
+//! rt.block_on(registry.remove_server(8389));
+//!
+//! // Closes all listeners, and waits for active relays to finish
+//! rt.block_on(registry.shutdown()).unwrap();
+//! ```
 
 #![crate_type = "lib"]
 #![crate_name = "shadowsocks"]
@@ -86,8 +107,13 @@ use tokio::runtime::Handle;
 pub const VERSION: &str = env!("CARGO_PKG_VERSION");
 
 pub use self::{
-    config::{ClientConfig, Config, ConfigType, Mode, ServerAddr, ServerConfig},
-    relay::{local::run as run_local, server::run as run_server, tcprelay::client::Socks5Client},
+    config::{ClientConfig, Config, ConfigType, Mode, ProxyConfig, ReplayFilterMode, ServerAddr, ServerConfig},
+    relay::{
+        local::run as run_local,
+        registry::ServerRegistry,
+        server::{run as run_server, run_with_registry as run_server_with_registry},
+        tcprelay::client::Socks5Client,
+    },
 };
 
 pub mod config;
@@ -0,0 +1,112 @@
+//! Loopback listeners of servers behind plugins
+//!
+//! On the server side, plugins forward connections to the port of ssserver. The port is bound before launching
+//! plugins and kept open while running, so it won't be taken by others between binding and listening.
+
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener as StdTcpListener, UdpSocket as StdUdpSocket},
+    sync::Arc,
+};
+
+use tokio::net::{TcpListener, UdpSocket};
+
+use crate::config::Mode;
+
+/// Sockets bound on a loopback port, shared by configurations of the server behind the plugin
+#[derive(Debug, Clone)]
+pub struct PluginListeners {
+    addr: SocketAddr,
+    tcp: Option<Arc<StdTcpListener>>,
+    udp: Option<Arc<StdUdpSocket>>,
+}
+
+impl PluginListeners {
+    /// Binds sockets of protocols relayed by the plugin, on the same port of `127.0.0.1`
+    pub fn bind(plugin_mode: Mode) -> io::Result<PluginListeners> {
+        let any_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 0);
+
+        if !plugin_mode.enable_tcp() {
+            let udp = StdUdpSocket::bind(any_addr)?;
+            udp.set_nonblocking(true)?;
+
+            return Ok(PluginListeners {
+                addr: udp.local_addr()?,
+                tcp: None,
+                udp: Some(Arc::new(udp)),
+            });
+        }
+
+        loop {
+            let tcp = StdTcpListener::bind(any_addr)?;
+            tcp.set_nonblocking(true)?;
+            let addr = tcp.local_addr()?;
+
+            let udp = if plugin_mode.enable_udp() {
+                match StdUdpSocket::bind(addr) {
+                    Ok(udp) => {
+                        udp.set_nonblocking(true)?;
+                        Some(Arc::new(udp))
+                    }
+                    // UDP port is taken, try another one
+                    Err(..) => continue,
+                }
+            } else {
+                None
+            };
+
+            return Ok(PluginListeners {
+                addr,
+                tcp: Some(Arc::new(tcp)),
+                udp,
+            });
+        }
+    }
+
+    /// Get the bound address
+    pub fn local_addr(&self) -> &SocketAddr {
+        &self.addr
+    }
+
+    /// Creates a TCP listener on the bound port, `None` if the plugin doesn't relay TCP
+    pub fn tcp_listener(&self) -> Option<io::Result<TcpListener>> {
+        self.tcp.as_ref().map(|l| l.try_clone().and_then(TcpListener::from_std))
+    }
+
+    /// Creates a UDP socket on the bound port, `None` if the plugin doesn't relay UDP
+    pub fn udp_socket(&self) -> Option<io::Result<UdpSocket>> {
+        self.udp.as_ref().map(|s| s.try_clone().and_then(UdpSocket::from_std))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use tokio::runtime::Builder;
+
+    #[test]
+    fn bind_same_port_for_udp() {
+        let listeners = PluginListeners::bind(Mode::TcpAndUdp).unwrap();
+        let addr = *listeners.local_addr();
+        assert!(addr.ip().is_loopback());
+
+        let mut rt = Builder::new().basic_scheduler().enable_all().build().unwrap();
+        rt.block_on(async {
+            let tcp = listeners.tcp_listener().unwrap().unwrap();
+            let udp = listeners.udp_socket().unwrap().unwrap();
+            assert_eq!(tcp.local_addr().unwrap(), addr);
+            assert_eq!(udp.local_addr().unwrap(), addr);
+        });
+
+        // Port is kept while listeners are alive
+        assert!(StdTcpListener::bind(addr).is_err());
+    }
+
+    #[test]
+    fn bind_udp_only() {
+        let listeners = PluginListeners::bind(Mode::UdpOnly).unwrap();
+        assert!(listeners.tcp_listener().is_none());
+        assert!(StdUdpSocket::bind(listeners.local_addr()).is_err());
+    }
+}
@@ -11,24 +11,52 @@
 //! |  SS Server +-- Local Loopback --+  Plugin Server (Tunnel)   +--+
 //! +------------+                    +---------------------------+
 //! ```
+//!
+//! Plugins relay TCP connections only, unless `plugin_mode` enables UDP (SIP003u). Those plugins listen on the same
+//! local port for both TCP and UDP. On the server side, that port is bound by ssserver itself, see mod `listener`.
+//!
+//! simple-obfs (`obfs-local` and `obfs-server`) is built in, see mod `obfs`. Other plugins are restarted if they
+//! exit, see mod `supervisor`.
 
-use crate::config::{Config, ServerAddr};
-use futures::stream::{FuturesUnordered, StreamExt};
+use self::{listener::PluginListeners, obfs::ObfsConfig, supervisor::PluginProcess};
+use crate::config::{Config, Mode, ServerAddr};
+use futures::future;
 use log::{error, info};
 use std::{
     io,
-    net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener},
+    net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener, UdpSocket},
+    process::Stdio,
 };
 use tokio::process::Child;
 
+pub mod listener;
+pub mod obfs;
 mod obfs_proxy;
 mod ss_plugin;
+pub mod supervisor;
 
 /// Config for plugin
 #[derive(Debug, Clone)]
 pub struct PluginConfig {
     pub plugin: String,
     pub plugin_opt: Option<String>,
+    /// Protocols relayed by the plugin
+    pub plugin_mode: Mode,
+}
+
+impl PluginConfig {
+    // `plugin_mode` in configuration, omitted for the default `tcp_only`
+    pub(crate) fn mode_to_config(&self) -> Option<String> {
+        match self.plugin_mode {
+            Mode::TcpOnly => None,
+            mode => Some(mode.to_string()),
+        }
+    }
+
+    /// Check if the plugin runs in process instead of a subprocess
+    pub fn is_builtin(&self) -> bool {
+        obfs::is_obfs_plugin(&self.plugin)
+    }
 }
 
 /// Mode of Plugin
@@ -40,30 +68,70 @@ pub enum PluginMode {
 
 /// Started plugins' subprocesses carrier
 pub struct Plugins {
-    plugins: FuturesUnordered<Child>,
+    plugins: Vec<PluginProcess>,
 }
 
 impl Plugins {
     /// Launch plugins in configuration.
     ///
-    /// Will modify servers' listen addresses to plugins' listen addresses.
+    /// Will modify servers' listen addresses to plugins' listen addresses. Built-in plugins are set to servers
+    /// without listening addresses.
+    ///
+    /// Plugins of `PluginMode::Server` relay from servers' public addresses (`SS_REMOTE_*`) to loopback ports
+    /// (`SS_LOCAL_*`), which are bound before launching and kept in servers' `plugin_listeners`.
     pub fn launch_plugins(config: &mut Config, mode: PluginMode) -> io::Result<Plugins> {
-        let plugins = FuturesUnordered::new();
+        let mut plugins = Vec::new();
+        let mut has_builtin = false;
 
         for svr in &mut config.server {
             let mut svr_addr_opt = None;
+            let mut status_opt = None;
+            let mut obfs_opt = None;
+            let mut listeners_opt = None;
+
+            if let Some(c) = svr.plugin().filter(|c| c.is_builtin()) {
+                if c.plugin_mode.enable_udp() {
+                    let msg = format!("built-in plugin \"{}\" doesn't relay UDP", c.plugin);
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+                }
 
-            if let Some(c) = svr.plugin() {
-                let loop_ip = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
-                let local_addr = SocketAddr::new(loop_ip, get_local_port()?);
+                let obfs = match ObfsConfig::from_opts(c.plugin_opt.as_ref().map(AsRef::as_ref)) {
+                    Ok(o) => o,
+                    Err(err) => {
+                        error!("Failed to start plugin \"{}\", err: {}", c.plugin, err);
+                        return Err(err);
+                    }
+                };
+
+                info!(
+                    "Started built-in plugin \"{}\" ({:?}) for {}",
+                    c.plugin,
+                    obfs.obfs(),
+                    svr.addr()
+                );
+                obfs_opt = Some(obfs);
+            } else if let Some(c) = svr.plugin() {
+                let (local_addr, listeners) = match mode {
+                    PluginMode::Client => {
+                        let loop_ip = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+                        let local_addr = SocketAddr::new(loop_ip, get_local_port(c.plugin_mode.enable_udp())?);
+                        (local_addr, None)
+                    }
+                    PluginMode::Server => {
+                        // Bound before launching, so plugins won't forward to a port taken by others
+                        let listeners = PluginListeners::bind(c.plugin_mode)?;
+                        (*listeners.local_addr(), Some(listeners))
+                    }
+                };
 
-                let svr_addr = match start_plugin(c, svr.addr(), &local_addr, mode) {
+                let svr_addr = match PluginProcess::start(c, svr.addr(), &local_addr, mode) {
                     Err(err) => {
                         error!("Failed to start plugin \"{}\", err: {}", c.plugin, err);
                         return Err(err);
                     }
                     Ok(process) => {
                         let svr_addr = ServerAddr::SocketAddr(local_addr);
+                        status_opt = Some(process.status().clone());
                         plugins.push(process);
 
                         // Replace addr with plugin
@@ -72,44 +140,59 @@ impl Plugins {
                 };
 
                 match mode {
-                    PluginMode::Client => info!("Started plugin \"{}\" on {} <-> {}", c.plugin, local_addr, svr.addr()),
-                    PluginMode::Server => info!("Started plugin \"{}\" on {} <-> {}", c.plugin, svr.addr(), local_addr),
+                    PluginMode::Client => info!(
+                        "Started plugin \"{}\" ({}) on {} <-> {}",
+                        c.plugin,
+                        c.plugin_mode,
+                        local_addr,
+                        svr.addr()
+                    ),
+                    PluginMode::Server => info!(
+                        "Started plugin \"{}\" ({}) on {} <-> {}",
+                        c.plugin,
+                        c.plugin_mode,
+                        svr.addr(),
+                        local_addr
+                    ),
                 }
 
                 svr_addr_opt = Some(svr_addr); // Fuck borrow checker
+                listeners_opt = listeners;
             }
 
             if let Some(svr_addr) = svr_addr_opt {
                 svr.set_plugin_addr(svr_addr);
             }
+
+            if let Some(listeners) = listeners_opt {
+                svr.set_plugin_listeners(listeners);
+            }
+
+            if let Some(status) = status_opt {
+                svr.set_plugin_status(status);
+            }
+
+            if let Some(obfs) = obfs_opt {
+                svr.set_obfs(obfs);
+                has_builtin = true;
+            }
         }
 
-        if plugins.is_empty() {
+        if plugins.is_empty() && !has_builtin {
             panic!("Didn't find any plugins to start");
         }
 
         Ok(Plugins { plugins })
     }
 
-    /// Returns a future that completes when any plugin terminates or there were an error in watching the subprocess.
+    /// Returns a future that supervises all plugins, exited plugins are restarted. It never completes.
+    ///
+    /// Plugins are killed when the future is dropped.
     pub async fn into_future(self) -> io::Result<()> {
-        // Turn the vector of `Child` futures into a single future that
-        // completes with an error if any of them exits or waiting for it
-        // fails. When this future completes, the remaining `Child`ren will be
-        // dropped and as a result the rest of the plugins will be killed
-        // automatically.
-
-        match self.plugins.into_future().await {
-            (Some(Ok(first_plugin_exit_status)), _) => {
-                let msg = format!("Plugin exited unexpectedly with {}", first_plugin_exit_status);
-                Err(io::Error::new(io::ErrorKind::Other, msg))
-            }
-            (Some(Err(first_plugin_error)), _) => {
-                error!("Error while waiting for plugin subprocess: {}", first_plugin_error);
-                Err(first_plugin_error)
-            }
-            _ => unreachable!(),
-        }
+        future::join_all(self.plugins.into_iter().map(PluginProcess::supervise)).await;
+
+        // Built-in plugins only
+        future::pending().await
     }
 }
 
@@ -119,13 +202,20 @@ fn start_plugin(plugin: &PluginConfig, remote: &ServerAddr, local: &SocketAddr,
     } else {
         ss_plugin::plugin_cmd(plugin, remote, local, mode)
     };
+    cmd.stdout(Stdio::piped()).stderr(Stdio::piped()).kill_on_drop(true);
     cmd.spawn()
 }
 
-fn get_local_port() -> io::Result<u16> {
-    let listener = TcpListener::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 0))?;
-    let addr = listener.local_addr()?;
-    Ok(addr.port())
+// Plugins relaying UDP listen on the same port of TCP and UDP, so both of them should be available
+fn get_local_port(with_udp: bool) -> io::Result<u16> {
+    loop {
+        let listener = TcpListener::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 0))?;
+        let addr = listener.local_addr()?;
+
+        if !with_udp || UdpSocket::bind(addr).is_ok() {
+            return Ok(addr.port());
+        }
+    }
 }
 
 #[cfg(test)]
@@ -134,7 +224,13 @@ mod test {
 
     #[test]
     fn generate_random_port() {
-        let port = get_local_port().unwrap();
+        let port = get_local_port(false).unwrap();
         println!("{:?}", port);
     }
+
+    #[test]
+    fn generate_random_port_with_udp() {
+        let port = get_local_port(true).unwrap();
+        UdpSocket::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), port)).unwrap();
+    }
 }
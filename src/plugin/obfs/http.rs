@@ -0,0 +1,225 @@
+//! simple-obfs HTTP
+//!
+//! The first packet of the client is sent with a WebSocket upgrade request, and the first packet of the server is
+//! sent with the `101 Switching Protocols` response. The rest of data is relayed as is.
+
+use std::io;
+
+use bytes::{Bytes, BytesMut};
+use rand::{self, Rng};
+use time::OffsetDateTime;
+
+use super::ObfsConfig;
+
+// Headers larger than this are not from simple-obfs
+const MAX_HEADER_SIZE: usize = 8 * 1024;
+
+pub struct HttpObfs {
+    // Request line and `Host` of the client, `None` for the server
+    request: Option<(String, String, String)>,
+    header_sent: bool,
+    header_received: bool,
+}
+
+impl HttpObfs {
+    pub fn client(config: &ObfsConfig, port: u16) -> HttpObfs {
+        let host = if port == 80 {
+            config.host.clone()
+        } else {
+            format!("{}:{}", config.host, port)
+        };
+
+        HttpObfs {
+            request: Some((config.method.clone(), config.uri.clone(), host)),
+            header_sent: false,
+            header_received: false,
+        }
+    }
+
+    pub fn server() -> HttpObfs {
+        HttpObfs {
+            request: None,
+            header_sent: false,
+            header_received: false,
+        }
+    }
+
+    pub fn encode(&mut self, payload: &[u8], buf: &mut BytesMut) -> usize {
+        if !self.header_sent {
+            self.header_sent = true;
+
+            let mut rng = rand::thread_rng();
+            let header = match self.request {
+                Some((ref method, ref uri, ref host)) => format!(
+                    "{} {} HTTP/1.1\r\n\
+                     Host: {}\r\n\
+                     User-Agent: curl/7.{}.{}\r\n\
+                     Upgrade: websocket\r\n\
+                     Connection: Upgrade\r\n\
+                     Sec-WebSocket-Key: {}\r\n\
+                     Content-Length: {}\r\n\
+                     \r\n",
+                    method,
+                    uri,
+                    host,
+                    rng.gen_range(0, 51),
+                    rng.gen_range(0, 2),
+                    base64::encode(&rand::random::<[u8; 16]>()),
+                    payload.len()
+                ),
+                None => format!(
+                    "HTTP/1.1 101 Switching Protocols\r\n\
+                     Server: nginx/1.{}.{}\r\n\
+                     Date: {}\r\n\
+                     Upgrade: websocket\r\n\
+                     Connection: Upgrade\r\n\
+                     Sec-WebSocket-Accept: {}\r\n\
+                     \r\n",
+                    rng.gen_range(0, 11),
+                    rng.gen_range(0, 12),
+                    OffsetDateTime::now_utc().format("%a, %d %b %Y %H:%M:%S GMT"),
+                    base64::encode(&rand::random::<[u8; 16]>())
+                ),
+            };
+            buf.extend_from_slice(header.as_bytes());
+        }
+
+        buf.extend_from_slice(payload);
+        payload.len()
+    }
+
+    pub fn decode(&mut self, buf: &mut BytesMut) -> io::Result<Option<Bytes>> {
+        if !self.header_received {
+            let header_len = match buf.windows(4).position(|w| w == b"\r\n\r\n") {
+                Some(pos) => pos + 4,
+                None if buf.len() > MAX_HEADER_SIZE => {
+                    let err = io::Error::new(io::ErrorKind::InvalidData, "obfs http header too large");
+                    return Err(err);
+                }
+                None => return Ok(None),
+            };
+
+            let header = buf.split_to(header_len);
+            let valid = match self.request {
+                Some(..) => header.starts_with(b"HTTP/1.1 101 "),
+                None => is_upgrade_request(&header),
+            };
+            if !valid {
+                let err = io::Error::new(io::ErrorKind::InvalidData, "invalid obfs http header");
+                return Err(err);
+            }
+
+            self.header_received = true;
+        }
+
+        if buf.is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some(buf.split().freeze()))
+        }
+    }
+}
+
+// Requests of simple-obfs upgrade to WebSocket
+fn is_upgrade_request(header: &[u8]) -> bool {
+    let header = match std::str::from_utf8(header) {
+        Ok(h) => h,
+        Err(..) => return false,
+    };
+
+    let mut lines = header.split("\r\n");
+    let request_line = lines.next().unwrap_or("");
+    if request_line.split(' ').nth(2) != Some("HTTP/1.1") {
+        return false;
+    }
+
+    for line in lines {
+        let mut sp = line.splitn(2, ':');
+        if let (Some(name), Some(value)) = (sp.next(), sp.next()) {
+            if name.trim().eq_ignore_ascii_case("upgrade") && value.trim().eq_ignore_ascii_case("websocket") {
+                return true;
+            }
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Written by `obfs_http_request` of simple-obfs with host `www.bing.com` on port 8388 and payload `payload`
+    const SIMPLE_OBFS_REQUEST: &[u8] = b"GET / HTTP/1.1\r\n\
+        Host: www.bing.com:8388\r\n\
+        User-Agent: curl/7.32.1\r\n\
+        Upgrade: websocket\r\n\
+        Connection: Upgrade\r\n\
+        Sec-WebSocket-Key: A9uGSbf2Bk7fEbDsdhyFHQ==\r\n\
+        Content-Length: 7\r\n\
+        \r\n\
+        payload";
+
+    // Written by `obfs_http_response` of simple-obfs with payload `response`
+    const SIMPLE_OBFS_RESPONSE: &[u8] = b"HTTP/1.1 101 Switching Protocols\r\n\
+        Server: nginx/1.8.4\r\n\
+        Date: Sat, 17 Oct 2026 05:03:12 GMT\r\n\
+        Upgrade: websocket\r\n\
+        Connection: Upgrade\r\n\
+        Sec-WebSocket-Accept: 3t8CeWtLcpNtHhJ3Wug5vQ==\r\n\
+        \r\n\
+        response";
+
+    #[test]
+    fn request_header() {
+        let config = ObfsConfig::from_opts(Some("obfs=http;obfs-host=www.bing.com")).unwrap();
+        let mut client = HttpObfs::client(&config, 8388);
+
+        let mut buf = BytesMut::new();
+        assert_eq!(client.encode(b"payload", &mut buf), 7);
+
+        assert!(buf.starts_with(b"GET / HTTP/1.1\r\nHost: www.bing.com:8388\r\nUser-Agent: curl/7."));
+        assert!(buf.ends_with(b"\r\nContent-Length: 7\r\n\r\npayload"));
+
+        let mut server = HttpObfs::server();
+        assert_eq!(server.decode(&mut buf).unwrap(), Some(Bytes::from_static(b"payload")));
+    }
+
+    #[test]
+    fn reject_plain_request() {
+        let mut server = HttpObfs::server();
+        let mut buf = BytesMut::from(&b"GET / HTTP/1.1\r\nHost: www.bing.com\r\n\r\n"[..]);
+        assert!(server.decode(&mut buf).is_err());
+    }
+
+    #[test]
+    fn request_of_simple_obfs() {
+        let mut server = HttpObfs::server();
+        let mut buf = BytesMut::from(SIMPLE_OBFS_REQUEST);
+        assert_eq!(server.decode(&mut buf).unwrap(), Some(Bytes::from_static(b"payload")));
+
+        buf.extend_from_slice(b"more");
+        assert_eq!(server.decode(&mut buf).unwrap(), Some(Bytes::from_static(b"more")));
+    }
+
+    #[test]
+    fn response_of_simple_obfs() {
+        let config = ObfsConfig::from_opts(Some("obfs=http;obfs-host=www.bing.com")).unwrap();
+        let mut client = HttpObfs::client(&config, 8388);
+        let mut buf = BytesMut::from(SIMPLE_OBFS_RESPONSE);
+        assert_eq!(client.decode(&mut buf).unwrap(), Some(Bytes::from_static(b"response")));
+
+        let mut server = HttpObfs::server();
+        let mut buf = BytesMut::new();
+        server.encode(b"response", &mut buf);
+
+        // Same headers in the same order, with different versions, date and key
+        let names = |header: &[u8]| {
+            std::str::from_utf8(header)
+                .unwrap()
+                .split("\r\n")
+                .map(|line| line.split(':').next().unwrap().to_owned())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(names(&buf[..]), names(SIMPLE_OBFS_RESPONSE));
+    }
+}
@@ -0,0 +1,353 @@
+//! Built-in simple-obfs transport
+//!
+//! Plugins `obfs-local` and `obfs-server` are not started as subprocesses. Connections between the local and the
+//! server are wrapped in the HTTP or TLS framing of [simple-obfs](https://github.com/shadowsocks/simple-obfs) in
+//! process, which interoperates with the simple-obfs binaries running on the other side.
+
+use std::{
+    cmp,
+    io,
+    ops::{Deref, DerefMut},
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use bytes::{Buf, Bytes, BytesMut};
+use futures::ready;
+use log::warn;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use self::{http::HttpObfs, tls::TlsObfs};
+
+mod http;
+mod tls;
+
+/// Names of the built-in plugins
+const OBFS_PLUGINS: &[&str] = &["obfs-local", "obfs-server"];
+
+const DEFAULT_OBFS_HOST: &str = "cloudfront.net";
+
+const READ_BUFFER_SIZE: usize = 8 * 1024;
+
+/// Check if `plugin` is the built-in simple-obfs
+pub fn is_obfs_plugin(plugin: &str) -> bool {
+    OBFS_PLUGINS.contains(&plugin)
+}
+
+/// Framing of the obfuscated stream
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ObfsType {
+    Http,
+    Tls,
+}
+
+/// Options of simple-obfs
+#[derive(Debug, Clone)]
+pub struct ObfsConfig {
+    obfs: ObfsType,
+    host: String,
+    uri: String,
+    method: String,
+}
+
+impl ObfsConfig {
+    /// Parses options of simple-obfs, e.g. `obfs=http;obfs-host=www.bing.com`
+    pub fn from_opts(opts: Option<&str>) -> io::Result<ObfsConfig> {
+        let mut obfs = None;
+        let mut host = DEFAULT_OBFS_HOST.to_owned();
+        let mut uri = "/".to_owned();
+        let mut method = "GET".to_owned();
+
+        for opt in opts.unwrap_or("").split(';') {
+            if opt.is_empty() {
+                continue;
+            }
+
+            let mut sp = opt.splitn(2, '=');
+            let key = sp.next().unwrap_or("");
+            let value = sp.next().unwrap_or("");
+
+            match key {
+                "obfs" => {
+                    obfs = match value {
+                        "http" => Some(ObfsType::Http),
+                        "tls" => Some(ObfsType::Tls),
+                        _ => {
+                            let msg = format!("unsupported obfs \"{}\", should be \"http\" or \"tls\"", value);
+                            return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+                        }
+                    }
+                }
+                "obfs-host" => host = value.to_owned(),
+                "obfs-uri" => uri = value.to_owned(),
+                "http-method" => method = value.to_owned(),
+                _ => warn!("Ignored unsupported option \"{}\" of simple-obfs", opt),
+            }
+        }
+
+        match obfs {
+            Some(obfs) => Ok(ObfsConfig {
+                obfs,
+                host,
+                uri,
+                method,
+            }),
+            None => {
+                let err = io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "simple-obfs requires `obfs=http` or `obfs=tls`",
+                );
+                Err(err)
+            }
+        }
+    }
+
+    /// Get framing of the obfuscated stream
+    pub fn obfs(&self) -> ObfsType {
+        self.obfs
+    }
+}
+
+enum Obfs {
+    Http(HttpObfs),
+    Tls(TlsObfs),
+}
+
+impl Obfs {
+    // Appends obfuscated `payload` to `buf`, returns length of the consumed payload
+    fn encode(&mut self, payload: &[u8], buf: &mut BytesMut) -> usize {
+        match *self {
+            Obfs::Http(ref mut o) => o.encode(payload, buf),
+            Obfs::Tls(ref mut o) => o.encode(payload, buf),
+        }
+    }
+
+    // Takes the payload out of `buf`, `None` if it needs more data
+    fn decode(&mut self, buf: &mut BytesMut) -> io::Result<Option<Bytes>> {
+        match *self {
+            Obfs::Http(ref mut o) => o.decode(buf),
+            Obfs::Tls(ref mut o) => o.decode(buf),
+        }
+    }
+}
+
+/// Stream wrapped in simple-obfs framing, data is relayed as is if obfs is not set
+pub struct ObfsStream<S> {
+    stream: S,
+    obfs: Option<Obfs>,
+    // Received obfuscated data
+    read_buf: BytesMut,
+    // Payload which is not read yet
+    plain: Bytes,
+    // Obfuscated data which is not sent yet
+    write_buf: BytesMut,
+}
+
+impl<S> ObfsStream<S> {
+    /// Create a stream without obfs
+    pub fn new(stream: S) -> ObfsStream<S> {
+        ObfsStream {
+            stream,
+            obfs: None,
+            read_buf: BytesMut::new(),
+            plain: Bytes::new(),
+            write_buf: BytesMut::new(),
+        }
+    }
+
+    /// Obfuscates as the client of a server listening on `port`
+    pub fn set_client_obfs(&mut self, config: &ObfsConfig, port: u16) {
+        self.obfs = Some(match config.obfs {
+            ObfsType::Http => Obfs::Http(HttpObfs::client(config, port)),
+            ObfsType::Tls => Obfs::Tls(TlsObfs::client(config)),
+        });
+    }
+
+    /// Obfuscates as the server
+    pub fn set_server_obfs(&mut self, config: &ObfsConfig) {
+        self.obfs = Some(match config.obfs {
+            ObfsType::Http => Obfs::Http(HttpObfs::server()),
+            ObfsType::Tls => Obfs::Tls(TlsObfs::server()),
+        });
+    }
+}
+
+impl<S> Deref for ObfsStream<S> {
+    type Target = S;
+
+    fn deref(&self) -> &Self::Target {
+        &self.stream
+    }
+}
+
+impl<S> DerefMut for ObfsStream<S> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.stream
+    }
+}
+
+impl<S> ObfsStream<S>
+where
+    S: AsyncWrite + Unpin,
+{
+    fn poll_write_pending(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.write_buf.is_empty() {
+            let n = ready!(Pin::new(&mut self.stream).poll_write(cx, &self.write_buf))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.write_buf.advance(n);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S> AsyncRead for ObfsStream<S>
+where
+    S: AsyncRead + Unpin,
+{
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        let obfs = match this.obfs {
+            Some(ref mut o) => o,
+            None => return Pin::new(&mut this.stream).poll_read(cx, buf),
+        };
+
+        loop {
+            if !this.plain.is_empty() {
+                let n = cmp::min(buf.len(), this.plain.len());
+                buf[..n].copy_from_slice(&this.plain[..n]);
+                this.plain.advance(n);
+                return Poll::Ready(Ok(n));
+            }
+
+            if let Some(plain) = obfs.decode(&mut this.read_buf)? {
+                this.plain = plain;
+                continue;
+            }
+
+            let mut chunk = [0u8; READ_BUFFER_SIZE];
+            let n = ready!(Pin::new(&mut this.stream).poll_read(cx, &mut chunk))?;
+            if n == 0 {
+                if this.read_buf.is_empty() {
+                    return Poll::Ready(Ok(0));
+                }
+
+                let err = io::Error::new(io::ErrorKind::UnexpectedEof, "unexpected eof in obfs frame");
+                return Poll::Ready(Err(err));
+            }
+            this.read_buf.extend_from_slice(&chunk[..n]);
+        }
+    }
+}
+
+impl<S> AsyncWrite for ObfsStream<S>
+where
+    S: AsyncWrite + Unpin,
+{
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        if this.obfs.is_none() {
+            return Pin::new(&mut this.stream).poll_write(cx, buf);
+        }
+
+        ready!(this.poll_write_pending(cx))?;
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        let n = match this.obfs {
+            Some(ref mut obfs) => obfs.encode(buf, &mut this.write_buf),
+            None => unreachable!(),
+        };
+
+        // Payload is accepted, the rest of the frame is sent in the following calls
+        if let Poll::Ready(Err(err)) = this.poll_write_pending(cx) {
+            return Poll::Ready(Err(err));
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_pending(cx))?;
+        Pin::new(&mut this.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_pending(cx))?;
+        Pin::new(&mut this.stream).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+        runtime::Builder,
+    };
+
+    async fn exchange(config: &ObfsConfig) {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+
+        let mut client = ObfsStream::new(client);
+        client.set_client_obfs(config, 8388);
+        let mut server = ObfsStream::new(server);
+        server.set_server_obfs(config);
+
+        let large = vec![0xA5u8; 40 * 1024];
+        let client_fut = async {
+            client.write_all(b"request").await.unwrap();
+            client.write_all(&large).await.unwrap();
+            client.flush().await.unwrap();
+
+            let mut buf = [0u8; 8];
+            client.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"response");
+        };
+        let server_fut = async {
+            let mut buf = [0u8; 7];
+            server.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"request");
+            let mut buf = vec![0u8; large.len()];
+            server.read_exact(&mut buf).await.unwrap();
+            assert_eq!(buf, large);
+
+            server.write_all(b"response").await.unwrap();
+            server.flush().await.unwrap();
+        };
+        futures::join!(client_fut, server_fut);
+    }
+
+    #[test]
+    fn parse_opts() {
+        let config = ObfsConfig::from_opts(Some("obfs=tls;obfs-host=www.bing.com;fast-open")).unwrap();
+        assert_eq!(config.obfs(), ObfsType::Tls);
+        assert_eq!(config.host, "www.bing.com");
+
+        assert!(ObfsConfig::from_opts(None).is_err());
+        assert!(ObfsConfig::from_opts(Some("obfs=ws")).is_err());
+    }
+
+    #[test]
+    fn http_exchange() {
+        let mut rt = Builder::new().basic_scheduler().enable_all().build().unwrap();
+        let config = ObfsConfig::from_opts(Some("obfs=http")).unwrap();
+        rt.block_on(exchange(&config));
+    }
+
+    #[test]
+    fn tls_exchange() {
+        let mut rt = Builder::new().basic_scheduler().enable_all().build().unwrap();
+        let config = ObfsConfig::from_opts(Some("obfs=tls")).unwrap();
+        rt.block_on(exchange(&config));
+    }
+}
@@ -0,0 +1,392 @@
+//! simple-obfs TLS
+//!
+//! The first packet of the client is sent in the session ticket of a TLS 1.2 ClientHello. The server responds with
+//! ServerHello and ChangeCipherSpec, then its first packet in an encrypted Finished handshake. The rest of data is
+//! sent in application data records.
+
+use std::{
+    cmp,
+    io,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+
+use super::ObfsConfig;
+
+const CONTENT_TYPE_CHANGE_CIPHER_SPEC: u8 = 0x14;
+const CONTENT_TYPE_HANDSHAKE: u8 = 0x16;
+const CONTENT_TYPE_APPLICATION_DATA: u8 = 0x17;
+
+const HANDSHAKE_TYPE_CLIENT_HELLO: u8 = 0x01;
+
+const EXTENSION_SERVER_NAME: u16 = 0x0000;
+const EXTENSION_SESSION_TICKET: u16 = 0x0023;
+
+const RECORD_HEADER_SIZE: usize = 5;
+
+// Payload in a record, maximum length of TLS plaintext
+const MAX_PAYLOAD_SIZE: usize = 16 * 1024;
+
+const CIPHER_SUITES: [u8; 56] = [
+    0xc0, 0x2c, 0xc0, 0x30, 0x00, 0x9f, 0xcc, 0xa9, 0xcc, 0xa8, 0xcc, 0xaa, 0xc0, 0x2b, 0xc0, 0x2f, 0x00, 0x9e, 0xc0,
+    0x24, 0xc0, 0x28, 0x00, 0x6b, 0xc0, 0x23, 0xc0, 0x27, 0x00, 0x67, 0xc0, 0x0a, 0xc0, 0x14, 0x00, 0x39, 0xc0, 0x09,
+    0xc0, 0x13, 0x00, 0x33, 0x00, 0x9d, 0x00, 0x9c, 0x00, 0x3d, 0x00, 0x3c, 0x00, 0x35, 0x00, 0x2f, 0x00, 0xff,
+];
+
+// ec_point_formats, elliptic_curves, signature_algorithms, encrypt_then_mac and extended_master_secret
+const CLIENT_HELLO_EXTENSIONS: [u8; 66] = [
+    0x00, 0x0b, 0x00, 0x04, 0x03, 0x01, 0x00, 0x02, 0x00, 0x0a, 0x00, 0x0a, 0x00, 0x08, 0x00, 0x1d, 0x00, 0x17, 0x00,
+    0x19, 0x00, 0x18, 0x00, 0x0d, 0x00, 0x20, 0x00, 0x1e, 0x06, 0x01, 0x06, 0x02, 0x06, 0x03, 0x05, 0x01, 0x05, 0x02,
+    0x05, 0x03, 0x04, 0x01, 0x04, 0x02, 0x04, 0x03, 0x03, 0x01, 0x03, 0x02, 0x03, 0x03, 0x02, 0x01, 0x02, 0x02, 0x02,
+    0x03, 0x00, 0x16, 0x00, 0x00, 0x00, 0x17, 0x00, 0x00,
+];
+
+// renegotiation_info, extended_master_secret and ec_point_formats
+const SERVER_HELLO_EXTENSIONS: [u8; 15] = [
+    0xff, 0x01, 0x00, 0x01, 0x00, 0x00, 0x17, 0x00, 0x00, 0x00, 0x0b, 0x00, 0x02, 0x01, 0x00,
+];
+
+pub struct TlsObfs {
+    // Server name of the client, `None` for the server
+    host: Option<String>,
+    // Session ID of the client, echoed in ServerHello
+    session_id: [u8; 32],
+    hello_sent: bool,
+    records_received: usize,
+}
+
+impl TlsObfs {
+    pub fn client(config: &ObfsConfig) -> TlsObfs {
+        TlsObfs {
+            host: Some(config.host.clone()),
+            session_id: rand::random(),
+            hello_sent: false,
+            records_received: 0,
+        }
+    }
+
+    pub fn server() -> TlsObfs {
+        TlsObfs {
+            host: None,
+            session_id: [0u8; 32],
+            hello_sent: false,
+            records_received: 0,
+        }
+    }
+
+    pub fn encode(&mut self, payload: &[u8], buf: &mut BytesMut) -> usize {
+        let payload = &payload[..cmp::min(payload.len(), MAX_PAYLOAD_SIZE)];
+
+        if self.hello_sent {
+            put_record_header(buf, CONTENT_TYPE_APPLICATION_DATA, 0x0303, payload.len());
+            buf.put_slice(payload);
+        } else {
+            self.hello_sent = true;
+            match self.host {
+                Some(ref host) => put_client_hello(buf, host, &self.session_id, payload),
+                None => {
+                    put_server_hello(buf, &self.session_id, payload.len());
+                    buf.put_slice(payload);
+                }
+            }
+        }
+
+        payload.len()
+    }
+
+    pub fn decode(&mut self, buf: &mut BytesMut) -> io::Result<Option<Bytes>> {
+        loop {
+            if buf.len() < RECORD_HEADER_SIZE {
+                return Ok(None);
+            }
+            let len = ((buf[3] as usize) << 8) | buf[4] as usize;
+            if buf.len() < RECORD_HEADER_SIZE + len {
+                return Ok(None);
+            }
+
+            let content_type = buf[0];
+            buf.advance(RECORD_HEADER_SIZE);
+            let body = buf.split_to(len).freeze();
+
+            let index = self.records_received;
+            self.records_received += 1;
+
+            let payload = match (self.host.is_some(), index, content_type) {
+                // ServerHello and ChangeCipherSpec
+                (true, 0, CONTENT_TYPE_HANDSHAKE) | (true, 1, CONTENT_TYPE_CHANGE_CIPHER_SPEC) => continue,
+                // Finished of the server
+                (true, 2, CONTENT_TYPE_HANDSHAKE) => body,
+                (true, i, CONTENT_TYPE_APPLICATION_DATA) if i > 2 => body,
+                (false, 0, CONTENT_TYPE_HANDSHAKE) => self.read_client_hello(&body)?,
+                (false, i, CONTENT_TYPE_APPLICATION_DATA) if i > 0 => body,
+                _ => {
+                    let err = io::Error::new(io::ErrorKind::InvalidData, "unexpected obfs tls record");
+                    return Err(err);
+                }
+            };
+
+            if !payload.is_empty() {
+                return Ok(Some(payload));
+            }
+        }
+    }
+
+    // Takes the session ID and the payload in the session ticket
+    fn read_client_hello(&mut self, body: &Bytes) -> io::Result<Bytes> {
+        let mut r = Reader(&body[..]);
+
+        if r.u8()? != HANDSHAKE_TYPE_CLIENT_HELLO {
+            let err = io::Error::new(io::ErrorKind::InvalidData, "obfs tls expecting ClientHello");
+            return Err(err);
+        }
+        r.take(3 + 2 + 32)?; // length, version and random
+
+        let session_id_len = r.u8()? as usize;
+        let session_id = r.take(session_id_len)?;
+        if session_id_len == self.session_id.len() {
+            self.session_id.copy_from_slice(session_id);
+        }
+
+        let cipher_suites_len = r.u16()? as usize;
+        r.take(cipher_suites_len)?;
+        let compression_methods_len = r.u8()? as usize;
+        r.take(compression_methods_len)?;
+
+        let extensions_len = r.u16()? as usize;
+        let mut r = Reader(r.take(extensions_len)?);
+        while !r.0.is_empty() {
+            let ext_type = r.u16()?;
+            let ext_len = r.u16()? as usize;
+            let ext = r.take(ext_len)?;
+
+            if ext_type == EXTENSION_SESSION_TICKET {
+                return Ok(body.slice_ref(ext));
+            }
+        }
+
+        let err = io::Error::new(
+            io::ErrorKind::InvalidData,
+            "obfs tls ClientHello without session ticket",
+        );
+        Err(err)
+    }
+}
+
+fn put_record_header(buf: &mut BytesMut, content_type: u8, version: u16, len: usize) {
+    buf.put_u8(content_type);
+    buf.put_u16(version);
+    buf.put_u16(len as u16);
+}
+
+fn put_random(buf: &mut BytesMut) {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    buf.put_u32(now as u32);
+    buf.put_slice(&rand::random::<[u8; 28]>());
+}
+
+// ClientHello with the payload in the session ticket extension, in the layout of simple-obfs
+fn put_client_hello(buf: &mut BytesMut, host: &str, session_id: &[u8; 32], payload: &[u8]) {
+    let server_name_len = 2 + 2 + 2 + 1 + 2 + host.len();
+    let session_ticket_len = 2 + 2 + payload.len();
+    let extensions_len = server_name_len + session_ticket_len + CLIENT_HELLO_EXTENSIONS.len();
+    let handshake_len = 2 + 32 + 1 + 32 + 2 + CIPHER_SUITES.len() + 1 + 1 + 2 + extensions_len;
+
+    buf.reserve(RECORD_HEADER_SIZE + 4 + handshake_len);
+
+    put_record_header(buf, CONTENT_TYPE_HANDSHAKE, 0x0301, 4 + handshake_len);
+    buf.put_u8(HANDSHAKE_TYPE_CLIENT_HELLO);
+    buf.put_u8(0);
+    buf.put_u16(handshake_len as u16);
+    buf.put_u16(0x0303);
+    put_random(buf);
+    buf.put_u8(session_id.len() as u8);
+    buf.put_slice(session_id);
+    buf.put_u16(CIPHER_SUITES.len() as u16);
+    buf.put_slice(&CIPHER_SUITES);
+    // Null compression only
+    buf.put_u8(1);
+    buf.put_u8(0);
+
+    buf.put_u16(extensions_len as u16);
+
+    // Servers of simple-obfs read the session ticket right after the header
+    buf.put_u16(EXTENSION_SESSION_TICKET);
+    buf.put_u16(payload.len() as u16);
+    buf.put_slice(payload);
+
+    buf.put_u16(EXTENSION_SERVER_NAME);
+    buf.put_u16((host.len() + 5) as u16);
+    buf.put_u16((host.len() + 3) as u16);
+    buf.put_u8(0); // host_name
+    buf.put_u16(host.len() as u16);
+    buf.put_slice(host.as_bytes());
+
+    buf.put_slice(&CLIENT_HELLO_EXTENSIONS);
+}
+
+// ServerHello, ChangeCipherSpec and the header of Finished carrying the payload
+fn put_server_hello(buf: &mut BytesMut, session_id: &[u8; 32], payload_len: usize) {
+    let handshake_len = 2 + 32 + 1 + 32 + 2 + 1 + 2 + SERVER_HELLO_EXTENSIONS.len();
+
+    put_record_header(buf, CONTENT_TYPE_HANDSHAKE, 0x0301, 4 + handshake_len);
+    buf.put_u8(0x02); // server_hello
+    buf.put_u8(0);
+    buf.put_u16(handshake_len as u16);
+    buf.put_u16(0x0303);
+    put_random(buf);
+    buf.put_u8(session_id.len() as u8);
+    buf.put_slice(session_id);
+    buf.put_u16(0xcca8); // ECDHE-RSA-CHACHA20-POLY1305
+    buf.put_u8(0);
+    buf.put_u16(SERVER_HELLO_EXTENSIONS.len() as u16);
+    buf.put_slice(&SERVER_HELLO_EXTENSIONS);
+
+    put_record_header(buf, CONTENT_TYPE_CHANGE_CIPHER_SPEC, 0x0303, 1);
+    buf.put_u8(0x01);
+
+    put_record_header(buf, CONTENT_TYPE_HANDSHAKE, 0x0303, payload_len);
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        if self.0.len() < n {
+            let err = io::Error::new(io::ErrorKind::InvalidData, "truncated obfs tls ClientHello");
+            return Err(err);
+        }
+        let (head, tail) = self.0.split_at(n);
+        self.0 = tail;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> io::Result<u16> {
+        let b = self.take(2)?;
+        Ok(((b[0] as u16) << 8) | b[1] as u16)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Written by `obfs_tls_request` of simple-obfs with host `www.bing.com` and payload `payload`, laid out from its
+    // `tls_client_hello`, `tls_ext_session_ticket`, `tls_ext_server_name` and `tls_ext_others` templates. Time and
+    // random bytes are at 11..43, and the session ID is at 44..76.
+    const SIMPLE_OBFS_CLIENT_HELLO: [u8; 236] = [
+        0x16, 0x03, 0x01, 0x00, 0xe7, 0x01, 0x00, 0x00, 0xe3, 0x03, 0x03, 0x5f, 0x5e, 0x10, 0x00, 0x40,
+        0x41, 0x42, 0x43, 0x44, 0x45, 0x46, 0x47, 0x48, 0x49, 0x4a, 0x4b, 0x4c, 0x4d, 0x4e, 0x4f, 0x50,
+        0x51, 0x52, 0x53, 0x54, 0x55, 0x56, 0x57, 0x58, 0x59, 0x5a, 0x5b, 0x20, 0x80, 0x81, 0x82, 0x83,
+        0x84, 0x85, 0x86, 0x87, 0x88, 0x89, 0x8a, 0x8b, 0x8c, 0x8d, 0x8e, 0x8f, 0x90, 0x91, 0x92, 0x93,
+        0x94, 0x95, 0x96, 0x97, 0x98, 0x99, 0x9a, 0x9b, 0x9c, 0x9d, 0x9e, 0x9f, 0x00, 0x38, 0xc0, 0x2c,
+        0xc0, 0x30, 0x00, 0x9f, 0xcc, 0xa9, 0xcc, 0xa8, 0xcc, 0xaa, 0xc0, 0x2b, 0xc0, 0x2f, 0x00, 0x9e,
+        0xc0, 0x24, 0xc0, 0x28, 0x00, 0x6b, 0xc0, 0x23, 0xc0, 0x27, 0x00, 0x67, 0xc0, 0x0a, 0xc0, 0x14,
+        0x00, 0x39, 0xc0, 0x09, 0xc0, 0x13, 0x00, 0x33, 0x00, 0x9d, 0x00, 0x9c, 0x00, 0x3d, 0x00, 0x3c,
+        0x00, 0x35, 0x00, 0x2f, 0x00, 0xff, 0x01, 0x00, 0x00, 0x62, 0x00, 0x23, 0x00, 0x07, 0x70, 0x61,
+        0x79, 0x6c, 0x6f, 0x61, 0x64, 0x00, 0x00, 0x00, 0x11, 0x00, 0x0f, 0x00, 0x00, 0x0c, 0x77, 0x77,
+        0x77, 0x2e, 0x62, 0x69, 0x6e, 0x67, 0x2e, 0x63, 0x6f, 0x6d, 0x00, 0x0b, 0x00, 0x04, 0x03, 0x01,
+        0x00, 0x02, 0x00, 0x0a, 0x00, 0x0a, 0x00, 0x08, 0x00, 0x1d, 0x00, 0x17, 0x00, 0x19, 0x00, 0x18,
+        0x00, 0x0d, 0x00, 0x20, 0x00, 0x1e, 0x06, 0x01, 0x06, 0x02, 0x06, 0x03, 0x05, 0x01, 0x05, 0x02,
+        0x05, 0x03, 0x04, 0x01, 0x04, 0x02, 0x04, 0x03, 0x03, 0x01, 0x03, 0x02, 0x03, 0x03, 0x02, 0x01,
+        0x02, 0x02, 0x02, 0x03, 0x00, 0x16, 0x00, 0x00, 0x00, 0x17, 0x00, 0x00,
+    ];
+
+    // Written by `obfs_tls_response` of simple-obfs to the ClientHello above with payload `response`, laid out from its
+    // `tls_server_hello`, `tls_change_spec` and `tls_encrypted_handshake` templates, followed by application data
+    // `more`. Time and random bytes are at 11..43.
+    const SIMPLE_OBFS_SERVER_HELLO: [u8; 124] = [
+        0x16, 0x03, 0x01, 0x00, 0x5b, 0x02, 0x00, 0x00, 0x57, 0x03, 0x03, 0x5f, 0x5e, 0x10, 0x01, 0x60,
+        0x61, 0x62, 0x63, 0x64, 0x65, 0x66, 0x67, 0x68, 0x69, 0x6a, 0x6b, 0x6c, 0x6d, 0x6e, 0x6f, 0x70,
+        0x71, 0x72, 0x73, 0x74, 0x75, 0x76, 0x77, 0x78, 0x79, 0x7a, 0x7b, 0x20, 0x80, 0x81, 0x82, 0x83,
+        0x84, 0x85, 0x86, 0x87, 0x88, 0x89, 0x8a, 0x8b, 0x8c, 0x8d, 0x8e, 0x8f, 0x90, 0x91, 0x92, 0x93,
+        0x94, 0x95, 0x96, 0x97, 0x98, 0x99, 0x9a, 0x9b, 0x9c, 0x9d, 0x9e, 0x9f, 0xcc, 0xa8, 0x00, 0x00,
+        0x0f, 0xff, 0x01, 0x00, 0x01, 0x00, 0x00, 0x17, 0x00, 0x00, 0x00, 0x0b, 0x00, 0x02, 0x01, 0x00,
+        0x14, 0x03, 0x03, 0x00, 0x01, 0x01, 0x16, 0x03, 0x03, 0x00, 0x08, 0x72, 0x65, 0x73, 0x70, 0x6f,
+        0x6e, 0x73, 0x65, 0x17, 0x03, 0x03, 0x00, 0x04, 0x6d, 0x6f, 0x72, 0x65,
+    ];
+
+    #[test]
+    fn client_hello_layout() {
+        let config = ObfsConfig::from_opts(Some("obfs=tls;obfs-host=www.bing.com")).unwrap();
+        let mut client = TlsObfs::client(&config);
+
+        let mut buf = BytesMut::new();
+        assert_eq!(client.encode(b"payload", &mut buf), 7);
+
+        // Sizes of tls_client_hello, tls_ext_session_ticket and tls_ext_server_name in simple-obfs
+        let sni = 138 + 4 + 7;
+        assert_eq!(buf.len(), sni + 9 + "www.bing.com".len() + 66);
+        assert_eq!(&buf[..3], &[0x16, 0x03, 0x01]);
+        assert_eq!(&buf[3..5], &((buf.len() - 5) as u16).to_be_bytes());
+        assert_eq!(&buf[138..142], &[0x00, 0x23, 0x00, 0x07]);
+        assert_eq!(&buf[142..sni], b"payload");
+        assert_eq!(&buf[sni..sni + 2], &[0x00, 0x00]);
+        assert_eq!(&buf[sni + 9..sni + 9 + 12], b"www.bing.com");
+
+        let mut server = TlsObfs::server();
+        assert_eq!(server.decode(&mut buf).unwrap(), Some(Bytes::from_static(b"payload")));
+        assert_eq!(server.session_id, client.session_id);
+    }
+
+    #[test]
+    fn server_hello_layout() {
+        let mut server = TlsObfs::server();
+
+        let mut buf = BytesMut::new();
+        server.encode(b"payload", &mut buf);
+
+        // Sizes of tls_server_hello, tls_change_spec and tls_encrypted_handshake in simple-obfs
+        assert_eq!(buf.len(), 96 + 6 + 5 + 7);
+        assert_eq!(&buf[..5], &[0x16, 0x03, 0x01, 0x00, 91]);
+        assert_eq!(&buf[96..102], &[0x14, 0x03, 0x03, 0x00, 0x01, 0x01]);
+        assert_eq!(&buf[102..107], &[0x16, 0x03, 0x03, 0x00, 0x07]);
+
+        server.encode(b"data", &mut buf);
+        assert_eq!(&buf[114..119], &[0x17, 0x03, 0x03, 0x00, 0x04]);
+    }
+
+    #[test]
+    fn client_hello_of_simple_obfs() {
+        let mut server = TlsObfs::server();
+        let mut buf = BytesMut::from(&SIMPLE_OBFS_CLIENT_HELLO[..]);
+        assert_eq!(server.decode(&mut buf).unwrap(), Some(Bytes::from_static(b"payload")));
+        assert_eq!(&server.session_id[..], &SIMPLE_OBFS_CLIENT_HELLO[44..76]);
+
+        let config = ObfsConfig::from_opts(Some("obfs=tls;obfs-host=www.bing.com")).unwrap();
+        let mut client = TlsObfs::client(&config);
+        client.session_id.copy_from_slice(&SIMPLE_OBFS_CLIENT_HELLO[44..76]);
+
+        let mut buf = BytesMut::new();
+        client.encode(b"payload", &mut buf);
+        assert_eq!(buf.len(), SIMPLE_OBFS_CLIENT_HELLO.len());
+        assert_eq!(&buf[..11], &SIMPLE_OBFS_CLIENT_HELLO[..11]);
+        assert_eq!(&buf[43..], &SIMPLE_OBFS_CLIENT_HELLO[43..]);
+    }
+
+    #[test]
+    fn server_hello_of_simple_obfs() {
+        let config = ObfsConfig::from_opts(Some("obfs=tls;obfs-host=www.bing.com")).unwrap();
+        let mut client = TlsObfs::client(&config);
+        let mut buf = BytesMut::from(&SIMPLE_OBFS_SERVER_HELLO[..]);
+        assert_eq!(client.decode(&mut buf).unwrap(), Some(Bytes::from_static(b"response")));
+        assert_eq!(client.decode(&mut buf).unwrap(), Some(Bytes::from_static(b"more")));
+
+        let mut server = TlsObfs::server();
+        server.session_id.copy_from_slice(&SIMPLE_OBFS_CLIENT_HELLO[44..76]);
+
+        let mut buf = BytesMut::new();
+        server.encode(b"response", &mut buf);
+        server.encode(b"more", &mut buf);
+        assert_eq!(buf.len(), SIMPLE_OBFS_SERVER_HELLO.len());
+        assert_eq!(&buf[..11], &SIMPLE_OBFS_SERVER_HELLO[..11]);
+        assert_eq!(&buf[43..], &SIMPLE_OBFS_SERVER_HELLO[43..]);
+    }
+}
@@ -4,6 +4,10 @@ use log::trace;
 use std::{net::SocketAddr, process::Stdio};
 use tokio::process::Command;
 
+/// Plugin command of SIP003
+///
+/// `SS_REMOTE_*` is the address of the remote server on the client side, or the public address that the plugin
+/// listens on on the server side. `SS_LOCAL_*` is always the loopback address between the plugin and shadowsocks.
 pub fn plugin_cmd(plugin: &PluginConfig, remote: &ServerAddr, local: &SocketAddr, _mode: PluginMode) -> Command {
     trace!("Start plugin \"{:?}\" remote: {}, local: {}", plugin, remote, local);
 
@@ -0,0 +1,234 @@
+//! Supervisor of plugin subprocesses
+//!
+//! Plugins are restarted with exponential backoff after they exit. Servers relaying through a plugin are marked
+//! unavailable until its port accepts connections again.
+
+use std::{
+    cmp,
+    io,
+    net::SocketAddr,
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use futures::{
+    future::{self, Either},
+    FutureExt,
+    StreamExt,
+};
+use log::{error, info};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, BufReader},
+    net::TcpStream,
+    process::Child,
+    time,
+};
+
+use super::{start_plugin, PluginConfig, PluginMode};
+use crate::config::ServerAddr;
+
+const INITIAL_RESTART_DELAY: Duration = Duration::from_secs(1);
+const MAX_RESTART_DELAY: Duration = Duration::from_secs(64);
+// Restart delay is reset if the plugin has been running for this long
+const STABLE_RUNNING_TIME: Duration = Duration::from_secs(60);
+const READY_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Availability of a plugin, shared by configurations of the server relaying through it
+#[derive(Debug, Clone, Default)]
+pub struct PluginStatus(Arc<AtomicBool>);
+
+impl PluginStatus {
+    /// Check if the plugin is accepting connections
+    pub fn is_available(&self) -> bool {
+        self.0.load(Ordering::Acquire)
+    }
+
+    fn set_available(&self, available: bool) {
+        self.0.store(available, Ordering::Release)
+    }
+}
+
+/// A running plugin subprocess
+pub struct PluginProcess {
+    plugin: PluginConfig,
+    remote: ServerAddr,
+    local: SocketAddr,
+    mode: PluginMode,
+    status: PluginStatus,
+    child: Child,
+}
+
+impl PluginProcess {
+    /// Starts the plugin, relaying between `remote` and `local`
+    pub fn start(
+        plugin: &PluginConfig,
+        remote: &ServerAddr,
+        local: &SocketAddr,
+        mode: PluginMode,
+    ) -> io::Result<PluginProcess> {
+        let child = spawn_plugin(plugin, remote, local, mode)?;
+        Ok(PluginProcess {
+            plugin: plugin.clone(),
+            remote: remote.clone(),
+            local: *local,
+            mode,
+            status: PluginStatus::default(),
+            child,
+        })
+    }
+
+    /// Get status of the plugin
+    pub fn status(&self) -> &PluginStatus {
+        &self.status
+    }
+
+    /// Restarts the plugin whenever it exits, never completes
+    pub async fn supervise(mut self) {
+        let ready_addr = self.ready_addr();
+        let mut delay = INITIAL_RESTART_DELAY;
+
+        loop {
+            let started = Instant::now();
+
+            let exit = match future::select(&mut self.child, wait_ready(ready_addr).boxed()).await {
+                Either::Left((exit, ..)) => exit,
+                Either::Right(..) => {
+                    info!("Plugin \"{}\" is ready on {}", self.plugin.plugin, self.local);
+                    self.status.set_available(true);
+                    (&mut self.child).await
+                }
+            };
+            self.status.set_available(false);
+
+            if started.elapsed() >= STABLE_RUNNING_TIME {
+                delay = INITIAL_RESTART_DELAY;
+            }
+
+            match exit {
+                Ok(status) => error!(
+                    "Plugin \"{}\" exited unexpectedly with {}, restarting in {:?}",
+                    self.plugin.plugin, status, delay
+                ),
+                Err(err) => error!(
+                    "Error while waiting for plugin \"{}\", {}, restarting in {:?}",
+                    self.plugin.plugin, err, delay
+                ),
+            }
+
+            loop {
+                time::delay_for(delay).await;
+                delay = cmp::min(delay * 2, MAX_RESTART_DELAY);
+
+                match spawn_plugin(&self.plugin, &self.remote, &self.local, self.mode) {
+                    Ok(child) => {
+                        info!("Restarted plugin \"{}\"", self.plugin.plugin);
+                        self.child = child;
+                        break;
+                    }
+                    Err(err) => error!(
+                        "Failed to restart plugin \"{}\", {}, retrying in {:?}",
+                        self.plugin.plugin, err, delay
+                    ),
+                }
+            }
+        }
+    }
+
+    // Local plugins relaying TCP are ready when connections are accepted, others are ready once started
+    fn ready_addr(&self) -> Option<SocketAddr> {
+        match self.mode {
+            PluginMode::Client if self.plugin.plugin_mode.enable_tcp() => Some(self.local),
+            _ => None,
+        }
+    }
+}
+
+fn spawn_plugin(plugin: &PluginConfig, remote: &ServerAddr, local: &SocketAddr, mode: PluginMode) -> io::Result<Child> {
+    let mut child = start_plugin(plugin, remote, local, mode)?;
+
+    // Outputs are logged with the plugin's name as the target
+    let target = Path::new(&plugin.plugin)
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or(&plugin.plugin)
+        .to_owned();
+    if let Some(stdout) = child.stdout.take() {
+        tokio::spawn(forward_output(target.clone(), stdout));
+    }
+    if let Some(stderr) = child.stderr.take() {
+        tokio::spawn(forward_output(target, stderr));
+    }
+
+    Ok(child)
+}
+
+async fn forward_output<R>(target: String, output: R)
+where
+    R: AsyncRead + Unpin,
+{
+    let mut lines = BufReader::new(output).lines();
+    while let Some(Ok(line)) = lines.next().await {
+        info!(target: &target, "{}", line);
+    }
+}
+
+async fn wait_ready(addr: Option<SocketAddr>) {
+    if let Some(addr) = addr {
+        while TcpStream::connect(addr).await.is_err() {
+            time::delay_for(READY_CHECK_INTERVAL).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::config::Mode;
+    use std::{
+        fs,
+        net::{IpAddr, Ipv4Addr},
+    };
+    use tokio::runtime::Builder;
+
+    #[cfg(unix)]
+    #[test]
+    fn restart_exited_plugin() {
+        use std::os::unix::fs::PermissionsExt;
+
+        // Plugin records every launch and exits immediately
+        let dir = std::env::temp_dir().join(format!("ss-plugin-supervisor-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let launches = dir.join("launches");
+        let script = dir.join("plugin.sh");
+        fs::write(&script, format!("#!/bin/sh\necho launched >> {}\n", launches.display())).unwrap();
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let mut rt = Builder::new().basic_scheduler().enable_all().build().unwrap();
+        rt.block_on(async {
+            let plugin = PluginConfig {
+                plugin: script.to_str().unwrap().to_owned(),
+                plugin_opt: None,
+                plugin_mode: Mode::TcpOnly,
+            };
+            let local = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 0);
+            let remote = "127.0.0.1:8388".parse().unwrap();
+            let process = PluginProcess::start(&plugin, &remote, &local, PluginMode::Client).unwrap();
+            let status = process.status().clone();
+
+            tokio::spawn(process.supervise());
+
+            // Restarted after 1s, then 2s later
+            time::delay_for(Duration::from_millis(1500)).await;
+            assert!(!status.is_available());
+        });
+
+        let count = fs::read_to_string(&launches).unwrap().lines().count();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(count, 2);
+    }
+}
@@ -0,0 +1,240 @@
+//! Access log of relays
+//!
+//! A record is written as a line of JSON object after each relay is finished, separated from the debug log.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, ErrorKind, Write},
+    marker::Unpin,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, Sender},
+    },
+    task::{Context, Poll},
+    thread::{self, JoinHandle},
+    time::Instant,
+};
+
+use futures::{future::Either, ready};
+use log::error;
+use serde::Serialize;
+use spin::Mutex;
+use time::{Format, OffsetDateTime};
+use tokio::io::AsyncRead;
+
+use crate::{config::ServerConfig, relay::socks5::Address};
+
+/// Writer of the access log file
+///
+/// Lines are written by a dedicated thread, so relays are never blocked by the file.
+pub struct AccessLog {
+    tx: Option<Mutex<Sender<String>>>,
+    writer: Option<JoinHandle<()>>,
+}
+
+impl AccessLog {
+    /// Opens `path` for appending records
+    pub fn open(path: &str) -> io::Result<AccessLog> {
+        let file = OpenOptions::new().append(true).create(true).open(path)?;
+
+        let (tx, rx) = mpsc::channel();
+        let writer = thread::Builder::new()
+            .name("access-log".to_owned())
+            .spawn(move || write_lines(file, rx.iter()))?;
+
+        Ok(AccessLog {
+            tx: Some(Mutex::new(tx)),
+            writer: Some(writer),
+        })
+    }
+
+    /// Writes `record` as a line
+    pub fn write(&self, record: &AccessRecord) {
+        let mut line = match serde_json::to_string(record) {
+            Ok(l) => l,
+            Err(err) => {
+                error!("Failed to serialize access record, {}", err);
+                return;
+            }
+        };
+        line.push('\n');
+
+        if let Some(ref tx) = self.tx {
+            if tx.lock().send(line).is_err() {
+                error!("Failed to write access log, writer is stopped");
+            }
+        }
+    }
+}
+
+impl Drop for AccessLog {
+    fn drop(&mut self) {
+        // Writer finishes after the queued lines are written
+        self.tx.take();
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+fn write_lines<I>(mut file: File, lines: I)
+where
+    I: Iterator<Item = String>,
+{
+    for line in lines {
+        if let Err(err) = file.write_all(line.as_bytes()) {
+            error!("Failed to write access log, {}", err);
+        }
+    }
+}
+
+/// A finished relay
+#[derive(Serialize, Debug)]
+pub struct AccessRecord {
+    /// Time of finishing, in RFC 3339
+    pub time: String,
+    /// Address of the client
+    pub client: SocketAddr,
+    /// Port of the shadowsocks server
+    pub server_port: u16,
+    /// User of a multi-user server
+    pub user: Option<String>,
+    /// Destination requested by the client
+    pub destination: String,
+    /// Address connected for the destination, only known by servers connecting it directly
+    pub resolved: Option<SocketAddr>,
+    /// Bytes sent from the client to the destination
+    pub upload: u64,
+    /// Bytes sent from the destination to the client
+    pub download: u64,
+    /// Duration of the relay in milliseconds
+    pub duration_ms: u64,
+    /// `client_closed`, `remote_closed`, `timed_out`, or the error closing the relay
+    pub close_reason: String,
+}
+
+/// Result of `future::select` on copies in both directions
+pub type RelayResult<A, B> = Either<(io::Result<u64>, A), (io::Result<u64>, B)>;
+
+/// Statistics of a running relay
+pub struct RelayStat {
+    started: Instant,
+    upload: AtomicU64,
+    download: AtomicU64,
+}
+
+impl RelayStat {
+    /// Starts counting a relay
+    pub fn new() -> RelayStat {
+        RelayStat {
+            started: Instant::now(),
+            upload: AtomicU64::new(0),
+            download: AtomicU64::new(0),
+        }
+    }
+
+    /// Counts bytes read from the client
+    pub fn upload_reader<R>(&self, reader: R) -> CountRead<'_, R> {
+        CountRead {
+            reader,
+            count: &self.upload,
+        }
+    }
+
+    /// Counts bytes read from the remote
+    pub fn download_reader<R>(&self, reader: R) -> CountRead<'_, R> {
+        CountRead {
+            reader,
+            count: &self.download,
+        }
+    }
+
+    /// Creates the record of the finished relay
+    ///
+    /// `res` is the result of relays in both directions, the client to remote one is on the left.
+    pub fn finish<A, B>(
+        &self,
+        client: SocketAddr,
+        svr_cfg: &ServerConfig,
+        destination: &Address,
+        res: &RelayResult<A, B>,
+    ) -> AccessRecord {
+        let close_reason = match *res {
+            Either::Left((Ok(..), _)) => "client_closed".to_owned(),
+            Either::Right((Ok(..), _)) => "remote_closed".to_owned(),
+            Either::Left((Err(ref err), _)) | Either::Right((Err(ref err), _)) => match err.kind() {
+                ErrorKind::TimedOut => "timed_out".to_owned(),
+                _ => err.to_string(),
+            },
+        };
+
+        AccessRecord {
+            time: OffsetDateTime::now_utc().format(Format::Rfc3339),
+            client,
+            server_port: svr_cfg.addr().port(),
+            user: None,
+            destination: destination.to_string(),
+            resolved: None,
+            upload: self.upload.load(Ordering::Relaxed),
+            download: self.download.load(Ordering::Relaxed),
+            duration_ms: self.started.elapsed().as_millis() as u64,
+            close_reason,
+        }
+    }
+}
+
+/// Reader counting bytes read from it
+pub struct CountRead<'a, R> {
+    reader: R,
+    count: &'a AtomicU64,
+}
+
+impl<R> AsyncRead for CountRead<'_, R>
+where
+    R: AsyncRead + Unpin,
+{
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        let n = ready!(Pin::new(&mut self.reader).poll_read(cx, buf))?;
+        self.count.fetch_add(n as u64, Ordering::Relaxed);
+        Poll::Ready(Ok(n))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::fs;
+
+    #[test]
+    fn write_escaped_line() {
+        let path = std::env::temp_dir().join(format!("ss-access-log-{}.log", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let access_log = AccessLog::open(path.to_str().unwrap()).unwrap();
+        access_log.write(&AccessRecord {
+            time: "2020-01-01T00:00:00Z".to_owned(),
+            client: "127.0.0.1:1080".parse().unwrap(),
+            server_port: 8388,
+            user: None,
+            destination: "example.com\n\u{1b}[31m:80".to_owned(),
+            resolved: None,
+            upload: 1,
+            download: 2,
+            duration_ms: 3,
+            close_reason: "client_closed".to_owned(),
+        });
+
+        // Queued lines are written before dropped
+        drop(access_log);
+
+        let content = fs::read_to_string(&path).unwrap();
+        let _ = fs::remove_file(&path);
+
+        let lines = content.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 1);
+        assert!(lines[0].contains(r#""destination":"example.com\n\u001b[31m:80""#));
+    }
+}
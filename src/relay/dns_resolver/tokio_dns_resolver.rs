@@ -17,6 +17,8 @@ pub async fn resolve(
 ) -> io::Result<impl Iterator<Item = SocketAddr>> {
     match lookup_host((addr, port)).await {
         Err(err) => {
+            context.metrics().incr_dns_resolve_errors();
+
             let err = Error::new(ErrorKind::Other, format!("dns resolve {}:{}, {}", addr, port, err));
             Err(err)
         }
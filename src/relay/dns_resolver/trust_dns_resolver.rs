@@ -79,6 +79,8 @@ pub async fn resolve(
 ) -> io::Result<impl Iterator<Item = SocketAddr>> {
     match context.dns_resolver().lookup_ip(addr).await {
         Err(err) => {
+            context.metrics().incr_dns_resolve_errors();
+
             let err = Error::new(ErrorKind::Other, format!("dns resolve {}:{}, {}", addr, port, err));
             Err(err)
         }
@@ -0,0 +1,205 @@
+//! Traffic statistic of servers
+//!
+//! Shared by TCP and UDP relays of the same server
+
+use std::{
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use log::info;
+use spin::RwLock;
+use tokio::time::{self, Instant};
+
+use crate::config::TrafficQuota;
+
+/// Traffic statistic of a server
+pub struct ServerFlowStatistic {
+    tx: AtomicUsize,
+    rx: AtomicUsize,
+    // Limit of quota, `None` if quota is not set
+    quota_limit: RwLock<Option<u64>>,
+    quota_used: AtomicU64,
+    // Start of the current quota period
+    quota_period_start: RwLock<Instant>,
+}
+
+pub type SharedServerFlowStatistic = Arc<ServerFlowStatistic>;
+
+impl ServerFlowStatistic {
+    /// Create a new statistic with optional quota
+    pub fn new(quota: Option<&TrafficQuota>) -> ServerFlowStatistic {
+        ServerFlowStatistic {
+            tx: AtomicUsize::new(0),
+            rx: AtomicUsize::new(0),
+            quota_limit: RwLock::new(quota.map(|q| q.bytes)),
+            quota_used: AtomicU64::new(0),
+            quota_period_start: RwLock::new(Instant::now()),
+        }
+    }
+
+    /// Create a new shared statistic with optional quota
+    pub fn new_shared(quota: Option<&TrafficQuota>) -> SharedServerFlowStatistic {
+        Arc::new(ServerFlowStatistic::new(quota))
+    }
+
+    pub fn incr_tx(&self, x: usize) {
+        self.tx.fetch_add(x, Ordering::Release);
+        self.incr_quota(x);
+    }
+
+    pub fn incr_rx(&self, x: usize) {
+        self.rx.fetch_add(x, Ordering::Release);
+        self.incr_quota(x);
+    }
+
+    fn incr_quota(&self, x: usize) {
+        if self.quota_limit.read().is_some() {
+            self.quota_used.fetch_add(x as u64, Ordering::Release);
+        }
+    }
+
+    /// Total bytes transferred through this server
+    pub fn transmission(&self) -> usize {
+        self.tx.load(Ordering::Acquire) + self.rx.load(Ordering::Acquire)
+    }
+
+    /// Check if quota is used up
+    pub fn quota_exceeded(&self) -> bool {
+        match *self.quota_limit.read() {
+            Some(limit) => self.quota_used.load(Ordering::Acquire) >= limit,
+            None => false,
+        }
+    }
+
+    /// Remaining bytes of quota, `None` if quota is not set
+    pub fn quota_remaining(&self) -> Option<u64> {
+        self.quota_limit
+            .read()
+            .map(|limit| limit.saturating_sub(self.quota_used.load(Ordering::Acquire)))
+    }
+
+    /// Starts a new quota period
+    pub fn reset_quota(&self) {
+        *self.quota_period_start.write() = Instant::now();
+        self.quota_used.store(0, Ordering::Release);
+    }
+
+    /// Start of the current quota period
+    pub fn quota_period_start(&self) -> Instant {
+        *self.quota_period_start.read()
+    }
+
+    /// Changes the limit of quota, the used bytes and the start of the current period are kept
+    ///
+    /// A new period starts if quota wasn't set.
+    pub fn set_quota(&self, quota: Option<&TrafficQuota>) {
+        let mut limit = self.quota_limit.write();
+        if limit.is_none() {
+            self.reset_quota();
+        }
+        *limit = quota.map(|q| q.bytes);
+    }
+}
+
+/// Resets quota of `flow` after every `period`, counted from the start of its current period
+pub async fn reset_quota_periodically(flow: SharedServerFlowStatistic, period: Duration, port: u16) {
+    loop {
+        time::delay_until(flow.quota_period_start() + period).await;
+
+        flow.reset_quota();
+        info!("Traffic quota of server on port {} is reset", port);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn quota_exceeded_and_reset() {
+        let quota = TrafficQuota {
+            bytes: 100,
+            period: None,
+        };
+        let flow = ServerFlowStatistic::new(Some(&quota));
+
+        flow.incr_rx(60);
+        assert!(!flow.quota_exceeded());
+        assert_eq!(flow.quota_remaining(), Some(40));
+
+        flow.incr_tx(40);
+        assert!(flow.quota_exceeded());
+        assert_eq!(flow.quota_remaining(), Some(0));
+
+        flow.reset_quota();
+        assert!(!flow.quota_exceeded());
+        assert_eq!(flow.quota_remaining(), Some(100));
+        assert_eq!(flow.transmission(), 100);
+    }
+
+    #[test]
+    fn set_quota_keeps_usage() {
+        let quota = TrafficQuota {
+            bytes: 100,
+            period: None,
+        };
+        let flow = ServerFlowStatistic::new(Some(&quota));
+        flow.incr_rx(60);
+
+        let quota = TrafficQuota {
+            bytes: 50,
+            period: None,
+        };
+        flow.set_quota(Some(&quota));
+        assert!(flow.quota_exceeded());
+        assert_eq!(flow.transmission(), 60);
+
+        flow.set_quota(None);
+        assert_eq!(flow.quota_remaining(), None);
+        flow.incr_tx(10);
+        flow.set_quota(Some(&quota));
+        assert_eq!(flow.quota_remaining(), Some(50));
+        assert_eq!(flow.transmission(), 70);
+    }
+
+    #[test]
+    fn reset_from_period_start() {
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .enable_all()
+            .build()
+            .unwrap();
+
+        rt.block_on(async {
+            let quota = TrafficQuota {
+                bytes: 100,
+                period: None,
+            };
+            let flow = ServerFlowStatistic::new_shared(Some(&quota));
+            flow.incr_rx(60);
+
+            // A replacing server starts resetting in the middle of the period
+            time::delay_for(Duration::from_millis(400)).await;
+            tokio::spawn(reset_quota_periodically(flow.clone(), Duration::from_millis(600), 8388));
+
+            time::delay_for(Duration::from_millis(100)).await;
+            assert_eq!(flow.quota_remaining(), Some(40));
+
+            // Reset 600ms after the period started, not after the replacement
+            time::delay_for(Duration::from_millis(300)).await;
+            assert_eq!(flow.quota_remaining(), Some(100));
+        });
+    }
+
+    #[test]
+    fn no_quota() {
+        let flow = ServerFlowStatistic::new(None);
+        flow.incr_tx(1 << 20);
+        assert!(!flow.quota_exceeded());
+        assert_eq!(flow.quota_remaining(), None);
+    }
+}
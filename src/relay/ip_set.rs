@@ -0,0 +1,425 @@
+//! Set of IP addresses, CIDR ranges and pre-defined groups, such as `forbidden_ip`
+//!
+//! Ranges are stored in binary prefix tries, one for IPv4 and one for IPv6, so matching an address takes at most
+//! 32 or 128 steps no matter how many ranges are in the set.
+
+use std::{
+    fmt::{self, Debug, Display, Formatter},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    str::FromStr,
+};
+
+/// Network of an IP address and a prefix length, like `10.0.0.0/8`
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct IpNet {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpNet {
+    /// Creates a network, bits of `addr` after `prefix_len` are cleared
+    ///
+    /// Returns `None` if `prefix_len` is longer than the address.
+    pub fn new(addr: IpAddr, prefix_len: u8) -> Option<IpNet> {
+        let (bits, max_len) = addr_bits(&addr);
+        if prefix_len > max_len {
+            return None;
+        }
+
+        let addr = match addr {
+            IpAddr::V4(..) => IpAddr::V4(Ipv4Addr::from(((bits & prefix_mask(prefix_len)) >> 96) as u32)),
+            IpAddr::V6(..) => IpAddr::V6(Ipv6Addr::from(bits & prefix_mask(prefix_len))),
+        };
+        Some(IpNet { addr, prefix_len })
+    }
+
+    /// Network address
+    pub fn addr(&self) -> IpAddr {
+        self.addr
+    }
+
+    /// Length of the prefix
+    pub fn prefix_len(&self) -> u8 {
+        self.prefix_len
+    }
+
+    /// Checks if `ip` is in this network
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        let (bits, len) = addr_bits(ip);
+        let (net_bits, net_len) = addr_bits(&self.addr);
+        len == net_len && bits & prefix_mask(self.prefix_len) == net_bits
+    }
+}
+
+impl From<IpAddr> for IpNet {
+    fn from(addr: IpAddr) -> IpNet {
+        let prefix_len = addr_bits(&addr).1;
+        IpNet { addr, prefix_len }
+    }
+}
+
+impl FromStr for IpNet {
+    type Err = IpRuleError;
+
+    fn from_str(s: &str) -> Result<IpNet, IpRuleError> {
+        let mut sp = s.splitn(2, '/');
+        let addr = match sp.next().unwrap().parse::<IpAddr>() {
+            Ok(a) => a,
+            Err(..) => return Err(IpRuleError::InvalidAddr),
+        };
+
+        match sp.next() {
+            None => Ok(IpNet::from(addr)),
+            Some(len) => match len.parse::<u8>() {
+                Ok(len) => IpNet::new(addr, len).ok_or(IpRuleError::InvalidPrefixLen),
+                Err(..) => Err(IpRuleError::InvalidPrefixLen),
+            },
+        }
+    }
+}
+
+impl Display for IpNet {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        if self.prefix_len == addr_bits(&self.addr).1 {
+            write!(f, "{}", self.addr)
+        } else {
+            write!(f, "{}/{}", self.addr, self.prefix_len)
+        }
+    }
+}
+
+/// Pre-defined groups of networks
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum IpGroup {
+    /// Private networks, `10.0.0.0/8`, `172.16.0.0/12`, `192.168.0.0/16` and `fc00::/7`
+    Private,
+    /// Loopback addresses, `127.0.0.0/8` and `::1`
+    Loopback,
+    /// Link-local addresses, `169.254.0.0/16` and `fe80::/10`
+    LinkLocal,
+    /// Multicast addresses, `224.0.0.0/4` and `ff00::/8`
+    Multicast,
+}
+
+impl IpGroup {
+    fn as_str(self) -> &'static str {
+        match self {
+            IpGroup::Private => "private",
+            IpGroup::Loopback => "loopback",
+            IpGroup::LinkLocal => "link-local",
+            IpGroup::Multicast => "multicast",
+        }
+    }
+
+    /// Networks of this group
+    pub fn networks(self) -> Vec<IpNet> {
+        let nets: &[&str] = match self {
+            IpGroup::Private => &["10.0.0.0/8", "172.16.0.0/12", "192.168.0.0/16", "fc00::/7"],
+            IpGroup::Loopback => &["127.0.0.0/8", "::1/128"],
+            IpGroup::LinkLocal => &["169.254.0.0/16", "fe80::/10"],
+            IpGroup::Multicast => &["224.0.0.0/4", "ff00::/8"],
+        };
+        nets.iter().map(|n| n.parse().expect("pre-defined network")).collect()
+    }
+}
+
+impl FromStr for IpGroup {
+    type Err = IpRuleError;
+
+    fn from_str(s: &str) -> Result<IpGroup, IpRuleError> {
+        match s {
+            "private" => Ok(IpGroup::Private),
+            "loopback" => Ok(IpGroup::Loopback),
+            "link-local" => Ok(IpGroup::LinkLocal),
+            "multicast" => Ok(IpGroup::Multicast),
+            _ => Err(IpRuleError::UnknownGroup),
+        }
+    }
+}
+
+impl Display for IpGroup {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// An entry of `IpSet`, which is a network or a pre-defined group
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum IpRule {
+    Net(IpNet),
+    Group(IpGroup),
+}
+
+impl FromStr for IpRule {
+    type Err = IpRuleError;
+
+    fn from_str(s: &str) -> Result<IpRule, IpRuleError> {
+        let first = s.chars().next();
+        let is_addr = match first {
+            Some(c) => c.is_ascii_digit() || s.contains(':'),
+            None => false,
+        };
+
+        if is_addr {
+            s.parse().map(IpRule::Net)
+        } else {
+            s.parse().map(IpRule::Group)
+        }
+    }
+}
+
+impl Display for IpRule {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            IpRule::Net(ref n) => Display::fmt(n, f),
+            IpRule::Group(ref g) => Display::fmt(g, f),
+        }
+    }
+}
+
+/// Error of parsing `IpRule`
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum IpRuleError {
+    InvalidAddr,
+    InvalidPrefixLen,
+    UnknownGroup,
+}
+
+impl Display for IpRuleError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            IpRuleError::InvalidAddr => f.write_str("invalid IP address"),
+            IpRuleError::InvalidPrefixLen => f.write_str("invalid prefix length"),
+            IpRuleError::UnknownGroup => f.write_str("unknown group"),
+        }
+    }
+}
+
+// Address as the most significant bits of an u128, and its length
+fn addr_bits(addr: &IpAddr) -> (u128, u8) {
+    match *addr {
+        IpAddr::V4(ref v4) => (u128::from(u32::from(*v4)) << 96, 32),
+        IpAddr::V6(ref v6) => (u128::from(*v6), 128),
+    }
+}
+
+// Mask of the first `prefix_len` bits of `addr_bits`
+fn prefix_mask(prefix_len: u8) -> u128 {
+    match prefix_len {
+        0 => 0,
+        n => !0u128 << (128 - u32::from(n)),
+    }
+}
+
+#[derive(Clone, Default)]
+struct TrieNode {
+    // Indexes of children in `PrefixTrie::nodes`, 0 for none since the root is never a child
+    children: [usize; 2],
+    // A network ends at this node, all addresses under it are matched
+    terminal: bool,
+}
+
+/// Binary trie of network prefixes
+#[derive(Clone)]
+struct PrefixTrie {
+    nodes: Vec<TrieNode>,
+}
+
+impl PrefixTrie {
+    fn new() -> PrefixTrie {
+        PrefixTrie {
+            nodes: vec![TrieNode::default()],
+        }
+    }
+
+    fn insert(&mut self, bits: u128, prefix_len: u8) {
+        let mut idx = 0;
+        for i in 0..prefix_len {
+            if self.nodes[idx].terminal {
+                // Already covered by a shorter prefix
+                return;
+            }
+
+            let bit = ((bits >> (127 - i)) & 1) as usize;
+            idx = match self.nodes[idx].children[bit] {
+                0 => {
+                    self.nodes.push(TrieNode::default());
+                    let child = self.nodes.len() - 1;
+                    self.nodes[idx].children[bit] = child;
+                    child
+                }
+                child => child,
+            };
+        }
+
+        // Longer prefixes under this node are useless now, they are kept in `nodes` but unreachable
+        let node = &mut self.nodes[idx];
+        node.terminal = true;
+        node.children = [0, 0];
+    }
+
+    fn contains(&self, bits: u128, len: u8) -> bool {
+        let mut idx = 0;
+        for i in 0..len {
+            if self.nodes[idx].terminal {
+                return true;
+            }
+
+            let bit = ((bits >> (127 - i)) & 1) as usize;
+            idx = match self.nodes[idx].children[bit] {
+                0 => return false,
+                child => child,
+            };
+        }
+        self.nodes[idx].terminal
+    }
+}
+
+/// Set of networks for matching IP addresses
+#[derive(Clone)]
+pub struct IpSet {
+    rules: Vec<IpRule>,
+    v4: PrefixTrie,
+    v6: PrefixTrie,
+}
+
+impl IpSet {
+    /// Creates an empty set
+    pub fn new() -> IpSet {
+        IpSet {
+            rules: Vec::new(),
+            v4: PrefixTrie::new(),
+            v6: PrefixTrie::new(),
+        }
+    }
+
+    /// Adds an IP address
+    pub fn insert(&mut self, ip: IpAddr) {
+        self.insert_rule(IpRule::Net(IpNet::from(ip)));
+    }
+
+    /// Adds a network or a group
+    pub fn insert_rule(&mut self, rule: IpRule) {
+        if self.rules.contains(&rule) {
+            return;
+        }
+
+        match rule {
+            IpRule::Net(ref net) => self.insert_net(net),
+            IpRule::Group(group) => {
+                for net in group.networks() {
+                    self.insert_net(&net);
+                }
+            }
+        }
+        self.rules.push(rule);
+    }
+
+    fn insert_net(&mut self, net: &IpNet) {
+        let (bits, _) = addr_bits(&net.addr);
+        match net.addr {
+            IpAddr::V4(..) => self.v4.insert(bits, net.prefix_len),
+            IpAddr::V6(..) => self.v6.insert(bits, net.prefix_len),
+        }
+    }
+
+    /// Checks if `ip` is in any network of the set
+    ///
+    /// IPv4-mapped IPv6 addresses, like `::ffff:10.0.0.1`, are also checked as IPv4 addresses.
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match *ip {
+            IpAddr::V4(..) => {
+                let (bits, len) = addr_bits(ip);
+                self.v4.contains(bits, len)
+            }
+            IpAddr::V6(ref v6) => {
+                let (bits, len) = addr_bits(ip);
+                if self.v6.contains(bits, len) {
+                    return true;
+                }
+
+                match v6.segments() {
+                    [0, 0, 0, 0, 0, 0xffff, ..] => self.contains(&IpAddr::V4(Ipv4Addr::from(bits as u32))),
+                    _ => false,
+                }
+            }
+        }
+    }
+
+    /// Checks if the set is empty
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Networks and groups in the set, in the order of insertion
+    pub fn rules(&self) -> &[IpRule] {
+        &self.rules
+    }
+}
+
+impl Default for IpSet {
+    fn default() -> IpSet {
+        IpSet::new()
+    }
+}
+
+impl Debug for IpSet {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_set()
+            .entries(self.rules.iter().map(ToString::to_string))
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parse_rules() {
+        assert_eq!("10.1.2.3/8".parse::<IpRule>().unwrap().to_string(), "10.0.0.0/8");
+        assert_eq!("::1".parse::<IpRule>().unwrap().to_string(), "::1");
+        assert_eq!("fe80::1/10".parse::<IpRule>().unwrap().to_string(), "fe80::/10");
+        assert_eq!(
+            "link-local".parse::<IpRule>().unwrap(),
+            IpRule::Group(IpGroup::LinkLocal)
+        );
+
+        assert_eq!("10.0.0.0/33".parse::<IpRule>(), Err(IpRuleError::InvalidPrefixLen));
+        assert_eq!("10.0.0/8".parse::<IpRule>(), Err(IpRuleError::InvalidAddr));
+        assert_eq!("public".parse::<IpRule>(), Err(IpRuleError::UnknownGroup));
+    }
+
+    #[test]
+    fn match_ip_set() {
+        let mut set = IpSet::new();
+        set.insert_rule("10.0.0.0/8".parse().unwrap());
+        set.insert_rule("10.1.0.0/16".parse().unwrap());
+        set.insert_rule("2001:db8::/32".parse().unwrap());
+        set.insert_rule("loopback".parse().unwrap());
+        set.insert(ip("8.8.8.8"));
+
+        assert!(set.contains(&ip("10.255.0.1")));
+        assert!(set.contains(&ip("10.1.2.3")));
+        assert!(!set.contains(&ip("11.0.0.1")));
+        assert!(set.contains(&ip("2001:db8:1::1")));
+        assert!(!set.contains(&ip("2001:db9::1")));
+        assert!(set.contains(&ip("127.0.0.2")));
+        assert!(set.contains(&ip("::1")));
+        assert!(set.contains(&ip("8.8.8.8")));
+        assert!(!set.contains(&ip("8.8.4.4")));
+
+        // IPv4-mapped addresses
+        assert!(set.contains(&ip("::ffff:10.0.0.1")));
+        assert!(!set.contains(&ip("::ffff:11.0.0.1")));
+
+        let mut all = IpSet::new();
+        all.insert_rule("0.0.0.0/0".parse().unwrap());
+        assert!(all.contains(&ip("1.2.3.4")));
+        assert!(!all.contains(&ip("::2")));
+    }
+}
@@ -0,0 +1,233 @@
+//! Limits of concurrent TCP connections and UDP associations
+//!
+//! Shared by all servers, so one client IP couldn't exhaust file descriptors by connecting to different ports.
+
+use std::{
+    collections::HashMap,
+    fmt::{self, Display},
+    hash::Hash,
+    net::IpAddr,
+    sync::Arc,
+};
+
+use spin::{Mutex, RwLock};
+
+use crate::config::Config;
+
+/// Which limit is reached
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Limit {
+    /// Concurrent TCP connections of a server port
+    TcpPerPort,
+    /// Concurrent TCP connections of a client IP
+    TcpPerIp,
+    /// UDP associations of a client IP
+    UdpPerIp,
+}
+
+impl Limit {
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            Limit::TcpPerPort => "tcp_per_port",
+            Limit::TcpPerIp => "tcp_per_ip",
+            Limit::UdpPerIp => "udp_per_ip",
+        }
+    }
+}
+
+impl Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Limits {
+    tcp_per_port: Option<usize>,
+    tcp_per_ip: Option<usize>,
+    udp_per_ip: Option<usize>,
+}
+
+impl Limits {
+    fn from_config(config: &Config) -> Limits {
+        Limits {
+            tcp_per_port: config.max_connections_per_port,
+            tcp_per_ip: config.max_connections_per_ip,
+            udp_per_ip: config.max_udp_associations_per_ip,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Counts {
+    tcp_ports: HashMap<u16, usize>,
+    tcp_ips: HashMap<IpAddr, usize>,
+    udp_ips: HashMap<IpAddr, usize>,
+}
+
+fn reached<K: Hash + Eq>(counts: &HashMap<K, usize>, key: &K, limit: Option<usize>) -> bool {
+    match limit {
+        Some(limit) => counts.get(key).cloned().unwrap_or(0) >= limit,
+        None => false,
+    }
+}
+
+fn incr<K: Hash + Eq>(counts: &mut HashMap<K, usize>, key: K) {
+    *counts.entry(key).or_insert(0) += 1;
+}
+
+fn decr<K: Hash + Eq>(counts: &mut HashMap<K, usize>, key: &K) {
+    if let Some(count) = counts.get_mut(key) {
+        *count -= 1;
+        if *count == 0 {
+            counts.remove(key);
+        }
+    }
+}
+
+/// Counts concurrent TCP connections and UDP associations, and rejects those over limits
+pub struct ConnectionLimiter {
+    limits: RwLock<Limits>,
+    counts: Mutex<Counts>,
+}
+
+pub type SharedConnectionLimiter = Arc<ConnectionLimiter>;
+
+impl ConnectionLimiter {
+    /// Creates a limiter with limits in `config`
+    pub fn new_shared(config: &Config) -> SharedConnectionLimiter {
+        Arc::new(ConnectionLimiter {
+            limits: RwLock::new(Limits::from_config(config)),
+            counts: Mutex::new(Counts::default()),
+        })
+    }
+
+    /// Applies limits in `config`, the established connections are not affected
+    pub fn reload(&self, config: &Config) {
+        *self.limits.write() = Limits::from_config(config);
+    }
+
+    /// Acquires a TCP connection accepted on `port` from `peer_ip`
+    ///
+    /// Limits of client IP are not checked if `peer_ip` is `None`, such as connections from plugins.
+    pub fn acquire_tcp(self: &Arc<Self>, port: u16, peer_ip: Option<IpAddr>) -> Result<LimitGuard, Limit> {
+        let limits = *self.limits.read();
+        let mut counts = self.counts.lock();
+
+        if reached(&counts.tcp_ports, &port, limits.tcp_per_port) {
+            return Err(Limit::TcpPerPort);
+        }
+        if let Some(ip) = peer_ip {
+            if reached(&counts.tcp_ips, &ip, limits.tcp_per_ip) {
+                return Err(Limit::TcpPerIp);
+            }
+            incr(&mut counts.tcp_ips, ip);
+        }
+        incr(&mut counts.tcp_ports, port);
+
+        Ok(LimitGuard {
+            limiter: self.clone(),
+            slot: Slot::Tcp(port, peer_ip),
+        })
+    }
+
+    /// Acquires a UDP association from `peer_ip`
+    ///
+    /// Limits of client IP are not checked if `peer_ip` is `None`, such as associations from plugins.
+    pub fn acquire_udp(self: &Arc<Self>, peer_ip: Option<IpAddr>) -> Result<LimitGuard, Limit> {
+        let limits = *self.limits.read();
+        let mut counts = self.counts.lock();
+
+        if let Some(ip) = peer_ip {
+            if reached(&counts.udp_ips, &ip, limits.udp_per_ip) {
+                return Err(Limit::UdpPerIp);
+            }
+            incr(&mut counts.udp_ips, ip);
+        }
+
+        Ok(LimitGuard {
+            limiter: self.clone(),
+            slot: Slot::Udp(peer_ip),
+        })
+    }
+}
+
+enum Slot {
+    Tcp(u16, Option<IpAddr>),
+    Udp(Option<IpAddr>),
+}
+
+/// Releases the acquired connection while dropping
+pub struct LimitGuard {
+    limiter: SharedConnectionLimiter,
+    slot: Slot,
+}
+
+impl Drop for LimitGuard {
+    fn drop(&mut self) {
+        let mut counts = self.limiter.counts.lock();
+        match self.slot {
+            Slot::Tcp(port, peer_ip) => {
+                decr(&mut counts.tcp_ports, &port);
+                if let Some(ip) = peer_ip {
+                    decr(&mut counts.tcp_ips, &ip);
+                }
+            }
+            Slot::Udp(peer_ip) => {
+                if let Some(ip) = peer_ip {
+                    decr(&mut counts.udp_ips, &ip);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::ConfigType;
+
+    #[test]
+    fn tcp_limits() {
+        let mut config = Config::new(ConfigType::Server);
+        config.max_connections_per_port = Some(3);
+        config.max_connections_per_ip = Some(2);
+        let limiter = ConnectionLimiter::new_shared(&config);
+
+        let ip1 = "127.0.0.1".parse().unwrap();
+        let ip2 = "127.0.0.2".parse().unwrap();
+
+        let g1 = limiter.acquire_tcp(8388, Some(ip1)).unwrap();
+        let _g2 = limiter.acquire_tcp(8388, Some(ip1)).unwrap();
+        assert_eq!(limiter.acquire_tcp(8388, Some(ip1)).err(), Some(Limit::TcpPerIp));
+
+        let _g3 = limiter.acquire_tcp(8388, Some(ip2)).unwrap();
+        assert_eq!(limiter.acquire_tcp(8388, Some(ip2)).err(), Some(Limit::TcpPerPort));
+
+        // Other ports have their own limits
+        let _g4 = limiter.acquire_tcp(8389, Some(ip2)).unwrap();
+
+        drop(g1);
+        let _g5 = limiter.acquire_tcp(8388, Some(ip1)).unwrap();
+    }
+
+    #[test]
+    fn udp_limits() {
+        let mut config = Config::new(ConfigType::Server);
+        config.max_udp_associations_per_ip = Some(1);
+        let limiter = ConnectionLimiter::new_shared(&config);
+
+        let ip = "127.0.0.1".parse().unwrap();
+        let guard = limiter.acquire_udp(Some(ip)).unwrap();
+        assert_eq!(limiter.acquire_udp(Some(ip)).err(), Some(Limit::UdpPerIp));
+
+        // Associations from plugins are not limited
+        let _plugin_guard = limiter.acquire_udp(None).unwrap();
+
+        // TCP connections are not affected
+        let _tcp_guard = limiter.acquire_tcp(8388, Some(ip)).unwrap();
+
+        drop(guard);
+        let _guard = limiter.acquire_udp(Some(ip)).unwrap();
+    }
+}
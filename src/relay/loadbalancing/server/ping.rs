@@ -16,6 +16,7 @@ use crate::{
     context::{Context, SharedContext},
     relay::{
         loadbalancing::server::LoadBalancer,
+        metrics::Protocol,
         socks5::Address,
         tcprelay::client::ServerClient as TcpServerClient,
         udprelay::client::ServerClient as UdpServerClient,
@@ -226,6 +227,14 @@ impl<S: Server + 'static> Inner<S> {
             score
         );
         sc.set_score(score);
+
+        let protocol = match server_type {
+            ServerType::Tcp => Protocol::Tcp,
+            ServerType::Udp => Protocol::Udp,
+        };
+        context
+            .metrics()
+            .set_server_score(sc.server_config().addr(), protocol, score);
     }
 
     async fn check_request_tcp(sc: &ServerConfig, context: &Context) -> io::Result<()> {
@@ -321,19 +330,30 @@ impl<S: Server + 'static> Inner<S> {
 
     // Choose the best server by servers' score
     //
+    // Servers are skipped while their plugins are unavailable, the best server is kept if none of them is available.
+    //
     // If the best server has been changed, return the (Last-BestServer, New-BestServer)
     fn choose_best_server(&self) -> Option<(&S, &S)> {
         // Choose the best one
-        let mut choosen_idx = 0;
-        let mut choosen = &*self.servers[choosen_idx];
+        let mut choosen_idx = self.best_idx();
+        let mut choosen = None::<&S>;
 
         for (idx, svr) in self.servers.iter().enumerate() {
-            if svr.score() < choosen.score() {
-                choosen = svr;
-                choosen_idx = idx;
+            if !svr.server_config().plugin_available() {
+                continue;
+            }
+
+            match choosen {
+                Some(c) if c.score() <= svr.score() => {}
+                _ => {
+                    choosen = Some(svr);
+                    choosen_idx = idx;
+                }
             }
         }
 
+        let choosen = choosen?;
+
         let best_idx = self.best_idx();
         let last_best = &self.servers[best_idx];
 
@@ -411,6 +431,17 @@ impl<S: Server + 'static> LoadBalancer for PingBalancer<S> {
     type Server = S;
 
     fn pick_server(&mut self) -> Arc<S> {
+        // Switches immediately if plugin of the best server is down
+        if !self.inner.best_server().server_config().plugin_available() {
+            if let Some((last_best, new_best)) = self.inner.choose_best_server() {
+                info!(
+                    "switched server from {} to {}, plugin is unavailable",
+                    last_best.server_config().addr(),
+                    new_best.server_config().addr(),
+                );
+            }
+        }
+
         self.inner.best_server().clone()
     }
 
@@ -10,7 +10,12 @@ use crate::{
     config::{Config, ConfigType},
     context::{Context, ServerState},
     plugin::{PluginMode, Plugins},
-    relay::{tcprelay::local::run as run_tcp, udprelay::local::run as run_udp, utils::set_nofile},
+    relay::{
+        metrics::run as run_metrics,
+        tcprelay::local::run as run_tcp,
+        udprelay::local::run as run_udp,
+        utils::set_nofile,
+    },
 };
 
 /// Relay server running under local environment.
@@ -41,23 +46,16 @@ pub async fn run(mut config: Config, rt: Handle) -> io::Result<()> {
 
     let mut vf = Vec::new();
 
+    if config.metrics_address.is_some() {
+        let metrics_fut = run_metrics(Context::new_shared(config.clone(), state.clone()));
+        vf.push(metrics_fut.boxed());
+    }
+
     let enable_udp = match config.config_type {
         ConfigType::Socks5Local | ConfigType::TunnelLocal => config.mode.enable_udp(),
         _ => false,
     };
 
-    if enable_udp {
-        // Clone config here, because the config for TCP relay will be modified
-        // after plugins started.
-        // But DNS resolver and running state flag is still shared.
-        let udp_context = Context::new_shared(config.clone(), state.clone());
-
-        // Run UDP relay before starting plugins
-        // Because plugins doesn't support UDP relay
-        let udp_fut = run_udp(udp_context);
-        vf.push(udp_fut.boxed());
-    }
-
     let enable_tcp = match config.config_type {
         // Socks5 always true, because UDP associate command also requires a TCP connection
         ConfigType::Socks5Local => true,
@@ -69,17 +67,26 @@ pub async fn run(mut config: Config, rt: Handle) -> io::Result<()> {
         _ => false,
     };
 
+    // Plugins are started before relays, UDP relay is sent to plugins supporting it (SIP003u)
+    if (enable_tcp && config.has_server_plugins()) || (enable_udp && config.has_udp_plugins()) {
+        let plugins = Plugins::launch_plugins(&mut config, PluginMode::Client)?;
+        vf.push(plugins.into_future().boxed());
+    }
+
+    if enable_udp {
+        // DNS resolver and running state flag is shared with TCP relay
+        let udp_context = Context::new_shared(config.clone(), state.clone());
+
+        let udp_fut = run_udp(udp_context);
+        vf.push(udp_fut.boxed());
+    }
+
     if enable_tcp {
         // Run TCP local server if
         //
         //  1. Enabled TCP relay
         //  2. Not in tunnel mode. (Socks5 UDP relay requires TCP port enabled)
 
-        if config.has_server_plugins() {
-            let plugins = Plugins::launch_plugins(&mut config, PluginMode::Client)?;
-            vf.push(plugins.into_future().boxed());
-        }
-
         let tcp_fut = run_tcp(Context::new_shared(config, state.clone()));
         vf.push(tcp_fut.boxed());
     }
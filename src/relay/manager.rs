@@ -0,0 +1,287 @@
+//! Server manager
+//!
+//! Implements the `ss-manager` UDP protocol of shadowsocks-libev / shadowsocks (python).
+//!
+//! ```plain
+//! add: {"server_port": 8001, "password": "7cd308cc059", "method": "aes-256-gcm", "quota": 1073741824, "quota_period": 2592000}
+//! remove: {"server_port": 8001}
+//! ping
+//! list
+//! ```
+//!
+//! Replies are `ok`, a JSON array of servers for `list` (with `quota_remaining` if quota is set), or `err`. `ping` is
+//! replied with server's statistic data `stat: {"8001": 11370}` as shadowsocks-libev does, which will also be sent to
+//! the last controller periodically.
+
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use log::{debug, error, info, trace, warn};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    self,
+    net::{
+        udp::{RecvHalf, SendHalf},
+        UdpSocket,
+    },
+    sync::Mutex,
+    time,
+};
+
+use crate::{
+    config::{ServerConfig, TrafficQuota},
+    context::SharedContext,
+    crypto::CipherType,
+    relay::registry::ServerRegistry,
+};
+
+/// Interval of sending `stat` to controller
+const STAT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Maximum size of a manager command
+const MAX_COMMAND_SIZE: usize = 65536;
+
+/// Parameters of `add` command
+#[derive(Deserialize, Debug)]
+struct AddRequest {
+    server_port: u16,
+    password: String,
+    method: Option<String>,
+    quota: Option<u64>,
+    quota_period: Option<u64>,
+}
+
+/// Parameters of `remove` command
+#[derive(Deserialize, Debug)]
+struct RemoveRequest {
+    server_port: u16,
+}
+
+/// Item of `list` command's response
+#[derive(Serialize, Debug)]
+struct ServerItem {
+    server_port: String,
+    password: String,
+    method: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    quota: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    quota_period: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    quota_remaining: Option<u64>,
+}
+
+struct Manager {
+    context: SharedContext,
+    registry: ServerRegistry,
+}
+
+impl Manager {
+    async fn handle_add(&self, param: &str) -> io::Result<()> {
+        let req: AddRequest = json5::from_str(param).map_err(|err| {
+            let msg = format!("invalid `add` parameters, {}", err);
+            io::Error::new(io::ErrorKind::InvalidInput, msg)
+        })?;
+
+        let method = match req.method {
+            Some(ref m) => m.parse::<CipherType>().map_err(|_| {
+                let msg = format!("unsupported method `{}`", m);
+                io::Error::new(io::ErrorKind::InvalidInput, msg)
+            })?,
+            None => match self.context.config().manager_method {
+                Some(m) => m,
+                None => {
+                    let err = io::Error::new(io::ErrorKind::InvalidInput, "missing `method`");
+                    return Err(err);
+                }
+            },
+        };
+
+        if method.check_key(&req.password).is_err() {
+            let msg = format!(
+                "invalid `password`, `{}` requires a base64 encoded key of {} bytes",
+                method,
+                method.key_size()
+            );
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+        }
+
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), req.server_port);
+        let mut svr_cfg = ServerConfig::basic(addr, req.password, method);
+
+        match (req.quota, req.quota_period) {
+            (Some(bytes), period) => svr_cfg.set_quota(TrafficQuota {
+                bytes,
+                period: period.map(Duration::from_secs),
+            }),
+            (None, None) => {}
+            (None, Some(..)) => {
+                let err = io::Error::new(io::ErrorKind::InvalidInput, "`quota_period` without `quota`");
+                return Err(err);
+            }
+        }
+
+        self.registry.add_server(svr_cfg).await
+    }
+
+    async fn handle_remove(&self, param: &str) -> io::Result<()> {
+        let req: RemoveRequest = json5::from_str(param).map_err(|err| {
+            let msg = format!("invalid `remove` parameters, {}", err);
+            io::Error::new(io::ErrorKind::InvalidInput, msg)
+        })?;
+
+        if self.registry.remove_server(req.server_port).await {
+            Ok(())
+        } else {
+            let msg = format!("server on port {} doesn't exist", req.server_port);
+            Err(io::Error::new(io::ErrorKind::NotFound, msg))
+        }
+    }
+
+    async fn handle_list(&self) -> io::Result<String> {
+        let stats = self.registry.stats().await;
+
+        let items = self
+            .registry
+            .servers()
+            .await
+            .into_iter()
+            .map(|svr_cfg| {
+                let port = svr_cfg.addr().port();
+                ServerItem {
+                    server_port: port.to_string(),
+                    password: svr_cfg.password().to_owned(),
+                    method: svr_cfg.method().to_string(),
+                    quota: svr_cfg.quota().map(|q| q.bytes),
+                    quota_period: svr_cfg.quota().and_then(|q| q.period).map(|p| p.as_secs()),
+                    quota_remaining: stats.get(&port).and_then(|s| s.quota_remaining),
+                }
+            })
+            .collect::<Vec<_>>();
+
+        json5::to_string(&items).map_err(|err| io::Error::new(io::ErrorKind::Other, err.to_string()))
+    }
+
+    async fn handle_command(&self, cmd: &str) -> String {
+        let (action, param) = match cmd.find(':') {
+            Some(pos) => (cmd[..pos].trim(), cmd[pos + 1..].trim()),
+            None => (cmd.trim(), ""),
+        };
+
+        let result = match action {
+            "add" => self.handle_add(param).await.map(|_| "ok".to_owned()),
+            "remove" => self.handle_remove(param).await.map(|_| "ok".to_owned()),
+            "ping" => Ok(self.stat_payload().await),
+            "list" => self.handle_list().await,
+            _ => Err(io::Error::new(io::ErrorKind::InvalidInput, "unrecognized command")),
+        };
+
+        match result {
+            Ok(resp) => resp,
+            Err(err) => {
+                warn!("Manager command {:?} failed, error: {}", cmd, err);
+                "err".to_owned()
+            }
+        }
+    }
+
+    async fn stat_payload(&self) -> String {
+        let stat = self
+            .registry
+            .stats()
+            .await
+            .into_iter()
+            .map(|(port, stat)| (port.to_string(), stat.transmission))
+            .collect::<HashMap<_, _>>();
+
+        // Serializing a map of integers never fails
+        format!("stat: {}", json5::to_string(&stat).expect("serialize stat"))
+    }
+}
+
+struct Controller {
+    socket: SendHalf,
+    addr: Option<SocketAddr>,
+}
+
+async fn stat_interval(manager: Arc<Manager>, controller: Arc<Mutex<Controller>>) {
+    let mut interval = time::interval(STAT_INTERVAL);
+    while manager.context.server_running() {
+        interval.tick().await;
+
+        let payload = manager.stat_payload().await;
+
+        let mut controller = controller.lock().await;
+        if let Some(addr) = controller.addr {
+            trace!("Sending statistic data to {}, payload: {}", addr, payload);
+
+            if let Err(err) = controller.socket.send_to(payload.as_bytes(), &addr).await {
+                error!("Failed to send statistic data to {}, error: {}", addr, err);
+            }
+        }
+    }
+}
+
+async fn serve_commands(manager: Arc<Manager>, controller: Arc<Mutex<Controller>>, mut r: RecvHalf) -> io::Result<()> {
+    let mut buf = vec![0u8; MAX_COMMAND_SIZE];
+
+    loop {
+        // Errors like ECONNREFUSED caused by the previous response shouldn't stop the manager
+        let (n, peer_addr) = match r.recv_from(&mut buf).await {
+            Ok(r) => r,
+            Err(err) => {
+                error!("Failed to receive manager command, error: {}", err);
+                continue;
+            }
+        };
+
+        let cmd = match std::str::from_utf8(&buf[..n]) {
+            Ok(s) => s,
+            Err(..) => {
+                warn!("Received non UTF-8 manager command from {}", peer_addr);
+                continue;
+            }
+        };
+
+        debug!("Received manager command {:?} from {}", cmd, peer_addr);
+
+        let resp = manager.handle_command(cmd).await;
+
+        let mut controller = controller.lock().await;
+        controller.addr = Some(peer_addr);
+        if let Err(err) = controller.socket.send_to(resp.as_bytes(), &peer_addr).await {
+            error!("Failed to send manager response to {}, error: {}", peer_addr, err);
+        }
+    }
+}
+
+/// Runs the manager service on `manager_address`
+///
+/// Servers in `registry` are controlled by commands received from controller.
+pub async fn run(context: SharedContext, registry: ServerRegistry) -> io::Result<()> {
+    let manager_addr = context
+        .config()
+        .manager_address
+        .as_ref()
+        .expect("manager_address must not be None");
+    let bind_addr = manager_addr.bind_addr(&context).await?;
+
+    let socket = UdpSocket::bind(&bind_addr).await?;
+    info!("ShadowSocks manager listening on {}", socket.local_addr()?);
+
+    let (r, w) = socket.split();
+
+    let manager = Arc::new(Manager { context, registry });
+    let controller = Arc::new(Mutex::new(Controller { socket: w, addr: None }));
+
+    tokio::spawn(stat_interval(manager.clone(), controller.clone()));
+
+    let res = serve_commands(manager, controller, r).await;
+    error!("Manager exited unexpectly, result: {:?}", res);
+    res
+}
@@ -0,0 +1,470 @@
+//! Prometheus metrics
+//!
+//! Metrics are collected in `ServerState` and exported in Prometheus text format on `metrics_address`.
+
+use std::{
+    collections::BTreeMap,
+    convert::Infallible,
+    fmt::Write,
+    io,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use hyper::{
+    header::CONTENT_TYPE,
+    service::{make_service_fn, service_fn},
+    Body,
+    Method,
+    Request,
+    Response,
+    Server,
+    StatusCode,
+};
+use log::{error, info};
+use spin::Mutex;
+
+use crate::{config::ServerAddr, context::SharedContext, relay::limiter::Limit};
+
+/// Metrics of a listening port
+pub struct PortMetrics {
+    tcp_active: AtomicUsize,
+    tcp_total: AtomicU64,
+    bytes_up: AtomicU64,
+    bytes_down: AtomicU64,
+    udp_associations: AtomicUsize,
+    rejected: RejectedCounter,
+    denied_destinations: ProtocolCounter,
+    // Active relays of all ports
+    active_relays: Arc<AtomicUsize>,
+}
+
+impl PortMetrics {
+    fn new(active_relays: Arc<AtomicUsize>) -> PortMetrics {
+        PortMetrics {
+            tcp_active: AtomicUsize::new(0),
+            tcp_total: AtomicU64::new(0),
+            bytes_up: AtomicU64::new(0),
+            bytes_down: AtomicU64::new(0),
+            udp_associations: AtomicUsize::new(0),
+            rejected: RejectedCounter::default(),
+            denied_destinations: ProtocolCounter::default(),
+            active_relays,
+        }
+    }
+
+    /// Record a new TCP connection, it is active until the returned guard is dropped
+    pub fn tcp_connection(self: &Arc<Self>) -> ActiveGuard {
+        self.tcp_total.fetch_add(1, Ordering::Relaxed);
+        self.tcp_active.fetch_add(1, Ordering::Relaxed);
+        self.active_relays.fetch_add(1, Ordering::AcqRel);
+        ActiveGuard {
+            metrics: self.clone(),
+            kind: ActiveKind::TcpConnection,
+        }
+    }
+
+    /// Record a new UDP association, it is active until the returned guard is dropped
+    pub fn udp_association(self: &Arc<Self>) -> ActiveGuard {
+        self.udp_associations.fetch_add(1, Ordering::Relaxed);
+        self.active_relays.fetch_add(1, Ordering::AcqRel);
+        ActiveGuard {
+            metrics: self.clone(),
+            kind: ActiveKind::UdpAssociation,
+        }
+    }
+
+    /// Bytes received from clients
+    pub fn incr_bytes_up(&self, n: usize) {
+        self.bytes_up.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    /// Bytes sent back to clients
+    pub fn incr_bytes_down(&self, n: usize) {
+        self.bytes_down.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    /// Rejected a TCP connection or UDP association because of `limit`
+    pub fn incr_rejected(&self, limit: Limit) {
+        self.rejected.incr(limit);
+    }
+
+    /// Refused a destination by the port policy of the server
+    pub fn incr_denied_destinations(&self, protocol: Protocol) {
+        self.denied_destinations.incr(protocol);
+    }
+}
+
+#[derive(Default)]
+struct RejectedCounter {
+    tcp_per_port: AtomicU64,
+    tcp_per_ip: AtomicU64,
+    udp_per_ip: AtomicU64,
+}
+
+impl RejectedCounter {
+    fn incr(&self, limit: Limit) {
+        self.counter(limit).fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn counter(&self, limit: Limit) -> &AtomicU64 {
+        match limit {
+            Limit::TcpPerPort => &self.tcp_per_port,
+            Limit::TcpPerIp => &self.tcp_per_ip,
+            Limit::UdpPerIp => &self.udp_per_ip,
+        }
+    }
+}
+
+enum ActiveKind {
+    TcpConnection,
+    UdpAssociation,
+}
+
+/// Decreases the active gauge while dropping
+pub struct ActiveGuard {
+    metrics: Arc<PortMetrics>,
+    kind: ActiveKind,
+}
+
+impl Drop for ActiveGuard {
+    fn drop(&mut self) {
+        match self.kind {
+            ActiveKind::TcpConnection => self.metrics.tcp_active.fetch_sub(1, Ordering::Relaxed),
+            ActiveKind::UdpAssociation => self.metrics.udp_associations.fetch_sub(1, Ordering::Relaxed),
+        };
+        self.metrics.active_relays.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// Protocol of a relay
+#[derive(Debug, Clone, Copy)]
+pub enum Protocol {
+    Tcp,
+    Udp,
+}
+
+impl Protocol {
+    fn as_str(self) -> &'static str {
+        match self {
+            Protocol::Tcp => "tcp",
+            Protocol::Udp => "udp",
+        }
+    }
+}
+
+#[derive(Default)]
+struct ProtocolCounter {
+    tcp: AtomicU64,
+    udp: AtomicU64,
+}
+
+impl ProtocolCounter {
+    fn incr(&self, protocol: Protocol) {
+        match protocol {
+            Protocol::Tcp => self.tcp.fetch_add(1, Ordering::Relaxed),
+            Protocol::Udp => self.udp.fetch_add(1, Ordering::Relaxed),
+        };
+    }
+}
+
+/// Metrics of the whole server
+#[derive(Default)]
+pub struct Metrics {
+    ports: Mutex<BTreeMap<u16, Arc<PortMetrics>>>,
+    active_relays: Arc<AtomicUsize>,
+    scores: Mutex<BTreeMap<(String, &'static str), u64>>,
+    handshake_failures: AtomicU64,
+    decrypt_failures: ProtocolCounter,
+    repeated_nonces: ProtocolCounter,
+    dns_resolve_errors: AtomicU64,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics::default()
+    }
+
+    /// Get metrics of a listening port
+    pub fn port(&self, port: u16) -> Arc<PortMetrics> {
+        let mut ports = self.ports.lock();
+        let active_relays = &self.active_relays;
+        ports
+            .entry(port)
+            .or_insert_with(|| Arc::new(PortMetrics::new(active_relays.clone())))
+            .clone()
+    }
+
+    /// Number of active TCP connections and UDP associations of all ports
+    pub fn active_relays(&self) -> usize {
+        self.active_relays.load(Ordering::Acquire)
+    }
+
+    /// Failed to read the target address from client
+    pub fn incr_handshake_failures(&self) {
+        self.handshake_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Failed to decrypt data
+    pub fn incr_decrypt_failures(&self, protocol: Protocol) {
+        self.decrypt_failures.incr(protocol);
+    }
+
+    /// Detected repeated iv/salt
+    pub fn incr_repeated_nonces(&self, protocol: Protocol) {
+        self.repeated_nonces.incr(protocol);
+    }
+
+    /// Failed to resolve a domain name
+    pub fn incr_dns_resolve_errors(&self) {
+        self.dns_resolve_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Update score of a server in load balancer
+    pub fn set_server_score(&self, server: &ServerAddr, protocol: Protocol, score: u64) {
+        let mut scores = self.scores.lock();
+        scores.insert((server.to_string(), protocol.as_str()), score);
+    }
+
+    /// Render all metrics in Prometheus text format
+    pub fn render(&self) -> String {
+        let mut buf = String::new();
+
+        let ports = self.ports.lock().clone();
+
+        write_header(&mut buf, "tcp_connections_active", "gauge", "Active TCP connections");
+        for (port, m) in &ports {
+            let _ = writeln!(
+                buf,
+                "shadowsocks_tcp_connections_active{{port=\"{}\"}} {}",
+                port,
+                m.tcp_active.load(Ordering::Relaxed)
+            );
+        }
+
+        write_header(&mut buf, "tcp_connections_total", "counter", "Accepted TCP connections");
+        for (port, m) in &ports {
+            let _ = writeln!(
+                buf,
+                "shadowsocks_tcp_connections_total{{port=\"{}\"}} {}",
+                port,
+                m.tcp_total.load(Ordering::Relaxed)
+            );
+        }
+
+        write_header(
+            &mut buf,
+            "traffic_bytes_total",
+            "counter",
+            "Bytes transferred with clients",
+        );
+        for (port, m) in &ports {
+            let _ = writeln!(
+                buf,
+                "shadowsocks_traffic_bytes_total{{port=\"{}\",direction=\"up\"}} {}",
+                port,
+                m.bytes_up.load(Ordering::Relaxed)
+            );
+            let _ = writeln!(
+                buf,
+                "shadowsocks_traffic_bytes_total{{port=\"{}\",direction=\"down\"}} {}",
+                port,
+                m.bytes_down.load(Ordering::Relaxed)
+            );
+        }
+
+        write_header(&mut buf, "udp_associations", "gauge", "Active UDP associations");
+        for (port, m) in &ports {
+            let _ = writeln!(
+                buf,
+                "shadowsocks_udp_associations{{port=\"{}\"}} {}",
+                port,
+                m.udp_associations.load(Ordering::Relaxed)
+            );
+        }
+
+        write_header(
+            &mut buf,
+            "rejected_connections_total",
+            "counter",
+            "TCP connections and UDP associations rejected by limits",
+        );
+        for (port, m) in &ports {
+            for &limit in &[Limit::TcpPerPort, Limit::TcpPerIp, Limit::UdpPerIp] {
+                let _ = writeln!(
+                    buf,
+                    "shadowsocks_rejected_connections_total{{port=\"{}\",limit=\"{}\"}} {}",
+                    port,
+                    limit.as_str(),
+                    m.rejected.counter(limit).load(Ordering::Relaxed)
+                );
+            }
+        }
+
+        write_header(
+            &mut buf,
+            "denied_destinations_total",
+            "counter",
+            "Destinations refused by port policies",
+        );
+        for (port, m) in &ports {
+            let counter = &m.denied_destinations;
+            for &(protocol, value) in &[(Protocol::Tcp, &counter.tcp), (Protocol::Udp, &counter.udp)] {
+                let _ = writeln!(
+                    buf,
+                    "shadowsocks_denied_destinations_total{{port=\"{}\",protocol=\"{}\"}} {}",
+                    port,
+                    protocol.as_str(),
+                    value.load(Ordering::Relaxed)
+                );
+            }
+        }
+
+        write_header(
+            &mut buf,
+            "handshake_failures_total",
+            "counter",
+            "Failed to read target address from clients",
+        );
+        let _ = writeln!(
+            buf,
+            "shadowsocks_handshake_failures_total {}",
+            self.handshake_failures.load(Ordering::Relaxed)
+        );
+
+        write_header(&mut buf, "decrypt_failures_total", "counter", "Failed to decrypt data");
+        write_protocol_counter(&mut buf, "decrypt_failures_total", &self.decrypt_failures);
+
+        write_header(
+            &mut buf,
+            "repeated_nonces_total",
+            "counter",
+            "Detected repeated iv/salt",
+        );
+        write_protocol_counter(&mut buf, "repeated_nonces_total", &self.repeated_nonces);
+
+        write_header(
+            &mut buf,
+            "dns_resolve_errors_total",
+            "counter",
+            "Failed DNS resolutions",
+        );
+        let _ = writeln!(
+            buf,
+            "shadowsocks_dns_resolve_errors_total {}",
+            self.dns_resolve_errors.load(Ordering::Relaxed)
+        );
+
+        write_header(
+            &mut buf,
+            "server_score",
+            "gauge",
+            "Score of servers in load balancer, the lower the better",
+        );
+        for ((server, protocol), score) in self.scores.lock().iter() {
+            let _ = writeln!(
+                buf,
+                "shadowsocks_server_score{{server=\"{}\",protocol=\"{}\"}} {}",
+                server, protocol, score
+            );
+        }
+
+        buf
+    }
+}
+
+fn write_header(buf: &mut String, name: &str, metric_type: &str, help: &str) {
+    let _ = writeln!(buf, "# HELP shadowsocks_{} {}", name, help);
+    let _ = writeln!(buf, "# TYPE shadowsocks_{} {}", name, metric_type);
+}
+
+fn write_protocol_counter(buf: &mut String, name: &str, counter: &ProtocolCounter) {
+    for &(protocol, value) in &[(Protocol::Tcp, &counter.tcp), (Protocol::Udp, &counter.udp)] {
+        let _ = writeln!(
+            buf,
+            "shadowsocks_{}{{protocol=\"{}\"}} {}",
+            name,
+            protocol.as_str(),
+            value.load(Ordering::Relaxed)
+        );
+    }
+}
+
+async fn handle_request(context: SharedContext, req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    if req.method() != Method::GET || req.uri().path() != "/metrics" {
+        let mut resp = Response::new(Body::empty());
+        *resp.status_mut() = StatusCode::NOT_FOUND;
+        return Ok(resp);
+    }
+
+    let mut resp = Response::new(Body::from(context.metrics().render()));
+    resp.headers_mut()
+        .insert(CONTENT_TYPE, "text/plain; version=0.0.4".parse().unwrap());
+    Ok(resp)
+}
+
+/// Serves metrics on `metrics_address`
+pub async fn run(context: SharedContext) -> io::Result<()> {
+    let metrics_addr = context
+        .config()
+        .metrics_address
+        .as_ref()
+        .expect("metrics_address must not be None");
+    let bind_addr = metrics_addr.bind_addr(&context).await?;
+
+    let make_service = make_service_fn(|_| {
+        let context = context.clone();
+        async move { Ok::<_, Infallible>(service_fn(move |req| handle_request(context.clone(), req))) }
+    });
+
+    let server = Server::try_bind(&bind_addr)
+        .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?
+        .serve(make_service);
+    info!("ShadowSocks metrics listening on {}", server.local_addr());
+
+    if let Err(err) = server.await {
+        error!("Metrics server error: {}", err);
+        return Err(io::Error::new(io::ErrorKind::Other, err));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn render_metrics() {
+        let metrics = Metrics::new();
+
+        let port = metrics.port(8388);
+        let guard = port.tcp_connection();
+        port.incr_bytes_up(10);
+        port.incr_bytes_down(20);
+        let _udp_guard = port.udp_association();
+        port.incr_rejected(Limit::TcpPerIp);
+        port.incr_denied_destinations(Protocol::Udp);
+        assert_eq!(metrics.active_relays(), 2);
+        drop(guard);
+        assert_eq!(metrics.active_relays(), 1);
+
+        metrics.incr_handshake_failures();
+        metrics.incr_repeated_nonces(Protocol::Tcp);
+        metrics.set_server_score(&ServerAddr::from(("example.com", 8388)), Protocol::Tcp, 100);
+
+        let output = metrics.render();
+        assert!(output.contains("shadowsocks_tcp_connections_active{port=\"8388\"} 0\n"));
+        assert!(output.contains("shadowsocks_tcp_connections_total{port=\"8388\"} 1\n"));
+        assert!(output.contains("shadowsocks_traffic_bytes_total{port=\"8388\",direction=\"up\"} 10\n"));
+        assert!(output.contains("shadowsocks_traffic_bytes_total{port=\"8388\",direction=\"down\"} 20\n"));
+        assert!(output.contains("shadowsocks_udp_associations{port=\"8388\"} 1\n"));
+        assert!(output.contains("shadowsocks_rejected_connections_total{port=\"8388\",limit=\"tcp_per_ip\"} 1\n"));
+        assert!(output.contains("shadowsocks_denied_destinations_total{port=\"8388\",protocol=\"udp\"} 1\n"));
+        assert!(output.contains("shadowsocks_handshake_failures_total 1\n"));
+        assert!(output.contains("shadowsocks_repeated_nonces_total{protocol=\"tcp\"} 1\n"));
+        assert!(output.contains("shadowsocks_server_score{server=\"example.com:8388\",protocol=\"tcp\"} 100\n"));
+    }
+}
@@ -1,10 +1,21 @@
 //! Relay server in local and server side implementations.
 
+pub(crate) mod access_log;
 pub(crate) mod dns_resolver;
+pub(crate) mod flow;
+pub mod ip_set;
+pub(crate) mod limiter;
 pub(crate) mod loadbalancing;
 pub mod local;
+pub(crate) mod manager;
+pub mod metrics;
+pub mod registry;
+pub(crate) mod replay;
 pub mod server;
+pub(crate) mod shaper;
 pub mod socks5;
 pub mod tcprelay;
 pub mod udprelay;
+pub(crate) mod upstream;
+pub(crate) mod users;
 pub(crate) mod utils;
@@ -0,0 +1,685 @@
+//! Registry of running servers
+//!
+//! Servers could be added or removed while the whole server is running. Each server owns its TCP and UDP listeners,
+//! so connections and associations of the other servers won't be affected.
+
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    net::{SocketAddr, TcpListener as StdTcpListener, UdpSocket as StdUdpSocket},
+    sync::Arc,
+    time::Duration,
+};
+
+use futures::future::{abortable, AbortHandle};
+use log::{debug, error, info, warn};
+use tokio::{
+    self,
+    net::{TcpListener, UdpSocket},
+    sync::{oneshot, Mutex},
+    time,
+};
+
+use crate::{
+    config::{Config, Mode, ServerConfig},
+    context::SharedContext,
+    relay::{
+        flow::{reset_quota_periodically, ServerFlowStatistic, SharedServerFlowStatistic},
+        tcprelay::{
+            server::{create_listener as create_tcp_listener, serve as serve_tcp},
+            server_context::TcpServerContext,
+            utils::create_std_listener as create_std_tcp_listener,
+        },
+        udprelay::{
+            server::{create_listener as create_udp_listener, serve as serve_udp},
+            utils::create_std_socket as create_std_udp_socket,
+        },
+        users::{reset_user_quota_periodically, ServerUsers, SharedServerUsers},
+    },
+};
+
+/// Default time for active relays to finish while shutting down
+pub const DEFAULT_SHUTDOWN_GRACE_PERIOD: Duration = Duration::from_secs(30);
+
+// Interval of checking whether all relays are finished while shutting down
+const DRAIN_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+// Listening sockets of a server
+//
+// They are taken over by the server replacing it on the same address, so the port is kept while replacing.
+#[derive(Clone)]
+struct ServerSockets {
+    addr: SocketAddr,
+    tcp: Option<Arc<StdTcpListener>>,
+    udp: Option<Arc<StdUdpSocket>>,
+}
+
+impl ServerSockets {
+    fn bind(addr: SocketAddr, mode: Mode) -> io::Result<ServerSockets> {
+        let tcp = if mode.enable_tcp() {
+            let listener = create_std_tcp_listener(&addr)?;
+            info!("ShadowSocks TCP Listening on {}", addr);
+            Some(Arc::new(listener))
+        } else {
+            None
+        };
+
+        let udp = if mode.enable_udp() {
+            let socket = create_std_udp_socket(&addr)?;
+            info!("ShadowSocks UDP listening on {}", addr);
+            Some(Arc::new(socket))
+        } else {
+            None
+        };
+
+        Ok(ServerSockets { addr, tcp, udp })
+    }
+}
+
+// A server with its listeners bound, it is not serving until started
+struct BoundServer {
+    svr_cfg: ServerConfig,
+    sockets: Option<ServerSockets>,
+    tcp_listener: Option<TcpListener>,
+    udp_listener: Option<UdpSocket>,
+    // Traffic statistics of the replaced server, kept so replacing won't reset the used quota
+    flow: Option<SharedServerFlowStatistic>,
+    users: Option<SharedServerUsers>,
+}
+
+impl BoundServer {
+    // Sockets of `running` are taken over if it is listening on the same address, and its traffic statistics are
+    // kept after the bound server started
+    async fn bind(
+        context: &SharedContext,
+        svr_cfg: ServerConfig,
+        running: Option<&ServerInstance>,
+    ) -> io::Result<BoundServer> {
+        let mode = context.config().mode;
+        let flow = running.map(|r| r.flow.clone());
+        let users = running.and_then(|r| r.users.clone());
+
+        if !svr_cfg.users().is_empty() && !ServerUsers::is_supported(svr_cfg.method()) {
+            let err = io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("multi-user servers don't support {}", svr_cfg.method()),
+            );
+            return Err(err);
+        }
+
+        // Keys of AEAD-2022 ciphers are decoded while serving, which must not fail
+        let method = svr_cfg.method();
+        let passwords = Some(svr_cfg.password()).into_iter().chain(svr_cfg.users().iter().map(|u| u.password()));
+        for password in passwords {
+            if method.check_key(password).is_err() {
+                let msg = format!(
+                    "invalid `password`, `{}` requires a base64 encoded key of {} bytes",
+                    method,
+                    method.key_size()
+                );
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+            }
+        }
+
+        // Ports of plugins are bound while launching
+        if svr_cfg.plugin().is_some() {
+            let tcp_listener = if mode.enable_tcp() {
+                Some(create_tcp_listener(context, &svr_cfg).await?)
+            } else {
+                None
+            };
+            let udp_listener = if mode.enable_udp() {
+                Some(create_udp_listener(context, &svr_cfg).await?)
+            } else {
+                None
+            };
+
+            return Ok(BoundServer {
+                svr_cfg,
+                sockets: None,
+                tcp_listener,
+                udp_listener,
+                flow,
+                users,
+            });
+        }
+
+        let addr = svr_cfg.addr().bind_addr(context).await?;
+        let sockets = match running.and_then(|r| r.sockets.as_ref()) {
+            Some(sockets) if sockets.addr == addr => {
+                debug!("Taking over listeners on {}", addr);
+                sockets.clone()
+            }
+            _ => ServerSockets::bind(addr, mode)?,
+        };
+
+        let tcp_listener = match sockets.tcp {
+            Some(ref listener) => Some(TcpListener::from_std(listener.try_clone()?)?),
+            None => None,
+        };
+        let udp_listener = match sockets.udp {
+            Some(ref socket) => Some(UdpSocket::from_std(socket.try_clone()?)?),
+            None => None,
+        };
+
+        Ok(BoundServer {
+            svr_cfg,
+            sockets: Some(sockets),
+            tcp_listener,
+            udp_listener,
+            flow,
+            users,
+        })
+    }
+}
+
+/// A running server, both TCP and UDP relays will be stopped while dropping
+struct ServerInstance {
+    svr_cfg: ServerConfig,
+    flow: SharedServerFlowStatistic,
+    users: Option<SharedServerUsers>,
+    sockets: Option<ServerSockets>,
+    tcp_abort_handle: Option<AbortHandle>,
+    udp_close_tx: Option<oneshot::Sender<()>>,
+    abort_handles: Vec<AbortHandle>,
+}
+
+impl Drop for ServerInstance {
+    fn drop(&mut self) {
+        self.close_tcp_listener();
+        for handle in &self.abort_handles {
+            handle.abort();
+        }
+    }
+}
+
+impl ServerInstance {
+    fn start(context: &SharedContext, bound: BoundServer) -> ServerInstance {
+        let BoundServer {
+            svr_cfg,
+            sockets,
+            tcp_listener,
+            udp_listener,
+            flow,
+            users,
+        } = bound;
+
+        let flow = match flow {
+            Some(flow) => {
+                flow.set_quota(svr_cfg.quota());
+                flow
+            }
+            None => ServerFlowStatistic::new_shared(svr_cfg.quota()),
+        };
+
+        let mut instance = ServerInstance {
+            flow,
+            users: ServerUsers::new_shared(&svr_cfg, users.as_deref()),
+            svr_cfg,
+            sockets,
+            tcp_abort_handle: None,
+            udp_close_tx: None,
+            abort_handles: Vec::new(),
+        };
+
+        if let Some(listener) = tcp_listener {
+            let svr_context = TcpServerContext::new(
+                context.clone(),
+                &instance.svr_cfg,
+                instance.flow.clone(),
+                instance.users.clone(),
+            );
+
+            let (fut, handle) = abortable(serve_tcp(svr_context, listener));
+            tokio::spawn(fut);
+
+            instance.tcp_abort_handle = Some(handle);
+        }
+
+        if let Some(listener) = udp_listener {
+            let (close_tx, close_rx) = oneshot::channel();
+            let (fut, handle) = abortable(serve_udp(
+                context.clone(),
+                Arc::new(instance.svr_cfg.clone()),
+                instance.flow.clone(),
+                instance.users.clone(),
+                listener,
+                close_rx,
+            ));
+            tokio::spawn(fut);
+
+            instance.udp_close_tx = Some(close_tx);
+            instance.abort_handles.push(handle);
+        }
+
+        if let Some(period) = instance.svr_cfg.quota().and_then(|q| q.period) {
+            let port = instance.svr_cfg.addr().port();
+            let (fut, handle) = abortable(reset_quota_periodically(instance.flow.clone(), period, port));
+            tokio::spawn(fut);
+
+            instance.abort_handles.push(handle);
+        }
+
+        if let Some(ref users) = instance.users {
+            let port = instance.svr_cfg.addr().port();
+            for user in users.users() {
+                let (fut, handle) = abortable(reset_user_quota_periodically(user.clone(), port));
+                tokio::spawn(fut);
+
+                instance.abort_handles.push(handle);
+            }
+        }
+
+        instance
+    }
+
+    // Stops accepting new connections, the established ones are not affected
+    fn close_tcp_listener(&mut self) {
+        if let Some(handle) = self.tcp_abort_handle.take() {
+            handle.abort();
+        }
+        if let Some(ref mut sockets) = self.sockets {
+            sockets.tcp = None;
+        }
+    }
+
+    // Stops accepting new associations, the existing ones are not affected
+    fn close_udp_listener(&mut self) {
+        if let Some(close_tx) = self.udp_close_tx.take() {
+            let _ = close_tx.send(());
+        }
+        if let Some(ref mut sockets) = self.sockets {
+            sockets.udp = None;
+        }
+    }
+
+    fn stat(&self) -> ServerStat {
+        ServerStat::from_flow(&self.flow)
+    }
+
+    fn user_stats(&self) -> HashMap<String, ServerStat> {
+        match self.users {
+            Some(ref users) => users
+                .users()
+                .iter()
+                .map(|u| (u.name().to_owned(), ServerStat::from_flow(u.flow())))
+                .collect(),
+            None => HashMap::new(),
+        }
+    }
+}
+
+/// Statistic data of a running server
+#[derive(Debug, Clone, Copy)]
+pub struct ServerStat {
+    /// Total bytes transferred, including both TCP and UDP
+    pub transmission: usize,
+    /// Remaining bytes of traffic quota in the current period, `None` if quota is not set
+    pub quota_remaining: Option<u64>,
+}
+
+impl ServerStat {
+    fn from_flow(flow: &ServerFlowStatistic) -> ServerStat {
+        ServerStat {
+            transmission: flow.transmission(),
+            quota_remaining: flow.quota_remaining(),
+        }
+    }
+
+    // Adds up statistic data of servers listening on the same port
+    fn merge(self, other: ServerStat) -> ServerStat {
+        let quota_remaining = match (self.quota_remaining, other.quota_remaining) {
+            (Some(q1), Some(q2)) => Some(q1.saturating_add(q2)),
+            (q1, q2) => q1.or(q2),
+        };
+
+        ServerStat {
+            transmission: self.transmission + other.transmission,
+            quota_remaining,
+        }
+    }
+}
+
+// Resolves addresses that `servers` listening on, servers sharing the same address are rejected
+async fn resolve_server_addrs(context: &SharedContext, servers: &[ServerConfig]) -> io::Result<Vec<SocketAddr>> {
+    let mut addrs = Vec::with_capacity(servers.len());
+    for svr_cfg in servers {
+        let addr = svr_cfg.addr().bind_addr(context).await?;
+        if addrs.contains(&addr) {
+            let err = io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("more than one server listening on {}", addr),
+            );
+            return Err(err);
+        }
+        addrs.push(addr);
+    }
+    Ok(addrs)
+}
+
+struct RegistryInner {
+    context: Option<SharedContext>,
+    // Servers are keyed by their listening addresses, so servers could share the same port on different IPs
+    servers: HashMap<SocketAddr, ServerInstance>,
+    // Addresses of servers from configuration, servers added in other ways are not touched while reloading
+    configured_addrs: HashSet<SocketAddr>,
+}
+
+impl RegistryInner {
+    fn context(&self) -> io::Result<SharedContext> {
+        match self.context {
+            Some(ref c) => Ok(c.clone()),
+            None => {
+                let err = io::Error::new(io::ErrorKind::NotConnected, "server is not running");
+                Err(err)
+            }
+        }
+    }
+
+    async fn start_server(
+        &mut self,
+        context: &SharedContext,
+        addr: SocketAddr,
+        svr_cfg: ServerConfig,
+    ) -> io::Result<()> {
+        // The running one is kept if it failed to bind
+        let bound = BoundServer::bind(context, svr_cfg, self.servers.get(&addr)).await?;
+        if self
+            .servers
+            .insert(addr, ServerInstance::start(context, bound))
+            .is_some()
+        {
+            debug!("Replaced server listening on {}", addr);
+        }
+
+        Ok(())
+    }
+}
+
+// Checks if a running server could be kept as is with the reloaded configuration
+fn is_same_server(running: &ServerConfig, svr_cfg: &ServerConfig) -> bool {
+    let same_plugin = match (running.plugin(), svr_cfg.plugin()) {
+        (None, None) => true,
+        (Some(p1), Some(p2)) => {
+            p1.plugin == p2.plugin && p1.plugin_opt == p2.plugin_opt && p1.plugin_mode == p2.plugin_mode
+        }
+        _ => false,
+    };
+
+    same_plugin
+        && running.addr().to_string() == svr_cfg.addr().to_string()
+        && running.password() == svr_cfg.password()
+        && running.method() == svr_cfg.method()
+        && running.timeout() == svr_cfg.timeout()
+        && running.quota() == svr_cfg.quota()
+        && running.bandwidth() == svr_cfg.bandwidth()
+        && running.connection_bandwidth() == svr_cfg.connection_bandwidth()
+        && running.port_policy() == svr_cfg.port_policy()
+        && running.outbound_proxy() == svr_cfg.outbound_proxy()
+        && running.fallback() == svr_cfg.fallback()
+        && running.allow_bind() == svr_cfg.allow_bind()
+        && running.users() == svr_cfg.users()
+}
+
+/// Registry of running servers, identified by their listening addresses
+///
+/// The registry is a cheap handle and could be cloned freely. It becomes usable after being passed to
+/// `run_server_with_registry`, and all servers in it will be stopped after the server exits.
+///
+/// NOTE: Plugins are only launched for servers in the configuration, servers added to the registry run without plugins.
+#[derive(Clone)]
+pub struct ServerRegistry {
+    inner: Arc<Mutex<RegistryInner>>,
+}
+
+impl Default for ServerRegistry {
+    fn default() -> ServerRegistry {
+        ServerRegistry::new()
+    }
+}
+
+impl ServerRegistry {
+    /// Creates an empty registry
+    pub fn new() -> ServerRegistry {
+        ServerRegistry {
+            inner: Arc::new(Mutex::new(RegistryInner {
+                context: None,
+                servers: HashMap::new(),
+                configured_addrs: HashSet::new(),
+            })),
+        }
+    }
+
+    /// Binds the registry to a running server
+    pub(crate) async fn attach(&self, context: SharedContext) {
+        let mut inner = self.inner.lock().await;
+        inner.context = Some(context);
+    }
+
+    /// Starts servers in configuration of the running server
+    pub(crate) async fn start_configured(&self) -> io::Result<()> {
+        let mut inner = self.inner.lock().await;
+        let context = inner.context()?;
+
+        let servers = &context.config().server;
+        let addrs = match resolve_server_addrs(&context, servers).await {
+            Ok(addrs) => addrs,
+            Err(err) => {
+                error!("Invalid servers in configuration, {}", err);
+                return Err(err);
+            }
+        };
+        for (addr, svr_cfg) in addrs.iter().zip(servers) {
+            if let Err(err) = inner.start_server(&context, *addr, svr_cfg.clone()).await {
+                error!("Failed to listen on {}, {}", svr_cfg.addr(), err);
+                return Err(err);
+            }
+        }
+
+        inner.configured_addrs = addrs.into_iter().collect();
+        Ok(())
+    }
+
+    /// Stops all servers, registry is not usable until it is attached again
+    pub(crate) async fn detach(&self) {
+        let mut inner = self.inner.lock().await;
+        inner.context = None;
+        inner.servers.clear();
+        inner.configured_addrs.clear();
+    }
+
+    /// Starts a server listening on `svr_cfg.addr()`
+    ///
+    /// The running server on the same address will be replaced.
+    pub async fn add_server(&self, svr_cfg: ServerConfig) -> io::Result<()> {
+        let mut inner = self.inner.lock().await;
+        let context = inner.context()?;
+
+        if context.server_draining() {
+            let err = io::Error::new(io::ErrorKind::Other, "server is shutting down");
+            return Err(err);
+        }
+
+        let addr = svr_cfg.addr().bind_addr(&context).await?;
+        inner.start_server(&context, addr, svr_cfg).await?;
+
+        info!("Server on {} added", addr);
+        Ok(())
+    }
+
+    /// Stops all servers listening on `port`
+    ///
+    /// Returns `false` if there is no such server
+    pub async fn remove_server(&self, port: u16) -> bool {
+        let mut inner = self.inner.lock().await;
+
+        let addrs = inner
+            .servers
+            .keys()
+            .filter(|addr| addr.port() == port)
+            .cloned()
+            .collect::<Vec<_>>();
+        for addr in &addrs {
+            inner.servers.remove(addr);
+            inner.configured_addrs.remove(addr);
+            info!("Server on {} removed", addr);
+        }
+
+        !addrs.is_empty()
+    }
+
+    /// Applies servers, `forbidden_ip` and DNS settings of `config` to the running server
+    ///
+    /// Servers are matched by their listening addresses. Only new or changed servers are (re)started, connections of
+    /// the unchanged ones are kept. Servers with plugins could not be reloaded, they are kept running until restart.
+    ///
+    /// Nothing will be changed if `config` is invalid, or any of the new or changed servers failed to bind.
+    pub async fn reload(&self, config: Config) -> io::Result<()> {
+        let mut inner = self.inner.lock().await;
+        let context = inner.context()?;
+
+        let addrs = resolve_server_addrs(&context, &config.server).await?;
+
+        // Binds new or changed servers first, nothing is changed if any of them failed
+        let mut bound_servers = Vec::new();
+        for (addr, svr_cfg) in addrs.iter().zip(&config.server) {
+            let running = inner.servers.get(addr);
+            let running_plugin = match running {
+                Some(instance) if is_same_server(&instance.svr_cfg, svr_cfg) => continue,
+                Some(instance) => instance.svr_cfg.plugin().is_some(),
+                None => false,
+            };
+
+            if running_plugin || svr_cfg.plugin().is_some() {
+                warn!("Server on {} with plugin won't be reloaded until restart", addr);
+                continue;
+            }
+
+            match BoundServer::bind(&context, svr_cfg.clone(), running).await {
+                Ok(bound) => bound_servers.push((*addr, bound)),
+                Err(err) => {
+                    error!("Failed to reload server on {}, {}", addr, err);
+                    return Err(err);
+                }
+            }
+        }
+
+        context.clone_server_state().reload(&config).await?;
+
+        let mut addrs = addrs.into_iter().collect::<HashSet<_>>();
+        let removed_addrs = inner.configured_addrs.difference(&addrs).cloned().collect::<Vec<_>>();
+        for addr in removed_addrs {
+            if let Some(instance) = inner.servers.get(&addr) {
+                if instance.svr_cfg.plugin().is_some() {
+                    warn!("Server on {} runs with plugin, kept until restart", addr);
+                    addrs.insert(addr);
+                    continue;
+                }
+            }
+
+            if inner.servers.remove(&addr).is_some() {
+                info!("Server on {} removed", addr);
+            }
+        }
+
+        for (addr, bound) in bound_servers {
+            let instance = ServerInstance::start(&context, bound);
+            inner.servers.insert(addr, instance);
+
+            info!("Server on {} reloaded", addr);
+        }
+
+        inner.configured_addrs = addrs;
+        Ok(())
+    }
+
+    /// Shuts down the server gracefully
+    ///
+    /// All listeners are closed at once, UDP servers throw away packets of new associations and stop after the
+    /// existing ones are finished. Active TCP relays and UDP associations are given `shutdown_grace_period` to
+    /// finish. `run_server_with_registry` returns `Ok(())` after they are all finished or the grace period elapsed.
+    pub async fn shutdown(&self) -> io::Result<()> {
+        let context = {
+            let mut inner = self.inner.lock().await;
+            let context = inner.context()?;
+
+            context.clone_server_state().start_draining();
+            for instance in inner.servers.values_mut() {
+                instance.close_tcp_listener();
+                instance.close_udp_listener();
+            }
+
+            context
+        };
+
+        let grace_period = context
+            .config()
+            .shutdown_grace_period
+            .unwrap_or(DEFAULT_SHUTDOWN_GRACE_PERIOD);
+        info!(
+            "Shutting down, waiting {} active relays to finish in {:?}",
+            context.metrics().active_relays(),
+            grace_period
+        );
+
+        let drain = async {
+            while context.metrics().active_relays() > 0 {
+                time::delay_for(DRAIN_CHECK_INTERVAL).await;
+            }
+        };
+        if time::timeout(grace_period, drain).await.is_err() {
+            warn!(
+                "Shutdown grace period elapsed, {} relays are still active",
+                context.metrics().active_relays()
+            );
+        }
+
+        self.detach().await;
+        context.server_stopped();
+
+        Ok(())
+    }
+
+    /// Configurations of all running servers
+    pub async fn servers(&self) -> Vec<ServerConfig> {
+        let inner = self.inner.lock().await;
+        inner.servers.values().map(|s| s.svr_cfg.clone()).collect()
+    }
+
+    /// Statistic data of running servers, keyed by port
+    ///
+    /// Servers listening on the same port are added up.
+    pub async fn stats(&self) -> HashMap<u16, ServerStat> {
+        let inner = self.inner.lock().await;
+
+        let mut stats = HashMap::new();
+        for (addr, s) in &inner.servers {
+            let stat = match stats.remove(&addr.port()) {
+                Some(stat) => s.stat().merge(stat),
+                None => s.stat(),
+            };
+            stats.insert(addr.port(), stat);
+        }
+        stats
+    }
+
+    /// Statistic data of each user of the running multi-user servers, keyed by port and user name
+    ///
+    /// Users of servers listening on the same port are added up.
+    pub async fn user_stats(&self) -> HashMap<u16, HashMap<String, ServerStat>> {
+        let inner = self.inner.lock().await;
+
+        let mut stats: HashMap<u16, HashMap<String, ServerStat>> = HashMap::new();
+        for (addr, s) in inner.servers.iter().filter(|(_, s)| s.users.is_some()) {
+            let port_stats = stats.entry(addr.port()).or_default();
+            for (name, stat) in s.user_stats() {
+                let stat = match port_stats.remove(&name) {
+                    Some(prev) => stat.merge(prev),
+                    None => stat,
+                };
+                port_stats.insert(name, stat);
+            }
+        }
+        stats
+    }
+}
@@ -0,0 +1,691 @@
+//! Replay protection
+//!
+//! IVs and salts received by servers are kept in bloom filters, packets or connections with a repeated one are
+//! rejected. Filters could be saved into a file, so they are still protected after restarting.
+//!
+//! AEAD-2022 ciphers have timestamps in headers, so their salts are only kept for a while, and UDP packets are
+//! checked with sliding windows of packet IDs, one for each session ID of peers.
+
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fs::{self, OpenOptions},
+    io::{self, Read, Write},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use bloomfilter::Bloom;
+use bytes::{Buf, BufMut, BytesMut};
+use log::{debug, warn};
+use spin::Mutex;
+
+use crate::{
+    config::{Config, ReplayFilterMode, ServerAddr},
+    crypto::aead2022,
+};
+
+// Entries for server's bloom filter
+//
+// Borrowed from shadowsocks-libev's default value
+const BF_NUM_ENTRIES_FOR_SERVER: usize = 1_000_000;
+
+// Entries for client's bloom filter
+//
+// Borrowed from shadowsocks-libev's default value
+const BF_NUM_ENTRIES_FOR_CLIENT: usize = 10_000;
+
+// Error rate for server's bloom filter
+//
+// Borrowed from shadowsocks-libev's default value
+const BF_ERROR_RATE_FOR_SERVER: f64 = 1e-6;
+
+// Error rate for client's bloom filter
+//
+// Borrowed from shadowsocks-libev's default value
+const BF_ERROR_RATE_FOR_CLIENT: f64 = 1e-15;
+
+// Salts of AEAD-2022 ciphers kept at most, the oldest ones are dropped if it is full
+const MAX_RECENT_SALTS: usize = 1_000_000;
+
+// Number of packet IDs kept in a window of UDP sessions
+const PACKET_WINDOW_SIZE: u64 = 1024;
+
+// Header of saved filters, followed by a version byte
+const FILE_MAGIC: &[u8] = b"SSRF";
+const FILE_VERSION: u8 = 1;
+
+// A bloom filter borrowed from shadowsocks-libev's `ppbloom`
+//
+// It contains 2 bloom filters and each one holds 1/2 entries.
+// Use them as a ring buffer.
+struct PingPongBloom {
+    blooms: [Bloom<[u8]>; 2],
+    bloom_count: [usize; 2],
+    item_count: usize,
+    current: usize,
+}
+
+impl PingPongBloom {
+    fn new(entries: usize, fp_p: f64) -> PingPongBloom {
+        let item_count = (entries / 2).max(1);
+
+        PingPongBloom {
+            blooms: [
+                Bloom::new_for_fp_rate(item_count, fp_p),
+                Bloom::new_for_fp_rate(item_count, fp_p),
+            ],
+            bloom_count: [0, 0],
+            item_count,
+            current: 0,
+        }
+    }
+
+    // Check if data in `buf` exist.
+    //
+    // Set into the current bloom filter if not exist.
+    //
+    // Return `true` if data exist in bloom filter.
+    fn check_and_set(&mut self, buf: &[u8]) -> bool {
+        for bloom in &self.blooms {
+            if bloom.check(buf) {
+                return true;
+            }
+        }
+
+        if self.bloom_count[self.current] >= self.item_count {
+            // Current bloom filter is full,
+            // Create a new one and use that one as current.
+
+            self.current = (self.current + 1) % 2;
+
+            self.bloom_count[self.current] = 0;
+            self.blooms[self.current].clear();
+        }
+
+        // Cannot be optimized by `check_and_set`
+        // Because we have to check every filters in `blooms` before `set`
+        self.blooms[self.current].set(buf);
+        self.bloom_count[self.current] += 1;
+
+        false
+    }
+
+    // Check if `other` is created with the same entries and error rate
+    fn is_same_size(&self, other: &PingPongBloom) -> bool {
+        self.item_count == other.item_count
+            && self.blooms[0].number_of_bits() == other.blooms[0].number_of_bits()
+            && self.blooms[0].number_of_hash_functions() == other.blooms[0].number_of_hash_functions()
+    }
+
+    fn write_to_buf<B: BufMut>(&self, buf: &mut B) {
+        buf.put_u64(self.item_count as u64);
+        buf.put_u8(self.current as u8);
+
+        for (bloom, count) in self.blooms.iter().zip(self.bloom_count.iter()) {
+            buf.put_u64(*count as u64);
+            buf.put_u64(bloom.number_of_bits());
+            buf.put_u32(bloom.number_of_hash_functions());
+            for (k0, k1) in bloom.sip_keys().iter() {
+                buf.put_u64(*k0);
+                buf.put_u64(*k1);
+            }
+
+            let bitmap = bloom.bitmap();
+            buf.put_u64(bitmap.len() as u64);
+            buf.put_slice(&bitmap);
+        }
+    }
+
+    fn read_from_buf<B: Buf>(buf: &mut B) -> io::Result<PingPongBloom> {
+        ensure_remaining(buf, 9)?;
+        let item_count = buf.get_u64() as usize;
+        let current = buf.get_u8() as usize;
+        if current > 1 {
+            return Err(invalid_file());
+        }
+
+        let mut blooms = Vec::with_capacity(2);
+        let mut bloom_count = [0usize; 2];
+        for count in bloom_count.iter_mut() {
+            ensure_remaining(buf, 8 + 8 + 4 + 32 + 8)?;
+            *count = buf.get_u64() as usize;
+            let bits = buf.get_u64();
+            let k_num = buf.get_u32();
+            let sip_keys = [(buf.get_u64(), buf.get_u64()), (buf.get_u64(), buf.get_u64())];
+
+            let len = buf.get_u64() as usize;
+            ensure_remaining(buf, len)?;
+
+            // `Bloom` panics with these parameters
+            if bits == 0 || k_num == 0 || bits > len as u64 * 8 {
+                return Err(invalid_file());
+            }
+
+            let mut bitmap = vec![0u8; len];
+            buf.copy_to_slice(&mut bitmap);
+
+            blooms.push(Bloom::from_existing(&bitmap, bits, k_num, sip_keys));
+        }
+
+        let second = blooms.pop().unwrap();
+        let first = blooms.pop().unwrap();
+
+        Ok(PingPongBloom {
+            blooms: [first, second],
+            bloom_count,
+            item_count,
+            current,
+        })
+    }
+}
+
+fn invalid_file() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "invalid replay filter file")
+}
+
+fn ensure_remaining<B: Buf>(buf: &B, n: usize) -> io::Result<()> {
+    if buf.remaining() < n {
+        Err(invalid_file())
+    } else {
+        Ok(())
+    }
+}
+
+/// Bloom filter of IVs and salts, shared by all servers or owned by one of them
+pub struct NonceFilter {
+    bloom: Mutex<PingPongBloom>,
+}
+
+/// `NonceFilter` wrapped in `Arc`
+pub type SharedNonceFilter = Arc<NonceFilter>;
+
+impl NonceFilter {
+    fn new_shared(bloom: PingPongBloom) -> SharedNonceFilter {
+        Arc::new(NonceFilter {
+            bloom: Mutex::new(bloom),
+        })
+    }
+
+    /// Check if nonce exist or not
+    ///
+    /// If not, set into the current bloom filter
+    pub fn check_and_set(&self, nonce: &[u8]) -> bool {
+        self.bloom.lock().check_and_set(nonce)
+    }
+}
+
+/// Replay filters of all servers
+///
+/// Servers share the global filter, or each of them has its own filter keyed by its address,
+/// which is created while it receives the first nonce.
+pub struct ReplayFilter {
+    entries: usize,
+    fp_rate: f64,
+    mode: ReplayFilterMode,
+    global: SharedNonceFilter,
+    servers: Mutex<HashMap<String, SharedNonceFilter>>,
+}
+
+impl ReplayFilter {
+    /// Creates empty filters with sizes in `config`
+    pub fn new(config: &Config) -> ReplayFilter {
+        let (entries, fp_rate) = if config.config_type.is_local() {
+            (BF_NUM_ENTRIES_FOR_CLIENT, BF_ERROR_RATE_FOR_CLIENT)
+        } else {
+            (BF_NUM_ENTRIES_FOR_SERVER, BF_ERROR_RATE_FOR_SERVER)
+        };
+        let entries = config.replay_filter_entries.unwrap_or(entries);
+        let fp_rate = config.replay_filter_fp_rate.unwrap_or(fp_rate);
+
+        ReplayFilter {
+            entries,
+            fp_rate,
+            mode: config.replay_filter_mode,
+            global: NonceFilter::new_shared(PingPongBloom::new(entries, fp_rate)),
+            servers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Get the filter for nonces of the server listening on `svr_addr`
+    pub fn filter(&self, svr_addr: &ServerAddr) -> SharedNonceFilter {
+        match self.mode {
+            ReplayFilterMode::Global => self.global.clone(),
+            ReplayFilterMode::PerServer => self
+                .servers
+                .lock()
+                .entry(svr_addr.to_string())
+                .or_insert_with(|| NonceFilter::new_shared(PingPongBloom::new(self.entries, self.fp_rate)))
+                .clone(),
+        }
+    }
+
+    /// Saves all filters into `path`
+    ///
+    /// The file is replaced after all filters are written into a temporary file.
+    pub fn save_to_file(&self, path: &str) -> io::Result<()> {
+        let mut filters = vec![(String::new(), self.global.clone())];
+        for (key, filter) in self.servers.lock().iter() {
+            filters.push((key.clone(), filter.clone()));
+        }
+
+        let mut buf = BytesMut::new();
+        buf.put_slice(FILE_MAGIC);
+        buf.put_u8(FILE_VERSION);
+        buf.put_u32(filters.len() as u32);
+        for (key, filter) in filters {
+            buf.put_u16(key.len() as u16);
+            buf.put_slice(key.as_bytes());
+            filter.bloom.lock().write_to_buf(&mut buf);
+        }
+
+        let tmp_path = format!("{}.tmp", path);
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&tmp_path)?;
+        file.write_all(&buf)?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, path)
+    }
+
+    /// Loads filters saved by `save_to_file` from `path`
+    ///
+    /// Filters with different sizes from the configured ones are dropped. Server filters are ignored
+    /// if the filter is global, and the global filter is ignored if each server has its own.
+    pub fn load_from_file(&self, path: &str) -> io::Result<()> {
+        let mut content = Vec::new();
+        OpenOptions::new().read(true).open(path)?.read_to_end(&mut content)?;
+
+        let mut buf = &content[..];
+        ensure_remaining(&buf, FILE_MAGIC.len() + 1 + 4)?;
+        if &buf[..FILE_MAGIC.len()] != FILE_MAGIC {
+            return Err(invalid_file());
+        }
+        buf.advance(FILE_MAGIC.len());
+
+        let version = buf.get_u8();
+        if version != FILE_VERSION {
+            let err = io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unsupported replay filter file version {}", version),
+            );
+            return Err(err);
+        }
+
+        let empty = PingPongBloom::new(self.entries, self.fp_rate);
+
+        // Filters are replaced after the whole file is read, so they are kept empty if the file is broken
+        let n = buf.get_u32();
+        let mut blooms = Vec::new();
+        for _ in 0..n {
+            ensure_remaining(&buf, 2)?;
+            let len = buf.get_u16() as usize;
+            ensure_remaining(&buf, len)?;
+            let key = String::from_utf8(buf[..len].to_vec()).map_err(|_| invalid_file())?;
+            buf.advance(len);
+
+            let bloom = PingPongBloom::read_from_buf(&mut buf)?;
+            blooms.push((key, bloom));
+        }
+
+        for (key, bloom) in blooms {
+            if !bloom.is_same_size(&empty) {
+                warn!("Dropped saved replay filter of \"{}\", its size is changed", key);
+                continue;
+            }
+
+            match (self.mode, key.is_empty()) {
+                (ReplayFilterMode::Global, true) => *self.global.bloom.lock() = bloom,
+                (ReplayFilterMode::PerServer, false) => {
+                    self.servers.lock().insert(key, NonceFilter::new_shared(bloom));
+                }
+                _ => debug!("Ignored saved replay filter of \"{}\", mode is {}", key, self.mode),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// Salts in the order they are received
+struct RecentSalts {
+    set: HashSet<Vec<u8>>,
+    queue: VecDeque<(Instant, Vec<u8>)>,
+}
+
+/// Salts of AEAD-2022 ciphers received recently
+///
+/// Headers with timestamps out of `MAX_TIME_DIFF` are rejected, so salts are only kept for twice of that, and no more
+/// than `MAX_RECENT_SALTS` of them are kept.
+pub struct SaltWindow {
+    salts: Mutex<RecentSalts>,
+    lifetime: Duration,
+    capacity: usize,
+}
+
+impl SaltWindow {
+    pub fn new() -> SaltWindow {
+        SaltWindow::with_limits(Duration::from_secs(aead2022::MAX_TIME_DIFF * 2), MAX_RECENT_SALTS)
+    }
+
+    fn with_limits(lifetime: Duration, capacity: usize) -> SaltWindow {
+        SaltWindow {
+            salts: Mutex::new(RecentSalts {
+                set: HashSet::new(),
+                queue: VecDeque::new(),
+            }),
+            lifetime,
+            capacity,
+        }
+    }
+
+    /// Check if salt exist or not
+    ///
+    /// If not, keep it until it is expired
+    pub fn check_and_set(&self, salt: &[u8]) -> bool {
+        let now = Instant::now();
+
+        let mut salts = self.salts.lock();
+        let RecentSalts {
+            ref mut set,
+            ref mut queue,
+        } = *salts;
+        while let Some(&(created, _)) = queue.front() {
+            if now.duration_since(created) < self.lifetime && queue.len() < self.capacity {
+                break;
+            }
+            let (_, expired) = queue.pop_front().unwrap();
+            set.remove(&expired);
+        }
+
+        if set.contains(salt) {
+            return true;
+        }
+        set.insert(salt.to_vec());
+        queue.push_back((now, salt.to_vec()));
+        false
+    }
+}
+
+impl Default for SaltWindow {
+    fn default() -> SaltWindow {
+        SaltWindow::new()
+    }
+}
+
+/// Sliding window of packet IDs received in a UDP session of AEAD-2022 ciphers
+///
+/// Packets too old for the window are treated as replayed.
+pub struct PacketWindow {
+    last_id: u64,
+    bitmap: [u64; (PACKET_WINDOW_SIZE / 64) as usize],
+}
+
+impl PacketWindow {
+    pub fn new() -> PacketWindow {
+        PacketWindow {
+            last_id: 0,
+            bitmap: [0; (PACKET_WINDOW_SIZE / 64) as usize],
+        }
+    }
+
+    /// Check if packet ID exist or not
+    ///
+    /// If not, set into the window
+    pub fn check_and_set(&mut self, id: u64) -> bool {
+        if id > self.last_id {
+            let diff = id - self.last_id;
+            if diff >= PACKET_WINDOW_SIZE {
+                self.bitmap = [0; (PACKET_WINDOW_SIZE / 64) as usize];
+            } else {
+                // Clear bits slid into the window
+                for i in self.last_id + 1..id {
+                    self.clear(i);
+                }
+                self.clear(id);
+            }
+            self.last_id = id;
+        } else if self.last_id - id >= PACKET_WINDOW_SIZE {
+            return true;
+        }
+
+        let (word, mask) = PacketWindow::position(id);
+        if self.bitmap[word] & mask != 0 {
+            return true;
+        }
+        self.bitmap[word] |= mask;
+        false
+    }
+
+    fn clear(&mut self, id: u64) {
+        let (word, mask) = PacketWindow::position(id);
+        self.bitmap[word] &= !mask;
+    }
+
+    fn position(id: u64) -> (usize, u64) {
+        let bit = id % PACKET_WINDOW_SIZE;
+        ((bit / 64) as usize, 1 << (bit % 64))
+    }
+}
+
+impl Default for PacketWindow {
+    fn default() -> PacketWindow {
+        PacketWindow::new()
+    }
+}
+
+// Window of a session and the time that its last packet is received
+struct SessionEntry {
+    last_seen: Instant,
+    window: PacketWindow,
+}
+
+struct Sessions {
+    entries: HashMap<u64, SessionEntry>,
+    last_cleared: Instant,
+}
+
+/// Packet windows of UDP sessions of AEAD-2022 ciphers, keyed by session IDs of peers
+///
+/// Windows are shared by all associations, so packets replayed from another address are still detected. Packets with
+/// timestamps out of `MAX_TIME_DIFF` are rejected, so windows are kept for twice of that after the last packet.
+pub struct SessionWindows {
+    sessions: Mutex<Sessions>,
+    lifetime: Duration,
+}
+
+impl SessionWindows {
+    pub fn new() -> SessionWindows {
+        SessionWindows::with_lifetime(Duration::from_secs(aead2022::MAX_TIME_DIFF * 2))
+    }
+
+    fn with_lifetime(lifetime: Duration) -> SessionWindows {
+        SessionWindows {
+            sessions: Mutex::new(Sessions {
+                entries: HashMap::new(),
+                last_cleared: Instant::now(),
+            }),
+            lifetime,
+        }
+    }
+
+    /// Check if packet ID of session exist or not
+    ///
+    /// If not, set into the window of the session
+    pub fn check_and_set(&self, session_id: u64, packet_id: u64) -> bool {
+        let now = Instant::now();
+        let lifetime = self.lifetime;
+
+        let mut sessions = self.sessions.lock();
+
+        // Expired ones are cleared at most once in a lifetime, they are never used before that
+        if now.duration_since(sessions.last_cleared) >= lifetime {
+            sessions
+                .entries
+                .retain(|_, entry| now.duration_since(entry.last_seen) < lifetime);
+            sessions.last_cleared = now;
+        }
+
+        let entry = sessions.entries.entry(session_id).or_insert_with(|| SessionEntry {
+            last_seen: now,
+            window: PacketWindow::new(),
+        });
+        if now.duration_since(entry.last_seen) >= lifetime {
+            entry.window = PacketWindow::new();
+        }
+        entry.last_seen = now;
+        entry.window.check_and_set(packet_id)
+    }
+}
+
+impl Default for SessionWindows {
+    fn default() -> SessionWindows {
+        SessionWindows::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::config::ConfigType;
+
+    fn svr_addr(port: u16) -> ServerAddr {
+        ServerAddr::SocketAddr(([127, 0, 0, 1], port).into())
+    }
+
+    #[test]
+    fn ping_pong_bloom() {
+        let mut bloom = PingPongBloom::new(2000, 1e-6);
+        for i in 0..2000u32 {
+            assert!(!bloom.check_and_set(&i.to_be_bytes()));
+        }
+        assert!(bloom.check_and_set(&0u32.to_be_bytes()));
+
+        // Both of them are full, the first one is cleared
+        assert!(!bloom.check_and_set(&2000u32.to_be_bytes()));
+        assert!(!bloom.check_and_set(&0u32.to_be_bytes()));
+        assert!(bloom.check_and_set(&1999u32.to_be_bytes()));
+    }
+
+    #[test]
+    fn per_server_filter() {
+        let mut config = Config::new(ConfigType::Server);
+        config.replay_filter_entries = Some(100);
+
+        let filter = ReplayFilter::new(&config);
+        assert!(!filter.filter(&svr_addr(8388)).check_and_set(b"salt"));
+        assert!(filter.filter(&svr_addr(8389)).check_and_set(b"salt"));
+
+        config.replay_filter_mode = ReplayFilterMode::PerServer;
+        let filter = ReplayFilter::new(&config);
+        assert!(!filter.filter(&svr_addr(8388)).check_and_set(b"salt"));
+        assert!(!filter.filter(&svr_addr(8389)).check_and_set(b"salt"));
+        assert!(filter.filter(&svr_addr(8388)).check_and_set(b"salt"));
+    }
+
+    #[test]
+    fn save_and_load() {
+        let path = std::env::temp_dir().join(format!("ss-replay-filter-{}", std::process::id()));
+        let path = path.to_str().unwrap();
+
+        let mut config = Config::new(ConfigType::Server);
+        config.replay_filter_entries = Some(100);
+        config.replay_filter_mode = ReplayFilterMode::PerServer;
+
+        let filter = ReplayFilter::new(&config);
+        filter.filter(&svr_addr(8388)).check_and_set(b"salt");
+        filter.save_to_file(path).unwrap();
+
+        let filter = ReplayFilter::new(&config);
+        filter.load_from_file(path).unwrap();
+        assert!(filter.filter(&svr_addr(8388)).check_and_set(b"salt"));
+        assert!(!filter.filter(&svr_addr(8389)).check_and_set(b"salt"));
+
+        // Filters with different sizes are dropped
+        config.replay_filter_entries = Some(1000);
+        let filter = ReplayFilter::new(&config);
+        filter.load_from_file(path).unwrap();
+        assert!(!filter.filter(&svr_addr(8388)).check_and_set(b"salt"));
+
+        let _ = fs::remove_file(path);
+    }
+
+    #[test]
+    fn read_broken_bloom() {
+        let mut buf = Vec::new();
+        PingPongBloom::new(100, 1e-6).write_to_buf(&mut buf);
+        assert!(PingPongBloom::read_from_buf(&mut &buf[..]).is_ok());
+
+        // Number of bits of the first filter is at 17, followed by number of hash functions
+        let mut zero_bits = buf.clone();
+        zero_bits[17..25].copy_from_slice(&0u64.to_be_bytes());
+        assert!(PingPongBloom::read_from_buf(&mut &zero_bits[..]).is_err());
+
+        let mut zero_k_num = buf.clone();
+        zero_k_num[25..29].copy_from_slice(&0u32.to_be_bytes());
+        assert!(PingPongBloom::read_from_buf(&mut &zero_k_num[..]).is_err());
+
+        let mut too_many_bits = buf;
+        too_many_bits[17..25].copy_from_slice(&u64::MAX.to_be_bytes());
+        assert!(PingPongBloom::read_from_buf(&mut &too_many_bits[..]).is_err());
+    }
+
+    #[test]
+    fn salt_window() {
+        let window = SaltWindow::with_limits(Duration::from_millis(100), 10);
+        assert!(!window.check_and_set(b"salt"));
+        assert!(window.check_and_set(b"salt"));
+        assert!(!window.check_and_set(b"pepper"));
+
+        std::thread::sleep(Duration::from_millis(150));
+        assert!(!window.check_and_set(b"salt"));
+    }
+
+    #[test]
+    fn salt_window_capacity() {
+        let window = SaltWindow::with_limits(Duration::from_secs(60), 2);
+        assert!(!window.check_and_set(b"salt"));
+        assert!(!window.check_and_set(b"pepper"));
+        assert!(!window.check_and_set(b"sugar"));
+
+        // The oldest one is dropped
+        assert!(window.salts.lock().set.len() <= 2);
+        assert!(!window.check_and_set(b"salt"));
+    }
+
+    #[test]
+    fn session_windows() {
+        let windows = SessionWindows::with_lifetime(Duration::from_millis(100));
+        assert!(!windows.check_and_set(1, 0));
+        assert!(!windows.check_and_set(2, 0));
+        assert!(windows.check_and_set(1, 0));
+
+        // Switching to another session doesn't reset the window of the old one
+        assert!(!windows.check_and_set(2, 1));
+        assert!(windows.check_and_set(1, 0));
+
+        std::thread::sleep(Duration::from_millis(150));
+        assert!(!windows.check_and_set(1, 0));
+    }
+
+    #[test]
+    fn packet_window() {
+        let mut window = PacketWindow::new();
+        assert!(!window.check_and_set(1));
+        assert!(!window.check_and_set(3));
+        assert!(!window.check_and_set(2));
+        assert!(window.check_and_set(2));
+
+        // Packets out of order are accepted while they are in the window
+        assert!(!window.check_and_set(1000));
+        assert!(!window.check_and_set(100));
+        assert!(window.check_and_set(3));
+
+        assert!(!window.check_and_set(PACKET_WINDOW_SIZE + 100));
+        assert!(window.check_and_set(99));
+        assert!(!window.check_and_set(1000 + PACKET_WINDOW_SIZE));
+        assert!(window.check_and_set(1000));
+    }
+}
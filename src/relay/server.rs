@@ -2,19 +2,32 @@
 
 use std::io::{self, ErrorKind};
 
-use futures::future::{select_all, FutureExt};
-use log::{debug, error, trace, warn};
+use futures::future::{self, select_all, Either, FutureExt};
+use log::{debug, error, info, trace, warn};
 use tokio::runtime::Handle;
 
 use crate::{
     config::Config,
     context::{Context, ServerState},
     plugin::{PluginMode, Plugins},
-    relay::{tcprelay::server::run as run_tcp, udprelay::server::run as run_udp, utils::set_nofile},
+    relay::{
+        manager::run as run_manager,
+        metrics::run as run_metrics,
+        registry::ServerRegistry,
+        utils::set_nofile,
+    },
 };
 
 /// Relay server running on server side.
-pub async fn run(mut config: Config, rt: Handle) -> io::Result<()> {
+pub async fn run(config: Config, rt: Handle) -> io::Result<()> {
+    run_with_registry(config, rt, ServerRegistry::new()).await
+}
+
+/// Relay server running on server side, servers are started in `registry`
+///
+/// Servers could be added or removed with `registry` while running, and it returns `Ok(())` after
+/// `registry.shutdown()` finished.
+pub async fn run_with_registry(mut config: Config, rt: Handle, registry: ServerRegistry) -> io::Result<()> {
     trace!("{:?}", config);
     assert!(config.config_type.is_server());
 
@@ -38,35 +51,55 @@ pub async fn run(mut config: Config, rt: Handle) -> io::Result<()> {
 
     // Create a context containing a DNS resolver and server running state flag.
     let state = ServerState::new(&config, rt).await?;
+    state.load_replay_filter(&config);
 
     let mut vf = Vec::new();
 
-    if config.mode.enable_udp() {
-        // Clone config here, because the config for TCP relay will be modified
-        // after plugins started
-        let udp_context = Context::new_shared(config.clone(), state.clone());
+    // Servers behind plugins listen on loopback ports, and plugins listen on servers' addresses instead
+    // UDP relay listens on server's address, unless plugins support UDP relay (SIP003u)
+    let mode = config.mode;
+    if (mode.enable_tcp() && config.has_server_plugins()) || (mode.enable_udp() && config.has_udp_plugins()) {
+        let plugins = Plugins::launch_plugins(&mut config, PluginMode::Server)?;
+        vf.push(plugins.into_future().boxed());
+    }
 
-        // Run UDP relay before starting plugins
-        // Because plugins doesn't support UDP relay
-        let udp_fut = run_udp(udp_context);
-        vf.push(udp_fut.boxed());
+    let context = Context::new_shared(config, state.clone());
+    registry.attach(context.clone()).await;
+
+    if let Err(err) = registry.start_configured().await {
+        registry.detach().await;
+        state.server_stopped();
+        return Err(err);
     }
 
-    if config.mode.enable_tcp() {
-        if config.has_server_plugins() {
-            let plugins = Plugins::launch_plugins(&mut config, PluginMode::Client)?;
-            vf.push(plugins.into_future().boxed());
-        }
+    if context.config().manager_address.is_some() {
+        let manager_fut = run_manager(context.clone(), registry.clone());
+        vf.push(manager_fut.boxed());
+    }
+
+    if context.config().metrics_address.is_some() {
+        vf.push(run_metrics(context.clone()).boxed());
+    }
 
-        let tcp_fut = run_tcp(Context::new_shared(config, state.clone()));
-        vf.push(tcp_fut.boxed());
+    if vf.is_empty() {
+        // Servers are running in background, and they are only stopped by registry
+        vf.push(future::pending().boxed());
     }
 
-    let (res, ..) = select_all(vf.into_iter()).await;
+    let res = match future::select(select_all(vf.into_iter()), state.wait_stopped().boxed()).await {
+        Either::Left(((res, ..), ..)) => res,
+        Either::Right(..) => {
+            info!("Server is shut down");
+            state.save_replay_filter(context.config());
+            return Ok(());
+        }
+    };
     error!("one of servers exited unexpectly, result: {:?}", res);
 
     // Tells all detached tasks to exit
+    registry.detach().await;
     state.server_stopped();
+    state.save_replay_filter(context.config());
 
     Err(io::Error::new(io::ErrorKind::Other, "server exited unexpectly"))
 }
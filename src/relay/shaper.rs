@@ -0,0 +1,270 @@
+//! Bandwidth shaping with token buckets
+//!
+//! Each server entry has buckets shared by all its TCP connections and UDP associations, and optionally buckets of
+//! each connection. Streams wait until all their buckets have tokens, while UDP packets are dropped if any of them
+//! is empty.
+
+use std::{
+    cmp,
+    collections::HashMap,
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures::ready;
+use spin::Mutex;
+use tokio::time::{self, Delay, Instant};
+
+use crate::config::{BandwidthLimit, ServerConfig};
+
+struct BucketState {
+    tokens: f64,
+    last: Instant,
+}
+
+/// Token bucket refilled at a constant rate, which could be consumed in debt
+///
+/// It holds at most 1 second of tokens, which is the maximum burst.
+pub struct TokenBucket {
+    rate: f64,
+    state: Mutex<BucketState>,
+}
+
+pub type SharedTokenBucket = Arc<TokenBucket>;
+
+impl TokenBucket {
+    /// Creates a full bucket refilled with `rate` tokens (bytes) per second
+    pub fn new(rate: u64) -> TokenBucket {
+        let rate = cmp::max(rate, 1);
+        TokenBucket {
+            rate: rate as f64,
+            state: Mutex::new(BucketState {
+                tokens: rate as f64,
+                last: Instant::now(),
+            }),
+        }
+    }
+
+    fn refill(&self, state: &mut BucketState) {
+        let now = Instant::now();
+        let elapsed = now - state.last;
+        state.tokens = (state.tokens + elapsed.as_secs_f64() * self.rate).min(self.rate);
+        state.last = now;
+    }
+
+    /// Time to wait until there are tokens, `None` if there are already
+    pub fn wait_time(&self) -> Option<Duration> {
+        let mut state = self.state.lock();
+        self.refill(&mut state);
+        if state.tokens >= 1.0 {
+            None
+        } else {
+            Some(Duration::from_secs_f64((1.0 - state.tokens) / self.rate))
+        }
+    }
+
+    /// Consumes `n` tokens, the bucket may be in debt
+    pub fn consume(&self, n: usize) {
+        let mut state = self.state.lock();
+        self.refill(&mut state);
+        state.tokens -= n as f64;
+    }
+}
+
+/// Token buckets applied to one direction of a connection
+#[derive(Clone, Default)]
+pub struct Shaper {
+    buckets: Vec<SharedTokenBucket>,
+}
+
+impl Shaper {
+    fn push(&mut self, bucket: Option<SharedTokenBucket>) {
+        if let Some(b) = bucket {
+            self.buckets.push(b);
+        }
+    }
+
+    /// Time to wait until all buckets have tokens
+    pub fn wait_time(&self) -> Option<Duration> {
+        self.buckets.iter().filter_map(|b| b.wait_time()).max()
+    }
+
+    /// Consumes `n` tokens from all buckets
+    pub fn consume(&self, n: usize) {
+        for b in &self.buckets {
+            b.consume(n);
+        }
+    }
+
+    /// Consumes `n` tokens if all buckets have tokens, for relaying a packet of `n` bytes
+    pub fn try_consume(&self, n: usize) -> bool {
+        if self.wait_time().is_some() {
+            return false;
+        }
+        self.consume(n);
+        true
+    }
+}
+
+/// Shaper of one direction of a stream
+pub struct StreamShaper {
+    shaper: Shaper,
+    delay: Option<Delay>,
+}
+
+impl StreamShaper {
+    pub fn new(shaper: Shaper) -> StreamShaper {
+        StreamShaper { shaper, delay: None }
+    }
+
+    /// Waits until all buckets have tokens
+    pub fn poll_acquire(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        loop {
+            if let Some(ref mut delay) = self.delay {
+                ready!(Pin::new(delay).poll(cx));
+                self.delay = None;
+            }
+
+            match self.shaper.wait_time() {
+                None => return Poll::Ready(()),
+                Some(wait) => self.delay = Some(time::delay_for(wait)),
+            }
+        }
+    }
+
+    /// Consumes tokens of `n` bytes transferred
+    pub fn consume(&self, n: usize) {
+        self.shaper.consume(n);
+    }
+}
+
+fn new_bucket(rate: Option<u64>) -> Option<SharedTokenBucket> {
+    rate.map(|r| Arc::new(TokenBucket::new(r)))
+}
+
+/// Bandwidth limits of a server entry
+pub struct ServerBandwidth {
+    bandwidth: Option<BandwidthLimit>,
+    connection_bandwidth: Option<BandwidthLimit>,
+    upload: Option<SharedTokenBucket>,
+    download: Option<SharedTokenBucket>,
+}
+
+impl ServerBandwidth {
+    fn new(svr_cfg: &ServerConfig) -> ServerBandwidth {
+        let bandwidth = svr_cfg.bandwidth().cloned();
+        ServerBandwidth {
+            bandwidth,
+            connection_bandwidth: svr_cfg.connection_bandwidth().cloned(),
+            upload: new_bucket(bandwidth.and_then(|b| b.upload)),
+            download: new_bucket(bandwidth.and_then(|b| b.download)),
+        }
+    }
+
+    fn is_same_limit(&self, svr_cfg: &ServerConfig) -> bool {
+        self.bandwidth.as_ref() == svr_cfg.bandwidth()
+            && self.connection_bandwidth.as_ref() == svr_cfg.connection_bandwidth()
+    }
+
+    /// Shapers of a new TCP connection or UDP association, in (upload, download)
+    pub fn shapers(&self) -> (Shaper, Shaper) {
+        let conn = self.connection_bandwidth.unwrap_or_default();
+
+        let mut upload = Shaper::default();
+        upload.push(self.upload.clone());
+        upload.push(new_bucket(conn.upload));
+
+        let mut download = Shaper::default();
+        download.push(self.download.clone());
+        download.push(new_bucket(conn.download));
+
+        (upload, download)
+    }
+}
+
+/// Bandwidth limits of all server entries, identified by their addresses
+#[derive(Default)]
+pub struct Bandwidth {
+    servers: Mutex<HashMap<String, Arc<ServerBandwidth>>>,
+}
+
+impl Bandwidth {
+    pub fn new() -> Bandwidth {
+        Bandwidth::default()
+    }
+
+    /// Get bandwidth limits of `svr_cfg`, `None` if it is unlimited
+    ///
+    /// Buckets are recreated if limits of the server are changed.
+    pub fn server(&self, svr_cfg: &ServerConfig) -> Option<Arc<ServerBandwidth>> {
+        let key = svr_cfg.addr().to_string();
+        let mut servers = self.servers.lock();
+
+        if svr_cfg.bandwidth().is_none() && svr_cfg.connection_bandwidth().is_none() {
+            servers.remove(&key);
+            return None;
+        }
+
+        match servers.get(&key) {
+            Some(b) if b.is_same_limit(svr_cfg) => Some(b.clone()),
+            _ => {
+                let b = Arc::new(ServerBandwidth::new(svr_cfg));
+                servers.insert(key, b.clone());
+                Some(b)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn token_bucket() {
+        let bucket = TokenBucket::new(1000);
+        assert_eq!(bucket.wait_time(), None);
+
+        bucket.consume(1500);
+        let wait = bucket.wait_time().unwrap();
+        assert!(wait > Duration::from_millis(400) && wait <= Duration::from_millis(501));
+
+        let shaper = Shaper {
+            buckets: vec![Arc::new(bucket)],
+        };
+        assert!(!shaper.try_consume(100));
+    }
+
+    #[test]
+    fn server_bandwidth() {
+        let mut svr_cfg = ServerConfig::basic(
+            "127.0.0.1:8388".parse().unwrap(),
+            "password".to_owned(),
+            crate::crypto::CipherType::Aes256Gcm,
+        );
+        let bandwidth = Bandwidth::new();
+        assert!(bandwidth.server(&svr_cfg).is_none());
+
+        svr_cfg.set_bandwidth(BandwidthLimit {
+            upload: Some(1000),
+            download: None,
+        });
+        svr_cfg.set_connection_bandwidth(BandwidthLimit {
+            upload: None,
+            download: Some(1000),
+        });
+        let server = bandwidth.server(&svr_cfg).unwrap();
+        assert!(Arc::ptr_eq(&server, &bandwidth.server(&svr_cfg).unwrap()));
+
+        // Upload bucket is shared by connections, download buckets are not
+        let (up1, down1) = server.shapers();
+        let (up2, down2) = server.shapers();
+        up1.consume(2000);
+        assert!(up2.wait_time().is_some());
+        down1.consume(2000);
+        assert!(down2.wait_time().is_none());
+    }
+}
@@ -21,6 +21,8 @@ pub use self::consts::{
     SOCKS5_AUTH_METHOD_NONE,
     SOCKS5_AUTH_METHOD_NOT_ACCEPTABLE,
     SOCKS5_AUTH_METHOD_PASSWORD,
+    SOCKS5_AUTH_PASSWORD_FAILED,
+    SOCKS5_AUTH_PASSWORD_SUCCEEDED,
 };
 
 #[rustfmt::skip]
@@ -32,6 +34,10 @@ mod consts {
     pub const SOCKS5_AUTH_METHOD_PASSWORD:             u8 = 0x02;
     pub const SOCKS5_AUTH_METHOD_NOT_ACCEPTABLE:       u8 = 0xff;
 
+    pub const SOCKS5_AUTH_PASSWORD_VERSION:            u8 = 0x01;
+    pub const SOCKS5_AUTH_PASSWORD_SUCCEEDED:          u8 = 0x00;
+    pub const SOCKS5_AUTH_PASSWORD_FAILED:             u8 = 0x01;
+
     pub const SOCKS5_CMD_TCP_CONNECT:                  u8 = 0x01;
     pub const SOCKS5_CMD_TCP_BIND:                     u8 = 0x02;
     pub const SOCKS5_CMD_UDP_ASSOCIATE:                u8 = 0x03;
@@ -307,6 +313,32 @@ impl Address {
     pub fn serialized_len(&self) -> usize {
         get_addr_len(self)
     }
+
+    /// Get length of the address serialized at the beginning of `buf`
+    ///
+    /// Returns `None` if the address type is not supported, or `buf` is too short.
+    pub fn peek_serialized_len(buf: &[u8]) -> Option<usize> {
+        let len = match *buf.first()? {
+            consts::SOCKS5_ADDR_TYPE_IPV4 => 1 + 4 + 2,
+            consts::SOCKS5_ADDR_TYPE_IPV6 => 1 + 16 + 2,
+            consts::SOCKS5_ADDR_TYPE_DOMAIN_NAME => 1 + 1 + *buf.get(1)? as usize + 2,
+            _ => return None,
+        };
+
+        if buf.len() < len {
+            None
+        } else {
+            Some(len)
+        }
+    }
+
+    /// Get port of the address
+    pub fn port(&self) -> u16 {
+        match *self {
+            Address::SocketAddress(ref addr) => addr.port(),
+            Address::DomainNameAddress(_, port) => port,
+        }
+    }
 }
 
 impl Debug for Address {
@@ -711,6 +743,155 @@ impl HandshakeResponse {
     }
 }
 
+/// SOCKS5 username/password authentication request packet, defined in RFC1929
+///
+/// ```plain
+/// +----+------+----------+------+----------+
+/// |VER | ULEN |  UNAME   | PLEN |  PASSWD  |
+/// +----+------+----------+------+----------+
+/// | 1  |  1   | 1 to 255 |  1   | 1 to 255 |
+/// +----+------+----------+------+----------+
+/// ```
+#[derive(Clone, Debug)]
+pub struct PasswdAuthRequest {
+    pub uname: Vec<u8>,
+    pub passwd: Vec<u8>,
+}
+
+impl PasswdAuthRequest {
+    /// Creates an authentication request
+    ///
+    /// Both `uname` and `passwd` are truncated to 255 bytes.
+    pub fn new<U, P>(uname: U, passwd: P) -> PasswdAuthRequest
+    where
+        U: Into<Vec<u8>>,
+        P: Into<Vec<u8>>,
+    {
+        let mut uname = uname.into();
+        uname.truncate(255);
+        let mut passwd = passwd.into();
+        passwd.truncate(255);
+
+        PasswdAuthRequest { uname, passwd }
+    }
+
+    /// Read from a reader
+    pub async fn read_from<R>(r: &mut R) -> io::Result<PasswdAuthRequest>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let mut buf = [0u8; 2];
+        let _ = r.read_exact(&mut buf).await?;
+
+        let ver = buf[0];
+        if ver != consts::SOCKS5_AUTH_PASSWORD_VERSION {
+            use std::io::{Error, ErrorKind};
+            let err = Error::new(
+                ErrorKind::InvalidData,
+                format!("unsupported password authentication version {:#x}", ver),
+            );
+            return Err(err);
+        }
+
+        let mut uname = vec![0u8; buf[1] as usize];
+        let _ = r.read_exact(&mut uname).await?;
+
+        let mut plen = [0u8; 1];
+        let _ = r.read_exact(&mut plen).await?;
+
+        let mut passwd = vec![0u8; plen[0] as usize];
+        let _ = r.read_exact(&mut passwd).await?;
+
+        Ok(PasswdAuthRequest { uname, passwd })
+    }
+
+    /// Write to a writer
+    pub async fn write_to<W>(&self, w: &mut W) -> io::Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let mut buf = BytesMut::with_capacity(self.serialized_len());
+        self.write_to_buf(&mut buf);
+        w.write_all(&buf).await
+    }
+
+    /// Write to buffer
+    pub fn write_to_buf<B: BufMut>(&self, buf: &mut B) {
+        buf.put_slice(&[consts::SOCKS5_AUTH_PASSWORD_VERSION, self.uname.len() as u8]);
+        buf.put_slice(&self.uname);
+        buf.put_u8(self.passwd.len() as u8);
+        buf.put_slice(&self.passwd);
+    }
+
+    /// Length in bytes
+    pub fn serialized_len(&self) -> usize {
+        3 + self.uname.len() + self.passwd.len()
+    }
+}
+
+/// SOCKS5 username/password authentication response packet, defined in RFC1929
+///
+/// ```plain
+/// +----+--------+
+/// |VER | STATUS |
+/// +----+--------+
+/// | 1  |   1    |
+/// +----+--------+
+/// ```
+#[derive(Clone, Debug, Copy)]
+pub struct PasswdAuthResponse {
+    /// `0x00` for success, others for failure
+    pub status: u8,
+}
+
+impl PasswdAuthResponse {
+    /// Creates an authentication response
+    pub fn new(status: u8) -> PasswdAuthResponse {
+        PasswdAuthResponse { status }
+    }
+
+    /// Read from a reader
+    pub async fn read_from<R>(r: &mut R) -> io::Result<PasswdAuthResponse>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let mut buf = [0u8; 2];
+        let _ = r.read_exact(&mut buf).await?;
+
+        let ver = buf[0];
+        if ver != consts::SOCKS5_AUTH_PASSWORD_VERSION {
+            use std::io::{Error, ErrorKind};
+            let err = Error::new(
+                ErrorKind::InvalidData,
+                format!("unsupported password authentication version {:#x}", ver),
+            );
+            return Err(err);
+        }
+
+        Ok(PasswdAuthResponse { status: buf[1] })
+    }
+
+    /// Write to a writer
+    pub async fn write_to<W>(self, w: &mut W) -> io::Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let mut buf = BytesMut::with_capacity(self.serialized_len());
+        self.write_to_buf(&mut buf);
+        w.write_all(&buf).await
+    }
+
+    /// Write to buffer
+    pub fn write_to_buf<B: BufMut>(self, buf: &mut B) {
+        buf.put_slice(&[consts::SOCKS5_AUTH_PASSWORD_VERSION, self.status]);
+    }
+
+    /// Length in bytes
+    pub fn serialized_len(self) -> usize {
+        2
+    }
+}
+
 /// UDP ASSOCIATE request header
 ///
 /// ```plain
@@ -54,6 +54,11 @@ use super::BUFFER_SIZE;
 /// AEAD packet payload must be smaller than 0x3FFF
 const MAX_PACKET_SIZE: usize = 0x3FFF;
 
+// Reports decrypt errors as `InvalidData`, to be distinguished from I/O errors of the underlying stream
+fn decrypt_failed(err: crypto::cipher::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err.to_string())
+}
+
 #[derive(Debug)]
 enum DecryptReadStep {
     Length,
@@ -84,6 +89,13 @@ impl DecryptedReader {
         }
     }
 
+    /// Creates a DecryptedReader with the encrypted `chunk` that is already read from the stream
+    pub fn with_first_chunk(t: CipherType, key: &[u8], nonce: &[u8], chunk: &[u8]) -> DecryptedReader {
+        let mut reader = DecryptedReader::new(t, key, nonce);
+        reader.buffer.extend_from_slice(chunk);
+        reader
+    }
+
     pub fn poll_read_decrypted<R>(
         &mut self,
         ctx: &mut Context<'_>,
@@ -126,7 +138,9 @@ impl DecryptedReader {
         // Done reading, decrypt it
         let len = {
             let mut len_buf = [0u8; 2];
-            self.cipher.decrypt(&self.buffer[..], &mut len_buf)?;
+            self.cipher
+                .decrypt(&self.buffer[..], &mut len_buf)
+                .map_err(decrypt_failed)?;
             BigEndian::read_u16(&len_buf) as usize
         };
 
@@ -154,7 +168,7 @@ impl DecryptedReader {
         unsafe {
             // It has enough space, I am sure about that
             let buffer = slice::from_raw_parts_mut(self.data.bytes_mut().as_mut_ptr() as *mut u8, size);
-            self.cipher.decrypt(&self.buffer[..], buffer)?;
+            self.cipher.decrypt(&self.buffer[..], buffer).map_err(decrypt_failed)?;
 
             // Move forward the pointer
             self.data.advance_mut(size);
@@ -0,0 +1,651 @@
+//! AEAD-2022 packet I/O facilities
+//!
+//! AEAD-2022 protocol is defined in [SIP022](https://github.com/shadowsocks/shadowsocks-org/issues/196). Chunks are
+//! the same as AEAD ciphers, except that the first ones are timestamped headers, and responses are bound to their
+//! requests by echoing the request salts.
+//!
+//! ```plain
+//! TCP request (after encryption, *ciphertext*)
+//! +--------+-----------------------+------------+--------------------------+------------+
+//! |  SALT  | *Fixed-length header* | Header_TAG | *Variable-length header* | Header_TAG |
+//! +--------+-----------------------+------------+--------------------------+------------+
+//! | Fixed  |          11           |   Fixed    |         Variable         |   Fixed    |
+//! +--------+-----------------------+------------+--------------------------+------------+
+//!
+//! Fixed-length header of requests (before encryption)
+//! +------+-----------+--------+
+//! | TYPE | TIMESTAMP | LENGTH |
+//! +------+-----------+--------+
+//! |  1   |     8     |   2    |
+//! +------+-----------+--------+
+//!
+//! Variable-length header of requests (before encryption)
+//! +------+----------+----------+------------+----------+-----------------+
+//! | ATYP | DST.ADDR | DST.PORT | PaddingLen | Padding  | Initial Payload |
+//! +------+----------+----------+------------+----------+-----------------+
+//! |  1   | Variable |    2     |     2      | Variable |    Variable     |
+//! +------+----------+----------+------------+----------+-----------------+
+//!
+//! TCP response (after encryption, *ciphertext*)
+//! +--------+-----------------------+------------+---------------+------------+
+//! |  SALT  | *Fixed-length header* | Header_TAG |    *Data*     |  Data_TAG  |
+//! +--------+-----------------------+------------+---------------+------------+
+//! | Fixed  |       Variable        |   Fixed    |    LENGTH     |   Fixed    |
+//! +--------+-----------------------+------------+---------------+------------+
+//!
+//! Fixed-length header of responses (before encryption)
+//! +------+-----------+--------------+--------+
+//! | TYPE | TIMESTAMP | Request SALT | LENGTH |
+//! +------+-----------+--------------+--------+
+//! |  1   |     8     |    Fixed     |   2    |
+//! +------+-----------+--------------+--------+
+//! ```
+//!
+//! Chunks after headers are the same as AEAD ciphers, with data up to 0xFFFF bytes.
+//!
+//! Requests to servers with identity keys have identity headers between SALT and the fixed-length header, one for
+//! each server on the path.
+
+use std::{
+    cmp,
+    io::{self, ErrorKind},
+    marker::Unpin,
+    pin::Pin,
+    slice,
+    task::{Context, Poll},
+};
+
+use byte_string::ByteStr;
+use byteorder::{BigEndian, ByteOrder};
+use bytes::{BufMut, Bytes, BytesMut};
+use futures::ready;
+use log::debug;
+use rand::{self, Rng};
+use tokio::prelude::*;
+
+use crate::{
+    context::SharedServerState,
+    crypto::{self, aead2022, BoxAeadDecryptor, BoxAeadEncryptor, CipherType},
+    relay::{metrics::Protocol, socks5::Address},
+};
+
+use super::BUFFER_SIZE;
+
+/// AEAD-2022 packet payload must be smaller than 0xFFFF
+const MAX_PACKET_SIZE: usize = 0xFFFF;
+
+/// Requests without initial payload are padded with 1 to 900 bytes
+const MAX_PADDING_SIZE: usize = 900;
+
+const HEADER_TYPE_REQUEST: u8 = 0;
+const HEADER_TYPE_RESPONSE: u8 = 1;
+
+// Reports decrypt errors and invalid headers as `InvalidData`, to be distinguished from I/O errors of the underlying
+// stream
+fn decrypt_failed(err: crypto::cipher::Error) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, err.to_string())
+}
+
+fn invalid_header(msg: &'static str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, msg)
+}
+
+#[derive(Debug)]
+enum DecryptReadStep {
+    FixedHeader,
+    VariableHeader(usize),
+    Length,
+    Data(usize),
+}
+
+/// Reader wrapper that will decrypt data automatically
+///
+/// It reads a request if `request_salt` is `None`, or the response of the request sent with `request_salt`.
+pub struct DecryptedReader {
+    buffer: BytesMut,
+    data: BytesMut,
+    cipher: BoxAeadDecryptor,
+    pos: usize,
+    tag_size: usize,
+    steps: DecryptReadStep,
+    got_final: bool,
+    request_salt: Option<Bytes>,
+    salt_window: Option<(SharedServerState, Bytes)>,
+}
+
+impl DecryptedReader {
+    pub fn new(t: CipherType, key: &[u8], salt: &[u8], request_salt: Option<Bytes>) -> DecryptedReader {
+        DecryptedReader {
+            buffer: BytesMut::with_capacity(BUFFER_SIZE),
+            data: BytesMut::with_capacity(BUFFER_SIZE),
+            cipher: crypto::new_aead_decryptor(t, key, salt),
+            pos: 0,
+            tag_size: t.tag_size(),
+            steps: DecryptReadStep::FixedHeader,
+            got_final: false,
+            request_salt,
+            salt_window: None,
+        }
+    }
+
+    /// Keeps `salt` in recent salts of `state` after the fixed-length header is authenticated
+    ///
+    /// The stream is rejected if `salt` is received recently.
+    pub fn set_salt_window(&mut self, state: SharedServerState, salt: &[u8]) {
+        self.salt_window = Some((state, Bytes::copy_from_slice(salt)));
+    }
+
+    pub fn poll_read_decrypted<R>(
+        &mut self,
+        ctx: &mut Context<'_>,
+        r: &mut R,
+        dst: &mut [u8],
+    ) -> Poll<io::Result<usize>>
+    where
+        R: AsyncRead + Unpin,
+    {
+        while self.pos >= self.data.len() {
+            // Already received EOF
+            if self.got_final {
+                return Poll::Ready(Ok(0));
+            }
+
+            // Refill buffer
+            match self.steps {
+                DecryptReadStep::FixedHeader => ready!(self.poll_read_fixed_header(ctx, r))?,
+                DecryptReadStep::VariableHeader(len) => ready!(self.poll_read_variable_header(ctx, r, len))?,
+                DecryptReadStep::Length => ready!(self.poll_read_decrypted_length(ctx, r))?,
+                DecryptReadStep::Data(len) => ready!(self.poll_read_decrypted_data(ctx, r, len))?,
+            }
+        }
+
+        let remaining_len = self.data.len() - self.pos;
+        let n = cmp::min(dst.len(), remaining_len);
+        dst[..n].copy_from_slice(&self.data[self.pos..self.pos + n]);
+        self.pos += n;
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_read_fixed_header<R>(&mut self, ctx: &mut Context<'_>, r: &mut R) -> Poll<io::Result<()>>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let header_len = match self.request_salt {
+            None => 1 + 8 + 2,
+            Some(ref salt) => 1 + 8 + salt.len() + 2,
+        };
+        ready!(self.poll_read_exact(ctx, r, header_len + self.tag_size, false))?;
+
+        let header = self.decrypt_buffer(header_len)?;
+
+        let expected_type = match self.request_salt {
+            None => HEADER_TYPE_REQUEST,
+            Some(..) => HEADER_TYPE_RESPONSE,
+        };
+        if header[0] != expected_type {
+            debug!(
+                "AEAD-2022 header type {} mismatched, expecting {}",
+                header[0], expected_type
+            );
+            return Poll::Ready(Err(invalid_header("invalid header type")));
+        }
+
+        let timestamp = BigEndian::read_u64(&header[1..9]);
+        if !aead2022::check_timestamp(timestamp) {
+            debug!(
+                "AEAD-2022 header timestamp {} is too far from now {}",
+                timestamp,
+                aead2022::unix_timestamp()
+            );
+            return Poll::Ready(Err(invalid_header("header timestamp out of window")));
+        }
+
+        if let Some(ref salt) = self.request_salt {
+            let echoed = &header[9..9 + salt.len()];
+            if echoed != &salt[..] {
+                debug!(
+                    "AEAD-2022 response salt {:?} mismatched, expecting {:?}",
+                    ByteStr::new(echoed),
+                    ByteStr::new(salt)
+                );
+                return Poll::Ready(Err(invalid_header("request salt mismatched")));
+            }
+        }
+
+        // Salts are only kept after headers are authenticated, so probers can't fill the window
+        if let Some((ref state, ref salt)) = self.salt_window {
+            if state.check_recent_salt_and_set(salt) {
+                debug!("Detected repeated salt {:?}", ByteStr::new(salt));
+                state.metrics().incr_repeated_nonces(Protocol::Tcp);
+
+                let err = io::Error::new(ErrorKind::Other, "detected repeated salt");
+                return Poll::Ready(Err(err));
+            }
+        }
+
+        let len = BigEndian::read_u16(&header[header_len - 2..]) as usize;
+
+        // Requests are followed by the variable-length header, responses are followed by the first chunk of data
+        self.steps = match self.request_salt {
+            None => DecryptReadStep::VariableHeader(len),
+            Some(..) => DecryptReadStep::Data(len),
+        };
+
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_read_variable_header<R>(&mut self, ctx: &mut Context<'_>, r: &mut R, size: usize) -> Poll<io::Result<()>>
+    where
+        R: AsyncRead + Unpin,
+    {
+        ready!(self.poll_read_exact(ctx, r, size + self.tag_size, false))?;
+
+        let header = self.decrypt_buffer(size)?;
+
+        // Address is kept, and padding is stripped before the initial payload
+        let addr_len = match Address::peek_serialized_len(&header) {
+            Some(len) if len + 2 <= header.len() => len,
+            _ => return Poll::Ready(Err(invalid_header("invalid variable-length header"))),
+        };
+        let padding_len = BigEndian::read_u16(&header[addr_len..]) as usize;
+        let payload_pos = addr_len + 2 + padding_len;
+        if payload_pos > header.len() {
+            return Poll::Ready(Err(invalid_header("invalid padding length")));
+        }
+
+        self.data.clear();
+        self.data.extend_from_slice(&header[..addr_len]);
+        self.data.extend_from_slice(&header[payload_pos..]);
+        self.pos = 0;
+
+        self.steps = DecryptReadStep::Length;
+        self.buffer.reserve(2 + self.tag_size);
+
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_read_decrypted_length<R>(&mut self, ctx: &mut Context<'_>, r: &mut R) -> Poll<io::Result<()>>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let buf_len = 2 + self.tag_size;
+        ready!(self.poll_read_exact(ctx, r, buf_len, true))?;
+        if self.got_final {
+            return Poll::Ready(Ok(()));
+        }
+
+        let len = BigEndian::read_u16(&self.decrypt_buffer(2)?) as usize;
+
+        // Next step, read data
+        self.steps = DecryptReadStep::Data(len);
+        self.buffer.reserve(len + self.tag_size);
+
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_read_decrypted_data<R>(&mut self, ctx: &mut Context<'_>, r: &mut R, size: usize) -> Poll<io::Result<()>>
+    where
+        R: AsyncRead + Unpin,
+    {
+        ready!(self.poll_read_exact(ctx, r, size + self.tag_size, false))?;
+
+        self.data.clear();
+        self.data.resize(size, 0);
+        self.cipher
+            .decrypt(&self.buffer[..], &mut self.data[..])
+            .map_err(decrypt_failed)?;
+
+        // Clear buffer before overwriting it
+        self.buffer.clear();
+
+        // Reset read position
+        self.pos = 0;
+
+        // Next step, read length
+        self.steps = DecryptReadStep::Length;
+        self.buffer.reserve(2 + self.tag_size);
+
+        Poll::Ready(Ok(()))
+    }
+
+    // Decrypts the whole buffer, which is `size` bytes with a tag
+    fn decrypt_buffer(&mut self, size: usize) -> io::Result<Vec<u8>> {
+        let mut plain = vec![0u8; size];
+        self.cipher
+            .decrypt(&self.buffer[..], &mut plain)
+            .map_err(decrypt_failed)?;
+        self.buffer.clear();
+        Ok(plain)
+    }
+
+    fn poll_read_exact<R>(
+        &mut self,
+        ctx: &mut Context<'_>,
+        r: &mut R,
+        size: usize,
+        allow_eof: bool,
+    ) -> Poll<io::Result<()>>
+    where
+        R: AsyncRead + Unpin,
+    {
+        self.buffer.reserve(size.saturating_sub(self.buffer.len()));
+
+        while self.buffer.len() < size {
+            let remaining = size - self.buffer.len();
+            unsafe {
+                // It has enough space, I am sure about that
+                let buffer = slice::from_raw_parts_mut(self.buffer.bytes_mut().as_mut_ptr() as *mut u8, remaining);
+                let n = ready!(Pin::new(&mut *r).poll_read(ctx, buffer))?;
+                if n == 0 {
+                    if self.buffer.is_empty() && allow_eof && !self.got_final {
+                        // Read nothing
+                        self.got_final = true;
+                        return Poll::Ready(Ok(()));
+                    } else {
+                        return Poll::Ready(Err(ErrorKind::UnexpectedEof.into()));
+                    }
+                }
+                self.buffer.advance_mut(n);
+            }
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+enum EncryptWriteStep {
+    Nothing,
+    /// (Buffer, written_bytes, consumed_data_bytes)
+    Writing(BytesMut, usize, usize),
+}
+
+/// Writer wrapper that will encrypt data automatically
+///
+/// The first write of requests must start with the target address. Responses couldn't be written until the request
+/// salt is set.
+pub struct EncryptedWriter {
+    cipher: BoxAeadEncryptor,
+    tag_size: usize,
+    steps: EncryptWriteStep,
+    salt: Bytes,
+    header_written: bool,
+    is_response: bool,
+    request_salt: Option<Bytes>,
+    identity_headers: Vec<u8>,
+}
+
+impl EncryptedWriter {
+    /// Creates a new EncryptedWriter
+    pub fn new(t: CipherType, key: &[u8], salt: Bytes, is_response: bool) -> EncryptedWriter {
+        EncryptedWriter {
+            cipher: crypto::new_aead_encryptor(t, key, &salt),
+            tag_size: t.tag_size(),
+            steps: EncryptWriteStep::Nothing,
+            salt,
+            header_written: false,
+            is_response,
+            request_salt: None,
+            identity_headers: Vec::new(),
+        }
+    }
+
+    /// Salt of this direction
+    pub fn salt(&self) -> &Bytes {
+        &self.salt
+    }
+
+    /// Check if this writer writes a response
+    pub fn is_response(&self) -> bool {
+        self.is_response
+    }
+
+    /// Sets salt of the request, which is echoed in the response header
+    pub fn set_request_salt(&mut self, salt: Bytes) {
+        self.request_salt = Some(salt);
+    }
+
+    /// Sets identity headers, which are written after salt of the request
+    pub fn set_identity_headers(&mut self, headers: Vec<u8>) {
+        self.identity_headers = headers;
+    }
+
+    pub fn poll_write_encrypted<W>(&mut self, ctx: &mut Context<'_>, w: &mut W, data: &[u8]) -> Poll<io::Result<usize>>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        loop {
+            match self.steps {
+                EncryptWriteStep::Nothing => {
+                    let (buf, consumed) = if self.header_written {
+                        let data = &data[..cmp::min(data.len(), MAX_PACKET_SIZE)];
+                        let mut buf = BytesMut::with_capacity(2 + data.len() + 2 * self.tag_size);
+                        self.put_chunk(&mut buf, data);
+                        (buf, data.len())
+                    } else if self.is_response {
+                        let data = &data[..cmp::min(data.len(), MAX_PACKET_SIZE)];
+                        (self.response_header(data)?, data.len())
+                    } else {
+                        let data = &data[..cmp::min(data.len(), MAX_PACKET_SIZE - 2 - MAX_PADDING_SIZE)];
+                        (self.request_header(data)?, data.len())
+                    };
+
+                    self.header_written = true;
+                    self.steps = EncryptWriteStep::Writing(buf, 0, consumed);
+                }
+                EncryptWriteStep::Writing(ref mut buf, ref mut pos, consumed) => {
+                    while *pos < buf.len() {
+                        let n = ready!(Pin::new(&mut *w).poll_write(ctx, &buf[*pos..]))?;
+                        if n == 0 {
+                            return Poll::Ready(Err(ErrorKind::UnexpectedEof.into()));
+                        }
+                        *pos += n;
+                    }
+
+                    self.steps = EncryptWriteStep::Nothing;
+                    return Poll::Ready(Ok(consumed));
+                }
+            }
+        }
+    }
+
+    fn request_header(&mut self, data: &[u8]) -> io::Result<BytesMut> {
+        let addr_len = match Address::peek_serialized_len(data) {
+            Some(len) => len,
+            None => {
+                let err = io::Error::new(ErrorKind::InvalidInput, "request must start with an address");
+                return Err(err);
+            }
+        };
+        let payload = &data[addr_len..];
+
+        // Padding is required if there is no initial payload
+        let padding_len = if payload.is_empty() {
+            rand::thread_rng().gen_range(1, MAX_PADDING_SIZE + 1)
+        } else {
+            0
+        };
+
+        let mut var_header = Vec::with_capacity(data.len() + 2 + padding_len);
+        var_header.put_slice(&data[..addr_len]);
+        var_header.put_u16(padding_len as u16);
+        var_header.resize(var_header.len() + padding_len, 0);
+        var_header.put_slice(payload);
+
+        let mut fixed_header = Vec::with_capacity(1 + 8 + 2);
+        fixed_header.put_u8(HEADER_TYPE_REQUEST);
+        fixed_header.put_u64(aead2022::unix_timestamp());
+        fixed_header.put_u16(var_header.len() as u16);
+
+        let mut buf = BytesMut::with_capacity(
+            self.salt.len() + self.identity_headers.len() + fixed_header.len() + var_header.len() + 2 * self.tag_size,
+        );
+        buf.put_slice(&self.salt);
+        buf.put_slice(&self.identity_headers);
+        self.put_encrypted(&mut buf, &fixed_header);
+        self.put_encrypted(&mut buf, &var_header);
+        Ok(buf)
+    }
+
+    fn response_header(&mut self, data: &[u8]) -> io::Result<BytesMut> {
+        let request_salt = match self.request_salt {
+            Some(ref salt) => salt.clone(),
+            None => {
+                let err = io::Error::new(ErrorKind::NotConnected, "request is not received yet");
+                return Err(err);
+            }
+        };
+
+        let mut fixed_header = Vec::with_capacity(1 + 8 + request_salt.len() + 2);
+        fixed_header.put_u8(HEADER_TYPE_RESPONSE);
+        fixed_header.put_u64(aead2022::unix_timestamp());
+        fixed_header.put_slice(&request_salt);
+        fixed_header.put_u16(data.len() as u16);
+
+        let mut buf = BytesMut::with_capacity(self.salt.len() + fixed_header.len() + data.len() + 2 * self.tag_size);
+        buf.put_slice(&self.salt);
+        self.put_encrypted(&mut buf, &fixed_header);
+        self.put_encrypted(&mut buf, data);
+        Ok(buf)
+    }
+
+    fn put_chunk(&mut self, buf: &mut BytesMut, data: &[u8]) {
+        let mut data_len_buf = [0u8; 2];
+        BigEndian::write_u16(&mut data_len_buf, data.len() as u16);
+
+        self.put_encrypted(buf, &data_len_buf);
+        self.put_encrypted(buf, data);
+    }
+
+    fn put_encrypted(&mut self, buf: &mut BytesMut, data: &[u8]) {
+        let pos = buf.len();
+        buf.resize(pos + data.len() + self.tag_size, 0);
+        self.cipher.encrypt(data, &mut buf[pos..]);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use futures::future;
+    use std::io::Cursor;
+    use tokio::runtime::Builder;
+
+    use crate::{
+        config::{Config, ConfigType},
+        context::ServerState,
+    };
+
+    const METHOD: CipherType = CipherType::Aead2022Blake3Aes256Gcm;
+
+    fn write_all(w: &mut EncryptedWriter, out: &mut Vec<u8>, data: &[u8]) -> io::Result<usize> {
+        futures::executor::block_on(future::poll_fn(|ctx| w.poll_write_encrypted(ctx, out, data)))
+    }
+
+    fn read_to_end(r: &mut DecryptedReader, input: &[u8]) -> io::Result<Vec<u8>> {
+        let mut cur = Cursor::new(input);
+        let mut result = Vec::new();
+        loop {
+            let mut buf = [0u8; 1024];
+            let n = futures::executor::block_on(future::poll_fn(|ctx| r.poll_read_decrypted(ctx, &mut cur, &mut buf)))?;
+            if n == 0 {
+                return Ok(result);
+            }
+            result.extend_from_slice(&buf[..n]);
+        }
+    }
+
+    fn address() -> Vec<u8> {
+        let mut buf = Vec::new();
+        Address::DomainNameAddress("example.com".to_owned(), 443).write_to_buf(&mut buf);
+        buf
+    }
+
+    #[test]
+    fn request_and_response() {
+        let key = [1u8; 32];
+        let request_salt = METHOD.gen_salt();
+        let response_salt = METHOD.gen_salt();
+
+        let mut request = Vec::new();
+        let mut writer = EncryptedWriter::new(METHOD, &key, request_salt.clone(), false);
+        assert_eq!(
+            write_all(&mut writer, &mut request, &address()).unwrap(),
+            address().len()
+        );
+        write_all(&mut writer, &mut request, b"hello").unwrap();
+
+        let mut reader = DecryptedReader::new(METHOD, &key, &request[..request_salt.len()], None);
+        let mut expected = address();
+        expected.extend_from_slice(b"hello");
+        assert_eq!(
+            read_to_end(&mut reader, &request[request_salt.len()..]).unwrap(),
+            expected
+        );
+
+        let mut response = Vec::new();
+        let mut writer = EncryptedWriter::new(METHOD, &key, response_salt.clone(), true);
+        assert!(write_all(&mut writer, &mut response, b"world").is_err());
+        writer.set_request_salt(request_salt.clone());
+        write_all(&mut writer, &mut response, b"world").unwrap();
+        write_all(&mut writer, &mut response, b"!").unwrap();
+
+        let mut reader = DecryptedReader::new(METHOD, &key, &response_salt, Some(request_salt));
+        assert_eq!(
+            read_to_end(&mut reader, &response[response_salt.len()..]).unwrap(),
+            b"world!"
+        );
+
+        // Responses of other requests are rejected
+        let mut reader = DecryptedReader::new(METHOD, &key, &response_salt, Some(METHOD.gen_salt()));
+        let err = read_to_end(&mut reader, &response[response_salt.len()..]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn request_with_initial_payload() {
+        let key = [2u8; 32];
+        let salt = METHOD.gen_salt();
+
+        let mut data = address();
+        data.extend_from_slice(b"initial payload");
+
+        let mut request = Vec::new();
+        let mut writer = EncryptedWriter::new(METHOD, &key, salt.clone(), false);
+        write_all(&mut writer, &mut request, &data).unwrap();
+
+        let mut reader = DecryptedReader::new(METHOD, &key, &salt, None);
+        assert_eq!(read_to_end(&mut reader, &request[salt.len()..]).unwrap(), data);
+    }
+
+    #[test]
+    fn salt_kept_after_authenticated() {
+        let mut rt = Builder::new().basic_scheduler().enable_all().build().unwrap();
+        let handle = rt.handle().clone();
+        let state = rt
+            .block_on(ServerState::new(&Config::new(ConfigType::Server), handle))
+            .unwrap();
+
+        let key = [3u8; 32];
+        let salt = METHOD.gen_salt();
+
+        let mut request = Vec::new();
+        let mut writer = EncryptedWriter::new(METHOD, &key, salt.clone(), false);
+        write_all(&mut writer, &mut request, &address()).unwrap();
+
+        // Probers with the same salt can't authenticate, the salt isn't kept
+        let mut reader = DecryptedReader::new(METHOD, &[4u8; 32], &salt, None);
+        reader.set_salt_window(state.clone(), &salt);
+        let err = read_to_end(&mut reader, &request[salt.len()..]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+
+        let mut reader = DecryptedReader::new(METHOD, &key, &salt, None);
+        reader.set_salt_window(state.clone(), &salt);
+        assert_eq!(read_to_end(&mut reader, &request[salt.len()..]).unwrap(), address());
+
+        let mut reader = DecryptedReader::new(METHOD, &key, &salt, None);
+        reader.set_salt_window(state, &salt);
+        let err = read_to_end(&mut reader, &request[salt.len()..]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Other);
+    }
+}
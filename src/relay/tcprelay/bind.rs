@@ -0,0 +1,271 @@
+//! SOCKS5 BIND relayed through servers
+//!
+//! This is an extension of shadowsocks. The local sends `BIND_REQUEST_DOMAIN` as the address in the request header,
+//! with the port that the client expects the inbound connection from, followed by the address in the SOCKS5 BIND
+//! request. The server listens on a new port of the address that the local connected to, and replies with two
+//! addresses in the stream, just like replies of SOCKS5 BIND:
+//!
+//! 1. The address that the server listens on, or `0.0.0.0:0` if the request is refused
+//! 2. The address of the inbound connection, after it is accepted
+//!
+//! Only inbound connections from the IP of the address in the BIND request, or the IPs that it resolves to, are
+//! accepted. Others are closed and the server keeps waiting.
+//!
+//! Data is relayed between the local and the inbound connection after that.
+//!
+//! The domain is in the reserved `.invalid` TLD, servers without this extension fail to resolve it and close the
+//! connection, so the local knows it is not supported.
+
+use std::{
+    io,
+    io::ErrorKind,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    time::Duration,
+};
+
+use futures::future::{self, Either, FutureExt};
+use log::{debug, error, trace, warn};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    time,
+};
+
+use crate::{
+    context::Context,
+    relay::{access_log::RelayStat, dns_resolver::resolve, metrics::Protocol, socks5::Address},
+};
+
+use super::{server_context::TcpServerContext, CryptoStream};
+
+/// Domain name in request headers of BIND
+pub const BIND_REQUEST_DOMAIN: &str = "bind.shadowsocks.invalid";
+
+// Time for waiting the inbound connection
+const DEFAULT_ACCEPT_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Address in request headers of BIND, `port` is the port that the client expects the inbound connection from
+pub fn bind_request_addr(port: u16) -> Address {
+    Address::DomainNameAddress(BIND_REQUEST_DOMAIN.to_owned(), port)
+}
+
+/// Address replied as the bound address if the server refused BIND
+pub fn bind_refused_addr() -> Address {
+    Address::SocketAddress(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0))
+}
+
+/// Check if `addr` in the request header is a BIND request
+pub fn is_bind_request(addr: &Address) -> bool {
+    match *addr {
+        Address::DomainNameAddress(ref dname, _) => dname.eq_ignore_ascii_case(BIND_REQUEST_DOMAIN),
+        Address::SocketAddress(..) => false,
+    }
+}
+
+/// Accepts an inbound connection on `bind_ip` for the local, and relays it with `stream`
+///
+/// `request_addr` is the address in the request header, its port is checked by the port policy of the server. The
+/// address in the BIND request is read from `stream`.
+pub(crate) async fn relay_bind<S>(
+    svr_context: &TcpServerContext,
+    mut stream: CryptoStream<S>,
+    peer_addr: SocketAddr,
+    request_addr: &Address,
+    bind_ip: IpAddr,
+) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let context = svr_context.context();
+    let svr_cfg = svr_context.svr_cfg();
+
+    let expected_addr = Address::read_from(&mut stream).await?;
+
+    if !svr_cfg.allow_bind() {
+        warn!("BIND {} refused, it is not allowed by the server", peer_addr);
+        return refuse_bind(&mut stream).await;
+    }
+
+    // Others can't connect to the loopback address that plugins forward to
+    if let Some(plugin_addr) = svr_cfg.tcp_plugin_addr() {
+        warn!("BIND {} refused, server is behind plugin on {}", peer_addr, plugin_addr);
+        return refuse_bind(&mut stream).await;
+    }
+
+    if !svr_cfg.port_policy().is_allowed(request_addr.port()) {
+        warn!("BIND {} refused, port {} is denied", peer_addr, request_addr.port());
+        svr_context.metrics().incr_denied_destinations(Protocol::Tcp);
+        return refuse_bind(&mut stream).await;
+    }
+
+    // The inbound connection is counted as a connection of the client
+    let port = svr_cfg.addr().port();
+    let _limit_guard = match context.limiter().acquire_tcp(port, Some(peer_addr.ip())) {
+        Ok(guard) => guard,
+        Err(limit) => {
+            warn!("BIND {} on port {} refused, reached limit {}", peer_addr, port, limit);
+            svr_context.metrics().incr_rejected(limit);
+            return refuse_bind(&mut stream).await;
+        }
+    };
+
+    let expected_ips = match expected_ips(context, &expected_addr).await {
+        Ok(ips) => ips,
+        Err(err) => {
+            warn!("BIND {} refused, invalid address {}, {}", peer_addr, expected_addr, err);
+            return refuse_bind(&mut stream).await;
+        }
+    };
+
+    let mut listener = TcpListener::bind(SocketAddr::new(bind_ip, 0)).await?;
+    let bound_addr = listener.local_addr()?;
+    debug!("BIND {} listening on {}", peer_addr, bound_addr);
+
+    Address::SocketAddress(bound_addr).write_to(&mut stream).await?;
+    stream.flush().await?;
+
+    // Local shouldn't send anything before the second reply, it is closed if it does
+    let accepted = {
+        let accept = time::timeout(
+            DEFAULT_ACCEPT_TIMEOUT,
+            accept_from(&mut listener, &expected_ips, peer_addr, bound_addr),
+        );
+        let mut buf = [0u8; 1];
+        let closed = stream.read(&mut buf);
+
+        match future::select(accept.boxed(), closed).await {
+            Either::Left((Ok(Ok(accepted)), ..)) => accepted,
+            Either::Left((Ok(Err(err)), ..)) => return Err(err),
+            Either::Left((Err(..), ..)) => {
+                let err = io::Error::new(ErrorKind::TimedOut, "no inbound connection");
+                return Err(err);
+            }
+            Either::Right(..) => {
+                trace!("BIND {} on {} cancelled", peer_addr, bound_addr);
+                return Ok(());
+            }
+        }
+    };
+    drop(listener);
+
+    let (mut remote_stream, remote_addr) = accepted;
+    if context.check_forbidden_ip(&remote_addr.ip()) {
+        warn!(
+            "BIND {} on {} refused inbound from forbidden {}",
+            peer_addr, bound_addr, remote_addr
+        );
+        let err = io::Error::new(ErrorKind::Other, format!("{} is forbidden", remote_addr.ip()));
+        return Err(err);
+    }
+
+    let _active_guard = svr_context.metrics().tcp_connection();
+
+    let remote_addr = Address::SocketAddress(remote_addr);
+    remote_addr.write_to(&mut stream).await?;
+    stream.flush().await?;
+
+    debug!("BIND {} <-> {} established", peer_addr, remote_addr);
+
+    let user = stream.user().map(|u| u.name().to_owned());
+    let stat = RelayStat::new();
+
+    let (cr, mut cw) = stream.split();
+    let (sr, mut sw) = remote_stream.split();
+
+    let mut cr = stat.upload_reader(cr);
+    let mut sr = stat.download_reader(sr);
+
+    let rhalf = tokio::io::copy(&mut cr, &mut sw);
+    let whalf = tokio::io::copy(&mut sr, &mut cw);
+
+    let res = future::select(rhalf, whalf).await;
+
+    if let Some(access_log) = context.access_log() {
+        let mut record = stat.finish(peer_addr, svr_context.svr_cfg(), &remote_addr, &res);
+        record.user = user;
+        access_log.write(&record);
+    }
+
+    match res {
+        Either::Left((Ok(_), _)) => trace!("BIND {} -> {} closed", peer_addr, remote_addr),
+        Either::Right((Ok(_), _)) => trace!("BIND {} <- {} closed", peer_addr, remote_addr),
+        Either::Left((Err(err), _)) | Either::Right((Err(err), _)) => {
+            error!("BIND {} <-> {} closed with error {}", peer_addr, remote_addr, err);
+        }
+    }
+
+    Ok(())
+}
+
+// IPs that inbound connections are accepted from
+async fn expected_ips(context: &Context, addr: &Address) -> io::Result<Vec<IpAddr>> {
+    let ips: Vec<IpAddr> = match *addr {
+        Address::SocketAddress(ref sa) => vec![sa.ip()],
+        Address::DomainNameAddress(ref dname, port) => {
+            resolve(context, dname, port, false).await?.map(|a| a.ip()).collect()
+        }
+    };
+
+    if ips.iter().any(IpAddr::is_unspecified) {
+        let err = io::Error::new(ErrorKind::InvalidInput, "unspecified address");
+        return Err(err);
+    }
+    Ok(ips)
+}
+
+// Accepts the first inbound connection from `expected_ips`, connections from others are closed
+async fn accept_from(
+    listener: &mut TcpListener,
+    expected_ips: &[IpAddr],
+    peer_addr: SocketAddr,
+    bound_addr: SocketAddr,
+) -> io::Result<(TcpStream, SocketAddr)> {
+    loop {
+        let (stream, remote_addr) = listener.accept().await?;
+        if expected_ips.contains(&canonical_ip(remote_addr.ip())) {
+            return Ok((stream, remote_addr));
+        }
+
+        warn!(
+            "BIND {} on {} closed inbound from unexpected {}",
+            peer_addr, bound_addr, remote_addr
+        );
+    }
+}
+
+// IPv4 clients of dual-stack listeners are in IPv4-mapped IPv6 addresses
+fn canonical_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => IpAddr::V4(v4),
+            None => ip,
+        },
+        ip => ip,
+    }
+}
+
+async fn refuse_bind<S>(stream: &mut CryptoStream<S>) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    bind_refused_addr().write_to(stream).await?;
+    stream.flush().await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn bind_request() {
+        assert!(is_bind_request(&bind_request_addr(0)));
+        assert!(is_bind_request(&Address::DomainNameAddress(
+            "Bind.Shadowsocks.Invalid".to_owned(),
+            1234
+        )));
+        assert!(!is_bind_request(&Address::DomainNameAddress(
+            "example.com".to_owned(),
+            80
+        )));
+    }
+}
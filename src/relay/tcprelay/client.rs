@@ -16,6 +16,8 @@ use crate::relay::socks5::{
     Command,
     HandshakeRequest,
     HandshakeResponse,
+    PasswdAuthRequest,
+    PasswdAuthResponse,
     Reply,
     TcpRequestHeader,
     TcpResponseHeader,
@@ -35,49 +37,85 @@ impl Socks5Client {
     where
         Address: From<A>,
     {
-        let mut s = TcpStream::connect(proxy).await?;
-
-        // 1. Handshake
-        let hs = HandshakeRequest::new(vec![socks5::SOCKS5_AUTH_METHOD_NONE]);
-        trace!("Client connected, going to send handshake: {:?}", hs);
+        let (c, _) = Socks5Client::request(Command::TcpConnect, From::from(addr), proxy, None).await?;
+        Ok(c)
+    }
 
-        hs.write_to(&mut s).await?;
-        s.flush().await?;
+    /// Connects to `addr` via `proxy`, authenticated with username/password (RFC 1929)
+    pub async fn connect_with_password<A>(
+        addr: A,
+        proxy: &SocketAddr,
+        username: &str,
+        password: &str,
+    ) -> io::Result<Socks5Client>
+    where
+        Address: From<A>,
+    {
+        let auth = Some((username, password));
+        let (c, _) = Socks5Client::request(Command::TcpConnect, From::from(addr), proxy, auth).await?;
+        Ok(c)
+    }
 
-        let hsp = HandshakeResponse::read_from(&mut s).await?;
+    /// UDP Associate `addr` via `proxy`
+    pub async fn udp_associate<A>(addr: A, proxy: &SocketAddr) -> io::Result<(Socks5Client, Address)>
+    where
+        Address: From<A>,
+    {
+        Socks5Client::request(Command::UdpAssociate, From::from(addr), proxy, None).await
+    }
 
-        trace!("Got handshake response: {:?}", hsp);
-        assert_eq!(hsp.chosen_method, socks5::SOCKS5_AUTH_METHOD_NONE);
+    /// UDP Associate `addr` via `proxy`, authenticated with username/password (RFC 1929)
+    pub async fn udp_associate_with_password<A>(
+        addr: A,
+        proxy: &SocketAddr,
+        username: &str,
+        password: &str,
+    ) -> io::Result<(Socks5Client, Address)>
+    where
+        Address: From<A>,
+    {
+        let auth = Some((username, password));
+        Socks5Client::request(Command::UdpAssociate, From::from(addr), proxy, auth).await
+    }
 
-        // 2. Send request header
-        let h = TcpRequestHeader::new(Command::TcpConnect, From::from(addr));
-        trace!("Going to connect, req: {:?}", h);
+    /// BIND via `proxy`, returns the address that the proxy listens on
+    ///
+    /// The address of the inbound connection is returned by `read_bind_reply` after it is accepted.
+    pub async fn bind<A>(addr: A, proxy: &SocketAddr) -> io::Result<(Socks5Client, Address)>
+    where
+        Address: From<A>,
+    {
+        Socks5Client::request(Command::TcpBind, From::from(addr), proxy, None).await
+    }
 
-        h.write_to(&mut s).await?;
-        s.flush().await?;
-        let hp = TcpResponseHeader::read_from(&mut s).await?;
+    /// Reads the second reply of BIND, returns the address of the inbound connection
+    pub async fn read_bind_reply(&mut self) -> io::Result<Address> {
+        let hp = TcpResponseHeader::read_from(&mut self.stream).await?;
 
-        trace!("Got response: {:?}", hp);
+        trace!("Got BIND response: {:?}", hp);
         match hp.reply {
-            Reply::Succeeded => (),
+            Reply::Succeeded => Ok(hp.address),
             r => {
                 let err = io::Error::new(io::ErrorKind::Other, format!("{}", r));
-                return Err(err);
+                Err(err)
             }
         }
-
-        Ok(Socks5Client { stream: s })
     }
 
-    /// UDP Associate `addr` via `proxy`
-    pub async fn udp_associate<A>(addr: A, proxy: &SocketAddr) -> io::Result<(Socks5Client, Address)>
-    where
-        Address: From<A>,
-    {
+    async fn request(
+        cmd: Command,
+        addr: Address,
+        proxy: &SocketAddr,
+        auth: Option<(&str, &str)>,
+    ) -> io::Result<(Socks5Client, Address)> {
         let mut s = TcpStream::connect(proxy).await?;
 
         // 1. Handshake
-        let hs = HandshakeRequest::new(vec![socks5::SOCKS5_AUTH_METHOD_NONE]);
+        let method = match auth {
+            Some(..) => socks5::SOCKS5_AUTH_METHOD_PASSWORD,
+            None => socks5::SOCKS5_AUTH_METHOD_NONE,
+        };
+        let hs = HandshakeRequest::new(vec![method]);
         trace!("Client connected, going to send handshake: {:?}", hs);
 
         hs.write_to(&mut s).await?;
@@ -86,10 +124,27 @@ impl Socks5Client {
         let hsp = HandshakeResponse::read_from(&mut s).await?;
 
         trace!("Got handshake response: {:?}", hsp);
-        assert_eq!(hsp.chosen_method, socks5::SOCKS5_AUTH_METHOD_NONE);
+        if hsp.chosen_method != method {
+            let err = io::Error::new(
+                io::ErrorKind::Other,
+                format!("proxy chose unsupported authentication method {:#x}", hsp.chosen_method),
+            );
+            return Err(err);
+        }
+
+        if let Some((username, password)) = auth {
+            PasswdAuthRequest::new(username, password).write_to(&mut s).await?;
+            s.flush().await?;
+
+            let resp = PasswdAuthResponse::read_from(&mut s).await?;
+            if resp.status != socks5::SOCKS5_AUTH_PASSWORD_SUCCEEDED {
+                let err = io::Error::new(io::ErrorKind::PermissionDenied, "authentication failed");
+                return Err(err);
+            }
+        }
 
         // 2. Send request header
-        let h = TcpRequestHeader::new(Command::UdpAssociate, From::from(addr));
+        let h = TcpRequestHeader::new(cmd, addr);
         trace!("Going to connect, req: {:?}", h);
 
         h.write_to(&mut s).await?;
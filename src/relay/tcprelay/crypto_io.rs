@@ -3,34 +3,44 @@
 use std::{
     io,
     marker::{PhantomData, Unpin},
+    net::IpAddr,
     pin::Pin,
+    sync::Arc,
     task::{Context, Poll},
 };
 
 use byte_string::ByteStr;
 use bytes::Bytes;
-use futures::ready;
+use futures::{future, ready};
 use log::{debug, trace};
 use tokio::prelude::*;
 
 use crate::{
     config::ServerConfig,
     context::{self, SharedServerState},
-    crypto::{CipherCategory, CipherType},
+    crypto::{aead2022, CipherCategory, CipherType},
+    relay::{
+        metrics::Protocol,
+        replay::{NonceFilter, SharedNonceFilter},
+        users::{ServerUsers, SharedServerUsers, UserContext},
+    },
 };
 
 use super::{
     aead::{DecryptedReader as AeadDecryptedReader, EncryptedWriter as AeadEncryptedWriter},
+    aead2022::{DecryptedReader as Aead2022DecryptedReader, EncryptedWriter as Aead2022EncryptedWriter},
     stream::{DecryptedReader as StreamDecryptedReader, EncryptedWriter as StreamEncryptedWriter},
 };
 
 enum DecryptedReader {
     Aead(AeadDecryptedReader),
+    Aead2022(Aead2022DecryptedReader),
     Stream(StreamDecryptedReader),
 }
 
 enum EncryptedWriter {
     Aead(AeadEncryptedWriter),
+    Aead2022(Aead2022EncryptedWriter),
     Stream(StreamEncryptedWriter),
 }
 
@@ -38,8 +48,15 @@ enum EncryptedWriter {
 enum ReadStatus {
     /// Waiting for initializing vector (or nonce for AEAD ciphers)
     ///
-    /// (context, Buffer, already_read_bytes, method, key)
-    WaitIv(SharedServerState, Vec<u8>, usize, CipherType, Bytes),
+    /// (Buffer, already_read_bytes, method, key)
+    WaitIv(Vec<u8>, usize, CipherType, Bytes),
+
+    /// Waiting for salt and the first chunk of a multi-user server, the user is identified with them
+    ///
+    /// The first chunk is the identity header for AEAD-2022 ciphers.
+    ///
+    /// (Buffer, already_read_bytes, method, local_salt, users, peer_ip)
+    WaitUser(Vec<u8>, usize, CipherType, Bytes, SharedServerUsers, IpAddr),
 
     /// Connection is established, DecryptedReader is initialized
     Established,
@@ -49,8 +66,12 @@ enum ReadStatus {
 pub struct CryptoStream<S> {
     stream: S,
     dec: Option<DecryptedReader>,
-    enc: EncryptedWriter,
+    enc: Option<EncryptedWriter>,
     read_status: ReadStatus,
+    state: SharedServerState,
+    nonce_filter: SharedNonceFilter,
+    user: Option<Arc<UserContext>>,
+    relay_salt: Option<Bytes>,
 }
 
 impl<S: Unpin> Unpin for CryptoStream<S> {}
@@ -61,14 +82,82 @@ impl<S> CryptoStream<S> {
         let method = svr_cfg.method();
         let prev_len = match method.category() {
             CipherCategory::Stream => method.iv_size(),
-            CipherCategory::Aead => method.salt_size(),
+            CipherCategory::Aead | CipherCategory::Aead2022 => method.salt_size(),
+        };
+
+        let nonce_filter = context.nonce_filter(svr_cfg.addr());
+        let iv = CryptoStream::<S>::generate_iv(&nonce_filter, method);
+        let enc = match method.category() {
+            CipherCategory::Stream => EncryptedWriter::Stream(StreamEncryptedWriter::new(method, svr_cfg.key(), iv)),
+            CipherCategory::Aead => EncryptedWriter::Aead(AeadEncryptedWriter::new(method, svr_cfg.key(), iv)),
+            CipherCategory::Aead2022 => {
+                // Servers write responses to the requests they read
+                let is_response = context.config().config_type.is_server();
+                let mut writer = Aead2022EncryptedWriter::new(method, svr_cfg.key(), iv.clone(), is_response);
+                if !is_response && !svr_cfg.identity_keys().is_empty() {
+                    let ids = aead2022::make_identity_headers(method, svr_cfg.identity_keys(), svr_cfg.key(), &iv);
+                    writer.set_identity_headers(ids);
+                }
+                EncryptedWriter::Aead2022(writer)
+            }
+        };
+
+        CryptoStream {
+            stream,
+            dec: None,
+            enc: Some(enc),
+            read_status: ReadStatus::WaitIv(vec![0u8; prev_len], 0usize, method, svr_cfg.clone_key()),
+            state: context.clone_server_state(),
+            nonce_filter,
+            user: None,
+            relay_salt: None,
+        }
+    }
+
+    /// Create a new CryptoStream accepted by a multi-user server
+    ///
+    /// User is identified by trying each user's key against the first AEAD chunk, nothing could be written before that.
+    /// Users of AEAD-2022 ciphers are identified by the identity header instead.
+    pub fn new_multi_user(
+        context: &context::Context,
+        stream: S,
+        svr_cfg: &ServerConfig,
+        users: SharedServerUsers,
+        peer_ip: IpAddr,
+    ) -> CryptoStream<S> {
+        let method = svr_cfg.method();
+        assert!(
+            ServerUsers::is_supported(method),
+            "multi-user servers don't support {}",
+            method
+        );
+
+        // Salt and the encrypted length of the first chunk, or the identity header
+        let prev_len = match method.category() {
+            CipherCategory::Aead2022 => method.salt_size() + aead2022::IDENTITY_HEADER_SIZE,
+            _ => method.salt_size() + 2 + method.tag_size(),
         };
+        let nonce_filter = context.nonce_filter(svr_cfg.addr());
+        let salt = CryptoStream::<S>::generate_iv(&nonce_filter, method);
 
-        let iv = match method.category() {
+        CryptoStream {
+            stream,
+            dec: None,
+            enc: None,
+            read_status: ReadStatus::WaitUser(vec![0u8; prev_len], 0usize, method, salt, users, peer_ip),
+            state: context.clone_server_state(),
+            nonce_filter,
+            user: None,
+            relay_salt: None,
+        }
+    }
+
+    fn generate_iv(nonce_filter: &NonceFilter, method: CipherType) -> Bytes {
+        match method.category() {
             CipherCategory::Stream => {
                 let local_iv = loop {
                     let iv = method.gen_init_vec();
-                    if context.check_nonce_and_set(&iv) {
+                    if nonce_filter.check_and_set(&iv) {
                         // IV exist, generate another one
                         continue;
                     }
@@ -77,10 +166,10 @@ impl<S> CryptoStream<S> {
                 trace!("Generated Stream cipher IV {:?}", local_iv);
                 local_iv
             }
-            CipherCategory::Aead => {
+            CipherCategory::Aead | CipherCategory::Aead2022 => {
                 let local_salt = loop {
                     let salt = method.gen_salt();
-                    if context.check_nonce_and_set(&salt) {
+                    if nonce_filter.check_and_set(&salt) {
                         // Salt exist, generate another one
                         continue;
                     }
@@ -89,26 +178,31 @@ impl<S> CryptoStream<S> {
                 trace!("Generated AEAD cipher salt {:?}", local_salt);
                 local_salt
             }
-        };
+        }
+    }
 
-        let method = svr_cfg.method();
-        let enc = match method.category() {
-            CipherCategory::Stream => EncryptedWriter::Stream(StreamEncryptedWriter::new(method, svr_cfg.key(), iv)),
-            CipherCategory::Aead => EncryptedWriter::Aead(AeadEncryptedWriter::new(method, svr_cfg.key(), iv)),
-        };
+    /// User of a multi-user server, available after the first chunk is read
+    pub fn user(&self) -> Option<&Arc<UserContext>> {
+        self.user.as_ref()
+    }
 
-        CryptoStream {
-            stream,
-            dec: None,
-            enc,
-            read_status: ReadStatus::WaitIv(
-                context.clone_server_state(),
-                vec![0u8; prev_len],
-                0usize,
-                method,
-                svr_cfg.clone_key(),
-            ),
-        }
+    /// Salt of a request to a user with `next_hop`, which should be relayed to the next hop with the rest of stream
+    ///
+    /// Available after the handshake is read, nothing could be read or written through this `CryptoStream`.
+    pub fn relay_salt(&self) -> Option<&Bytes> {
+        self.relay_salt.as_ref()
+    }
+
+    /// Get mutable reference of the underlying stream
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.stream
+    }
+
+    /// Consumes the `CryptoStream`, returning the underlying stream
+    ///
+    /// Data buffered for encryption and decryption are dropped.
+    pub fn into_inner(self) -> S {
+        self.stream
     }
 }
 
@@ -117,7 +211,7 @@ where
     S: AsyncRead + Unpin,
 {
     fn poll_read_handshake(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
-        if let ReadStatus::WaitIv(ref ctx, ref mut buf, ref mut pos, method, ref key) = self.read_status {
+        if let ReadStatus::WaitIv(ref mut buf, ref mut pos, method, ref key) = self.read_status {
             while *pos < buf.len() {
                 let n = ready!(Pin::new(&mut self.stream).poll_read(cx, &mut buf[*pos..]))?;
                 if n == 0 {
@@ -128,10 +222,13 @@ where
             }
 
             // Got iv/salt, check if it is repeated
-            if ctx.check_nonce_and_set(buf) {
+            //
+            // Salts of AEAD-2022 ciphers are checked after the header is authenticated
+            if method.category() != CipherCategory::Aead2022 && self.nonce_filter.check_and_set(buf) {
                 use std::io::{Error, ErrorKind};
 
                 debug!("Detected repeated iv/salt {:?}", ByteStr::new(buf));
+                self.state.metrics().incr_repeated_nonces(Protocol::Tcp);
 
                 let err = Error::new(ErrorKind::Other, "detected repeated iv/salt");
                 return Poll::Ready(Err(err));
@@ -146,22 +243,126 @@ where
                     trace!("Got AEAD cipher salt {:?}", ByteStr::new(&buf));
                     DecryptedReader::Aead(AeadDecryptedReader::new(method, key, &buf))
                 }
+                CipherCategory::Aead2022 => {
+                    trace!("Got AEAD-2022 cipher salt {:?}", ByteStr::new(buf));
+
+                    // Requests are read by servers, which echo their salts in responses. Clients read responses of
+                    // their own requests.
+                    let request_salt = match self.enc {
+                        Some(EncryptedWriter::Aead2022(ref mut w)) if w.is_response() => {
+                            w.set_request_salt(Bytes::copy_from_slice(buf));
+                            None
+                        }
+                        Some(EncryptedWriter::Aead2022(ref w)) => Some(w.salt().clone()),
+                        _ => unreachable!("AEAD-2022 stream without AEAD-2022 writer"),
+                    };
+                    let mut dec = Aead2022DecryptedReader::new(method, key, buf, request_salt);
+                    dec.set_salt_window(self.state.clone(), buf);
+                    DecryptedReader::Aead2022(dec)
+                }
             };
 
             self.dec = Some(dec);
             self.read_status = ReadStatus::Established;
         }
 
+        if let ReadStatus::WaitUser(ref mut buf, ref mut pos, method, ref local_salt, ref users, peer_ip) =
+            self.read_status
+        {
+            while *pos < buf.len() {
+                let n = ready!(Pin::new(&mut self.stream).poll_read(cx, &mut buf[*pos..]))?;
+                if n == 0 {
+                    use std::io::ErrorKind;
+                    return Poll::Ready(Err(ErrorKind::UnexpectedEof.into()));
+                }
+                *pos += n;
+            }
+
+            let (salt, chunk) = buf.split_at(method.salt_size());
+
+            // Got salt, check if it is repeated
+            //
+            // Salts of AEAD-2022 ciphers are checked after the header is authenticated, by the next hop if it is relayed
+            if method.category() != CipherCategory::Aead2022 && self.nonce_filter.check_and_set(salt) {
+                use std::io::{Error, ErrorKind};
+
+                debug!("Detected repeated salt {:?}", ByteStr::new(salt));
+                self.state.metrics().incr_repeated_nonces(Protocol::Tcp);
+
+                let err = Error::new(ErrorKind::Other, "detected repeated salt");
+                return Poll::Ready(Err(err));
+            }
+
+            let user = match users.identify_chunk(peer_ip, salt, chunk) {
+                Some(u) => u,
+                None => {
+                    debug!("No user matches the first chunk from {}", peer_ip);
+                    self.state.metrics().incr_decrypt_failures(Protocol::Tcp);
+
+                    let err = io::Error::new(io::ErrorKind::InvalidData, "no user matched");
+                    return Poll::Ready(Err(err));
+                }
+            };
+
+            trace!("Got AEAD cipher salt {:?} of user {}", ByteStr::new(salt), user.name());
+
+            if user.next_hop().is_some() {
+                self.relay_salt = Some(Bytes::copy_from_slice(salt));
+            } else if method.category() == CipherCategory::Aead2022 {
+                let mut dec = Aead2022DecryptedReader::new(method, user.key(), salt, None);
+                dec.set_salt_window(self.state.clone(), salt);
+                let mut enc = Aead2022EncryptedWriter::new(method, user.key(), local_salt.clone(), true);
+                enc.set_request_salt(Bytes::copy_from_slice(salt));
+
+                self.dec = Some(DecryptedReader::Aead2022(dec));
+                self.enc = Some(EncryptedWriter::Aead2022(enc));
+            } else {
+                let dec = AeadDecryptedReader::with_first_chunk(method, user.key(), salt, chunk);
+                let enc = AeadEncryptedWriter::new(method, user.key(), local_salt.clone());
+
+                self.dec = Some(DecryptedReader::Aead(dec));
+                self.enc = Some(EncryptedWriter::Aead(enc));
+            }
+            self.user = Some(user);
+            self.read_status = ReadStatus::Established;
+        }
+
         Poll::Ready(Ok(()))
     }
 
+    /// Reads salt (or IV) of the peer, and identifies the user of multi-user servers
+    pub async fn read_handshake(&mut self) -> io::Result<()> {
+        future::poll_fn(|cx| self.poll_read_handshake(cx)).await
+    }
+
     fn priv_poll_read(mut self: Pin<&mut Self>, ctx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
         ready!(self.poll_read_handshake(ctx))?;
 
         let stream = unsafe { &mut *(&mut self.stream as *mut _) };
-        match *self.dec.as_mut().unwrap() {
-            DecryptedReader::Aead(ref mut r) => r.poll_read_decrypted(ctx, stream, buf),
-            DecryptedReader::Stream(ref mut r) => r.poll_read_decrypted(ctx, stream, buf),
+        match self.dec {
+            Some(DecryptedReader::Aead(ref mut r)) => {
+                let result = ready!(r.poll_read_decrypted(ctx, stream, buf));
+                if let Err(ref err) = result {
+                    if err.kind() == io::ErrorKind::InvalidData {
+                        self.state.metrics().incr_decrypt_failures(Protocol::Tcp);
+                    }
+                }
+                Poll::Ready(result)
+            }
+            Some(DecryptedReader::Aead2022(ref mut r)) => {
+                let result = ready!(r.poll_read_decrypted(ctx, stream, buf));
+                if let Err(ref err) = result {
+                    if err.kind() == io::ErrorKind::InvalidData {
+                        self.state.metrics().incr_decrypt_failures(Protocol::Tcp);
+                    }
+                }
+                Poll::Ready(result)
+            }
+            Some(DecryptedReader::Stream(ref mut r)) => r.poll_read_decrypted(ctx, stream, buf),
+            None => {
+                let err = io::Error::new(io::ErrorKind::NotConnected, "stream is relayed to the next hop");
+                Poll::Ready(Err(err))
+            }
         }
     }
 }
@@ -173,8 +374,13 @@ where
     fn priv_poll_write(mut self: Pin<&mut Self>, ctx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
         let stream = unsafe { &mut *(&mut self.stream as *mut _) };
         match self.enc {
-            EncryptedWriter::Aead(ref mut w) => w.poll_write_encrypted(ctx, stream, buf),
-            EncryptedWriter::Stream(ref mut w) => w.poll_write_encrypted(ctx, stream, buf),
+            Some(EncryptedWriter::Aead(ref mut w)) => w.poll_write_encrypted(ctx, stream, buf),
+            Some(EncryptedWriter::Aead2022(ref mut w)) => w.poll_write_encrypted(ctx, stream, buf),
+            Some(EncryptedWriter::Stream(ref mut w)) => w.poll_write_encrypted(ctx, stream, buf),
+            None => {
+                let err = io::Error::new(io::ErrorKind::NotConnected, "user is not identified yet");
+                Poll::Ready(Err(err))
+            }
         }
     }
 
@@ -0,0 +1,151 @@
+//! Forwarding connections failed to authenticate to the fallback address
+//!
+//! Bytes read from clients are recorded until the target address is decrypted. If it fails, the recorded bytes
+//! and the rest of the connection are forwarded to the fallback address, so probes see the service behind it
+//! instead of a connection closed by a shadowsocks server.
+
+use std::{
+    io::{self, ErrorKind},
+    marker::Unpin,
+    net::SocketAddr,
+    pin::Pin,
+    task::{Context as TaskContext, Poll},
+};
+
+use futures::{
+    future::{self, Either},
+    ready,
+};
+use log::{debug, error, trace};
+use tokio::io::{self as tokio_io, AsyncRead, AsyncWrite, AsyncWriteExt};
+
+use crate::{config::ServerAddr, context::Context};
+
+use super::utils::connect_tcp_stream;
+
+/// Stream recording bytes read from it
+pub struct RecordStream<S> {
+    stream: S,
+    recorded: Option<Vec<u8>>,
+}
+
+impl<S> RecordStream<S> {
+    /// Creates a stream, which records bytes read from `stream` if `record` is `true`
+    pub fn new(stream: S, record: bool) -> RecordStream<S> {
+        RecordStream {
+            stream,
+            recorded: if record { Some(Vec::new()) } else { None },
+        }
+    }
+
+    /// Stops recording, bytes recorded are dropped
+    pub fn stop_recording(&mut self) {
+        self.recorded = None;
+    }
+
+    /// Stops recording, and takes bytes recorded
+    pub fn take_recorded(&mut self) -> Vec<u8> {
+        self.recorded.take().unwrap_or_default()
+    }
+
+    /// Unwraps the stream, bytes recorded are dropped
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+}
+
+impl<S> AsyncRead for RecordStream<S>
+where
+    S: AsyncRead + Unpin,
+{
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        let n = ready!(Pin::new(&mut self.stream).poll_read(cx, buf))?;
+        if let Some(ref mut recorded) = self.recorded {
+            recorded.extend_from_slice(&buf[..n]);
+        }
+        Poll::Ready(Ok(n))
+    }
+}
+
+impl<S> AsyncWrite for RecordStream<S>
+where
+    S: AsyncWrite + Unpin,
+{
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+/// Sends `recorded` and relays the rest of `stream` to `fallback`
+pub async fn relay_fallback<S>(
+    context: &Context,
+    stream: S,
+    recorded: &[u8],
+    peer_addr: SocketAddr,
+    fallback: &ServerAddr,
+) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut fallback_stream = match *fallback {
+        ServerAddr::SocketAddr(ref addr) => connect_tcp_stream(addr, &None).await,
+        ServerAddr::DomainName(ref dname, port) => {
+            let result = lookup_then!(context, dname.as_str(), port, false, |addr| {
+                connect_tcp_stream(&addr, &None).await
+            });
+            result.map(|(_, s)| s)
+        }
+    }
+    .map_err(|err| {
+        error!("Failed to connect fallback {} for {}, {}", fallback, peer_addr, err);
+        err
+    })?;
+
+    debug!(
+        "Relay {} <-> fallback {} established, {} bytes received before",
+        peer_addr,
+        fallback,
+        recorded.len()
+    );
+
+    fallback_stream.write_all(recorded).await?;
+
+    let (mut cr, mut cw) = tokio_io::split(stream);
+    let (mut fr, mut fw) = fallback_stream.split();
+
+    // CLIENT -> FALLBACK
+    let rhalf = tokio_io::copy(&mut cr, &mut fw);
+
+    // CLIENT <- FALLBACK
+    let whalf = tokio_io::copy(&mut fr, &mut cw);
+
+    match future::select(rhalf, whalf).await {
+        Either::Left((Ok(_), _)) => trace!("Relay {} -> fallback {} closed", peer_addr, fallback),
+        Either::Right((Ok(_), _)) => trace!("Relay {} <- fallback {} closed", peer_addr, fallback),
+        Either::Left((Err(err), _)) | Either::Right((Err(err), _)) => {
+            if let ErrorKind::TimedOut = err.kind() {
+                trace!(
+                    "Relay {} <-> fallback {} closed with error {}",
+                    peer_addr,
+                    fallback,
+                    err
+                );
+            } else {
+                debug!(
+                    "Relay {} <-> fallback {} closed with error {}",
+                    peer_addr, fallback, err
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
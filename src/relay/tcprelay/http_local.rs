@@ -41,8 +41,9 @@ use tower;
 use super::{CryptoStream, STcpStream};
 use crate::{
     config::ServerConfig,
-    context::SharedContext,
+    context::{Context, SharedContext},
     relay::{
+        access_log::RelayStat,
         loadbalancing::server::{LoadBalancer, PingBalancer, PingServer, PingServerType},
         socks5::Address,
     },
@@ -199,6 +200,7 @@ impl Connection for CryptoStream<STcpStream> {
 }
 
 async fn establish_connect_tunnel(
+    context: &Context,
     upgraded: Upgraded,
     mut stream: CryptoStream<STcpStream>,
     svr_cfg: &ServerConfig,
@@ -207,8 +209,12 @@ async fn establish_connect_tunnel(
 ) {
     use tokio::io::{copy, split};
 
-    let (mut r, mut w) = split(upgraded);
-    let (mut svr_r, mut svr_w) = stream.split();
+    let (r, mut w) = split(upgraded);
+    let (svr_r, mut svr_w) = stream.split();
+
+    let stat = RelayStat::new();
+    let mut r = stat.upload_reader(r);
+    let mut svr_r = stat.download_reader(svr_r);
 
     let rhalf = copy(&mut r, &mut svr_w);
     let whalf = copy(&mut svr_r, &mut w);
@@ -220,7 +226,13 @@ async fn establish_connect_tunnel(
         addr
     );
 
-    match future::select(rhalf, whalf).await {
+    let res = future::select(rhalf, whalf).await;
+
+    if let Some(access_log) = context.access_log() {
+        access_log.write(&stat.finish(client_addr, svr_cfg, &addr, &res));
+    }
+
+    match res {
         Either::Left((Ok(..), _)) => trace!("CONNECT relay {} -> {} ({}) closed", client_addr, svr_cfg.addr(), addr),
         Either::Left((Err(err), _)) => {
             if let ErrorKind::TimedOut = err.kind() {
@@ -330,7 +342,7 @@ async fn server_dispatch(
                         host
                     );
 
-                    establish_connect_tunnel(upgraded, stream, svr_cfg, client_addr, host).await
+                    establish_connect_tunnel(&context, upgraded, stream, svr_cfg, client_addr, host).await
                 }
                 Err(e) => {
                     error!(
@@ -24,23 +24,32 @@ use tokio::{
 };
 
 use crate::{
-    config::{ConfigType, ServerAddr, ServerConfig},
+    config::{ConfigType, ProxyConfig, ServerAddr, ServerConfig},
     context::Context,
-    relay::{socks5::Address, utils::try_timeout},
+    plugin::obfs::ObfsStream,
+    relay::{
+        shaper::{Shaper, StreamShaper},
+        socks5::Address,
+        upstream,
+        utils::try_timeout,
+    },
 };
 
 mod aead;
+mod aead2022;
+mod bind;
 pub mod client;
 mod crypto_io;
+mod fallback;
 mod http_local;
 pub mod local;
 mod monitor;
 pub mod server;
-mod server_context;
+pub(crate) mod server_context;
 mod socks5_local;
 mod stream;
 mod tunnel_local;
-mod utils;
+pub(crate) mod utils;
 
 pub use self::crypto_io::CryptoStream;
 
@@ -48,7 +57,7 @@ const BUFFER_SIZE: usize = 8 * 1024; // 8K buffer
 
 /// Shadowsocks' Connection
 ///
-/// The only feature: Supports timeout
+/// Supports timeout and bandwidth shaping
 pub struct Connection<S> {
     // Actual connection socket
     stream: BufReader<S>,
@@ -57,6 +66,9 @@ pub struct Connection<S> {
     timer: Option<Delay>,
     // User defined server timeout
     timeout: Option<Duration>,
+    // Bandwidth shapers of reading and writing
+    read_shaper: Option<StreamShaper>,
+    write_shaper: Option<StreamShaper>,
 }
 
 impl<S> Connection<S>
@@ -71,6 +83,8 @@ where
             stream: BufReader::new(stream),
             timer: None,
             timeout,
+            read_shaper: None,
+            write_shaper: None,
         }
     }
 }
@@ -100,6 +114,12 @@ impl<S> Connection<S> {
     fn cancel_timeout(&mut self) {
         let _ = self.timer.take();
     }
+
+    /// Limits bandwidth of reading and writing with token buckets
+    pub fn set_shapers(&mut self, read: Shaper, write: Shaper) {
+        self.read_shaper = Some(StreamShaper::new(read));
+        self.write_shaper = Some(StreamShaper::new(write));
+    }
 }
 
 impl<S> Connection<S>
@@ -139,9 +159,16 @@ where
     S: AsyncRead + Unpin,
 {
     fn poll_read(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        if let Some(ref mut shaper) = self.read_shaper {
+            ready!(shaper.poll_acquire(cx));
+        }
+
         match Pin::new(&mut self.stream).poll_read(cx, buf) {
             Poll::Ready(r) => {
                 self.cancel_timeout();
+                if let (Ok(n), Some(ref shaper)) = (&r, &self.read_shaper) {
+                    shaper.consume(*n);
+                }
                 Poll::Ready(r)
             }
             Poll::Pending => {
@@ -157,9 +184,16 @@ where
     S: AsyncRead + AsyncWrite + Unpin,
 {
     fn poll_write(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        if let Some(ref mut shaper) = self.write_shaper {
+            ready!(shaper.poll_acquire(cx));
+        }
+
         match Pin::new(&mut self.stream).poll_write(cx, buf) {
             Poll::Ready(r) => {
                 self.cancel_timeout();
+                if let (Ok(n), Some(ref shaper)) = (&r, &self.write_shaper) {
+                    shaper.consume(*n);
+                }
                 Poll::Ready(r)
             }
             Poll::Pending => {
@@ -188,23 +222,38 @@ where
 }
 
 /// Secured TcpStream
-pub type STcpStream = Connection<TcpStream>;
+///
+/// Wrapped in the framing of the built-in simple-obfs if it is enabled
+pub type STcpStream = Connection<ObfsStream<TcpStream>>;
 
 async fn connect_proxy_server_internal(
     context: &Context,
     svr_addr: &ServerAddr,
+    upstream_proxy: Option<&ProxyConfig>,
     timeout: Option<Duration>,
 ) -> io::Result<STcpStream> {
+    if let Some(proxy) = upstream_proxy {
+        // Domain name of the server is resolved by the upstream proxy
+        let addr = match *svr_addr {
+            ServerAddr::SocketAddr(ref addr) => Address::SocketAddress(*addr),
+            ServerAddr::DomainName(ref domain, port) => Address::DomainNameAddress(domain.clone(), port),
+        };
+
+        let stream = try_timeout(upstream::connect(context, proxy, &addr, &None), timeout).await?;
+        debug!("Connected proxy {} through upstream proxy {}", svr_addr, proxy.addr);
+        return Ok(STcpStream::new(ObfsStream::new(stream), timeout));
+    }
+
     match svr_addr {
         ServerAddr::SocketAddr(ref addr) => {
             let stream = try_timeout(TcpStream::connect(addr), timeout).await?;
             debug!("Connected proxy {}", addr);
-            Ok(STcpStream::new(stream, timeout))
+            Ok(STcpStream::new(ObfsStream::new(stream), timeout))
         }
         ServerAddr::DomainName(ref domain, port) => {
             let result = lookup_then!(context, domain.as_str(), *port, false, |addr| {
                 match try_timeout(TcpStream::connect(addr), timeout).await {
-                    Ok(s) => Ok(STcpStream::new(s, timeout)),
+                    Ok(s) => Ok(STcpStream::new(ObfsStream::new(s), timeout)),
                     Err(e) => {
                         debug!(
                             "Failed to connect proxy {}:{} ({}), try another (err: {})",
@@ -236,10 +285,16 @@ async fn connect_proxy_server(context: &Context, svr_cfg: &ServerConfig) -> io::
     let svr_addr = match context.config().config_type {
         ConfigType::Server => svr_cfg.addr(),
         ConfigType::Socks5Local | ConfigType::TunnelLocal | ConfigType::HttpLocal => {
-            svr_cfg.plugin_addr().as_ref().unwrap_or_else(|| svr_cfg.addr())
+            svr_cfg.tcp_plugin_addr().unwrap_or_else(|| svr_cfg.addr())
         }
     };
 
+    // Plugins are listening locally, they connect to the server by themselves
+    let upstream_proxy = match svr_cfg.tcp_plugin_addr() {
+        Some(..) => None,
+        None => svr_cfg.upstream_proxy(),
+    };
+
     // Retry if connect failed
     //
     // FIXME: This won't work if server is actually down.
@@ -251,8 +306,18 @@ async fn connect_proxy_server(context: &Context, svr_cfg: &ServerConfig) -> io::
     trace!("Connecting to proxy {}, timeout: {:?}", svr_addr, timeout);
     let mut last_err = None;
     for retry_time in 0..RETRY_TIMES {
-        match connect_proxy_server_internal(context, svr_addr, timeout).await {
-            Ok(s) => return Ok(s),
+        match connect_proxy_server_internal(context, svr_addr, upstream_proxy, timeout).await {
+            Ok(mut s) => {
+                if let Some(bandwidth) = context.bandwidth().server(svr_cfg) {
+                    // Writing to the server is upload
+                    let (upload, download) = bandwidth.shapers();
+                    s.set_shapers(download, upload);
+                }
+                if let Some(obfs) = svr_cfg.obfs() {
+                    s.set_client_obfs(obfs, svr_cfg.addr().port());
+                }
+                return Ok(s);
+            }
             Err(err) => {
                 // Connection failure, retry
                 debug!(
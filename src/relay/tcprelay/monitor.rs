@@ -10,16 +10,54 @@ use std::{
 
 use tokio::io::{AsyncRead, AsyncWrite};
 
+use crate::relay::flow::SharedServerFlowStatistic;
+
 use super::server_context::SharedTcpServerContext;
 
 pub struct TcpMonStream<S> {
     stream: S,
     context: SharedTcpServerContext,
+    user_flow: Option<SharedServerFlowStatistic>,
+    // Traffic before the user is known, attributed to the user later
+    unattributed_rx: usize,
+    unattributed_tx: usize,
 }
 
 impl<S> TcpMonStream<S> {
     pub fn new(c: SharedTcpServerContext, s: S) -> TcpMonStream<S> {
-        TcpMonStream { stream: s, context: c }
+        TcpMonStream {
+            stream: s,
+            context: c,
+            user_flow: None,
+            unattributed_rx: 0,
+            unattributed_tx: 0,
+        }
+    }
+
+    /// Attributes the following traffic to a user of multi-user server
+    ///
+    /// Traffic of the handshake is also attributed to the user.
+    pub fn set_user_flow(&mut self, flow: SharedServerFlowStatistic) {
+        flow.incr_rx(self.unattributed_rx);
+        flow.incr_tx(self.unattributed_tx);
+        self.user_flow = Some(flow);
+    }
+
+    /// Unwraps the stream, traffic of it won't be counted anymore
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+
+    fn check_quota(&self) -> io::Result<()> {
+        let user_exceeded = match self.user_flow {
+            Some(ref f) => f.quota_exceeded(),
+            None => false,
+        };
+        if self.context.flow().quota_exceeded() || user_exceeded {
+            Err(io::Error::new(io::ErrorKind::Other, "traffic quota exceeded"))
+        } else {
+            Ok(())
+        }
     }
 }
 
@@ -28,11 +66,16 @@ where
     S: AsyncRead + Unpin,
 {
     fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        self.check_quota()?;
         let n = match Pin::new(&mut self.stream).poll_read(cx, buf)? {
             Poll::Ready(n) => n,
             Poll::Pending => return Poll::Pending,
         };
         self.context.incr_rx(n);
+        match self.user_flow {
+            Some(ref flow) => flow.incr_rx(n),
+            None => self.unattributed_rx += n,
+        }
         Poll::Ready(Ok(n))
     }
 }
@@ -42,11 +85,16 @@ where
     S: AsyncWrite + Unpin,
 {
     fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        self.check_quota()?;
         let n = match Pin::new(&mut self.stream).poll_write(cx, buf)? {
             Poll::Ready(n) => n,
             Poll::Pending => return Poll::Pending,
         };
         self.context.incr_tx(n);
+        match self.user_flow {
+            Some(ref flow) => flow.incr_tx(n),
+            None => self.unattributed_tx += n,
+        }
         Poll::Ready(Ok(n))
     }
 
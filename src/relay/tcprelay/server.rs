@@ -2,26 +2,142 @@
 
 use std::{io, io::ErrorKind, net::SocketAddr};
 
-use futures::{
-    future::{self, Either},
-    stream::{FuturesUnordered, StreamExt},
-};
-use log::{debug, error, info, trace};
+use bytes::Bytes;
+use futures::future::{self, Either, FutureExt};
+use log::{debug, error, info, trace, warn};
 use tokio::{
     self,
+    io::{AsyncRead, AsyncWriteExt},
     net::{TcpListener, TcpStream},
 };
 
-use crate::{context::SharedContext, relay::socks5::Address};
+use crate::{
+    config::{ServerAddr, ServerConfig},
+    context::Context,
+    plugin::obfs::ObfsStream,
+    relay::{
+        access_log::RelayStat,
+        metrics::Protocol,
+        socks5::Address,
+        upstream,
+        users::UserContext,
+        utils::try_timeout,
+    },
+};
 
 use super::{
+    bind,
+    fallback::{relay_fallback, RecordStream},
     monitor::TcpMonStream,
     server_context::{SharedTcpServerContext, TcpServerContext},
     utils::connect_tcp_stream,
-    CryptoStream,
-    STcpStream,
+    CryptoStream, STcpStream,
 };
 
+type ServerStream = TcpMonStream<RecordStream<STcpStream>>;
+
+// Reads the remote address, or `None` if the stream should be relayed to the user's next hop
+async fn read_remote_addr<S>(stream: &mut CryptoStream<S>) -> io::Result<Option<Address>>
+where
+    S: AsyncRead + Unpin,
+{
+    stream.read_handshake().await?;
+    if stream.relay_salt().is_some() {
+        return Ok(None);
+    }
+
+    match Address::read_from(stream).await {
+        Ok(addr) => Ok(Some(addr)),
+        Err(err) => Err(From::from(err)),
+    }
+}
+
+// Checks the quota of the identified user, and counts traffic of the connection to it
+fn attribute_user(stream: &mut CryptoStream<ServerStream>, peer_addr: SocketAddr) -> io::Result<()> {
+    if let Some(user) = stream.user().cloned() {
+        if user.flow().quota_exceeded() {
+            debug!(
+                "Traffic quota of user {} exceeded, refused connection from {}",
+                user.name(),
+                peer_addr
+            );
+            let err = io::Error::new(io::ErrorKind::Other, "traffic quota exceeded");
+            return Err(err);
+        }
+
+        trace!("Connection from {} is attributed to user {}", peer_addr, user.name());
+        stream.get_mut().set_user_flow(user.flow().clone());
+    }
+    Ok(())
+}
+
+/// Relays a request of `user` to its next hop, with identity headers of the rest servers on the path
+async fn relay_next_hop(
+    svr_context: &TcpServerContext,
+    stream: ServerStream,
+    salt: Bytes,
+    user: &UserContext,
+    peer_addr: SocketAddr,
+) -> io::Result<()> {
+    let context = svr_context.context();
+    let next_hop = user.next_hop().expect("relayed user without next hop");
+
+    let mut remote_stream =
+        match super::connect_proxy_server_internal(context, next_hop, None, svr_context.svr_cfg().timeout()).await {
+            Ok(s) => s,
+            Err(err) => {
+                error!(
+                    "Failed to connect next hop {} of user {}, {}",
+                    next_hop,
+                    user.name(),
+                    err
+                );
+                return Err(err);
+            }
+        };
+
+    debug!("Relay {} <-> next hop {} established", peer_addr, next_hop);
+
+    // Salt is shared by all hops, identity header of this server is already consumed
+    remote_stream.write_all(&salt).await?;
+
+    let stat = RelayStat::new();
+
+    let (cr, mut cw) = tokio::io::split(stream);
+    let (sr, mut sw) = remote_stream.split();
+
+    let mut cr = stat.upload_reader(cr);
+    let mut sr = stat.download_reader(sr);
+
+    let rhalf = tokio::io::copy(&mut cr, &mut sw);
+    let whalf = tokio::io::copy(&mut sr, &mut cw);
+
+    let res = future::select(rhalf, whalf).await;
+
+    if let Some(access_log) = context.access_log() {
+        let destination = match *next_hop {
+            ServerAddr::SocketAddr(addr) => Address::SocketAddress(addr),
+            ServerAddr::DomainName(ref domain, port) => Address::DomainNameAddress(domain.clone(), port),
+        };
+        let mut record = stat.finish(peer_addr, svr_context.svr_cfg(), &destination, &res);
+        record.user = Some(user.name().to_owned());
+        access_log.write(&record);
+    }
+
+    match res {
+        Either::Left((Ok(_), _)) => trace!("Relay {} -> next hop {} closed", peer_addr, next_hop),
+        Either::Right((Ok(_), _)) => trace!("Relay {} <- next hop {} closed", peer_addr, next_hop),
+        Either::Left((Err(err), _)) | Either::Right((Err(err), _)) => {
+            debug!(
+                "Relay {} <-> next hop {} closed with error {}",
+                peer_addr, next_hop, err
+            );
+        }
+    }
+
+    Ok(())
+}
+
 #[allow(clippy::cognitive_complexity)]
 async fn handle_client(
     svr_context: SharedTcpServerContext,
@@ -33,6 +149,7 @@ async fn handle_client(
     }
 
     let context = svr_context.context();
+    let local_addr = socket.local_addr()?;
 
     if context.config().no_delay {
         if let Err(err) = socket.set_nodelay(true) {
@@ -46,26 +163,79 @@ async fn handle_client(
         svr_context.svr_cfg()
     );
 
-    let stream = TcpMonStream::new(
-        svr_context.clone(),
-        STcpStream::new(socket, svr_context.svr_cfg().timeout()),
-    );
+    let mut socket = STcpStream::new(ObfsStream::new(socket), svr_context.svr_cfg().timeout());
+    if let Some(bandwidth) = context.bandwidth().server(svr_context.svr_cfg()) {
+        // Reading from the client is upload
+        let (upload, download) = bandwidth.shapers();
+        socket.set_shapers(upload, download);
+    }
+    if let Some(obfs) = svr_context.svr_cfg().obfs() {
+        socket.set_server_obfs(obfs);
+    }
+
+    // Bytes are recorded for forwarding to the fallback if it fails to authenticate
+    let fallback = svr_context.svr_cfg().fallback();
+    let socket = RecordStream::new(socket, fallback.is_some());
+
+    let stream = TcpMonStream::new(svr_context.clone(), socket);
 
     // Do server-client handshake
     // Perform encryption IV exchange
-    let mut stream = CryptoStream::new(context, stream, svr_context.svr_cfg());
+    let mut stream = match svr_context.users() {
+        Some(users) => {
+            CryptoStream::new_multi_user(context, stream, svr_context.svr_cfg(), users.clone(), peer_addr.ip())
+        }
+        None => CryptoStream::new(context, stream, svr_context.svr_cfg()),
+    };
 
     // Read remote Address
-    let remote_addr = match Address::read_from(&mut stream).await {
-        Ok(o) => o,
+    let remote_addr = match read_remote_addr(&mut stream).await {
+        Ok(Some(addr)) => addr,
+        Ok(None) => {
+            stream.get_mut().stop_recording();
+            attribute_user(&mut stream, peer_addr)?;
+
+            let user = stream.user().cloned().expect("relayed stream without user");
+            let salt = stream.relay_salt().cloned().expect("relayed stream without salt");
+            return relay_next_hop(&svr_context, stream.into_inner(), salt, &user, peer_addr).await;
+        }
         Err(err) => {
             error!(
                 "Failed to decode Address, may be wrong method or key, peer {}, error: {}",
                 peer_addr, err
             );
-            return Err(From::from(err));
+            context.metrics().incr_handshake_failures();
+
+            // Fallback traffic is not relayed for clients, it isn't counted in traffic statistics or quota
+            if let Some(fallback) = fallback {
+                let mut stream = stream.into_inner().into_inner();
+                let recorded = stream.take_recorded();
+                let stream = stream.into_inner();
+                return relay_fallback(context, stream, &recorded, peer_addr, fallback).await;
+            }
+
+            return Err(err);
         }
     };
+    stream.get_mut().stop_recording();
+
+    // Listens on the address that the client connected to
+    if bind::is_bind_request(&remote_addr) {
+        attribute_user(&mut stream, peer_addr)?;
+        return bind::relay_bind(&svr_context, stream, peer_addr, &remote_addr, local_addr.ip()).await;
+    }
+
+    if !svr_context.svr_cfg().port_policy().is_allowed(remote_addr.port()) {
+        warn!(
+            "Relay {} -> {} refused, destination port is denied",
+            peer_addr, remote_addr
+        );
+        svr_context.metrics().incr_denied_destinations(Protocol::Tcp);
+        let err = io::Error::new(io::ErrorKind::Other, "destination port is denied");
+        return Err(err);
+    }
+
+    attribute_user(&mut stream, peer_addr)?;
 
     debug!("Relay {} <-> {} establishing", peer_addr, remote_addr);
 
@@ -79,30 +249,59 @@ async fn handle_client(
         }
     };
 
-    let mut remote_stream = match remote_addr {
-        Address::SocketAddress(ref saddr) => {
-            if context.check_forbidden_ip(&saddr.ip()) {
-                error!("{} is forbidden, failed to connect {}", saddr.ip(), saddr);
-                let err = io::Error::new(
-                    io::ErrorKind::Other,
-                    format!("{} is forbidden, failed to connect {}", saddr.ip(), saddr),
-                );
-                return Err(err);
-            }
+    if let Address::SocketAddress(ref saddr) = remote_addr {
+        if context.check_forbidden_ip(&saddr.ip()) {
+            error!("{} is forbidden, failed to connect {}", saddr.ip(), saddr);
+            let err = io::Error::new(
+                io::ErrorKind::Other,
+                format!("{} is forbidden, failed to connect {}", saddr.ip(), saddr),
+            );
+            return Err(err);
+        }
+    }
 
-            match connect_tcp_stream(saddr, &bind_addr).await {
+    let mut remote_stream = match (context.outbound_proxy(svr_context.svr_cfg()), &remote_addr) {
+        (Some(proxy), addr) => {
+            let timeout = svr_context
+                .svr_cfg()
+                .timeout()
+                .unwrap_or(upstream::DEFAULT_HANDSHAKE_TIMEOUT);
+            let addr = match upstream::resolve_destination(context, addr).await {
+                Ok(addr) => addr,
+                Err(err) => {
+                    error!(
+                        "Failed to connect remote {} through proxy {}, {}",
+                        remote_addr, proxy.addr, err
+                    );
+                    return Err(err);
+                }
+            };
+            match try_timeout(upstream::connect(context, proxy, &addr, &bind_addr), Some(timeout)).await {
                 Ok(s) => {
-                    debug!("Connected to remote {}", saddr);
+                    debug!("Connected to remote {} through proxy {}", addr, proxy.addr);
                     s
                 }
                 Err(err) => {
-                    error!("Failed to connect remote {}, {}", saddr, err);
+                    error!(
+                        "Failed to connect remote {} through proxy {}, {}",
+                        addr, proxy.addr, err
+                    );
                     return Err(err);
                 }
             }
         }
-        Address::DomainNameAddress(ref dname, port) => {
-            let result = lookup_then!(&*context, dname.as_str(), port, true, |addr| {
+        (None, Address::SocketAddress(ref saddr)) => match connect_tcp_stream(saddr, &bind_addr).await {
+            Ok(s) => {
+                debug!("Connected to remote {}", saddr);
+                s
+            }
+            Err(err) => {
+                error!("Failed to connect remote {}, {}", saddr, err);
+                return Err(err);
+            }
+        },
+        (None, Address::DomainNameAddress(ref dname, port)) => {
+            let result = lookup_then!(&*context, dname.as_str(), *port, true, |addr| {
                 match connect_tcp_stream(&addr, &bind_addr).await {
                     Ok(s) => Ok(s),
                     Err(err) => {
@@ -130,8 +329,19 @@ async fn handle_client(
 
     debug!("Relay {} <-> {} established", peer_addr, remote_addr);
 
-    let (mut cr, mut cw) = stream.split();
-    let (mut sr, mut sw) = remote_stream.split();
+    let user = stream.user().map(|u| u.name().to_owned());
+    let resolved = match context.outbound_proxy(svr_context.svr_cfg()) {
+        Some(..) => None,
+        None => remote_stream.peer_addr().ok(),
+    };
+
+    let stat = RelayStat::new();
+
+    let (cr, mut cw) = stream.split();
+    let (sr, mut sw) = remote_stream.split();
+
+    let mut cr = stat.upload_reader(cr);
+    let mut sr = stat.download_reader(sr);
 
     use tokio::io::copy;
 
@@ -141,7 +351,16 @@ async fn handle_client(
     // CLIENT <- SERVER
     let whalf = copy(&mut sr, &mut cw);
 
-    match future::select(rhalf, whalf).await {
+    let res = future::select(rhalf, whalf).await;
+
+    if let Some(access_log) = context.access_log() {
+        let mut record = stat.finish(peer_addr, svr_context.svr_cfg(), &remote_addr, &res);
+        record.user = user;
+        record.resolved = resolved;
+        access_log.write(&record);
+    }
+
+    match res {
         Either::Left((Ok(_), _)) => trace!("Relay {} -> {} closed", peer_addr, remote_addr),
         Either::Left((Err(err), _)) => {
             if let ErrorKind::TimedOut = err.kind() {
@@ -165,52 +384,71 @@ async fn handle_client(
     Ok(())
 }
 
-/// Runs the server
-pub async fn run(context: SharedContext) -> io::Result<()> {
-    let vec_fut = FuturesUnordered::new();
-
-    for svr_cfg in &context.config().server {
-        let mut listener = {
-            let addr = svr_cfg.plugin_addr().as_ref().unwrap_or_else(|| svr_cfg.addr());
-            let addr = addr.bind_addr(&*context).await?;
+/// Creates the listener of a server
+pub(crate) async fn create_listener(context: &Context, svr_cfg: &ServerConfig) -> io::Result<TcpListener> {
+    // Plugins forward to the loopback port bound while launching
+    let listener = match svr_cfg.plugin_listeners().and_then(|l| l.tcp_listener()) {
+        Some(listener) => listener?,
+        None => {
+            let addr = svr_cfg.tcp_plugin_addr().unwrap_or_else(|| svr_cfg.addr());
+            let addr = addr.bind_addr(context).await?;
+            TcpListener::bind(&addr).await?
+        }
+    };
 
-            let listener = TcpListener::bind(&addr)
-                .await
-                .unwrap_or_else(|err| panic!("Failed to listen on {}, {}", addr, err));
+    let local_addr = listener.local_addr().expect("Could not determine port bound to");
+    info!("ShadowSocks TCP Listening on {}", local_addr);
 
-            let local_addr = listener.local_addr().expect("Could not determine port bound to");
-            info!("ShadowSocks TCP Listening on {}", local_addr);
+    Ok(listener)
+}
 
-            listener
-        };
+/// Accepts clients from `listener` and relays them with `svr_context`
+pub(crate) async fn serve(svr_context: SharedTcpServerContext, mut listener: TcpListener) -> io::Result<()> {
+    loop {
+        match listener.accept().await {
+            Ok((socket, peer_addr)) => {
+                if svr_context.flow().quota_exceeded() {
+                    debug!(
+                        "Traffic quota of server {} exceeded, refused connection from {}",
+                        svr_context.svr_cfg().addr(),
+                        peer_addr
+                    );
+                    continue;
+                }
 
-        // Creates a shared context for spawning new clients
-        let svr_context = TcpServerContext::new(context.clone(), svr_cfg);
-
-        vec_fut.push(async move {
-            loop {
-                match listener.accept().await {
-                    Ok((socket, peer_addr)) => {
-                        let svr_context = svr_context.clone();
-                        tokio::spawn(async move {
-                            let _ = handle_client(svr_context, socket, peer_addr).await;
-                        });
-                    }
-                    Err(err) => {
-                        error!("Server run failed: {}", err);
-                        break;
+                // Connections from plugins are all from the plugin's address, only limited by port
+                let peer_ip = match svr_context.svr_cfg().tcp_plugin_addr() {
+                    Some(..) => None,
+                    None => Some(peer_addr.ip()),
+                };
+                let port = svr_context.svr_cfg().addr().port();
+                let limit_guard = match svr_context.context().limiter().acquire_tcp(port, peer_ip) {
+                    Ok(guard) => guard,
+                    Err(limit) => {
+                        warn!(
+                            "Connection from {} to port {} rejected, reached limit {}",
+                            peer_addr, port, limit
+                        );
+                        svr_context.metrics().incr_rejected(limit);
+                        continue;
                     }
-                }
+                };
+
+                let svr_context = svr_context.clone();
+                let active_guard = svr_context.metrics().tcp_connection();
+                tokio::spawn(async move {
+                    // Relays are kept while draining, until the server is stopped
+                    let context = svr_context.context().clone();
+                    let client = handle_client(svr_context, socket, peer_addr);
+                    let _ = future::select(client.boxed(), context.wait_stopped().boxed()).await;
+                    drop(active_guard);
+                    drop(limit_guard);
+                });
+            }
+            Err(err) => {
+                error!("Server run failed: {}", err);
+                return Err(err);
             }
-        });
-    }
-
-    match vec_fut.into_future().await.0 {
-        Some(()) => {
-            error!("One of TCP servers exited unexpectly");
-            let err = io::Error::new(io::ErrorKind::Other, "server exited unexpectly");
-            Err(err)
         }
-        None => unreachable!(),
     }
 }
@@ -1,69 +1,41 @@
 //! TCP Relay Context
 
-use std::{
-    io,
-    net::SocketAddr,
-    sync::{
-        atomic::{AtomicUsize, Ordering},
-        Arc,
-    },
-    time::Duration,
-};
+use std::sync::Arc;
 
 use crate::{
-    config::{ServerAddr, ServerConfig},
+    config::ServerConfig,
     context::SharedContext,
+    relay::{flow::SharedServerFlowStatistic, metrics::PortMetrics, users::SharedServerUsers},
 };
 
-use log::debug;
-use tokio::{self, net::UdpSocket, time};
-
 /// TCP Relay Server Context
 pub struct TcpServerContext {
-    tx: AtomicUsize,
-    rx: AtomicUsize,
+    flow: SharedServerFlowStatistic,
+    users: Option<SharedServerUsers>,
+    metrics: Arc<PortMetrics>,
     context: SharedContext,
-    // This is a pointer referencing one of the ServerConfig inside context's config (self referencing)
-    // And because SharedContext is a Arc<Context>, svr_cfg should have the same lifetime as context.
-    // I don't know how to convince rustc that it is safe.
-    svr_cfg: *const ServerConfig,
+    svr_cfg: ServerConfig,
 }
 
-unsafe impl Send for TcpServerContext {}
-unsafe impl Sync for TcpServerContext {}
-
-const UPDATE_INTERVAL: Duration = Duration::from_secs(5);
-
 pub type SharedTcpServerContext = Arc<TcpServerContext>;
 
 impl TcpServerContext {
     /// Create a new server context
-    pub fn new(context: SharedContext, svr_cfg: &ServerConfig) -> SharedTcpServerContext {
+    pub fn new(
+        context: SharedContext,
+        svr_cfg: &ServerConfig,
+        flow: SharedServerFlowStatistic,
+        users: Option<SharedServerUsers>,
+    ) -> SharedTcpServerContext {
         let ctx = TcpServerContext {
-            tx: AtomicUsize::new(0),
-            rx: AtomicUsize::new(0),
+            flow,
+            users,
+            metrics: context.metrics().port(svr_cfg.addr().port()),
             context,
-            svr_cfg: svr_cfg as *const _,
+            svr_cfg: svr_cfg.clone(),
         };
 
-        let ctx = Arc::new(ctx);
-
-        if ctx.context.config().manager_address.is_some() {
-            let ctx = ctx.clone();
-            tokio::spawn(async move {
-                let mut interval = time::interval(UPDATE_INTERVAL);
-                while ctx.context.server_running() {
-                    interval.tick().await;
-
-                    let ctx = ctx.clone();
-                    tokio::spawn(async move {
-                        let _ = ctx.stat_interval().await;
-                    });
-                }
-            });
-        }
-
-        ctx
+        Arc::new(ctx)
     }
 
     pub fn context(&self) -> &SharedContext {
@@ -71,55 +43,31 @@ impl TcpServerContext {
     }
 
     pub fn incr_tx(&self, x: usize) {
-        self.tx.fetch_add(x, Ordering::Release);
+        self.flow.incr_tx(x);
+        self.metrics.incr_bytes_down(x);
     }
 
     pub fn incr_rx(&self, x: usize) {
-        self.rx.fetch_add(x, Ordering::Release);
+        self.flow.incr_rx(x);
+        self.metrics.incr_bytes_up(x);
     }
 
-    pub fn svr_cfg(&self) -> &ServerConfig {
-        unsafe { &*self.svr_cfg }
+    /// Traffic statistic of this server
+    pub fn flow(&self) -> &SharedServerFlowStatistic {
+        &self.flow
     }
 
-    async fn stat_interval(&self) -> io::Result<()> {
-        let transmission = self.tx.load(Ordering::Acquire) + self.rx.load(Ordering::Acquire);
-
-        let any_addr = "0.0.0.0:0".parse::<SocketAddr>().unwrap();
-        let mut socket = UdpSocket::bind(&any_addr).await?;
-
-        let payload = format!("stat: {{\"{}\": {}}}", self.svr_cfg().addr().port(), transmission);
-
-        let maddr = self
-            .context
-            .config()
-            .manager_address
-            .as_ref()
-            .expect("manager_address must not be None");
-
-        debug!("Sending Tcp Relay to {}, payload: {}", maddr, payload);
-
-        match maddr {
-            ServerAddr::SocketAddr(ref addr) => {
-                socket.send_to(payload.as_ref(), addr).await?;
-                Ok(())
-            }
-            ServerAddr::DomainName(ref domain, ref port) => {
-                use crate::relay::dns_resolver::resolve;
+    /// Users of this server, `None` if it is not a multi-user server
+    pub fn users(&self) -> Option<&SharedServerUsers> {
+        self.users.as_ref()
+    }
 
-                let addrs = resolve(&*self.context, &domain[..], *port, false).await?;
+    /// Metrics of this server's port
+    pub fn metrics(&self) -> &Arc<PortMetrics> {
+        &self.metrics
+    }
 
-                let mut last_err = None;
-                for addr in addrs {
-                    match socket.send_to(payload.as_ref(), addr).await {
-                        Ok(..) => return Ok(()),
-                        Err(err) => {
-                            last_err = Some(err);
-                        }
-                    }
-                }
-                Err(last_err.unwrap())
-            }
-        }
+    pub fn svr_cfg(&self) -> &ServerConfig {
+        &self.svr_cfg
     }
 }
@@ -12,6 +12,7 @@ use std::{
 
 use futures::future::{self, Either};
 use log::{debug, error, info, trace, warn};
+use ring::constant_time;
 use tokio::{
     self,
     net::{
@@ -26,12 +27,26 @@ use crate::{
     config::ServerConfig,
     context::{Context, SharedContext},
     relay::{
+        access_log::RelayStat,
         loadbalancing::server::{LoadBalancer, PingBalancer, PingServer, PingServerType},
-        socks5::{self, Address, HandshakeRequest, HandshakeResponse, TcpRequestHeader, TcpResponseHeader},
+        socks5::{
+            self,
+            Address,
+            HandshakeRequest,
+            HandshakeResponse,
+            PasswdAuthRequest,
+            PasswdAuthResponse,
+            Reply,
+            TcpRequestHeader,
+            TcpResponseHeader,
+        },
     },
 };
 
-use super::ignore_until_end;
+use super::{
+    bind::{bind_refused_addr, bind_request_addr},
+    ignore_until_end,
+};
 
 #[derive(Debug, Clone)]
 struct UdpConfig {
@@ -41,7 +56,7 @@ struct UdpConfig {
 
 async fn handle_socks5_connect<'a>(
     context: &Context,
-    (mut r, mut w): (ReadHalf<'a>, WriteHalf<'a>),
+    (r, mut w): (ReadHalf<'a>, WriteHalf<'a>),
     client_addr: SocketAddr,
     addr: &Address,
     svr_cfg: &ServerConfig,
@@ -60,8 +75,6 @@ async fn handle_socks5_connect<'a>(
             svr_s
         }
         Err(err) => {
-            use crate::relay::socks5::Reply;
-
             error!("Failed to connect remote server {}, err: {}", svr_cfg.addr(), err);
 
             let reply = match err.kind() {
@@ -69,20 +82,18 @@ async fn handle_socks5_connect<'a>(
                 ErrorKind::ConnectionAborted => Reply::HostUnreachable,
                 _ => Reply::NetworkUnreachable,
             };
-
-            let header = TcpResponseHeader::new(
-                reply,
-                Address::SocketAddress("0.0.0.0:0".parse::<SocketAddr>().unwrap()),
-            );
-            header.write_to(&mut w).await?;
-            w.flush().await?;
+            write_failure(&mut w, reply).await?;
 
             return Err(err);
         }
     };
 
     let mut svr_s = super::proxy_server_handshake(context, svr_s, svr_cfg, addr).await?;
-    let (mut svr_r, mut svr_w) = svr_s.split();
+    let (svr_r, mut svr_w) = svr_s.split();
+
+    let stat = RelayStat::new();
+    let mut r = stat.upload_reader(r);
+    let mut svr_r = stat.download_reader(svr_r);
 
     use tokio::io::copy;
 
@@ -96,7 +107,13 @@ async fn handle_socks5_connect<'a>(
         addr
     );
 
-    match future::select(rhalf, whalf).await {
+    let res = future::select(rhalf, whalf).await;
+
+    if let Some(access_log) = context.access_log() {
+        access_log.write(&stat.finish(client_addr, svr_cfg, addr, &res));
+    }
+
+    match res {
         Either::Left((Ok(..), _)) => trace!("CONNECT relay {} -> {} ({}) closed", client_addr, svr_cfg.addr(), addr),
         Either::Left((Err(err), _)) => {
             if let ErrorKind::TimedOut = err.kind() {
@@ -144,6 +161,183 @@ async fn handle_socks5_connect<'a>(
     Ok(())
 }
 
+async fn write_failure(w: &mut WriteHalf<'_>, reply: Reply) -> io::Result<()> {
+    let header = TcpResponseHeader::new(
+        reply,
+        Address::SocketAddress("0.0.0.0:0".parse::<SocketAddr>().unwrap()),
+    );
+    header.write_to(w).await?;
+    w.flush().await
+}
+
+async fn handle_socks5_bind<'a>(
+    context: &Context,
+    (r, mut w): (ReadHalf<'a>, WriteHalf<'a>),
+    client_addr: SocketAddr,
+    addr: &Address,
+    svr_cfg: &ServerConfig,
+) -> io::Result<()> {
+    let svr_s = match super::connect_proxy_server(context, svr_cfg).await {
+        Ok(s) => s,
+        Err(err) => {
+            error!("Failed to connect remote server {}, err: {}", svr_cfg.addr(), err);
+            write_failure(&mut w, Reply::NetworkUnreachable).await?;
+            return Err(err);
+        }
+    };
+
+    let request_addr = bind_request_addr(addr.port());
+    let mut svr_s = super::proxy_server_handshake(context, svr_s, svr_cfg, &request_addr).await?;
+    // Servers only accept inbound connections from this address
+    addr.write_to(&mut svr_s).await?;
+    svr_s.flush().await?;
+
+    // Servers without the extension close the connection without replying
+    let bound_addr = match Address::read_from(&mut svr_s).await {
+        Ok(addr) => addr,
+        Err(err) => {
+            warn!("Server {} doesn't support BIND, {}", svr_cfg.addr(), err);
+            write_failure(&mut w, Reply::CommandNotSupported).await?;
+            return Ok(());
+        }
+    };
+
+    if bound_addr == bind_refused_addr() {
+        warn!("Server {} refused BIND {}", svr_cfg.addr(), addr);
+        write_failure(&mut w, Reply::ConnectionNotAllowed).await?;
+        return Ok(());
+    }
+
+    trace!("BIND listening on {} of server {}", bound_addr, svr_cfg.addr());
+    TcpResponseHeader::new(Reply::Succeeded, bound_addr)
+        .write_to(&mut w)
+        .await?;
+    w.flush().await?;
+
+    let remote_addr = match Address::read_from(&mut svr_s).await {
+        Ok(addr) => addr,
+        Err(err) => {
+            write_failure(&mut w, Reply::GeneralFailure).await?;
+            return Err(From::from(err));
+        }
+    };
+
+    TcpResponseHeader::new(Reply::Succeeded, remote_addr.clone())
+        .write_to(&mut w)
+        .await?;
+    w.flush().await?;
+
+    debug!(
+        "BIND relay established {} <-> {} ({})",
+        client_addr,
+        svr_cfg.addr(),
+        remote_addr
+    );
+
+    let (svr_r, mut svr_w) = svr_s.split();
+
+    let stat = RelayStat::new();
+    let mut r = stat.upload_reader(r);
+    let mut svr_r = stat.download_reader(svr_r);
+
+    let rhalf = tokio::io::copy(&mut r, &mut svr_w);
+    let whalf = tokio::io::copy(&mut svr_r, &mut w);
+
+    let res = future::select(rhalf, whalf).await;
+
+    if let Some(access_log) = context.access_log() {
+        access_log.write(&stat.finish(client_addr, svr_cfg, &remote_addr, &res));
+    }
+
+    match res {
+        Either::Left((Err(err), _)) | Either::Right((Err(err), _)) => debug!(
+            "BIND relay {} <-> {} ({}) closed with error {}",
+            client_addr,
+            svr_cfg.addr(),
+            remote_addr,
+            err
+        ),
+        _ => debug!(
+            "BIND relay {} <-> {} ({}) closed",
+            client_addr,
+            svr_cfg.addr(),
+            remote_addr
+        ),
+    }
+
+    Ok(())
+}
+
+// Negotiates the authentication method, clients must authenticate with username/password if `socks5_users` is set
+async fn handshake(
+    context: &Context,
+    r: &mut ReadHalf<'_>,
+    w: &mut WriteHalf<'_>,
+    client_addr: SocketAddr,
+) -> io::Result<()> {
+    let handshake_req = HandshakeRequest::read_from(r).await?;
+
+    // Socks5 handshakes
+    trace!("Socks5 {:?}", handshake_req);
+
+    let users = &context.config().socks5_users;
+    let method = if users.is_empty() {
+        socks5::SOCKS5_AUTH_METHOD_NONE
+    } else {
+        socks5::SOCKS5_AUTH_METHOD_PASSWORD
+    };
+
+    if !handshake_req.methods.contains(&method) {
+        let resp = HandshakeResponse::new(socks5::SOCKS5_AUTH_METHOD_NOT_ACCEPTABLE);
+        resp.write_to(w).await?;
+        w.flush().await?;
+
+        warn!(
+            "Socks5 client {} doesn't support authentication method {:#x}",
+            client_addr, method
+        );
+        let err = io::Error::new(io::ErrorKind::PermissionDenied, "no acceptable authentication method");
+        return Err(err);
+    }
+
+    // Reply to client
+    let resp = HandshakeResponse::new(method);
+    trace!("Reply handshake {:?}", resp);
+    resp.write_to(w).await?;
+    w.flush().await?;
+
+    if method == socks5::SOCKS5_AUTH_METHOD_NONE {
+        return Ok(());
+    }
+
+    // Username/password authentication (RFC 1929)
+    let auth_req = PasswdAuthRequest::read_from(r).await?;
+    // Every user is compared in constant time, so timing doesn't tell which part of the credential is wrong
+    let authenticated = users.iter().fold(false, |found, u| {
+        let uname_eq = constant_time::verify_slices_are_equal(u.username().as_bytes(), &auth_req.uname).is_ok();
+        let passwd_eq = constant_time::verify_slices_are_equal(u.password().as_bytes(), &auth_req.passwd).is_ok();
+        found | (uname_eq & passwd_eq)
+    });
+
+    let status = if authenticated {
+        socks5::SOCKS5_AUTH_PASSWORD_SUCCEEDED
+    } else {
+        socks5::SOCKS5_AUTH_PASSWORD_FAILED
+    };
+    PasswdAuthResponse::new(status).write_to(w).await?;
+    w.flush().await?;
+
+    let uname = String::from_utf8_lossy(&auth_req.uname);
+    if authenticated {
+        debug!("Socks5 client {} authenticated as \"{}\"", client_addr, uname);
+        Ok(())
+    } else {
+        warn!("Socks5 client {} failed to authenticate as \"{}\"", client_addr, uname);
+        let err = io::Error::new(io::ErrorKind::PermissionDenied, "authentication failed");
+        Err(err)
+    }
+}
+
 #[allow(clippy::cognitive_complexity)]
 async fn handle_socks5_client(
     context: &Context,
@@ -167,32 +361,7 @@ async fn handle_socks5_client(
 
     let (mut r, mut w) = s.split();
 
-    let handshake_req = HandshakeRequest::read_from(&mut r).await?;
-
-    // Socks5 handshakes
-    trace!("Socks5 {:?}", handshake_req);
-
-    let (handshake_resp, res) = if !handshake_req.methods.contains(&socks5::SOCKS5_AUTH_METHOD_NONE) {
-        let resp = HandshakeResponse::new(socks5::SOCKS5_AUTH_METHOD_NOT_ACCEPTABLE);
-        warn!("Currently shadowsocks-rust does not support authentication");
-        (
-            resp,
-            Err(io::Error::new(
-                io::ErrorKind::Other,
-                "Currently shadowsocks-rust does not support authentication",
-            )),
-        )
-    } else {
-        // Reply to client
-        let resp = HandshakeResponse::new(socks5::SOCKS5_AUTH_METHOD_NONE);
-        trace!("Reply handshake {:?}", resp);
-        (resp, Ok(()))
-    };
-
-    handshake_resp.write_to(&mut w).await?;
-    w.flush().await?;
-
-    res?;
+    handshake(context, &mut r, &mut w, client_addr).await?;
 
     // Fetch headers
     let header = match TcpRequestHeader::read_from(&mut r).await {
@@ -230,11 +399,24 @@ async fn handle_socks5_client(
             }
         }
         socks5::Command::TcpBind => {
-            warn!("BIND is not supported");
-            let rh = TcpResponseHeader::new(socks5::Reply::CommandNotSupported, addr);
-            rh.write_to(&mut w).await?;
+            let enable_tcp = context.config().mode.enable_tcp();
+            if enable_tcp {
+                debug!("BIND {}", addr);
 
-            Ok(())
+                match handle_socks5_bind(context, (r, w), client_addr, &addr, conf).await {
+                    Ok(..) => Ok(()),
+                    Err(err) => Err(io::Error::new(
+                        err.kind(),
+                        format!("BIND {} failed with error \"{}\"", addr, err),
+                    )),
+                }
+            } else {
+                warn!("BIND is not enabled");
+                let rh = TcpResponseHeader::new(socks5::Reply::CommandNotSupported, addr);
+                rh.write_to(&mut w).await?;
+
+                Ok(())
+            }
         }
         socks5::Command::UdpAssociate => {
             if udp_conf.enable_udp {
@@ -243,6 +425,9 @@ async fn handle_socks5_client(
                 rh.write_to(&mut w).await?;
                 w.flush().await?;
 
+                // Datagrams from the client are relayed while the connection is alive
+                let _guard = context.udp_associate_clients().associate(client_addr.ip());
+
                 // Hold the connection until it ends by its own
                 ignore_until_end(&mut r).await?;
 
@@ -18,6 +18,7 @@ use crate::{
     config::ServerConfig,
     context::{Context, SharedContext},
     relay::{
+        access_log::RelayStat,
         loadbalancing::server::{LoadBalancer, PingBalancer, PingServer, PingServerType},
         socks5::Address,
     },
@@ -46,9 +47,13 @@ async fn establish_client_tcp_tunnel<'a>(
     };
 
     let mut svr_s = super::proxy_server_handshake(context, svr_s, svr_cfg, addr).await?;
-    let (mut svr_r, mut svr_w) = svr_s.split();
+    let (svr_r, mut svr_w) = svr_s.split();
 
-    let (mut r, mut w) = s.split();
+    let (r, mut w) = s.split();
+
+    let stat = RelayStat::new();
+    let mut r = stat.upload_reader(r);
+    let mut svr_r = stat.download_reader(svr_r);
 
     use tokio::io::copy;
 
@@ -62,7 +67,13 @@ async fn establish_client_tcp_tunnel<'a>(
         addr
     );
 
-    match future::select(rhalf, whalf).await {
+    let res = future::select(rhalf, whalf).await;
+
+    if let Some(access_log) = context.access_log() {
+        access_log.write(&stat.finish(client_addr, svr_cfg, addr, &res));
+    }
+
+    match res {
         Either::Left((Ok(..), _)) => trace!("TUNNEL relay {} -> {} ({}) closed", client_addr, svr_cfg.addr(), addr),
         Either::Left((Err(err), _)) => {
             if let ErrorKind::TimedOut = err.kind() {
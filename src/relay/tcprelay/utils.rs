@@ -1,6 +1,9 @@
 //! Utility functions
 
-use std::{io, net::SocketAddr};
+use std::{
+    io,
+    net::{SocketAddr, TcpListener as StdTcpListener},
+};
 
 use log::trace;
 use socket2::{Domain, SockAddr, Socket, Type};
@@ -47,3 +50,25 @@ pub async fn connect_tcp_stream(addr: &SocketAddr, outbound_addr: &Option<Socket
         }
     }
 }
+
+/// Creates a listener bound to `addr`, which could be shared by `try_clone`
+///
+/// Address is reused like tokio's `TcpListener::bind`, so it could be bound again right after the previous one is
+/// closed.
+pub fn create_std_listener(addr: &SocketAddr) -> io::Result<StdTcpListener> {
+    let socket = match *addr {
+        SocketAddr::V4(..) => Socket::new(Domain::ipv4(), Type::stream(), None)?,
+        SocketAddr::V6(..) => Socket::new(Domain::ipv6(), Type::stream(), None)?,
+    };
+
+    // Windows allows binding to ports in use with SO_REUSEADDR
+    #[cfg(not(windows))]
+    socket.set_reuse_address(true)?;
+
+    socket.bind(&SockAddr::from(*addr))?;
+    socket.listen(1024)?;
+
+    let listener = socket.into_tcp_listener();
+    listener.set_nonblocking(true)?;
+    Ok(listener)
+}
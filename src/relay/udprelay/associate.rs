@@ -0,0 +1,81 @@
+//! Clients holding UDP ASSOCIATE sessions of the local SOCKS5 server
+//!
+//! Datagrams are only relayed for these clients if SOCKS5 authentication is enabled, otherwise the UDP relay would be
+//! open to anyone who can reach it.
+//!
+//! Clients are identified by IP, because the address in UDP ASSOCIATE requests is usually unset or even the
+//! destination in practice, so the port that clients send datagrams from is unknown.
+
+use std::{collections::HashMap, net::IpAddr, sync::Arc};
+
+use spin::Mutex;
+
+/// Clients with live UDP ASSOCIATE sessions
+#[derive(Default)]
+pub struct UdpAssociateClients {
+    clients: Mutex<HashMap<IpAddr, usize>>,
+}
+
+pub type SharedUdpAssociateClients = Arc<UdpAssociateClients>;
+
+impl UdpAssociateClients {
+    /// Creates an empty set of clients
+    pub fn new_shared() -> SharedUdpAssociateClients {
+        Arc::new(UdpAssociateClients::default())
+    }
+
+    /// Registers a session of client `ip`, which is kept until the returned guard is dropped
+    pub fn associate(self: &Arc<Self>, ip: IpAddr) -> UdpAssociateGuard {
+        *self.clients.lock().entry(ip).or_insert(0) += 1;
+
+        UdpAssociateGuard {
+            clients: self.clone(),
+            ip,
+        }
+    }
+
+    /// Checks if client `ip` holds a live session
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        self.clients.lock().contains_key(ip)
+    }
+}
+
+/// Ends the session while dropping
+pub struct UdpAssociateGuard {
+    clients: SharedUdpAssociateClients,
+    ip: IpAddr,
+}
+
+impl Drop for UdpAssociateGuard {
+    fn drop(&mut self) {
+        let mut clients = self.clients.clients.lock();
+        if let Some(count) = clients.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                clients.remove(&self.ip);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn associate_and_release() {
+        let clients = UdpAssociateClients::new_shared();
+        let ip1 = "127.0.0.1".parse().unwrap();
+        let ip2 = "127.0.0.2".parse().unwrap();
+
+        let g1 = clients.associate(ip1);
+        let g2 = clients.associate(ip1);
+        assert!(clients.contains(&ip1));
+        assert!(!clients.contains(&ip2));
+
+        drop(g1);
+        assert!(clients.contains(&ip1));
+        drop(g2);
+        assert!(!clients.contains(&ip1));
+    }
+}
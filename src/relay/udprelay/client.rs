@@ -18,7 +18,7 @@ use crate::{
 };
 
 use super::{
-    crypto_io::{decrypt_payload, encrypt_payload},
+    crypto_io::{decrypt_payload, encrypt_payload, UdpSession},
     utils::create_socket,
     DEFAULT_TIMEOUT,
     MAXIMUM_UDP_PAYLOAD_SIZE,
@@ -30,6 +30,8 @@ pub struct ServerClient {
     method: CipherType,
     key: Bytes,
     server_addr: ServerAddr,
+    relay_addr: ServerAddr,
+    session: UdpSession,
 }
 
 impl ServerClient {
@@ -41,6 +43,8 @@ impl ServerClient {
             method: svr_cfg.method(),
             key: svr_cfg.clone_key(),
             server_addr: svr_cfg.addr().clone(),
+            relay_addr: svr_cfg.udp_plugin_addr().unwrap_or_else(|| svr_cfg.addr()).clone(),
+            session: UdpSession::with_identity_keys(false, svr_cfg.identity_keys().to_vec()),
         })
     }
 
@@ -60,9 +64,17 @@ impl ServerClient {
         send_buf.extend_from_slice(payload);
 
         let mut encrypt_buf = BytesMut::new();
-        encrypt_payload(context, self.method, &self.key, &send_buf, &mut encrypt_buf)?;
-
-        let send_len = match self.server_addr {
+        encrypt_payload(
+            context,
+            &self.server_addr,
+            self.method,
+            &self.key,
+            &self.session,
+            &send_buf,
+            &mut encrypt_buf,
+        )?;
+
+        let send_len = match self.relay_addr {
             ServerAddr::SocketAddr(ref remote_addr) => {
                 try_timeout(self.socket.send_to(&encrypt_buf[..], remote_addr), Some(timeout)).await?
             }
@@ -86,7 +98,14 @@ impl ServerClient {
         let mut recv_buf = [0u8; MAXIMUM_UDP_PAYLOAD_SIZE];
         let (recv_n, ..) = try_timeout(self.socket.recv_from(&mut recv_buf), Some(timeout)).await?;
 
-        let decrypt_buf = match decrypt_payload(context, self.method, &self.key, &recv_buf[..recv_n])? {
+        let decrypt_buf = match decrypt_payload(
+            context,
+            &self.server_addr,
+            self.method,
+            &self.key,
+            &self.session,
+            &recv_buf[..recv_n],
+        )? {
             None => {
                 error!("UDP packet too short, received length {}", recv_n);
                 let err = io::Error::new(io::ErrorKind::InvalidData, "packet too short");
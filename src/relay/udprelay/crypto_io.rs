@@ -19,34 +19,121 @@
 //! | Fixed  | Variable  |   Fixed   |
 //! +--------+-----------+-----------+
 //! ```
+//!
+//! Payload with AEAD-2022 cipher, encrypted with the session key of the client's or server's session ID.
+//! ChaCha20-Poly1305 packets have a random 24 bytes nonce instead of the separate header, and the separate header is
+//! encrypted in *Data*.
+//!
+//! ```plain
+//! UDP (after encryption, *ciphertext*)
+//! +---------------------+-----------+-----------+
+//! | *Separate header*   |  *Data*   |  Data_TAG |
+//! +---------------------+-----------+-----------+
+//! |         16          | Variable  |   Fixed   |
+//! +---------------------+-----------+-----------+
+//!
+//! Separate header (before AES encryption with the pre-shared key)
+//! +------------+-----------+
+//! | SESSION ID | PACKET ID |
+//! +------------+-----------+
+//! |     8      |     8     |
+//! +------------+-----------+
+//!
+//! Packets of clients to servers with identity keys have identity headers after the separate header, which is
+//! encrypted with the first identity key instead.
+//!
+//! Data of clients (before encryption), and of servers with the client's session ID after TIMESTAMP
+//! +------+-----------+------------+----------+---------+
+//! | TYPE | TIMESTAMP | PaddingLen | Padding  | Payload |
+//! +------+-----------+------------+----------+---------+
+//! |  1   |     8     |     2      | Variable | Variable|
+//! +------+-----------+------------+----------+---------+
+//! ```
 
-use std::{io, slice};
+use std::{
+    io,
+    slice,
+    sync::atomic::{AtomicU64, Ordering},
+};
 
 use byte_string::ByteStr;
-use bytes::{BufMut, BytesMut};
+use byteorder::{BigEndian, ByteOrder};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
 use log::{debug, trace};
 
 use crate::{
+    config::ServerAddr,
     context::Context,
-    crypto::{self, CipherCategory, CipherType, CryptoMode},
+    crypto::{self, aead2022, CipherCategory, CipherType, CryptoMode},
+    relay::metrics::Protocol,
 };
 
+const HEADER_TYPE_CLIENT: u8 = 0;
+const HEADER_TYPE_SERVER: u8 = 1;
+
+/// Session of a UDP association, for AEAD-2022 ciphers
+///
+/// Packets of AEAD-2022 ciphers are identified by session IDs and packet IDs instead of salts. Replayed packets are
+/// detected by windows of peers' sessions in the context.
+pub struct UdpSession {
+    is_server: bool,
+    session_id: u64,
+    packet_id: AtomicU64,
+    peer_session_id: AtomicU64,
+    identity_keys: Vec<Bytes>,
+}
+
+impl UdpSession {
+    /// Creates a new session for clients, or for servers if `is_server` is `true`
+    pub fn new(is_server: bool) -> UdpSession {
+        UdpSession::with_identity_keys(is_server, Vec::new())
+    }
+
+    /// Creates a new session with identity headers
+    ///
+    /// Clients write identity headers of `identity_keys`, and servers read them with their own identity key.
+    pub fn with_identity_keys(is_server: bool, identity_keys: Vec<Bytes>) -> UdpSession {
+        UdpSession {
+            is_server,
+            session_id: rand::random(),
+            packet_id: AtomicU64::new(0),
+            peer_session_id: AtomicU64::new(0),
+            identity_keys,
+        }
+    }
+
+    fn next_packet_id(&self) -> u64 {
+        self.packet_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    fn peer_session_id(&self) -> u64 {
+        self.peer_session_id.load(Ordering::Relaxed)
+    }
+}
+
 /// Encrypt payload into ShadowSocks UDP encrypted packet
+///
+/// `svr_addr` is the address of the server, whose replay filter is used for the generated nonce. `session` is only
+/// used by AEAD-2022 ciphers.
 pub fn encrypt_payload(
     context: &Context,
+    svr_addr: &ServerAddr,
     t: CipherType,
     key: &[u8],
+    session: &UdpSession,
     payload: &[u8],
     dst: &mut BytesMut,
 ) -> io::Result<()> {
     match t.category() {
-        CipherCategory::Stream => encrypt_payload_stream(context, t, key, payload, dst),
-        CipherCategory::Aead => encrypt_payload_aead(context, t, key, payload, dst),
+        CipherCategory::Stream => encrypt_payload_stream(context, svr_addr, t, key, payload, dst),
+        CipherCategory::Aead => encrypt_payload_aead(context, svr_addr, t, key, payload, dst),
+        CipherCategory::Aead2022 => encrypt_payload_aead_2022(t, key, session, payload, dst),
     }
 }
 
 fn encrypt_payload_stream(
     context: &Context,
+    svr_addr: &ServerAddr,
     t: CipherType,
     key: &[u8],
     payload: &[u8],
@@ -54,7 +141,7 @@ fn encrypt_payload_stream(
 ) -> io::Result<()> {
     let iv = loop {
         let iv = t.gen_init_vec();
-        if context.check_nonce_and_set(&iv) {
+        if context.check_nonce_and_set(svr_addr, &iv) {
             continue;
         }
         break iv;
@@ -77,6 +164,7 @@ fn encrypt_payload_stream(
 
 fn encrypt_payload_aead(
     context: &Context,
+    svr_addr: &ServerAddr,
     t: CipherType,
     key: &[u8],
     payload: &[u8],
@@ -84,7 +172,7 @@ fn encrypt_payload_aead(
 ) -> io::Result<()> {
     let salt = loop {
         let salt = t.gen_salt();
-        if context.check_nonce_and_set(&salt) {
+        if context.check_nonce_and_set(svr_addr, &salt) {
             continue;
         }
         break salt;
@@ -111,25 +199,112 @@ fn encrypt_payload_aead(
     Ok(())
 }
 
+fn encrypt_payload_aead_2022(
+    t: CipherType,
+    key: &[u8],
+    session: &UdpSession,
+    payload: &[u8],
+    dst: &mut BytesMut,
+) -> io::Result<()> {
+    let packet_id = session.next_packet_id();
+
+    let mut data = Vec::with_capacity(16 + 1 + 8 + 8 + 2 + payload.len() + t.tag_size());
+    let separate_header = aead2022::has_udp_separate_header(t);
+    if !separate_header {
+        data.put_u64(session.session_id);
+        data.put_u64(packet_id);
+    }
+    if session.is_server {
+        data.put_u8(HEADER_TYPE_SERVER);
+        data.put_u64(aead2022::unix_timestamp());
+        data.put_u64(session.peer_session_id());
+    } else {
+        data.put_u8(HEADER_TYPE_CLIENT);
+        data.put_u64(aead2022::unix_timestamp());
+    }
+    // No padding
+    data.put_u16(0);
+    data.put_slice(payload);
+
+    trace!(
+        "UDP packet of AEAD-2022 session {:016x}, packet id {}",
+        session.session_id,
+        packet_id
+    );
+
+    if separate_header {
+        let mut header = [0u8; aead2022::UDP_SEPARATE_HEADER_SIZE];
+        BigEndian::write_u64(&mut header[..8], session.session_id);
+        BigEndian::write_u64(&mut header[8..], packet_id);
+
+        let skey = aead2022::make_skey(key, &header[..8]);
+        aead2022::seal_udp(t, &skey, &header[4..], &mut data);
+
+        // Responses of servers are encrypted with the user's key
+        let identity_headers = match session.identity_keys.first() {
+            Some(identity_key) if !session.is_server => {
+                let ids = aead2022::make_udp_identity_headers(t, &session.identity_keys, key, &header);
+                aead2022::encrypt_udp_header(t, identity_key, &mut header);
+                ids
+            }
+            _ => {
+                aead2022::encrypt_udp_header(t, key, &mut header);
+                Vec::new()
+            }
+        };
+
+        dst.reserve(header.len() + identity_headers.len() + data.len());
+        dst.put_slice(&header);
+        dst.put_slice(&identity_headers);
+    } else {
+        let nonce: [u8; aead2022::UDP_XCHACHA20_NONCE_SIZE] = rand::random();
+        aead2022::seal_udp(t, key, &nonce, &mut data);
+
+        dst.reserve(nonce.len() + data.len());
+        dst.put_slice(&nonce);
+    }
+    dst.put_slice(&data);
+
+    Ok(())
+}
+
 /// Decrypt payload from ShadowSocks UDP encrypted packet
-pub fn decrypt_payload(context: &Context, t: CipherType, key: &[u8], payload: &[u8]) -> io::Result<Option<Vec<u8>>> {
+///
+/// `svr_addr` is the address of the server, whose replay filter is used for the received nonce. `session` is only
+/// used by AEAD-2022 ciphers.
+pub fn decrypt_payload(
+    context: &Context,
+    svr_addr: &ServerAddr,
+    t: CipherType,
+    key: &[u8],
+    session: &UdpSession,
+    payload: &[u8],
+) -> io::Result<Option<Vec<u8>>> {
     match t.category() {
-        CipherCategory::Stream => decrypt_payload_stream(context, t, key, payload),
-        CipherCategory::Aead => decrypt_payload_aead(context, t, key, payload),
+        CipherCategory::Stream => decrypt_payload_stream(context, svr_addr, t, key, payload),
+        CipherCategory::Aead => decrypt_payload_aead(context, svr_addr, t, key, payload),
+        CipherCategory::Aead2022 => decrypt_payload_aead_2022(context, t, key, session, payload),
     }
 }
 
-fn decrypt_payload_stream(context: &Context, t: CipherType, key: &[u8], payload: &[u8]) -> io::Result<Option<Vec<u8>>> {
+fn decrypt_payload_stream(
+    context: &Context,
+    svr_addr: &ServerAddr,
+    t: CipherType,
+    key: &[u8],
+    payload: &[u8],
+) -> io::Result<Option<Vec<u8>>> {
     let iv_size = t.iv_size();
     if payload.len() < iv_size {
         return Ok(None);
     }
 
     let iv = &payload[..iv_size];
-    if context.check_nonce_and_set(iv) {
+    if context.check_nonce_and_set(svr_addr, iv) {
         use std::io::{Error, ErrorKind};
 
         debug!("Detected repeated iv {:?}", ByteStr::new(iv));
+        context.metrics().incr_repeated_nonces(Protocol::Udp);
 
         let err = Error::new(ErrorKind::Other, "detected repeated iv");
         return Err(err);
@@ -148,7 +323,13 @@ fn decrypt_payload_stream(context: &Context, t: CipherType, key: &[u8], payload:
     Ok(Some(recv_payload))
 }
 
-fn decrypt_payload_aead(context: &Context, t: CipherType, key: &[u8], payload: &[u8]) -> io::Result<Option<Vec<u8>>> {
+fn decrypt_payload_aead(
+    context: &Context,
+    svr_addr: &ServerAddr,
+    t: CipherType,
+    key: &[u8],
+    payload: &[u8],
+) -> io::Result<Option<Vec<u8>>> {
     let tag_size = t.tag_size();
     let salt_size = t.salt_size();
 
@@ -157,10 +338,11 @@ fn decrypt_payload_aead(context: &Context, t: CipherType, key: &[u8], payload: &
     }
 
     let salt = &payload[..salt_size];
-    if context.check_nonce_and_set(salt) {
+    if context.check_nonce_and_set(svr_addr, salt) {
         use std::io::{Error, ErrorKind};
 
         debug!("Detected repeated salt {:?}", ByteStr::new(salt));
+        context.metrics().incr_repeated_nonces(Protocol::Udp);
 
         let err = Error::new(ErrorKind::Other, "detected repeated salt");
         return Err(err);
@@ -175,7 +357,151 @@ fn decrypt_payload_aead(context: &Context, t: CipherType, key: &[u8], payload: &
     let mut cipher = crypto::new_aead_decryptor(t, key, salt);
 
     let mut recv_payload = vec![0u8; data_length];
-    cipher.decrypt(data, &mut recv_payload)?;
+    if let Err(err) = cipher.decrypt(data, &mut recv_payload) {
+        context.metrics().incr_decrypt_failures(Protocol::Udp);
+        return Err(err.into());
+    }
 
     Ok(Some(recv_payload))
 }
+
+fn decrypt_payload_aead_2022(
+    context: &Context,
+    t: CipherType,
+    key: &[u8],
+    session: &UdpSession,
+    payload: &[u8],
+) -> io::Result<Option<Vec<u8>>> {
+    let (session_id, packet_id, data) = if aead2022::has_udp_separate_header(t) {
+        if payload.len() < aead2022::UDP_SEPARATE_HEADER_SIZE + t.tag_size() {
+            return Ok(None);
+        }
+
+        let mut header = [0u8; aead2022::UDP_SEPARATE_HEADER_SIZE];
+        header.copy_from_slice(&payload[..aead2022::UDP_SEPARATE_HEADER_SIZE]);
+        let mut data = &payload[aead2022::UDP_SEPARATE_HEADER_SIZE..];
+
+        // Servers with identity keys read the identity header of the user's key
+        match session.identity_keys.first() {
+            Some(identity_key) if session.is_server => {
+                if data.len() < aead2022::IDENTITY_HEADER_SIZE + t.tag_size() {
+                    return Ok(None);
+                }
+
+                aead2022::decrypt_udp_header(t, identity_key, &mut header);
+                let hash = aead2022::decrypt_udp_identity_header(t, identity_key, &header, data);
+                if hash != aead2022::key_hash(key) {
+                    use std::io::{Error, ErrorKind};
+
+                    context.metrics().incr_decrypt_failures(Protocol::Udp);
+
+                    let err = Error::new(ErrorKind::InvalidData, "identity header mismatched");
+                    return Err(err);
+                }
+                data = &data[aead2022::IDENTITY_HEADER_SIZE..];
+            }
+            _ => aead2022::decrypt_udp_header(t, key, &mut header),
+        }
+
+        let skey = aead2022::make_skey(key, &header[..8]);
+        let mut data = data.to_vec();
+        if let Err(err) = aead2022::open_udp(t, &skey, &header[4..], &mut data) {
+            context.metrics().incr_decrypt_failures(Protocol::Udp);
+            return Err(err.into());
+        }
+
+        (
+            BigEndian::read_u64(&header[..8]),
+            BigEndian::read_u64(&header[8..]),
+            data,
+        )
+    } else {
+        if payload.len() < aead2022::UDP_XCHACHA20_NONCE_SIZE + 16 + t.tag_size() {
+            return Ok(None);
+        }
+
+        let (nonce, data) = payload.split_at(aead2022::UDP_XCHACHA20_NONCE_SIZE);
+        let mut data = data.to_vec();
+        if let Err(err) = aead2022::open_udp(t, key, nonce, &mut data) {
+            context.metrics().incr_decrypt_failures(Protocol::Udp);
+            return Err(err.into());
+        }
+
+        let header = data.drain(..16).collect::<Vec<u8>>();
+        (
+            BigEndian::read_u64(&header[..8]),
+            BigEndian::read_u64(&header[8..]),
+            data,
+        )
+    };
+
+    // Clients read packets of servers, which have the client's session ID
+    let (expected_type, header_len) = if session.is_server {
+        (HEADER_TYPE_CLIENT, 1 + 8 + 2)
+    } else {
+        (HEADER_TYPE_SERVER, 1 + 8 + 8 + 2)
+    };
+    if data.len() < header_len {
+        return Ok(None);
+    }
+
+    let mut cur = &data[..];
+    let header_type = cur.get_u8();
+    let timestamp = cur.get_u64();
+    if header_type != expected_type || !aead2022::check_timestamp(timestamp) {
+        use std::io::{Error, ErrorKind};
+
+        debug!(
+            "UDP packet of AEAD-2022 session {:016x} with invalid type {} or timestamp {}",
+            session_id, header_type, timestamp
+        );
+
+        let err = Error::new(ErrorKind::InvalidData, "invalid packet header");
+        return Err(err);
+    }
+    if !session.is_server {
+        let client_session_id = cur.get_u64();
+        if client_session_id != session.session_id {
+            use std::io::{Error, ErrorKind};
+
+            debug!(
+                "UDP packet of client session {:016x}, expecting {:016x}",
+                client_session_id, session.session_id
+            );
+
+            let err = Error::new(ErrorKind::InvalidData, "client session id mismatched");
+            return Err(err);
+        }
+    }
+
+    let padding_len = cur.get_u16() as usize;
+    if cur.len() < padding_len {
+        return Ok(None);
+    }
+    cur.advance(padding_len);
+
+    // Packets are authenticated, then check if they are replayed
+    if context.check_packet_and_set(session_id, packet_id) {
+        use std::io::{Error, ErrorKind};
+
+        debug!(
+            "Detected repeated packet id {} of session {:016x}",
+            packet_id, session_id
+        );
+        context.metrics().incr_repeated_nonces(Protocol::Udp);
+
+        let err = Error::new(ErrorKind::Other, "detected repeated packet id");
+        return Err(err);
+    }
+
+    // Responses are sent to the latest session of the peer
+    session.peer_session_id.store(session_id, Ordering::Relaxed);
+
+    trace!(
+        "UDP packet got AEAD-2022 session {:016x}, packet id {}",
+        session_id,
+        packet_id
+    );
+
+    Ok(Some(cur.to_vec()))
+}
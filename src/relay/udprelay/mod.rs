@@ -49,12 +49,13 @@
 
 use std::time::Duration;
 
+pub(crate) mod associate;
 pub mod client;
 pub mod local;
 pub mod server;
 pub(crate) mod socks5_local;
 pub(crate) mod tunnel_local;
-mod utils;
+pub(crate) mod utils;
 
 mod crypto_io;
 
@@ -8,30 +8,65 @@ use std::{
 };
 
 use bytes::BytesMut;
-use futures::{self, future, stream::FuturesUnordered, FutureExt, StreamExt};
-use log::{debug, error, info, trace};
+use futures::{
+    self,
+    future::{self, Either},
+    FutureExt,
+};
+use log::{debug, error, info, trace, warn};
 use lru_time_cache::{Entry, LruCache};
 use tokio::{
     self,
-    net::udp::{RecvHalf, SendHalf},
+    net::{
+        udp::{RecvHalf, SendHalf},
+        UdpSocket,
+    },
     sync::{mpsc, oneshot, Mutex},
     time,
 };
 
 use crate::{
-    config::ServerConfig,
+    config::{ProxyProtocol, ServerAddr, ServerConfig},
     context::{Context, SharedContext},
-    relay::{socks5::Address, utils::try_timeout},
+    crypto::{aead2022, CipherCategory},
+    relay::{
+        flow::{ServerFlowStatistic, SharedServerFlowStatistic},
+        limiter::LimitGuard,
+        metrics::{ActiveGuard, Protocol},
+        shaper::Shaper,
+        socks5::{Address, UdpAssociateHeader},
+        upstream::{self, Socks5UdpAssociation, DEFAULT_HANDSHAKE_TIMEOUT},
+        users::{SharedServerUsers, UserContext},
+        utils::try_timeout,
+    },
 };
 
 use super::{
-    crypto_io::{decrypt_payload, encrypt_payload},
+    crypto_io::{decrypt_payload, encrypt_payload, UdpSession},
     utils::create_socket,
     DEFAULT_TIMEOUT,
     MAXIMUM_UDP_PAYLOAD_SIZE,
 };
 
-struct UdpAssociationWatcher(oneshot::Sender<()>);
+// Interval of checking whether all associations are finished after the server is closed
+const CLOSED_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+struct UdpAssociationWatcher(oneshot::Sender<()>, ActiveGuard, LimitGuard);
+
+// Values of an association decided by the first packet of the client
+struct UdpAssociationParams {
+    // Traffic of the server
+    flow: SharedServerFlowStatistic,
+
+    // User of multi-user server
+    user: Option<Arc<UserContext>>,
+
+    // AEAD-2022 session that the first packet is opened with
+    session: Arc<UdpSession>,
+
+    // Slot in connection limits, released after the association is finished
+    limit_guard: LimitGuard,
+}
 
 // Represent a UDP association
 #[derive(Clone)]
@@ -42,6 +77,15 @@ struct UdpAssociation {
 
     // local <- remote task life watcher
     watcher: Arc<UdpAssociationWatcher>,
+
+    // User of multi-user server
+    user: Option<Arc<UserContext>>,
+
+    // Bandwidth shaper of local -> remote
+    upload: Shaper,
+
+    // AEAD-2022 session shared by both directions
+    session: Arc<UdpSession>,
 }
 
 impl UdpAssociation {
@@ -49,9 +93,17 @@ impl UdpAssociation {
     async fn associate(
         context: SharedContext,
         svr_cfg: Arc<ServerConfig>,
+        params: UdpAssociationParams,
         src_addr: SocketAddr,
         mut response_tx: mpsc::Sender<(SocketAddr, BytesMut)>,
     ) -> io::Result<UdpAssociation> {
+        let UdpAssociationParams {
+            flow,
+            user,
+            session,
+            limit_guard,
+        } = params;
+
         // Create a socket for receiving packets
         let local_addr = match context.config().local {
             None => {
@@ -63,6 +115,10 @@ impl UdpAssociation {
                 addr.bind_addr(&*context).await?
             }
         };
+
+        // Local address for connecting to the outbound proxy
+        let bind_addr = context.config().local.as_ref().map(|_| local_addr);
+
         let remote_udp = create_socket(&local_addr).await?;
 
         let local_addr = remote_udp.local_addr().expect("Could not determine port bound to");
@@ -75,22 +131,73 @@ impl UdpAssociation {
         // Create a watcher for local <- remote task
         let (watcher_tx, watcher_rx) = oneshot::channel::<()>();
 
-        let close_flag = Arc::new(UdpAssociationWatcher(watcher_tx));
+        // Relay address of the outbound proxy, sent to local <- remote task after handshaking
+        let (relay_tx, relay_rx) = oneshot::channel::<Option<SocketAddr>>();
+
+        let active_guard = context.metrics().port(svr_cfg.addr().port()).udp_association();
+        let close_flag = Arc::new(UdpAssociationWatcher(watcher_tx, active_guard, limit_guard));
 
         // Splits socket into sender and receiver
         let (mut receiver, mut sender) = remote_udp.split();
 
         let timeout = context.config().udp_timeout.unwrap_or(DEFAULT_TIMEOUT);
 
+        let (upload, download) = match context.bandwidth().server(&svr_cfg) {
+            Some(bandwidth) => bandwidth.shapers(),
+            None => (Shaper::default(), Shaper::default()),
+        };
+
         // local -> remote
         let c_svr_cfg = svr_cfg.clone();
         let c_context = context.clone();
+        let c_user = user.clone();
         tokio::spawn(async move {
+            // UDP ASSOCIATE lasts until its control connection is closed
+            let result = match c_user {
+                // Next hops are not connected through the outbound proxy
+                Some(ref user) if user.next_hop().is_some() => Ok(None),
+                _ => UdpAssociation::associate_proxy(&c_context, &c_svr_cfg, src_addr, &bind_addr).await,
+            };
+            let socks5_assoc = match result {
+                Ok(assoc) => assoc,
+                Err(err) => {
+                    error!("UDP ASSOCIATE {} -> .. refused, error: {}", src_addr, err);
+                    return;
+                }
+            };
+            let socks5_relay = socks5_assoc.as_ref().map(Socks5UdpAssociation::relay_addr);
+            let _ = relay_tx.send(socks5_relay);
+
             while let Some(pkt) = rx.recv().await {
-                // pkt is already a raw packet, so just send it
-                if let Err(err) =
-                    UdpAssociation::relay_l2r(&*c_context, src_addr, &mut sender, &pkt[..], timeout, &*c_svr_cfg).await
-                {
+                let result = match c_user {
+                    Some(ref user) if user.next_hop().is_some() => {
+                        UdpAssociation::relay_next_hop_l2r(
+                            &*c_context,
+                            src_addr,
+                            &mut sender,
+                            &pkt[..],
+                            timeout,
+                            &*c_svr_cfg,
+                            user,
+                        )
+                        .await
+                    }
+                    _ => {
+                        // pkt is already decrypted
+                        UdpAssociation::relay_l2r(
+                            &*c_context,
+                            src_addr,
+                            &mut sender,
+                            &pkt[..],
+                            timeout,
+                            &*c_svr_cfg,
+                            socks5_relay,
+                        )
+                        .await
+                    }
+                };
+
+                if let Err(err) = result {
                     error!("Failed to relay packet, {} -> ..., error: {}", src_addr, err);
 
                     // FIXME: Ignore? Or how to deal with it?
@@ -101,13 +208,48 @@ impl UdpAssociation {
         });
 
         // local <- remote
+        let c_user = user.clone();
+        let c_session = session.clone();
         tokio::spawn(async move {
             let transfer_fut = async move {
+                // Closed if the local -> remote task failed to associate with the outbound proxy
+                let socks5_relay = match relay_rx.await {
+                    Ok(relay_addr) => relay_addr,
+                    Err(..) => return,
+                };
+
                 loop {
                     // Read and send back to source
-                    match UdpAssociation::relay_r2l(&*context, src_addr, &mut receiver, &mut response_tx, &*svr_cfg)
-                        .await
-                    {
+                    let result = match c_user {
+                        Some(ref user) if user.next_hop().is_some() => {
+                            UdpAssociation::relay_next_hop_r2l(
+                                src_addr,
+                                &mut receiver,
+                                &mut response_tx,
+                                &*flow,
+                                user,
+                                &download,
+                            )
+                            .await
+                        }
+                        _ => {
+                            UdpAssociation::relay_r2l(
+                                &*context,
+                                src_addr,
+                                &mut receiver,
+                                &mut response_tx,
+                                &*svr_cfg,
+                                &*flow,
+                                c_user.as_deref(),
+                                &c_session,
+                                &download,
+                                socks5_relay,
+                            )
+                            .await
+                        }
+                    };
+
+                    match result {
                         Ok(..) => {}
                         Err(err) => {
                             error!("Failed to receive packet, {} <- .., error: {}", src_addr, err);
@@ -128,43 +270,114 @@ impl UdpAssociation {
         Ok(UdpAssociation {
             tx,
             watcher: close_flag,
+            user,
+            upload,
+            session,
         })
     }
 
-    /// Relay packets from local to remote
+    /// Associates with the outbound SOCKS5 proxy of the server
+    ///
+    /// Returns `None` if packets are sent directly. Associations are refused if the proxy couldn't relay UDP packets,
+    /// unless `outbound_proxy_udp_direct` is set.
+    async fn associate_proxy(
+        context: &Context,
+        svr_cfg: &ServerConfig,
+        src_addr: SocketAddr,
+        bind_addr: &Option<SocketAddr>,
+    ) -> io::Result<Option<Socks5UdpAssociation>> {
+        let proxy = match context.outbound_proxy(svr_cfg) {
+            Some(proxy) => proxy,
+            None => return Ok(None),
+        };
+
+        let result = if proxy.protocol == ProxyProtocol::Socks5 {
+            let timeout = svr_cfg.timeout().unwrap_or(DEFAULT_HANDSHAKE_TIMEOUT);
+            try_timeout(
+                Socks5UdpAssociation::associate(context, proxy, bind_addr),
+                Some(timeout),
+            )
+            .await
+        } else {
+            let err = io::Error::new(
+                io::ErrorKind::Other,
+                format!("proxy {} doesn't relay UDP packets", proxy.addr),
+            );
+            Err(err)
+        };
+
+        match result {
+            Ok(assoc) => Ok(Some(assoc)),
+            Err(err) if context.config().outbound_proxy_udp_direct => {
+                warn!(
+                    "UDP ASSOCIATE with proxy {} failed, relaying {} directly, error: {}",
+                    proxy.addr, src_addr, err
+                );
+                Ok(None)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Relay decrypted packets from local to remote
     async fn relay_l2r(
         context: &Context,
         src: SocketAddr,
         remote_udp: &mut SendHalf,
-        pkt: &[u8],
+        decrypted_pkt: &[u8],
         timeout: Duration,
         svr_cfg: &ServerConfig,
+        socks5_relay: Option<SocketAddr>,
     ) -> io::Result<()> {
-        // First of all, decrypt payload CLIENT -> SERVER
-        let decrypted_pkt = match decrypt_payload(context, svr_cfg.method(), svr_cfg.key(), pkt) {
-            Ok(Some(pkt)) => pkt,
-            Ok(None) => {
-                error!("Failed to decrypt pkt in UDP relay, packet too short");
-                let err = io::Error::new(io::ErrorKind::InvalidData, "packet too short");
-                return Err(err);
-            }
-            Err(err) => {
-                error!("Failed to decrypt pkt in UDP relay: {}", err);
-                let err = io::Error::new(io::ErrorKind::InvalidData, "decrypt failed");
-                return Err(err);
-            }
-        };
-
         // CLIENT -> SERVER protocol: ADDRESS + PAYLOAD
         let mut cur = Cursor::new(decrypted_pkt);
 
         let addr = Address::read_from(&mut cur).await?;
 
-        // Take out internal buffer for optimizing one byte copy
+        if !svr_cfg.port_policy().is_allowed(addr.port()) {
+            warn!("UDP ASSOCIATE {} -> {} refused, destination port is denied", src, addr);
+            context
+                .metrics()
+                .port(svr_cfg.addr().port())
+                .incr_denied_destinations(Protocol::Udp);
+            return Ok(());
+        }
+
         let header_len = cur.position() as usize;
-        let decrypted_pkt = cur.into_inner();
         let body = &decrypted_pkt[header_len..];
 
+        if let Address::SocketAddress(ref remote_addr) = addr {
+            if context.check_forbidden_ip(&remote_addr.ip()) {
+                debug!("UDP ASSOCIATE {} -> {}, which is forbidden", src, remote_addr);
+                let err = io::Error::new(
+                    io::ErrorKind::Other,
+                    format!("{} is forbidden, failed to send to {}", remote_addr.ip(), remote_addr),
+                );
+                return Err(err);
+            }
+        }
+
+        if let Some(relay_addr) = socks5_relay {
+            // Destinations are resolved by the proxy, unless resolved IPs should be checked
+            let addr = upstream::resolve_destination(context, &addr).await?;
+            let header = UdpAssociateHeader::new(0, addr);
+            let mut send_buf = BytesMut::with_capacity(header.serialized_len() + body.len());
+            header.write_to_buf(&mut send_buf);
+            send_buf.extend_from_slice(body);
+
+            debug!(
+                "UDP ASSOCIATE {} -> {} (proxy {}), payload length {} bytes",
+                src,
+                header.address,
+                relay_addr,
+                body.len()
+            );
+            let send_len = try_timeout(remote_udp.send_to(&send_buf, &relay_addr), Some(timeout)).await?;
+            assert_eq!(send_buf.len(), send_len);
+
+            return Ok(());
+        }
+
         let send_len = match addr {
             Address::SocketAddress(ref remote_addr) => {
                 debug!(
@@ -209,33 +422,95 @@ impl UdpAssociation {
     }
 
     /// Relay packets from remote to local
+    #[allow(clippy::too_many_arguments)]
     async fn relay_r2l(
         context: &Context,
         src_addr: SocketAddr,
         remote_udp: &mut RecvHalf,
         response_tx: &mut mpsc::Sender<(SocketAddr, BytesMut)>,
         svr_cfg: &ServerConfig,
+        flow: &ServerFlowStatistic,
+        user: Option<&UserContext>,
+        session: &UdpSession,
+        download: &Shaper,
+        socks5_relay: Option<SocketAddr>,
     ) -> io::Result<()> {
         // Waiting for response from server SERVER -> CLIENT
         // Packet length is limited by MAXIMUM_UDP_PAYLOAD_SIZE, excess bytes will be discarded.
         let mut remote_buf = [0u8; MAXIMUM_UDP_PAYLOAD_SIZE];
         let (remote_recv_len, remote_addr) = remote_udp.recv_from(&mut remote_buf).await?;
 
+        // FIXME: The Address should be the Address that client sent
+        let (addr, payload) = match socks5_relay {
+            Some(relay_addr) => {
+                if remote_addr != relay_addr {
+                    debug!(
+                        "UDP ASSOCIATE {} <- {}, not from proxy {}, throwing away packet {} bytes",
+                        src_addr, remote_addr, relay_addr, remote_recv_len
+                    );
+                    return Ok(());
+                }
+
+                // Packets relayed by the proxy start with the address of the remote
+                let mut cur = Cursor::new(&remote_buf[..remote_recv_len]);
+                let header = UdpAssociateHeader::read_from(&mut cur).await?;
+                let header_len = cur.position() as usize;
+                (header.address, &remote_buf[header_len..remote_recv_len])
+            }
+            None => (Address::SocketAddress(remote_addr), &remote_buf[..remote_recv_len]),
+        };
+
+        let user_exceeded = match user {
+            Some(u) => u.flow().quota_exceeded(),
+            None => false,
+        };
+        if flow.quota_exceeded() || user_exceeded {
+            debug!(
+                "UDP ASSOCIATE {} <- {}, traffic quota exceeded, throwing away packet {} bytes",
+                src_addr,
+                addr,
+                payload.len()
+            );
+            return Ok(());
+        }
+
         debug!(
             "UDP ASSOCIATE {} <- {}, payload length {} bytes",
-            src_addr, remote_addr, remote_recv_len
+            src_addr,
+            addr,
+            payload.len()
         );
 
-        // FIXME: The Address should be the Address that client sent
-        let addr = Address::SocketAddress(remote_addr);
-
         // CLIENT <- SERVER protocol: ADDRESS + PAYLOAD
         let mut send_buf = Vec::new();
         addr.write_to_buf(&mut send_buf);
-        send_buf.extend_from_slice(&remote_buf[..remote_recv_len]);
+        send_buf.extend_from_slice(payload);
 
+        let key = user.map_or(svr_cfg.key(), |u| u.key());
         let mut encrypt_buf = BytesMut::new();
-        encrypt_payload(context, svr_cfg.method(), svr_cfg.key(), &send_buf, &mut encrypt_buf)?;
+        encrypt_payload(
+            context,
+            svr_cfg.addr(),
+            svr_cfg.method(),
+            key,
+            session,
+            &send_buf,
+            &mut encrypt_buf,
+        )?;
+
+        if !download.try_consume(encrypt_buf.len()) {
+            debug!(
+                "UDP ASSOCIATE {} <- {}, bandwidth limit exceeded, throwing away packet {} bytes",
+                src_addr,
+                addr,
+                encrypt_buf.len()
+            );
+            return Ok(());
+        }
+
+        if let Some(user) = user {
+            user.flow().incr_tx(encrypt_buf.len());
+        }
 
         // Send back to src_addr
         if let Err(err) = response_tx.send((src_addr, encrypt_buf)).await {
@@ -247,24 +522,198 @@ impl UdpAssociation {
         Ok(())
     }
 
+    /// Relay packets of a user to its next hop
+    ///
+    /// The separate header is encrypted again with the identity key of the next hop, and the identity header of this
+    /// server is removed. Identity headers of `pkt` are already checked by `open_packet`.
+    async fn relay_next_hop_l2r(
+        context: &Context,
+        src: SocketAddr,
+        remote_udp: &mut SendHalf,
+        pkt: &[u8],
+        timeout: Duration,
+        svr_cfg: &ServerConfig,
+        user: &UserContext,
+    ) -> io::Result<()> {
+        const HEADERS_SIZE: usize = aead2022::UDP_SEPARATE_HEADER_SIZE + aead2022::IDENTITY_HEADER_SIZE;
+
+        let method = svr_cfg.method();
+        let mut header = pkt[..aead2022::UDP_SEPARATE_HEADER_SIZE].to_vec();
+        aead2022::decrypt_udp_header(method, svr_cfg.key(), &mut header);
+        aead2022::encrypt_udp_header(method, user.key(), &mut header);
+
+        let mut send_buf = Vec::with_capacity(pkt.len() - aead2022::IDENTITY_HEADER_SIZE);
+        send_buf.extend_from_slice(&header);
+        send_buf.extend_from_slice(&pkt[HEADERS_SIZE..]);
+
+        let next_hop = user.next_hop().expect("relayed user without next hop");
+        debug!(
+            "UDP ASSOCIATE {} -> next hop {}, packet length {} bytes",
+            src,
+            next_hop,
+            send_buf.len()
+        );
+
+        let send_len = match *next_hop {
+            ServerAddr::SocketAddr(ref addr) => try_timeout(remote_udp.send_to(&send_buf, addr), Some(timeout)).await?,
+            ServerAddr::DomainName(ref dname, port) => lookup_then!(context, dname, port, false, |addr| {
+                try_timeout(remote_udp.send_to(&send_buf, &addr), Some(timeout)).await
+            })
+            .map(|(_, l)| l)?,
+        };
+        assert_eq!(send_buf.len(), send_len);
+
+        Ok(())
+    }
+
+    /// Relay packets of the next hop back to the user, as is
+    async fn relay_next_hop_r2l(
+        src_addr: SocketAddr,
+        remote_udp: &mut RecvHalf,
+        response_tx: &mut mpsc::Sender<(SocketAddr, BytesMut)>,
+        flow: &ServerFlowStatistic,
+        user: &UserContext,
+        download: &Shaper,
+    ) -> io::Result<()> {
+        let mut remote_buf = [0u8; MAXIMUM_UDP_PAYLOAD_SIZE];
+        let (remote_recv_len, remote_addr) = remote_udp.recv_from(&mut remote_buf).await?;
+
+        if flow.quota_exceeded() || user.flow().quota_exceeded() {
+            debug!(
+                "UDP ASSOCIATE {} <- next hop {}, traffic quota exceeded, throwing away packet {} bytes",
+                src_addr, remote_addr, remote_recv_len
+            );
+            return Ok(());
+        }
+
+        if !download.try_consume(remote_recv_len) {
+            debug!(
+                "UDP ASSOCIATE {} <- next hop {}, bandwidth limit exceeded, throwing away packet {} bytes",
+                src_addr, remote_addr, remote_recv_len
+            );
+            return Ok(());
+        }
+
+        debug!(
+            "UDP ASSOCIATE {} <- next hop {}, packet length {} bytes",
+            src_addr, remote_addr, remote_recv_len
+        );
+
+        user.flow().incr_tx(remote_recv_len);
+
+        let pkt = BytesMut::from(&remote_buf[..remote_recv_len]);
+        if let Err(err) = response_tx.send((src_addr, pkt)).await {
+            error!("Failed to send packet into response channel, error: {}", err);
+        }
+
+        Ok(())
+    }
+
     // Send packet to remote
     //
-    // Return `Err` if receiver have been closed
-    async fn send(&mut self, pkt: Vec<u8>) {
-        if let Err(..) = self.tx.send(pkt).await {
-            // SHOULDn't HAPPEN
-            unreachable!("UDP Association local -> remote Queue closed unexpectly");
+    // Return `false` if receiver have been closed, which happens if it failed to associate with the outbound proxy
+    async fn send(&mut self, pkt: Vec<u8>) -> bool {
+        self.tx.send(pkt).await.is_ok()
+    }
+}
+
+// Creates the AEAD-2022 session of an association, users are identified by the server's identity key
+fn new_session(svr_cfg: &ServerConfig, user: Option<&UserContext>) -> Arc<UdpSession> {
+    match user {
+        Some(..) if svr_cfg.method().category() == CipherCategory::Aead2022 => {
+            Arc::new(UdpSession::with_identity_keys(true, vec![svr_cfg.clone_key()]))
         }
+        _ => Arc::new(UdpSession::new(true)),
     }
 }
 
-async fn listen(context: SharedContext, svr_cfg: Arc<ServerConfig>) -> io::Result<()> {
-    let listen_addr = svr_cfg.addr().bind_addr(&*context).await?;
+// Opens a packet from the client of an association, so only packets from the client are relayed and counted
+//
+// Packets are decrypted, except those relayed to next hops, which are kept as is after their identity headers are
+// checked.
+fn open_packet(
+    context: &Context,
+    svr_cfg: &ServerConfig,
+    user: Option<&UserContext>,
+    session: &UdpSession,
+    pkt: &[u8],
+) -> io::Result<Vec<u8>> {
+    if let Some(user) = user.filter(|u| u.next_hop().is_some()) {
+        const HEADERS_SIZE: usize = aead2022::UDP_SEPARATE_HEADER_SIZE + aead2022::IDENTITY_HEADER_SIZE;
+        if pkt.len() < HEADERS_SIZE {
+            let err = io::Error::new(io::ErrorKind::InvalidData, "packet too short");
+            return Err(err);
+        }
+
+        let method = svr_cfg.method();
+        let (header, identity_header) = pkt[..HEADERS_SIZE].split_at(aead2022::UDP_SEPARATE_HEADER_SIZE);
+        let mut header = header.to_vec();
+        aead2022::decrypt_udp_header(method, svr_cfg.key(), &mut header);
+
+        // Packets of associations are all from the identified user
+        let hash = aead2022::decrypt_udp_identity_header(method, svr_cfg.key(), &header, identity_header);
+        if hash != aead2022::key_hash(user.key()) {
+            context.metrics().incr_decrypt_failures(Protocol::Udp);
+            let err = io::Error::new(io::ErrorKind::InvalidData, "identity header mismatched");
+            return Err(err);
+        }
 
-    let listener = create_socket(&listen_addr).await?;
+        return Ok(pkt.to_vec());
+    }
+
+    let key = user.map_or(svr_cfg.key(), |u| u.key());
+    match decrypt_payload(context, svr_cfg.addr(), svr_cfg.method(), key, session, pkt)? {
+        Some(pkt) => Ok(pkt),
+        None => {
+            let err = io::Error::new(io::ErrorKind::InvalidData, "packet too short");
+            Err(err)
+        }
+    }
+}
+
+type AssociationMap = LruCache<String, UdpAssociation>;
+
+// Associations hold senders of the response channel, so the response task (and the socket it holds) won't finish
+// until all associations are dropped
+struct AssociationMapGuard(Arc<Mutex<AssociationMap>>);
+
+impl Drop for AssociationMapGuard {
+    fn drop(&mut self) {
+        if let Ok(mut assoc_map) = self.0.try_lock() {
+            assoc_map.clear();
+        }
+    }
+}
+
+/// Creates the listening socket of a server
+pub(crate) async fn create_listener(context: &Context, svr_cfg: &ServerConfig) -> io::Result<UdpSocket> {
+    // Plugins relaying UDP forward packets to their local port, which is bound while launching
+    let listener = match svr_cfg.plugin_listeners().and_then(|l| l.udp_socket()) {
+        Some(listener) => listener?,
+        None => {
+            let listen_addr = svr_cfg.udp_plugin_addr().unwrap_or_else(|| svr_cfg.addr());
+            let listen_addr = listen_addr.bind_addr(context).await?;
+            create_socket(&listen_addr).await?
+        }
+    };
     let local_addr = listener.local_addr().expect("Could not determine port bound to");
     info!("ShadowSocks UDP listening on {}", local_addr);
 
+    Ok(listener)
+}
+
+/// Relays packets received from `listener` with `svr_cfg`
+///
+/// After `close_rx` is notified, packets of new associations are thrown away, and it returns after all existing
+/// associations are finished.
+pub(crate) async fn serve(
+    context: SharedContext,
+    svr_cfg: Arc<ServerConfig>,
+    flow: SharedServerFlowStatistic,
+    users: Option<SharedServerUsers>,
+    listener: UdpSocket,
+    close_rx: oneshot::Receiver<()>,
+) -> io::Result<()> {
     let (mut r, mut w) = listener.split();
 
     // NOTE: Associations are only eliminated by expire time
@@ -272,11 +721,17 @@ async fn listen(context: SharedContext, svr_cfg: Arc<ServerConfig>) -> io::Resul
     let timeout = context.config().udp_timeout.unwrap_or(DEFAULT_TIMEOUT);
     let assoc_map = Arc::new(Mutex::new(LruCache::with_expiry_duration(timeout)));
     let assoc_map_cloned = assoc_map.clone();
+    let _assoc_map_guard = AssociationMapGuard(assoc_map.clone());
 
     // FIXME: Channel size 1024?
     let (tx, mut rx) = mpsc::channel::<(SocketAddr, BytesMut)>(1024);
+    let flow_cloned = flow.clone();
+    let metrics = context.metrics().port(svr_cfg.addr().port());
+    let metrics_cloned = metrics.clone();
     tokio::spawn(async move {
         let assoc_map = assoc_map_cloned;
+        let flow = flow_cloned;
+        let metrics = metrics_cloned;
 
         while let Some((src, pkt)) = rx.recv().await {
             let cache_key = src.to_string();
@@ -294,9 +749,15 @@ async fn listen(context: SharedContext, svr_cfg: Arc<ServerConfig>) -> io::Resul
                 }
             }
 
-            if let Err(err) = w.send_to(&pkt, &src).await {
-                error!("UDP packet send failed, err: {:?}", err);
-                break;
+            match w.send_to(&pkt, &src).await {
+                Ok(n) => {
+                    flow.incr_tx(n);
+                    metrics.incr_bytes_down(n);
+                }
+                Err(err) => {
+                    error!("UDP packet send failed, err: {:?}", err);
+                    break;
+                }
             }
         }
 
@@ -304,9 +765,33 @@ async fn listen(context: SharedContext, svr_cfg: Arc<ServerConfig>) -> io::Resul
     });
 
     let mut pkt_buf = [0u8; MAXIMUM_UDP_PAYLOAD_SIZE];
+    let mut close_rx = Some(close_rx);
 
     loop {
-        let (recv_len, src) = match time::timeout(timeout, r.recv_from(&mut pkt_buf)).await {
+        let closed = close_rx.is_none();
+        if closed && assoc_map.lock().await.is_empty() {
+            debug!("UDP server {} closed, all associations are finished", svr_cfg.addr());
+            return Ok(());
+        }
+
+        let check_interval = if closed { CLOSED_CHECK_INTERVAL } else { timeout };
+        let recv_fut = time::timeout(check_interval, r.recv_from(&mut pkt_buf));
+        let received = match close_rx {
+            Some(ref mut rx) => match future::select(recv_fut.boxed(), rx).await {
+                Either::Left((received, ..)) => received,
+                Either::Right(..) => {
+                    debug!(
+                        "UDP server {} is closing, stop accepting new associations",
+                        svr_cfg.addr()
+                    );
+                    close_rx = None;
+                    continue;
+                }
+            },
+            None => recv_fut.await,
+        };
+
+        let (recv_len, src) = match received {
             Ok(r) => r?,
             Err(..) => {
                 // Cleanup expired association
@@ -333,48 +818,147 @@ async fn listen(context: SharedContext, svr_cfg: Arc<ServerConfig>) -> io::Resul
             continue;
         }
 
-        // Check or (re)create an association
-        let mut assoc = {
+        if flow.quota_exceeded() {
+            debug!(
+                "Traffic quota of server {} exceeded, throwing away packet from {}, length {} bytes",
+                svr_cfg.addr(),
+                src,
+                recv_len
+            );
+            continue;
+        }
+
+        // Check or (re)create an association, the first packet of new associations is opened while creating
+        let (mut assoc, opened_pkt) = {
             // Locks the whole association map
             let mut assoc_map = assoc_map.lock().await;
 
             // Get or create an association
-            let assoc = match assoc_map.entry(src.to_string()) {
-                Entry::Occupied(oc) => oc.into_mut(),
-                Entry::Vacant(vc) => vc.insert(
-                    UdpAssociation::associate(context.clone(), svr_cfg.clone(), src, tx.clone())
-                        .await
-                        .expect("Failed to create udp association"),
-                ),
+            let (assoc, opened_pkt) = match assoc_map.entry(src.to_string()) {
+                Entry::Occupied(oc) => (oc.into_mut(), None),
+                Entry::Vacant(..) if closed || context.server_draining() => {
+                    debug!(
+                        "Server is closing, throwing away packet from {}, length {} bytes",
+                        src, recv_len
+                    );
+                    continue;
+                }
+                Entry::Vacant(vc) => {
+                    // Users of multi-user server are identified by the first packet of associations
+                    let user = match users {
+                        Some(ref users) => match users.identify_packet(src.ip(), pkt) {
+                            Some(user) => Some(user),
+                            None => {
+                                debug!("No user matches packet from {}, throwing away {} bytes", src, recv_len);
+                                context.metrics().incr_decrypt_failures(Protocol::Udp);
+                                continue;
+                            }
+                        },
+                        None => None,
+                    };
+
+                    // Associations are only created for the client, so others couldn't take its slots in limits
+                    let session = new_session(&svr_cfg, user.as_deref());
+                    let opened_pkt = match open_packet(&context, &svr_cfg, user.as_deref(), &session, pkt) {
+                        Ok(pkt) => pkt,
+                        Err(err) => {
+                            debug!(
+                                "Failed to open packet from {}, throwing away {} bytes, error: {}",
+                                src, recv_len, err
+                            );
+                            continue;
+                        }
+                    };
+
+                    // Packets from plugins are all from the plugin's address, not limited
+                    let peer_ip = match svr_cfg.udp_plugin_addr() {
+                        Some(..) => None,
+                        None => Some(src.ip()),
+                    };
+                    let limit_guard = match context.limiter().acquire_udp(peer_ip) {
+                        Ok(guard) => guard,
+                        Err(limit) => {
+                            warn!("UDP association from {} rejected, reached limit {}", src, limit);
+                            metrics.incr_rejected(limit);
+                            continue;
+                        }
+                    };
+
+                    // Handshakes with the outbound proxy are done in the spawned task, packets are queued until then
+                    let params = UdpAssociationParams {
+                        flow: flow.clone(),
+                        user,
+                        session,
+                        limit_guard,
+                    };
+                    let result =
+                        UdpAssociation::associate(context.clone(), svr_cfg.clone(), params, src, tx.clone()).await;
+                    let assoc = match result {
+                        Ok(assoc) => assoc,
+                        Err(err) => {
+                            error!(
+                                "Failed to create UDP association for {}, throwing away packet {} bytes, error: {}",
+                                src, recv_len, err
+                            );
+                            continue;
+                        }
+                    };
+                    (vc.insert(assoc), Some(opened_pkt))
+                }
             };
 
             // Clone the handle and release the lock.
             // Make sure we keep the critical section small
-            assoc.clone()
+            (assoc.clone(), opened_pkt)
         };
 
-        // Send to local -> remote task
-        assoc.send(pkt.to_vec()).await;
-    }
-}
+        // Only packets from the client are counted, so others couldn't use up its traffic quota
+        let opened_pkt = match opened_pkt {
+            Some(pkt) => pkt,
+            None => match open_packet(&context, &svr_cfg, assoc.user.as_deref(), &assoc.session, pkt) {
+                Ok(pkt) => pkt,
+                Err(err) => {
+                    debug!(
+                        "Failed to open packet from {}, throwing away {} bytes, error: {}",
+                        src, recv_len, err
+                    );
+                    continue;
+                }
+            },
+        };
 
-/// Starts a UDP relay server
-pub async fn run(context: SharedContext) -> io::Result<()> {
-    let vec_fut = FuturesUnordered::new();
+        if let Some(ref user) = assoc.user {
+            if user.flow().quota_exceeded() {
+                debug!(
+                    "Traffic quota of user {} exceeded, throwing away packet from {}, length {} bytes",
+                    user.name(),
+                    src,
+                    recv_len
+                );
+                continue;
+            }
 
-    for svr in &context.config().server {
-        let svr_cfg = Arc::new(svr.clone());
+            user.flow().incr_rx(recv_len);
+        }
 
-        let svr_fut = listen(context.clone(), svr_cfg);
-        vec_fut.push(svr_fut);
-    }
+        flow.incr_rx(recv_len);
+        metrics.incr_bytes_up(recv_len);
 
-    match vec_fut.into_future().await.0 {
-        Some(res) => {
-            error!("One of UDP servers exited unexpectly, result: {:?}", res);
-            let err = io::Error::new(io::ErrorKind::Other, "server exited unexpectly");
-            Err(err)
+        if !assoc.upload.try_consume(recv_len) {
+            debug!(
+                "UDP ASSOCIATE {} -> .., bandwidth limit exceeded, throwing away packet {} bytes",
+                src, recv_len
+            );
+            continue;
+        }
+
+        // Send to local -> remote task
+        if !assoc.send(opened_pkt).await {
+            debug!(
+                "UDP association {} <-> ... is closed, throwing away packet {} bytes",
+                src, recv_len
+            );
+            assoc_map.lock().await.remove(&src.to_string());
         }
-        None => unreachable!(),
     }
 }
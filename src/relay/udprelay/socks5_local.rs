@@ -26,13 +26,15 @@ use crate::{
     context::{Context, SharedContext},
     relay::{
         loadbalancing::server::{LoadBalancer, PingBalancer, PingServer, PingServerType},
+        metrics::ActiveGuard,
+        shaper::Shaper,
         socks5::{Address, UdpAssociateHeader},
         utils::try_timeout,
     },
 };
 
 use super::{
-    crypto_io::{decrypt_payload, encrypt_payload},
+    crypto_io::{decrypt_payload, encrypt_payload, UdpSession},
     utils::create_socket,
     DEFAULT_TIMEOUT,
     MAXIMUM_UDP_PAYLOAD_SIZE,
@@ -59,7 +61,7 @@ async fn parse_packet(pkt: &[u8]) -> io::Result<(Address, Vec<u8>)> {
     Ok((addr, payload))
 }
 
-struct UdpAssociationWatcher(oneshot::Sender<()>);
+struct UdpAssociationWatcher(oneshot::Sender<()>, ActiveGuard);
 
 // Represent a UDP association
 #[derive(Clone)]
@@ -94,23 +96,45 @@ impl UdpAssociation {
         // Create a watcher for local <- remote task
         let (watcher_tx, watcher_rx) = oneshot::channel::<()>();
 
-        let close_flag = Arc::new(UdpAssociationWatcher(watcher_tx));
+        let local_port = context.config().local.as_ref().map(ServerAddr::port).unwrap_or(0);
+        let active_guard = context.metrics().port(local_port).udp_association();
+        let close_flag = Arc::new(UdpAssociationWatcher(watcher_tx, active_guard));
 
         // Splits socket into sender and receiver
         let (mut receiver, mut sender) = remote_udp.split();
 
         let timeout = context.config().udp_timeout.unwrap_or(DEFAULT_TIMEOUT);
 
+        // Sending to the server is upload
+        let (upload, download) = match context.bandwidth().server(svr_cfg.server_config()) {
+            Some(bandwidth) => bandwidth.shapers(),
+            None => (Shaper::default(), Shaper::default()),
+        };
+
+        // Both directions share the AEAD-2022 session
+        let identity_keys = svr_cfg.server_config().identity_keys().to_vec();
+        let session = Arc::new(UdpSession::with_identity_keys(false, identity_keys));
+
         // local -> remote
         let c_svr_cfg = svr_cfg.clone();
         let c_context = context.clone();
+        let c_session = session.clone();
         tokio::spawn(async move {
             let svr_cfg = c_svr_cfg.server_config();
 
             while let Some(pkt) = rx.recv().await {
                 // pkt is already a raw packet, so just send it
-                if let Err(err) =
-                    UdpAssociation::relay_l2r(&*c_context, src_addr, &mut sender, &pkt[..], timeout, svr_cfg).await
+                if let Err(err) = UdpAssociation::relay_l2r(
+                    &*c_context,
+                    src_addr,
+                    &mut sender,
+                    &pkt[..],
+                    timeout,
+                    svr_cfg,
+                    &c_session,
+                    &upload,
+                )
+                .await
                 {
                     error!("Failed to send packet {} -> ..., error: {}", src_addr, err);
 
@@ -128,7 +152,16 @@ impl UdpAssociation {
 
                 loop {
                     // Read and send back to source
-                    match UdpAssociation::relay_r2l(&*context, src_addr, &mut receiver, &mut response_tx, svr_cfg).await
+                    match UdpAssociation::relay_r2l(
+                        &*context,
+                        src_addr,
+                        &mut receiver,
+                        &mut response_tx,
+                        svr_cfg,
+                        &session,
+                        &download,
+                    )
+                    .await
                     {
                         Ok(..) => {}
                         Err(err) => {
@@ -154,6 +187,7 @@ impl UdpAssociation {
     }
 
     /// Relay packets from local to remote
+    #[allow(clippy::too_many_arguments)]
     async fn relay_l2r(
         context: &Context,
         src: SocketAddr,
@@ -161,6 +195,8 @@ impl UdpAssociation {
         pkt: &[u8],
         timeout: Duration,
         svr_cfg: &ServerConfig,
+        session: &UdpSession,
+        upload: &Shaper,
     ) -> io::Result<()> {
         let (addr, payload) = parse_packet(&pkt).await?;
 
@@ -177,9 +213,28 @@ impl UdpAssociation {
         send_buf.extend_from_slice(&payload);
 
         let mut encrypt_buf = BytesMut::new();
-        encrypt_payload(context, svr_cfg.method(), svr_cfg.key(), &send_buf, &mut encrypt_buf)?;
+        encrypt_payload(
+            context,
+            svr_cfg.addr(),
+            svr_cfg.method(),
+            svr_cfg.key(),
+            session,
+            &send_buf,
+            &mut encrypt_buf,
+        )?;
+
+        if !upload.try_consume(encrypt_buf.len()) {
+            debug!(
+                "UDP ASSOCIATE {} -> {}, bandwidth limit exceeded, throwing away packet {} bytes",
+                src,
+                addr,
+                encrypt_buf.len()
+            );
+            return Ok(());
+        }
 
-        let send_len = match svr_cfg.addr() {
+        // Plugins relaying UDP send packets to the server by themselves
+        let send_len = match svr_cfg.udp_plugin_addr().unwrap_or_else(|| svr_cfg.addr()) {
             ServerAddr::SocketAddr(ref remote_addr) => {
                 try_timeout(remote_udp.send_to(&encrypt_buf[..], remote_addr), Some(timeout)).await?
             }
@@ -201,13 +256,30 @@ impl UdpAssociation {
         remote_udp: &mut RecvHalf,
         response_tx: &mut mpsc::Sender<(SocketAddr, Vec<u8>)>,
         svr_cfg: &ServerConfig,
+        session: &UdpSession,
+        download: &Shaper,
     ) -> io::Result<()> {
         // Waiting for response from server SERVER -> CLIENT
         // Packet length is limited by MAXIMUM_UDP_PAYLOAD_SIZE, excess bytes will be discarded.
         let mut recv_buf = [0u8; MAXIMUM_UDP_PAYLOAD_SIZE];
         let (recv_n, remote_addr) = remote_udp.recv_from(&mut recv_buf).await?;
 
-        let decrypt_buf = match decrypt_payload(context, svr_cfg.method(), svr_cfg.key(), &recv_buf[..recv_n])? {
+        if !download.try_consume(recv_n) {
+            debug!(
+                "UDP ASSOCIATE {} <- {}, bandwidth limit exceeded, throwing away packet {} bytes",
+                src_addr, remote_addr, recv_n
+            );
+            return Ok(());
+        }
+
+        let decrypt_buf = match decrypt_payload(
+            context,
+            svr_cfg.addr(),
+            svr_cfg.method(),
+            svr_cfg.key(),
+            session,
+            &recv_buf[..recv_n],
+        )? {
             None => {
                 error!("UDP packet too short, received length {}", recv_n);
                 let err = io::Error::new(io::ErrorKind::InvalidData, "packet too short");
@@ -366,6 +438,15 @@ pub async fn run(context: SharedContext) -> io::Result<()> {
             continue;
         }
 
+        // Clients must authenticate with a TCP connection before sending datagrams if `socks5_users` is set
+        if !context.config().socks5_users.is_empty() && !context.udp_associate_clients().contains(&src.ip()) {
+            debug!(
+                "UDP packet from {} without an authenticated UDP ASSOCIATE session, throwing away {} bytes",
+                src, recv_len
+            );
+            continue;
+        }
+
         // Check or (re)create an association
         let mut assoc = {
             // Locks the whole association map
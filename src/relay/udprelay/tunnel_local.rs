@@ -26,20 +26,22 @@ use crate::{
     context::{Context, SharedContext},
     relay::{
         loadbalancing::server::{LoadBalancer, PingBalancer, PingServer, PingServerType},
+        metrics::ActiveGuard,
+        shaper::Shaper,
         socks5::Address,
         utils::try_timeout,
     },
 };
 
 use super::{
-    crypto_io::{decrypt_payload, encrypt_payload},
+    crypto_io::{decrypt_payload, encrypt_payload, UdpSession},
     utils::create_socket,
     DEFAULT_TIMEOUT,
     MAXIMUM_UDP_PAYLOAD_SIZE,
 };
 
 // Drop the oneshot::Sender<()> will trigger local <- remote task to finish
-struct UdpAssociationWatcher(oneshot::Sender<()>);
+struct UdpAssociationWatcher(oneshot::Sender<()>, ActiveGuard);
 
 // Represent a UDP association
 #[derive(Clone)]
@@ -74,23 +76,45 @@ impl UdpAssociation {
         // Create a watcher for local <- remote task
         let (watcher_tx, watcher_rx) = oneshot::channel::<()>();
 
-        let close_flag = Arc::new(UdpAssociationWatcher(watcher_tx));
+        let local_port = context.config().local.as_ref().map(ServerAddr::port).unwrap_or(0);
+        let active_guard = context.metrics().port(local_port).udp_association();
+        let close_flag = Arc::new(UdpAssociationWatcher(watcher_tx, active_guard));
 
         // Splits socket into sender and receiver
         let (mut receiver, mut sender) = remote_udp.split();
 
         let timeout = context.config().udp_timeout.unwrap_or(DEFAULT_TIMEOUT);
 
+        // Sending to the server is upload
+        let (upload, download) = match context.bandwidth().server(svr_cfg.server_config()) {
+            Some(bandwidth) => bandwidth.shapers(),
+            None => (Shaper::default(), Shaper::default()),
+        };
+
+        // Both directions share the AEAD-2022 session
+        let identity_keys = svr_cfg.server_config().identity_keys().to_vec();
+        let session = Arc::new(UdpSession::with_identity_keys(false, identity_keys));
+
         // local -> remote
         let c_svr_cfg = svr_cfg.clone();
         let c_context = context.clone();
+        let c_session = session.clone();
         tokio::spawn(async move {
             let svr_cfg = c_svr_cfg.server_config();
 
             while let Some(pkt) = rx.recv().await {
                 // pkt is already a raw packet, so just send it
-                if let Err(err) =
-                    UdpAssociation::relay_l2r(&*c_context, src_addr, &mut sender, &pkt[..], timeout, svr_cfg).await
+                if let Err(err) = UdpAssociation::relay_l2r(
+                    &*c_context,
+                    src_addr,
+                    &mut sender,
+                    &pkt[..],
+                    timeout,
+                    svr_cfg,
+                    &c_session,
+                    &upload,
+                )
+                .await
                 {
                     error!("failed to send packet {} -> ..., error: {}", src_addr, err);
 
@@ -108,7 +132,16 @@ impl UdpAssociation {
             let transfer_fut = async move {
                 loop {
                     // Read and send back to source
-                    match UdpAssociation::relay_r2l(&*context, src_addr, &mut receiver, &mut response_tx, svr_cfg).await
+                    match UdpAssociation::relay_r2l(
+                        &*context,
+                        src_addr,
+                        &mut receiver,
+                        &mut response_tx,
+                        svr_cfg,
+                        &session,
+                        &download,
+                    )
+                    .await
                     {
                         Ok(..) => {}
                         Err(err) => {
@@ -134,6 +167,7 @@ impl UdpAssociation {
     }
 
     /// Relay packets from local to remote
+    #[allow(clippy::too_many_arguments)]
     async fn relay_l2r(
         context: &Context,
         src: SocketAddr,
@@ -141,6 +175,8 @@ impl UdpAssociation {
         payload: &[u8],
         timeout: Duration,
         svr_cfg: &ServerConfig,
+        session: &UdpSession,
+        upload: &Shaper,
     ) -> io::Result<()> {
         let addr = context.config().forward.as_ref().unwrap();
 
@@ -152,9 +188,28 @@ impl UdpAssociation {
         send_buf.extend_from_slice(payload);
 
         let mut encrypt_buf = BytesMut::new();
-        encrypt_payload(context, svr_cfg.method(), svr_cfg.key(), &send_buf, &mut encrypt_buf)?;
+        encrypt_payload(
+            context,
+            svr_cfg.addr(),
+            svr_cfg.method(),
+            svr_cfg.key(),
+            session,
+            &send_buf,
+            &mut encrypt_buf,
+        )?;
+
+        if !upload.try_consume(encrypt_buf.len()) {
+            debug!(
+                "UDP TUNNEL {} -> {}, bandwidth limit exceeded, throwing away packet {} bytes",
+                src,
+                addr,
+                encrypt_buf.len()
+            );
+            return Ok(());
+        }
 
-        let send_len = match svr_cfg.addr() {
+        // Plugins relaying UDP send packets to the server by themselves
+        let send_len = match svr_cfg.udp_plugin_addr().unwrap_or_else(|| svr_cfg.addr()) {
             ServerAddr::SocketAddr(ref remote_addr) => {
                 try_timeout(remote_udp.send_to(&encrypt_buf[..], remote_addr), Some(timeout)).await?
             }
@@ -176,6 +231,8 @@ impl UdpAssociation {
         remote_udp: &mut RecvHalf,
         response_tx: &mut mpsc::Sender<(SocketAddr, Vec<u8>)>,
         svr_cfg: &ServerConfig,
+        session: &UdpSession,
+        download: &Shaper,
     ) -> io::Result<()> {
         // Waiting for response from server SERVER -> CLIENT
         // Packet length is limited by MAXIMUM_UDP_PAYLOAD_SIZE, excess bytes will be discarded.
@@ -183,7 +240,22 @@ impl UdpAssociation {
 
         let (recv_n, remote_addr) = remote_udp.recv_from(&mut recv_buf).await?;
 
-        let decrypt_buf = match decrypt_payload(context, svr_cfg.method(), svr_cfg.key(), &recv_buf[..recv_n])? {
+        if !download.try_consume(recv_n) {
+            debug!(
+                "UDP TUNNEL {} <- {}, bandwidth limit exceeded, throwing away packet {} bytes",
+                src_addr, remote_addr, recv_n
+            );
+            return Ok(());
+        }
+
+        let decrypt_buf = match decrypt_payload(
+            context,
+            svr_cfg.addr(),
+            svr_cfg.method(),
+            svr_cfg.key(),
+            session,
+            &recv_buf[..recv_n],
+        )? {
             None => {
                 error!("UDP packet too short, received length {}", recv_n);
                 let err = io::Error::new(io::ErrorKind::InvalidData, "packet too short");
@@ -1,6 +1,9 @@
 //! Utilities for UDP relay
 
-use std::{io, net::SocketAddr};
+use std::{
+    io,
+    net::{SocketAddr, UdpSocket as StdUdpSocket},
+};
 
 use tokio::net::UdpSocket;
 
@@ -12,6 +15,20 @@ pub async fn create_socket(addr: &SocketAddr) -> io::Result<UdpSocket> {
 
 #[cfg(windows)]
 pub async fn create_socket(addr: &SocketAddr) -> io::Result<UdpSocket> {
+    UdpSocket::from_std(create_std_socket(addr)?)
+}
+
+/// Creates a socket bound to `addr`, which could be shared by `try_clone`
+#[cfg(not(windows))]
+pub fn create_std_socket(addr: &SocketAddr) -> io::Result<StdUdpSocket> {
+    let socket = StdUdpSocket::bind(addr)?;
+    socket.set_nonblocking(true)?;
+    Ok(socket)
+}
+
+/// Creates a socket bound to `addr`, which could be shared by `try_clone`
+#[cfg(windows)]
+pub fn create_std_socket(addr: &SocketAddr) -> io::Result<StdUdpSocket> {
     use std::{mem, os::windows::io::AsRawSocket, ptr};
     use winapi::{
         shared::minwindef::{BOOL, DWORD, FALSE, LPDWORD, LPVOID},
@@ -24,7 +41,7 @@ pub async fn create_socket(addr: &SocketAddr) -> io::Result<UdpSocket> {
     // FIXME: Temporary solution. Should be replaced by tokio's UdpSocket::as_raw_socket
     // https://github.com/tokio-rs/tokio/issues/2017
 
-    let socket = ::std::net::UdpSocket::bind(addr)?;
+    let socket = StdUdpSocket::bind(addr)?;
     let handle = socket.as_raw_socket() as SOCKET;
 
     unsafe {
@@ -62,5 +79,6 @@ pub async fn create_socket(addr: &SocketAddr) -> io::Result<UdpSocket> {
         }
     }
 
-    UdpSocket::from_std(socket)
+    socket.set_nonblocking(true)?;
+    Ok(socket)
 }
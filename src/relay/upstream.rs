@@ -0,0 +1,235 @@
+//! Clients of upstream proxies
+//!
+//! Connections could be sent through an HTTP proxy with `CONNECT`, or a SOCKS5 proxy with `CONNECT` and
+//! `UDP ASSOCIATE`. Domain names of destinations are passed to proxies unresolved, unless servers have `forbidden_ip`,
+//! see `resolve_destination`.
+
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    time::Duration,
+};
+
+use base64::encode;
+use log::trace;
+use tokio::{net::TcpStream, prelude::*};
+
+use crate::{
+    config::{ProxyConfig, ProxyProtocol, ServerAddr},
+    context::Context,
+    relay::{
+        dns_resolver::{resolve, resolve_bind_addr},
+        socks5::{
+            self,
+            Address,
+            Command,
+            HandshakeRequest,
+            HandshakeResponse,
+            PasswdAuthRequest,
+            PasswdAuthResponse,
+            Reply,
+            TcpRequestHeader,
+            TcpResponseHeader,
+        },
+        tcprelay::utils::connect_tcp_stream,
+    },
+};
+
+/// Maximum length of response headers of HTTP proxies
+const MAX_HTTP_HEADER_SIZE: usize = 8192;
+
+/// Timeout of connecting and handshaking with proxies, if servers don't have their own `timeout`
+pub const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+
+async fn connect_proxy(
+    context: &Context,
+    proxy: &ProxyConfig,
+    bind_addr: &Option<SocketAddr>,
+) -> io::Result<TcpStream> {
+    match proxy.addr {
+        ServerAddr::SocketAddr(ref addr) => connect_tcp_stream(addr, bind_addr).await,
+        ServerAddr::DomainName(ref domain, port) => {
+            let result = lookup_then!(context, domain.as_str(), port, false, |addr| {
+                connect_tcp_stream(&addr, bind_addr).await
+            });
+            result.map(|(_, s)| s)
+        }
+    }
+}
+
+/// Destination of servers sent to proxies
+///
+/// Proxies can't check resolved IPs with `forbidden_ip`, so domain names are resolved by servers if it is set, and the
+/// first allowed IP is sent instead.
+pub async fn resolve_destination(context: &Context, addr: &Address) -> io::Result<Address> {
+    match *addr {
+        Address::DomainNameAddress(ref dname, port) if context.has_forbidden_ip() => {
+            let mut addrs = resolve(context, dname, port, true).await?;
+            Ok(Address::SocketAddress(addrs.next().expect("resolved empty addresses")))
+        }
+        _ => Ok(addr.clone()),
+    }
+}
+
+/// Connects to `addr` through `proxy`
+///
+/// `bind_addr` is the local address for connecting to the proxy.
+pub async fn connect(
+    context: &Context,
+    proxy: &ProxyConfig,
+    addr: &Address,
+    bind_addr: &Option<SocketAddr>,
+) -> io::Result<TcpStream> {
+    let mut stream = connect_proxy(context, proxy, bind_addr).await?;
+    trace!("Connected proxy {}, going to connect {}", proxy.addr, addr);
+
+    match proxy.protocol {
+        ProxyProtocol::Socks5 => {
+            socks5_handshake(&mut stream, proxy).await?;
+            let _ = socks5_request(&mut stream, Command::TcpConnect, addr.clone()).await?;
+        }
+        ProxyProtocol::Http => http_connect(&mut stream, proxy, addr).await?,
+    }
+
+    Ok(stream)
+}
+
+async fn socks5_handshake(stream: &mut TcpStream, proxy: &ProxyConfig) -> io::Result<()> {
+    let methods = match proxy.auth {
+        Some(..) => vec![socks5::SOCKS5_AUTH_METHOD_NONE, socks5::SOCKS5_AUTH_METHOD_PASSWORD],
+        None => vec![socks5::SOCKS5_AUTH_METHOD_NONE],
+    };
+    HandshakeRequest::new(methods).write_to(stream).await?;
+
+    let hsp = HandshakeResponse::read_from(stream).await?;
+    trace!("Got handshake response from proxy {}: {:?}", proxy.addr, hsp);
+
+    match (hsp.chosen_method, &proxy.auth) {
+        (socks5::SOCKS5_AUTH_METHOD_NONE, _) => Ok(()),
+        (socks5::SOCKS5_AUTH_METHOD_PASSWORD, Some((ref user, ref pwd))) => {
+            PasswdAuthRequest::new(user.as_str(), pwd.as_str())
+                .write_to(stream)
+                .await?;
+
+            let resp = PasswdAuthResponse::read_from(stream).await?;
+            if resp.status != socks5::SOCKS5_AUTH_PASSWORD_SUCCEEDED {
+                let err = io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    format!("authentication failed with proxy {}", proxy.addr),
+                );
+                return Err(err);
+            }
+            Ok(())
+        }
+        (method, _) => {
+            let err = io::Error::new(
+                io::ErrorKind::Other,
+                format!(
+                    "proxy {} chose unsupported authentication method {:#x}",
+                    proxy.addr, method
+                ),
+            );
+            Err(err)
+        }
+    }
+}
+
+async fn socks5_request(stream: &mut TcpStream, cmd: Command, addr: Address) -> io::Result<Address> {
+    TcpRequestHeader::new(cmd, addr).write_to(stream).await?;
+
+    let hp = TcpResponseHeader::read_from(stream).await?;
+    match hp.reply {
+        Reply::Succeeded => Ok(hp.address),
+        r => {
+            let err = io::Error::new(io::ErrorKind::Other, format!("{}", r));
+            Err(err)
+        }
+    }
+}
+
+async fn http_connect(stream: &mut TcpStream, proxy: &ProxyConfig, addr: &Address) -> io::Result<()> {
+    let mut req = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", addr);
+    if let Some((ref user, ref pwd)) = proxy.auth {
+        req += &format!(
+            "Proxy-Authorization: Basic {}\r\n",
+            encode(&format!("{}:{}", user, pwd))
+        );
+    }
+    req += "\r\n";
+    stream.write_all(req.as_bytes()).await?;
+
+    // Read byte by byte, data sent by the destination right after the headers must be kept in the stream
+    let mut header = Vec::new();
+    while !header.ends_with(b"\r\n\r\n") {
+        if header.len() >= MAX_HTTP_HEADER_SIZE {
+            let err = io::Error::new(io::ErrorKind::InvalidData, "response header of HTTP proxy is too long");
+            return Err(err);
+        }
+
+        let mut b = [0u8; 1];
+        stream.read_exact(&mut b).await?;
+        header.push(b[0]);
+    }
+
+    let header = String::from_utf8_lossy(&header);
+    let status_line = header.lines().next().unwrap_or("");
+    trace!("Got response from proxy {}: {}", proxy.addr, status_line);
+
+    let mut sp = status_line.split_whitespace();
+    match (sp.next(), sp.next()) {
+        (Some(version), Some(code)) if version.starts_with("HTTP/") && code.starts_with('2') => Ok(()),
+        _ => {
+            let err = io::Error::new(
+                io::ErrorKind::Other,
+                format!("proxy {} refused to connect {}, {}", proxy.addr, addr, status_line),
+            );
+            Err(err)
+        }
+    }
+}
+
+/// UDP ASSOCIATE with a SOCKS5 proxy
+///
+/// The association lasts until the control connection is closed, so it must be kept while relaying packets.
+pub struct Socks5UdpAssociation {
+    _control: TcpStream,
+    relay_addr: SocketAddr,
+}
+
+impl Socks5UdpAssociation {
+    /// Associates with `proxy`, which must be a SOCKS5 proxy
+    pub async fn associate(
+        context: &Context,
+        proxy: &ProxyConfig,
+        bind_addr: &Option<SocketAddr>,
+    ) -> io::Result<Socks5UdpAssociation> {
+        let mut stream = connect_proxy(context, proxy, bind_addr).await?;
+        socks5_handshake(&mut stream, proxy).await?;
+
+        // Packets' source address is unknown before binding, which is allowed to be all zeros
+        let src_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0);
+        let bound_addr = socks5_request(&mut stream, Command::UdpAssociate, Address::SocketAddress(src_addr)).await?;
+        let relay_addr = match bound_addr {
+            // Relaying on the same host of the proxy
+            Address::SocketAddress(addr) if addr.ip().is_unspecified() => {
+                SocketAddr::new(stream.peer_addr()?.ip(), addr.port())
+            }
+            Address::SocketAddress(addr) => addr,
+            Address::DomainNameAddress(dname, port) => {
+                resolve_bind_addr(context, &ServerAddr::DomainName(dname, port)).await?
+            }
+        };
+
+        trace!("Proxy {} relays UDP packets on {}", proxy.addr, relay_addr);
+
+        Ok(Socks5UdpAssociation {
+            _control: stream,
+            relay_addr,
+        })
+    }
+
+    /// Address that packets should be sent to, each of them must start with a `UdpAssociateHeader`
+    pub fn relay_addr(&self) -> SocketAddr {
+        self.relay_addr
+    }
+}
@@ -0,0 +1,306 @@
+//! Users of multi-user servers
+//!
+//! All users of a multi-user server share one port. A connection is attributed to the user whose key decrypts its
+//! first AEAD chunk (or the whole packet for UDP), so traffic statistics and quotas are kept for each user.
+//!
+//! AEAD-2022 clients send identity headers instead, which carry hash of the user's key encrypted by the server's
+//! identity key. Users with a `next_hop` are servers of the next hop, their connections are relayed as is.
+
+use std::{collections::HashMap, net::IpAddr, sync::Arc, time::Duration};
+
+use bytes::Bytes;
+use log::info;
+use lru_time_cache::LruCache;
+use spin::Mutex;
+use tokio::time;
+
+use crate::{
+    config::{ServerAddr, ServerConfig},
+    crypto::{self, aead2022, CipherCategory, CipherType},
+    relay::flow::{ServerFlowStatistic, SharedServerFlowStatistic},
+};
+
+// Peers are likely to connect with the same user again, their last matched users are tried first
+const MATCHED_CACHE_CAPACITY: usize = 4096;
+const MATCHED_CACHE_EXPIRY: Duration = Duration::from_secs(30 * 60);
+
+/// A user of a running multi-user server
+pub struct UserContext {
+    name: String,
+    key: Bytes,
+    quota_period: Option<Duration>,
+    next_hop: Option<ServerAddr>,
+    flow: SharedServerFlowStatistic,
+}
+
+impl UserContext {
+    /// User name
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Encryption key derived from user's password
+    pub fn key(&self) -> &[u8] {
+        &self.key[..]
+    }
+
+    /// Server that connections of this user are relayed to
+    pub fn next_hop(&self) -> Option<&ServerAddr> {
+        self.next_hop.as_ref()
+    }
+
+    /// Traffic statistic of this user
+    pub fn flow(&self) -> &SharedServerFlowStatistic {
+        &self.flow
+    }
+}
+
+/// Users of a running multi-user server
+pub struct ServerUsers {
+    method: CipherType,
+    users: Vec<Arc<UserContext>>,
+    matched: Mutex<LruCache<IpAddr, usize>>,
+    // Server's identity key and users indexed by hash of their keys, only for AEAD-2022 ciphers
+    identity_key: Bytes,
+    key_hashes: HashMap<[u8; aead2022::IDENTITY_HEADER_SIZE], usize>,
+}
+
+pub type SharedServerUsers = Arc<ServerUsers>;
+
+impl ServerUsers {
+    /// Checks if servers with `method` could have multiple users
+    pub fn is_supported(method: CipherType) -> bool {
+        match method.category() {
+            CipherCategory::Aead => true,
+            CipherCategory::Aead2022 => aead2022::has_udp_separate_header(method),
+            _ => false,
+        }
+    }
+
+    /// Creates users of `svr_cfg`, `None` if it is not a multi-user server
+    ///
+    /// Traffic statistics of users in `running` are kept for users with the same names.
+    pub fn new_shared(svr_cfg: &ServerConfig, running: Option<&ServerUsers>) -> Option<SharedServerUsers> {
+        if svr_cfg.users().is_empty() {
+            return None;
+        }
+
+        let method = svr_cfg.method();
+        let users: Vec<Arc<UserContext>> = svr_cfg
+            .users()
+            .iter()
+            .map(|u| {
+                let running_flow = running
+                    .and_then(|r| r.users.iter().find(|ru| ru.name == u.name()))
+                    .map(|ru| ru.flow.clone());
+                let flow = match running_flow {
+                    Some(flow) => {
+                        flow.set_quota(u.quota());
+                        flow
+                    }
+                    None => ServerFlowStatistic::new_shared(u.quota()),
+                };
+
+                Arc::new(UserContext {
+                    name: u.name().to_owned(),
+                    key: method.bytes_to_key(u.password().as_bytes()),
+                    quota_period: u.quota().and_then(|q| q.period),
+                    next_hop: u.next_hop().cloned(),
+                    flow,
+                })
+            })
+            .collect();
+
+        let (identity_key, key_hashes) = if method.category() == CipherCategory::Aead2022 {
+            let key_hashes = users
+                .iter()
+                .enumerate()
+                .map(|(idx, u)| (aead2022::key_hash(u.key()), idx))
+                .collect();
+            (svr_cfg.clone_key(), key_hashes)
+        } else {
+            (Bytes::new(), HashMap::new())
+        };
+
+        Some(Arc::new(ServerUsers {
+            method,
+            users,
+            matched: Mutex::new(LruCache::with_expiry_duration_and_capacity(
+                MATCHED_CACHE_EXPIRY,
+                MATCHED_CACHE_CAPACITY,
+            )),
+            identity_key,
+            key_hashes,
+        }))
+    }
+
+    /// All users of the server
+    pub fn users(&self) -> &[Arc<UserContext>] {
+        &self.users
+    }
+
+    /// Identifies the user of a TCP connection from `peer_ip` by its `salt` and the first encrypted length `chunk`
+    ///
+    /// `chunk` is the identity header for AEAD-2022 ciphers.
+    pub fn identify_chunk(&self, peer_ip: IpAddr, salt: &[u8], chunk: &[u8]) -> Option<Arc<UserContext>> {
+        let method = self.method;
+        if method.category() == CipherCategory::Aead2022 {
+            let hash = aead2022::decrypt_identity_header(method, &self.identity_key, salt, chunk);
+            return self.identify_hash(&hash);
+        }
+
+        let mut len_buf = [0u8; 2];
+        self.identify(peer_ip, |key| {
+            let mut cipher = crypto::new_aead_decryptor(method, key, salt);
+            cipher.decrypt(chunk, &mut len_buf).is_ok()
+        })
+    }
+
+    /// Identifies the user of an encrypted UDP `packet` from `peer_ip`
+    pub fn identify_packet(&self, peer_ip: IpAddr, packet: &[u8]) -> Option<Arc<UserContext>> {
+        let method = self.method;
+        if method.category() == CipherCategory::Aead2022 {
+            const HEADERS_SIZE: usize = aead2022::UDP_SEPARATE_HEADER_SIZE + aead2022::IDENTITY_HEADER_SIZE;
+            if packet.len() < HEADERS_SIZE {
+                return None;
+            }
+
+            let (separate_header, identity_header) =
+                packet[..HEADERS_SIZE].split_at(aead2022::UDP_SEPARATE_HEADER_SIZE);
+            let mut separate_header = separate_header.to_vec();
+            aead2022::decrypt_udp_header(method, &self.identity_key, &mut separate_header);
+            let hash =
+                aead2022::decrypt_udp_identity_header(method, &self.identity_key, &separate_header, identity_header);
+            return self.identify_hash(&hash);
+        }
+
+        let salt_size = method.salt_size();
+        let tag_size = method.tag_size();
+        if packet.len() < salt_size + tag_size {
+            return None;
+        }
+
+        let (salt, data) = packet.split_at(salt_size);
+        let mut buf = vec![0u8; data.len() - tag_size];
+        self.identify(peer_ip, |key| {
+            let mut cipher = crypto::new_aead_decryptor(method, key, salt);
+            cipher.decrypt(data, &mut buf).is_ok()
+        })
+    }
+
+    fn identify_hash(&self, hash: &[u8; aead2022::IDENTITY_HEADER_SIZE]) -> Option<Arc<UserContext>> {
+        self.key_hashes.get(hash).map(|&idx| self.users[idx].clone())
+    }
+
+    fn identify<F>(&self, peer_ip: IpAddr, mut try_key: F) -> Option<Arc<UserContext>>
+    where
+        F: FnMut(&[u8]) -> bool,
+    {
+        let last_matched = self.matched.lock().get(&peer_ip).cloned();
+        if let Some(idx) = last_matched {
+            if try_key(self.users[idx].key()) {
+                return Some(self.users[idx].clone());
+            }
+        }
+
+        for (idx, user) in self.users.iter().enumerate() {
+            if Some(idx) == last_matched {
+                continue;
+            }
+
+            if try_key(user.key()) {
+                self.matched.lock().insert(peer_ip, idx);
+                return Some(user.clone());
+            }
+        }
+
+        None
+    }
+}
+
+/// Resets quota of `user` after every period, counted from the start of its current period
+///
+/// Returns immediately if the quota is never reset.
+pub async fn reset_user_quota_periodically(user: Arc<UserContext>, port: u16) {
+    let period = match user.quota_period {
+        Some(p) => p,
+        None => return,
+    };
+
+    loop {
+        time::delay_until(user.flow.quota_period_start() + period).await;
+
+        user.flow.reset_quota();
+        info!("Traffic quota of user {} on port {} is reset", user.name, port);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use byteorder::{BigEndian, ByteOrder};
+
+    use super::*;
+    use crate::config::ServerUser;
+
+    #[test]
+    fn identify_user() {
+        let method = CipherType::Aes256Gcm;
+        let mut svr_cfg = ServerConfig::basic("127.0.0.1:8388".parse().unwrap(), String::new(), method);
+        for name in &["alice", "bob", "carol"] {
+            svr_cfg.add_user(ServerUser::new((*name).to_owned(), format!("{}-password", name)));
+        }
+        let users = ServerUsers::new_shared(&svr_cfg, None).unwrap();
+
+        let salt = method.gen_salt();
+        let key = method.bytes_to_key(b"bob-password");
+        let mut cipher = crypto::new_aead_encryptor(method, &key, &salt);
+
+        let mut len_buf = [0u8; 2];
+        BigEndian::write_u16(&mut len_buf, 100);
+        let mut chunk = vec![0u8; 2 + method.tag_size()];
+        cipher.encrypt(&len_buf, &mut chunk);
+
+        let peer_ip = "127.0.0.1".parse().unwrap();
+        let user = users.identify_chunk(peer_ip, &salt, &chunk).unwrap();
+        assert_eq!(user.name(), "bob");
+
+        // Tries the last matched user first
+        let user = users.identify_chunk(peer_ip, &salt, &chunk).unwrap();
+        assert_eq!(user.name(), "bob");
+
+        chunk[0] ^= 0xFF;
+        assert!(users.identify_chunk(peer_ip, &salt, &chunk).is_none());
+    }
+
+    #[test]
+    fn identify_user_by_identity_header() {
+        let method = CipherType::Aead2022Blake3Aes128Gcm;
+        let identity_key = "AAECAwQFBgcICQoLDA0ODw==";
+        let mut svr_cfg = ServerConfig::basic("127.0.0.1:8388".parse().unwrap(), identity_key.to_owned(), method);
+        svr_cfg.add_user(ServerUser::new(
+            "alice".to_owned(),
+            "EBESExQVFhcYGRobHB0eHw==".to_owned(),
+        ));
+        svr_cfg.add_user(ServerUser::new("bob".to_owned(), "ICEiIyQlJicoKSorLC0uLw==".to_owned()));
+        let users = ServerUsers::new_shared(&svr_cfg, None).unwrap();
+
+        let identity_keys = [svr_cfg.clone_key()];
+        let user_key = method.bytes_to_key(b"ICEiIyQlJicoKSorLC0uLw==");
+        let peer_ip = "127.0.0.1".parse().unwrap();
+
+        let salt = method.gen_salt();
+        let header = aead2022::make_identity_headers(method, &identity_keys, &user_key, &salt);
+        let user = users.identify_chunk(peer_ip, &salt, &header).unwrap();
+        assert_eq!(user.name(), "bob");
+
+        let mut separate_header = [7u8; aead2022::UDP_SEPARATE_HEADER_SIZE];
+        let mut packet = aead2022::make_udp_identity_headers(method, &identity_keys, &user_key, &separate_header);
+        aead2022::encrypt_udp_header(method, &identity_keys[0], &mut separate_header);
+        packet.splice(0..0, separate_header.iter().cloned());
+        let user = users.identify_packet(peer_ip, &packet).unwrap();
+        assert_eq!(user.name(), "bob");
+
+        packet[20] ^= 0xFF;
+        assert!(users.identify_packet(peer_ip, &packet).is_none());
+    }
+}
@@ -0,0 +1,126 @@
+use std::{env, fs, net::SocketAddr};
+
+use serde::Deserialize;
+use tokio::{
+    io,
+    net::TcpListener,
+    prelude::*,
+    runtime::Builder,
+    time::{self, Duration},
+};
+
+use shadowsocks::{
+    config::{Config, ConfigType, ServerAddr, ServerConfig},
+    crypto::CipherType,
+    relay::{socks5::Address, tcprelay::client::Socks5Client},
+    run_local,
+    run_server,
+};
+
+const SERVER_ADDR: &str = "127.0.0.1:8197";
+const LOCAL_ADDR: &str = "127.0.0.1:8301";
+const ECHO_SERVER_ADDR: &str = "127.0.0.1:50482";
+
+const PASSWORD: &str = "test-password";
+const METHOD: CipherType = CipherType::Aes256Gcm;
+
+#[derive(Deserialize)]
+struct Record {
+    server_port: u16,
+    destination: String,
+    resolved: Option<String>,
+    upload: u64,
+    download: u64,
+    close_reason: String,
+}
+
+fn get_svr_config(access_log: &str) -> Config {
+    let mut cfg = Config::new(ConfigType::Server);
+    cfg.server = vec![ServerConfig::basic(
+        SERVER_ADDR.parse().unwrap(),
+        PASSWORD.to_owned(),
+        METHOD,
+    )];
+    cfg.access_log = Some(access_log.to_owned());
+    cfg
+}
+
+fn get_cli_config() -> Config {
+    let mut cfg = Config::new(ConfigType::Socks5Local);
+    cfg.local = Some(ServerAddr::from(LOCAL_ADDR.parse::<SocketAddr>().unwrap()));
+    cfg.server = vec![ServerConfig::basic(
+        SERVER_ADDR.parse().unwrap(),
+        PASSWORD.to_owned(),
+        METHOD,
+    )];
+    cfg
+}
+
+fn start_echo_server() {
+    tokio::spawn(async {
+        let mut listener = TcpListener::bind(ECHO_SERVER_ADDR).await.unwrap();
+
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let (mut r, mut w) = stream.split();
+                let _ = io::copy(&mut r, &mut w).await;
+            });
+        }
+    });
+}
+
+#[test]
+fn access_log() {
+    let _ = env_logger::try_init();
+
+    let path = env::temp_dir().join("shadowsocks-test-access-log.jsonl");
+    let _ = fs::remove_file(&path);
+    let path_str = path.to_str().unwrap().to_owned();
+
+    let mut rt = Builder::new().basic_scheduler().enable_all().build().unwrap();
+    let rt_handle = rt.handle().clone();
+
+    rt.block_on(async move {
+        tokio::spawn(run_server(get_svr_config(&path_str), rt_handle.clone()));
+        tokio::spawn(run_local(get_cli_config(), rt_handle));
+        start_echo_server();
+
+        time::delay_for(Duration::from_secs(1)).await;
+
+        let mut c = Socks5Client::connect(
+            Address::SocketAddress(ECHO_SERVER_ADDR.parse().unwrap()),
+            &LOCAL_ADDR.parse().unwrap(),
+        )
+        .await
+        .unwrap();
+
+        c.write_all(b"hello shadowsocks").await.unwrap();
+        c.flush().await.unwrap();
+
+        let mut buf = [0u8; 17];
+        time::timeout(Duration::from_secs(5), c.read_exact(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&buf, b"hello shadowsocks");
+
+        drop(c);
+
+        time::delay_for(Duration::from_secs(1)).await;
+    });
+
+    let content = fs::read_to_string(&path).unwrap();
+    let lines = content.lines().collect::<Vec<_>>();
+    assert_eq!(lines.len(), 1);
+
+    let record: Record = json5::from_str(lines[0]).unwrap();
+    assert_eq!(record.server_port, 8197);
+    assert_eq!(record.destination, ECHO_SERVER_ADDR);
+    assert_eq!(record.resolved.as_deref(), Some(ECHO_SERVER_ADDR));
+    assert_eq!(record.upload, 17);
+    assert_eq!(record.download, 17);
+    assert_eq!(record.close_reason, "client_closed");
+
+    let _ = fs::remove_file(&path);
+}
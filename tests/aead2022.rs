@@ -0,0 +1,286 @@
+use std::{io::Cursor, net::SocketAddr};
+
+use bytes::{BufMut, BytesMut};
+use tokio::{
+    io,
+    net::{TcpListener, UdpSocket},
+    prelude::*,
+    runtime::Builder,
+    sync::mpsc,
+    time::{self, Duration},
+};
+
+use shadowsocks::{
+    config::{Config, ConfigType, Mode, ServerAddr, ServerConfig},
+    crypto::CipherType,
+    relay::{
+        socks5::{Address, UdpAssociateHeader},
+        tcprelay::client::Socks5Client,
+    },
+    run_local, run_server,
+};
+
+const TCP_SERVER_ADDR: &str = "127.0.0.1:8198";
+const TCP_LOCAL_ADDR: &str = "127.0.0.1:8302";
+const TCP_ECHO_SERVER_ADDR: &str = "127.0.0.1:50483";
+
+const UDP_SERVER_ADDR: &str = "127.0.0.1:8199";
+const UDP_LOCAL_ADDR: &str = "127.0.0.1:8303";
+const UDP_ECHO_SERVER_ADDR: &str = "127.0.0.1:50484";
+const UDP_CLIENT_ADDR: &str = "127.0.0.1:50485";
+
+const REPLAY_SERVER_ADDR: &str = "127.0.0.1:8222";
+const REPLAY_CAPTURE_ADDR: &str = "127.0.0.1:8223";
+const REPLAY_LOCAL_ADDR: &str = "127.0.0.1:8318";
+const REPLAY_ECHO_SERVER_ADDR: &str = "127.0.0.1:50495";
+const REPLAY_CLIENT_ADDR: &str = "127.0.0.1:50496";
+
+const AES_KEY: &str = "AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8=";
+const AES_128_KEY: &str = "AAECAwQFBgcICQoLDA0ODw==";
+const CHACHA20_KEY: &str = "ZGVmZ2hpamtsbW5vcHFyc3R1dnd4eXp7fH1+f4CBgoM=";
+
+fn get_config(config_type: ConfigType, svr_addr: &str, local_addr: &str, key: &str, method: CipherType) -> Config {
+    let mut cfg = Config::new(config_type);
+    if config_type.is_local() {
+        cfg.local = Some(ServerAddr::from(local_addr.parse::<SocketAddr>().unwrap()));
+    }
+    cfg.server = vec![ServerConfig::basic(svr_addr.parse().unwrap(), key.to_owned(), method)];
+    cfg
+}
+
+#[test]
+fn tcp_relay() {
+    let _ = env_logger::try_init();
+
+    let method = CipherType::Aead2022Blake3Aes256Gcm;
+
+    let mut rt = Builder::new().basic_scheduler().enable_all().build().unwrap();
+    let rt_handle = rt.handle().clone();
+
+    rt.block_on(async move {
+        let svr_cfg = get_config(ConfigType::Server, TCP_SERVER_ADDR, TCP_LOCAL_ADDR, AES_KEY, method);
+        let cli_cfg = get_config(
+            ConfigType::Socks5Local,
+            TCP_SERVER_ADDR,
+            TCP_LOCAL_ADDR,
+            AES_KEY,
+            method,
+        );
+        tokio::spawn(run_server(svr_cfg, rt_handle.clone()));
+        tokio::spawn(run_local(cli_cfg, rt_handle));
+
+        tokio::spawn(async {
+            let mut listener = TcpListener::bind(TCP_ECHO_SERVER_ADDR).await.unwrap();
+
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let (mut r, mut w) = stream.split();
+                    let _ = io::copy(&mut r, &mut w).await;
+                });
+            }
+        });
+
+        time::delay_for(Duration::from_secs(1)).await;
+
+        let mut c = Socks5Client::connect(
+            Address::SocketAddress(TCP_ECHO_SERVER_ADDR.parse().unwrap()),
+            &TCP_LOCAL_ADDR.parse().unwrap(),
+        )
+        .await
+        .unwrap();
+
+        // Response header is written with the first chunk of echoed data
+        for message in &[&b"hello shadowsocks 2022"[..], &b"second message"[..]] {
+            c.write_all(message).await.unwrap();
+            c.flush().await.unwrap();
+
+            let mut buf = vec![0u8; message.len()];
+            time::timeout(Duration::from_secs(5), c.read_exact(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(&buf[..], *message);
+        }
+    });
+}
+
+#[test]
+fn udp_relay() {
+    let _ = env_logger::try_init();
+
+    let method = CipherType::Aead2022Blake3ChaCha20Poly1305;
+    let remote_addr = Address::SocketAddress(UDP_ECHO_SERVER_ADDR.parse().unwrap());
+
+    let mut rt = Builder::new().basic_scheduler().enable_all().build().unwrap();
+    let rt_handle = rt.handle().clone();
+
+    rt.block_on(async move {
+        let mut svr_cfg = get_config(
+            ConfigType::Server,
+            UDP_SERVER_ADDR,
+            UDP_LOCAL_ADDR,
+            CHACHA20_KEY,
+            method,
+        );
+        svr_cfg.mode = Mode::UdpOnly;
+        let mut cli_cfg = get_config(
+            ConfigType::Socks5Local,
+            UDP_SERVER_ADDR,
+            UDP_LOCAL_ADDR,
+            CHACHA20_KEY,
+            method,
+        );
+        cli_cfg.mode = Mode::UdpOnly;
+        tokio::spawn(run_server(svr_cfg, rt_handle.clone()));
+        tokio::spawn(run_local(cli_cfg, rt_handle));
+
+        tokio::spawn(async {
+            let mut socket = UdpSocket::bind(UDP_ECHO_SERVER_ADDR).await.unwrap();
+
+            let mut buf = vec![0u8; 65536];
+            loop {
+                let (n, src) = socket.recv_from(&mut buf).await.unwrap();
+                socket.send_to(&buf[..n], &src).await.unwrap();
+            }
+        });
+
+        time::delay_for(Duration::from_secs(1)).await;
+
+        // Holds the association until the test is finished
+        let local_addr = UDP_LOCAL_ADDR.parse::<SocketAddr>().unwrap();
+        let (_assoc, _) = Socks5Client::udp_associate(remote_addr.clone(), &local_addr)
+            .await
+            .unwrap();
+
+        let mut socket = UdpSocket::bind(UDP_CLIENT_ADDR).await.unwrap();
+
+        // Packet IDs keep increasing in the sessions of both sides
+        for payload in &[&b"HEllo WORld"[..], &b"another packet"[..]] {
+            let header = UdpAssociateHeader::new(0, remote_addr.clone());
+            let mut buf = BytesMut::with_capacity(header.serialized_len() + payload.len());
+            header.write_to_buf(&mut buf);
+            buf.put_slice(payload);
+            socket.send_to(&buf[..], &local_addr).await.unwrap();
+
+            let mut buf = vec![0u8; 65536];
+            let (n, _) = time::timeout(Duration::from_secs(5), socket.recv_from(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+
+            let mut cur = Cursor::new(buf[..n].to_vec());
+            UdpAssociateHeader::read_from(&mut cur).await.unwrap();
+            let header_len = cur.position() as usize;
+            assert_eq!(&cur.into_inner()[header_len..], *payload);
+        }
+    });
+}
+
+#[test]
+fn udp_replay_from_another_address() {
+    let _ = env_logger::try_init();
+
+    let method = CipherType::Aead2022Blake3Aes128Gcm;
+    let remote_addr = Address::SocketAddress(REPLAY_ECHO_SERVER_ADDR.parse().unwrap());
+
+    let mut rt = Builder::new().basic_scheduler().enable_all().build().unwrap();
+    let rt_handle = rt.handle().clone();
+
+    rt.block_on(async move {
+        let mut svr_cfg = get_config(
+            ConfigType::Server,
+            REPLAY_SERVER_ADDR,
+            REPLAY_LOCAL_ADDR,
+            AES_128_KEY,
+            method,
+        );
+        svr_cfg.mode = Mode::UdpOnly;
+        // Local sends packets to the server through the capturing relay
+        let mut cli_cfg = get_config(
+            ConfigType::Socks5Local,
+            REPLAY_CAPTURE_ADDR,
+            REPLAY_LOCAL_ADDR,
+            AES_128_KEY,
+            method,
+        );
+        cli_cfg.mode = Mode::UdpOnly;
+        tokio::spawn(run_server(svr_cfg, rt_handle.clone()));
+        tokio::spawn(run_local(cli_cfg, rt_handle));
+
+        let (mut echoed_tx, mut echoed_rx) = mpsc::channel(16);
+        tokio::spawn(async move {
+            let mut socket = UdpSocket::bind(REPLAY_ECHO_SERVER_ADDR).await.unwrap();
+
+            let mut buf = vec![0u8; 65536];
+            loop {
+                let (n, src) = socket.recv_from(&mut buf).await.unwrap();
+                socket.send_to(&buf[..n], &src).await.unwrap();
+                let _ = echoed_tx.send(buf[..n].to_vec()).await;
+            }
+        });
+
+        // Relays packets between the local and the server, and keeps packets sent by the local
+        let (mut captured_tx, mut captured_rx) = mpsc::channel(16);
+        tokio::spawn(async move {
+            let capture_socket = UdpSocket::bind(REPLAY_CAPTURE_ADDR).await.unwrap();
+            let (mut capture_r, mut capture_w) = capture_socket.split();
+            let server_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let (mut server_r, mut server_w) = server_socket.split();
+
+            let mut buf = vec![0u8; 65536];
+            let (n, local_addr) = capture_r.recv_from(&mut buf).await.unwrap();
+            let _ = captured_tx.send(buf[..n].to_vec()).await;
+            let svr_addr = REPLAY_SERVER_ADDR.parse::<SocketAddr>().unwrap();
+            server_w.send_to(&buf[..n], &svr_addr).await.unwrap();
+
+            tokio::spawn(async move {
+                let mut buf = vec![0u8; 65536];
+                loop {
+                    let (n, _) = server_r.recv_from(&mut buf).await.unwrap();
+                    capture_w.send_to(&buf[..n], &local_addr).await.unwrap();
+                }
+            });
+
+            loop {
+                let (n, _) = capture_r.recv_from(&mut buf).await.unwrap();
+                let _ = captured_tx.send(buf[..n].to_vec()).await;
+                server_w.send_to(&buf[..n], &svr_addr).await.unwrap();
+            }
+        });
+
+        time::delay_for(Duration::from_secs(1)).await;
+
+        let local_addr = REPLAY_LOCAL_ADDR.parse::<SocketAddr>().unwrap();
+        let (_assoc, _) = Socks5Client::udp_associate(remote_addr.clone(), &local_addr)
+            .await
+            .unwrap();
+
+        let mut socket = UdpSocket::bind(REPLAY_CLIENT_ADDR).await.unwrap();
+
+        let payload = b"captured packet";
+        let header = UdpAssociateHeader::new(0, remote_addr.clone());
+        let mut buf = BytesMut::with_capacity(header.serialized_len() + payload.len());
+        header.write_to_buf(&mut buf);
+        buf.put_slice(payload);
+        socket.send_to(&buf[..], &local_addr).await.unwrap();
+
+        let mut buf = vec![0u8; 65536];
+        time::timeout(Duration::from_secs(5), socket.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let echoed = echoed_rx.recv().await.unwrap();
+        assert_eq!(&echoed[..], &payload[..]);
+
+        // Replaying the captured packet from another socket starts another association on the server
+        let captured = captured_rx.recv().await.unwrap();
+        let mut replay_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        replay_socket
+            .send_to(&captured, &REPLAY_SERVER_ADDR.parse::<SocketAddr>().unwrap())
+            .await
+            .unwrap();
+
+        assert!(time::timeout(Duration::from_secs(1), echoed_rx.recv()).await.is_err());
+    });
+}
@@ -0,0 +1,225 @@
+use std::{io::Cursor, net::SocketAddr};
+
+use bytes::{BufMut, BytesMut};
+use tokio::{
+    io,
+    net::{TcpListener, UdpSocket},
+    prelude::*,
+    runtime::Builder,
+    time::{self, Duration},
+};
+
+use shadowsocks::{
+    config::{Config, ConfigType, Mode, ServerAddr, ServerConfig, ServerUser},
+    crypto::CipherType,
+    relay::{
+        socks5::{Address, UdpAssociateHeader},
+        tcprelay::client::Socks5Client,
+    },
+    run_local, run_server,
+};
+
+const TCP_EXIT_SERVER_ADDR: &str = "127.0.0.1:8210";
+const TCP_RELAY_SERVER_ADDR: &str = "127.0.0.1:8211";
+const TCP_DIRECT_LOCAL_ADDR: &str = "127.0.0.1:8304";
+const TCP_CHAIN_LOCAL_ADDR: &str = "127.0.0.1:8305";
+const TCP_UNKNOWN_LOCAL_ADDR: &str = "127.0.0.1:8306";
+const TCP_ECHO_SERVER_ADDR: &str = "127.0.0.1:50486";
+
+const UDP_EXIT_SERVER_ADDR: &str = "127.0.0.1:8212";
+const UDP_RELAY_SERVER_ADDR: &str = "127.0.0.1:8213";
+const UDP_DIRECT_LOCAL_ADDR: &str = "127.0.0.1:8307";
+const UDP_CHAIN_LOCAL_ADDR: &str = "127.0.0.1:8308";
+const UDP_ECHO_SERVER_ADDR: &str = "127.0.0.1:50487";
+const UDP_DIRECT_CLIENT_ADDR: &str = "127.0.0.1:50488";
+const UDP_CHAIN_CLIENT_ADDR: &str = "127.0.0.1:50489";
+
+const METHOD: CipherType = CipherType::Aead2022Blake3Aes128Gcm;
+
+const EXIT_IDENTITY_KEY: &str = "AAECAwQFBgcICQoLDA0ODw==";
+const RELAY_IDENTITY_KEY: &str = "EBESExQVFhcYGRobHB0eHw==";
+const ALICE_KEY: &str = "ICEiIyQlJicoKSorLC0uLw==";
+const BOB_KEY: &str = "MDEyMzQ1Njc4OTo7PD0+Pw==";
+const UNKNOWN_KEY: &str = "QEFCQ0RFRkdISUpLTE1OTw==";
+
+fn get_exit_config(svr_addr: &str, mode: Mode) -> Config {
+    let mut svr_cfg = ServerConfig::basic(svr_addr.parse().unwrap(), EXIT_IDENTITY_KEY.to_owned(), METHOD);
+    svr_cfg.add_user(ServerUser::new("alice".to_owned(), ALICE_KEY.to_owned()));
+    svr_cfg.add_user(ServerUser::new("bob".to_owned(), BOB_KEY.to_owned()));
+
+    let mut cfg = Config::new(ConfigType::Server);
+    cfg.server = vec![svr_cfg];
+    cfg.mode = mode;
+    cfg
+}
+
+fn get_relay_config(svr_addr: &str, exit_addr: &str, mode: Mode) -> Config {
+    let mut svr_cfg = ServerConfig::basic(svr_addr.parse().unwrap(), RELAY_IDENTITY_KEY.to_owned(), METHOD);
+    let mut exit = ServerUser::new("exit".to_owned(), EXIT_IDENTITY_KEY.to_owned());
+    exit.set_next_hop(exit_addr.parse().unwrap());
+    svr_cfg.add_user(exit);
+
+    let mut cfg = Config::new(ConfigType::Server);
+    cfg.server = vec![svr_cfg];
+    cfg.mode = mode;
+    cfg
+}
+
+fn get_cli_config(svr_addr: &str, local_addr: &str, password: String, mode: Mode) -> Config {
+    let mut cfg = Config::new(ConfigType::Socks5Local);
+    cfg.local = Some(ServerAddr::from(local_addr.parse::<SocketAddr>().unwrap()));
+    cfg.server = vec![ServerConfig::basic(svr_addr.parse().unwrap(), password, METHOD)];
+    cfg.mode = mode;
+    cfg
+}
+
+async fn tcp_echo(local_addr: &str, payload: &[u8]) -> io::Result<()> {
+    let mut c = Socks5Client::connect(
+        Address::SocketAddress(TCP_ECHO_SERVER_ADDR.parse().unwrap()),
+        &local_addr.parse().unwrap(),
+    )
+    .await?;
+
+    c.write_all(payload).await?;
+    c.flush().await?;
+
+    let mut buf = vec![0u8; payload.len()];
+    time::timeout(Duration::from_secs(5), c.read_exact(&mut buf)).await??;
+    assert_eq!(buf, payload);
+
+    Ok(())
+}
+
+async fn udp_echo(local_addr: &str, client_addr: &str, payload: &[u8]) {
+    let remote_addr = Address::SocketAddress(UDP_ECHO_SERVER_ADDR.parse().unwrap());
+
+    // Holds the association until the packet is echoed
+    let local_addr = local_addr.parse::<SocketAddr>().unwrap();
+    let (_assoc, _) = Socks5Client::udp_associate(remote_addr.clone(), &local_addr)
+        .await
+        .unwrap();
+
+    let mut socket = UdpSocket::bind(client_addr).await.unwrap();
+
+    let header = UdpAssociateHeader::new(0, remote_addr);
+    let mut buf = BytesMut::with_capacity(header.serialized_len() + payload.len());
+    header.write_to_buf(&mut buf);
+    buf.put_slice(payload);
+    socket.send_to(&buf[..], &local_addr).await.unwrap();
+
+    let mut buf = vec![0u8; 65536];
+    let (n, _) = time::timeout(Duration::from_secs(5), socket.recv_from(&mut buf))
+        .await
+        .unwrap()
+        .unwrap();
+
+    let mut cur = Cursor::new(buf[..n].to_vec());
+    UdpAssociateHeader::read_from(&mut cur).await.unwrap();
+    let header_len = cur.position() as usize;
+    assert_eq!(&cur.into_inner()[header_len..], payload);
+}
+
+#[test]
+fn tcp_identity_headers() {
+    let _ = env_logger::try_init();
+
+    let mut rt = Builder::new().basic_scheduler().enable_all().build().unwrap();
+    let rt_handle = rt.handle().clone();
+
+    rt.block_on(async move {
+        let mode = Mode::TcpOnly;
+
+        // Relay and exit servers keep their own salts
+        tokio::spawn(run_server(
+            get_exit_config(TCP_EXIT_SERVER_ADDR, mode),
+            rt_handle.clone(),
+        ));
+        tokio::spawn(run_server(
+            get_relay_config(TCP_RELAY_SERVER_ADDR, TCP_EXIT_SERVER_ADDR, mode),
+            rt_handle.clone(),
+        ));
+
+        let direct = format!("{}:{}", EXIT_IDENTITY_KEY, ALICE_KEY);
+        tokio::spawn(run_local(
+            get_cli_config(TCP_EXIT_SERVER_ADDR, TCP_DIRECT_LOCAL_ADDR, direct, mode),
+            rt_handle.clone(),
+        ));
+        let chain = format!("{}:{}:{}", RELAY_IDENTITY_KEY, EXIT_IDENTITY_KEY, BOB_KEY);
+        tokio::spawn(run_local(
+            get_cli_config(TCP_RELAY_SERVER_ADDR, TCP_CHAIN_LOCAL_ADDR, chain, mode),
+            rt_handle.clone(),
+        ));
+        let unknown = format!("{}:{}", EXIT_IDENTITY_KEY, UNKNOWN_KEY);
+        tokio::spawn(run_local(
+            get_cli_config(TCP_EXIT_SERVER_ADDR, TCP_UNKNOWN_LOCAL_ADDR, unknown, mode),
+            rt_handle,
+        ));
+
+        tokio::spawn(async {
+            let mut listener = TcpListener::bind(TCP_ECHO_SERVER_ADDR).await.unwrap();
+
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let (mut r, mut w) = stream.split();
+                    let _ = io::copy(&mut r, &mut w).await;
+                });
+            }
+        });
+
+        time::delay_for(Duration::from_secs(1)).await;
+
+        tcp_echo(TCP_DIRECT_LOCAL_ADDR, b"hello alice").await.unwrap();
+        tcp_echo(TCP_CHAIN_LOCAL_ADDR, b"hello bob through relay")
+            .await
+            .unwrap();
+        assert!(tcp_echo(TCP_UNKNOWN_LOCAL_ADDR, b"hello").await.is_err());
+    });
+}
+
+#[test]
+fn udp_identity_headers() {
+    let _ = env_logger::try_init();
+
+    let mut rt = Builder::new().basic_scheduler().enable_all().build().unwrap();
+    let rt_handle = rt.handle().clone();
+
+    rt.block_on(async move {
+        let mode = Mode::UdpOnly;
+
+        tokio::spawn(run_server(
+            get_exit_config(UDP_EXIT_SERVER_ADDR, mode),
+            rt_handle.clone(),
+        ));
+        tokio::spawn(run_server(
+            get_relay_config(UDP_RELAY_SERVER_ADDR, UDP_EXIT_SERVER_ADDR, mode),
+            rt_handle.clone(),
+        ));
+
+        let direct = format!("{}:{}", EXIT_IDENTITY_KEY, ALICE_KEY);
+        tokio::spawn(run_local(
+            get_cli_config(UDP_EXIT_SERVER_ADDR, UDP_DIRECT_LOCAL_ADDR, direct, mode),
+            rt_handle.clone(),
+        ));
+        let chain = format!("{}:{}:{}", RELAY_IDENTITY_KEY, EXIT_IDENTITY_KEY, BOB_KEY);
+        tokio::spawn(run_local(
+            get_cli_config(UDP_RELAY_SERVER_ADDR, UDP_CHAIN_LOCAL_ADDR, chain, mode),
+            rt_handle,
+        ));
+
+        tokio::spawn(async {
+            let mut socket = UdpSocket::bind(UDP_ECHO_SERVER_ADDR).await.unwrap();
+
+            let mut buf = vec![0u8; 65536];
+            loop {
+                let (n, src) = socket.recv_from(&mut buf).await.unwrap();
+                socket.send_to(&buf[..n], &src).await.unwrap();
+            }
+        });
+
+        time::delay_for(Duration::from_secs(1)).await;
+
+        udp_echo(UDP_DIRECT_LOCAL_ADDR, UDP_DIRECT_CLIENT_ADDR, b"hello alice").await;
+        udp_echo(UDP_CHAIN_LOCAL_ADDR, UDP_CHAIN_CLIENT_ADDR, b"hello bob through relay").await;
+    });
+}
@@ -0,0 +1,105 @@
+use std::net::SocketAddr;
+
+use tokio::{
+    io,
+    net::TcpListener,
+    prelude::*,
+    runtime::Builder,
+    time::{self, Duration, Instant},
+};
+
+use shadowsocks::{
+    config::{BandwidthLimit, Config, ConfigType, Mode, ServerAddr, ServerConfig},
+    crypto::CipherType,
+    relay::{socks5::Address, tcprelay::client::Socks5Client},
+    run_local,
+    run_server,
+};
+
+const SERVER_ADDR: &str = "127.0.0.1:8180";
+const LOCAL_ADDR: &str = "127.0.0.1:8280";
+const ECHO_SERVER_ADDR: &str = "127.0.0.1:50450";
+
+const PASSWORD: &str = "test-password";
+const METHOD: CipherType = CipherType::Aes256Gcm;
+
+// Bytes per second
+const DOWNLOAD_LIMIT: u64 = 16 * 1024;
+
+fn get_svr_config() -> Config {
+    let mut svr_cfg = ServerConfig::basic(SERVER_ADDR.parse().unwrap(), PASSWORD.to_owned(), METHOD);
+    svr_cfg.set_connection_bandwidth(BandwidthLimit {
+        upload: None,
+        download: Some(DOWNLOAD_LIMIT),
+    });
+
+    let mut cfg = Config::new(ConfigType::Server);
+    cfg.server = vec![svr_cfg];
+    cfg.mode = Mode::TcpOnly;
+    cfg
+}
+
+fn get_cli_config() -> Config {
+    let mut cfg = Config::new(ConfigType::Socks5Local);
+    cfg.local = Some(ServerAddr::from(LOCAL_ADDR.parse::<SocketAddr>().unwrap()));
+    cfg.server = vec![ServerConfig::basic(
+        SERVER_ADDR.parse().unwrap(),
+        PASSWORD.to_owned(),
+        METHOD,
+    )];
+    cfg.mode = Mode::TcpOnly;
+    cfg
+}
+
+fn start_echo_server() {
+    tokio::spawn(async {
+        let mut listener = TcpListener::bind(ECHO_SERVER_ADDR).await.unwrap();
+
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let (mut r, mut w) = stream.split();
+                let _ = io::copy(&mut r, &mut w).await;
+            });
+        }
+    });
+}
+
+#[test]
+fn tcp_download_limit() {
+    let _ = env_logger::try_init();
+
+    let mut rt = Builder::new().basic_scheduler().enable_all().build().unwrap();
+    let rt_handle = rt.handle().clone();
+
+    rt.block_on(async move {
+        tokio::spawn(run_server(get_svr_config(), rt_handle.clone()));
+        tokio::spawn(run_local(get_cli_config(), rt_handle));
+        start_echo_server();
+
+        time::delay_for(Duration::from_secs(1)).await;
+
+        let mut c = Socks5Client::connect(
+            Address::SocketAddress(ECHO_SERVER_ADDR.parse().unwrap()),
+            &LOCAL_ADDR.parse().unwrap(),
+        )
+        .await
+        .unwrap();
+
+        // 1 second of burst, then 2 seconds for the rest
+        let payload = vec![0u8; 3 * DOWNLOAD_LIMIT as usize];
+        let start = Instant::now();
+
+        c.write_all(&payload).await.unwrap();
+        c.flush().await.unwrap();
+
+        let mut buf = vec![0u8; payload.len()];
+        time::timeout(Duration::from_secs(10), c.read_exact(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(buf, payload);
+
+        assert!(start.elapsed() >= Duration::from_millis(1500));
+    });
+}
@@ -0,0 +1,121 @@
+use std::net::SocketAddr;
+
+use tokio::{
+    io,
+    net::{TcpListener, TcpStream},
+    prelude::*,
+    runtime::Builder,
+    time::{self, Duration},
+};
+
+use shadowsocks::{
+    config::{Config, ConfigType, ServerAddr, ServerConfig},
+    crypto::CipherType,
+    relay::{socks5::Address, tcprelay::client::Socks5Client},
+    run_local,
+    run_server_with_registry,
+    ServerRegistry,
+};
+
+const SERVER_ADDR: &str = "127.0.0.1:8196";
+const LOCAL_ADDR: &str = "127.0.0.1:8300";
+const FALLBACK_ADDR: &str = "127.0.0.1:50481";
+
+const PASSWORD: &str = "test-password";
+const METHOD: CipherType = CipherType::Aes256Gcm;
+
+fn get_svr_config() -> Config {
+    let mut svr_cfg = ServerConfig::basic(SERVER_ADDR.parse().unwrap(), PASSWORD.to_owned(), METHOD);
+    svr_cfg.set_fallback(FALLBACK_ADDR.parse().unwrap());
+
+    let mut cfg = Config::new(ConfigType::Server);
+    cfg.server = vec![svr_cfg];
+    cfg
+}
+
+fn get_cli_config() -> Config {
+    let mut cfg = Config::new(ConfigType::Socks5Local);
+    cfg.local = Some(ServerAddr::from(LOCAL_ADDR.parse::<SocketAddr>().unwrap()));
+    cfg.server = vec![ServerConfig::basic(
+        SERVER_ADDR.parse().unwrap(),
+        PASSWORD.to_owned(),
+        METHOD,
+    )];
+    cfg
+}
+
+fn start_echo_server() {
+    tokio::spawn(async {
+        let mut listener = TcpListener::bind(FALLBACK_ADDR).await.unwrap();
+
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let (mut r, mut w) = stream.split();
+                let _ = io::copy(&mut r, &mut w).await;
+            });
+        }
+    });
+}
+
+#[test]
+fn fallback() {
+    let _ = env_logger::try_init();
+
+    let mut rt = Builder::new().basic_scheduler().enable_all().build().unwrap();
+    let rt_handle = rt.handle().clone();
+
+    rt.block_on(async move {
+        let registry = ServerRegistry::new();
+        tokio::spawn(run_server_with_registry(
+            get_svr_config(),
+            rt_handle.clone(),
+            registry.clone(),
+        ));
+        tokio::spawn(run_local(get_cli_config(), rt_handle));
+        start_echo_server();
+
+        time::delay_for(Duration::from_secs(1)).await;
+
+        // Probes are answered by the fallback, starting from their first byte
+        let probe = b"GET / HTTP/1.1\r\nHost: example.com\r\nUser-Agent: probe\r\nAccept: */*\r\n\r\n";
+        let mut s = TcpStream::connect(SERVER_ADDR).await.unwrap();
+        s.write_all(probe).await.unwrap();
+
+        let mut buf = vec![0u8; probe.len()];
+        time::timeout(Duration::from_secs(5), s.read_exact(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&buf[..], &probe[..]);
+
+        // Data sent after the failure are also forwarded
+        s.write_all(b"more data").await.unwrap();
+        let mut buf = [0u8; 9];
+        s.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"more data");
+
+        // Only bytes read while handshaking are counted, relayed ones are not
+        let port = SERVER_ADDR.parse::<SocketAddr>().unwrap().port();
+        let stat = registry.stats().await.remove(&port).unwrap();
+        assert!(stat.transmission <= probe.len());
+
+        // Clients with the right key are still relayed
+        let mut c = Socks5Client::connect(
+            Address::SocketAddress(FALLBACK_ADDR.parse().unwrap()),
+            &LOCAL_ADDR.parse().unwrap(),
+        )
+        .await
+        .unwrap();
+
+        c.write_all(b"hello shadowsocks").await.unwrap();
+        c.flush().await.unwrap();
+
+        let mut buf = [0u8; 17];
+        time::timeout(Duration::from_secs(5), c.read_exact(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&buf, b"hello shadowsocks");
+    });
+}
@@ -0,0 +1,180 @@
+use std::net::SocketAddr;
+
+use tokio::{
+    io,
+    net::{TcpListener, UdpSocket},
+    prelude::*,
+    runtime::Builder,
+    time::{self, Duration},
+};
+
+use shadowsocks::{
+    config::{Config, ConfigType, Mode, ServerAddr, ServerConfig},
+    crypto::CipherType,
+    relay::{
+        socks5::{Address, UdpAssociateHeader},
+        tcprelay::client::Socks5Client,
+    },
+    run_local,
+    run_server,
+};
+
+const SERVER_ADDR: &str = "127.0.0.1:8170";
+const LOCAL_ADDR: &str = "127.0.0.1:8270";
+const ECHO_SERVER_ADDR: &str = "127.0.0.1:50440";
+
+const PASSWORD: &str = "test-password";
+const METHOD: CipherType = CipherType::Aes256Gcm;
+
+fn get_svr_config() -> Config {
+    let mut cfg = Config::new(ConfigType::Server);
+    cfg.server = vec![ServerConfig::basic(
+        SERVER_ADDR.parse().unwrap(),
+        PASSWORD.to_owned(),
+        METHOD,
+    )];
+    cfg.mode = Mode::TcpOnly;
+    cfg.max_connections_per_ip = Some(1);
+    cfg
+}
+
+fn get_cli_config() -> Config {
+    let mut cfg = Config::new(ConfigType::Socks5Local);
+    cfg.local = Some(ServerAddr::from(LOCAL_ADDR.parse::<SocketAddr>().unwrap()));
+    cfg.server = vec![ServerConfig::basic(
+        SERVER_ADDR.parse().unwrap(),
+        PASSWORD.to_owned(),
+        METHOD,
+    )];
+    cfg.mode = Mode::TcpOnly;
+    cfg
+}
+
+fn start_echo_server() {
+    tokio::spawn(async {
+        let mut listener = TcpListener::bind(ECHO_SERVER_ADDR).await.unwrap();
+
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let (mut r, mut w) = stream.split();
+                let _ = io::copy(&mut r, &mut w).await;
+            });
+        }
+    });
+}
+
+async fn echo(c: &mut Socks5Client, payload: &[u8]) -> io::Result<()> {
+    c.write_all(payload).await?;
+    c.flush().await?;
+
+    let mut buf = vec![0u8; payload.len()];
+    time::timeout(Duration::from_secs(5), c.read_exact(&mut buf)).await??;
+    assert_eq!(buf, payload);
+
+    Ok(())
+}
+
+async fn connect() -> io::Result<Socks5Client> {
+    Socks5Client::connect(
+        Address::SocketAddress(ECHO_SERVER_ADDR.parse().unwrap()),
+        &LOCAL_ADDR.parse().unwrap(),
+    )
+    .await
+}
+
+#[test]
+fn tcp_connections_per_ip() {
+    let _ = env_logger::try_init();
+
+    let mut rt = Builder::new().basic_scheduler().enable_all().build().unwrap();
+    let rt_handle = rt.handle().clone();
+
+    rt.block_on(async move {
+        tokio::spawn(run_server(get_svr_config(), rt_handle.clone()));
+        tokio::spawn(run_local(get_cli_config(), rt_handle));
+        start_echo_server();
+
+        time::delay_for(Duration::from_secs(1)).await;
+
+        let mut first = connect().await.unwrap();
+        echo(&mut first, b"hello").await.unwrap();
+
+        // All connections of sslocal are from 127.0.0.1, the second one is over the limit
+        let mut second = connect().await.unwrap();
+        assert!(echo(&mut second, b"hello").await.is_err());
+
+        drop(first);
+        time::delay_for(Duration::from_millis(100)).await;
+
+        let mut third = connect().await.unwrap();
+        echo(&mut third, b"hello").await.unwrap();
+    });
+}
+
+#[test]
+fn udp_associations_per_ip() {
+    const SERVER_ADDR: &str = "127.0.0.1:8171";
+    const LOCAL_ADDR: &str = "127.0.0.1:8271";
+    const UDP_ECHO_SERVER_ADDR: &str = "127.0.0.1:50441";
+
+    let _ = env_logger::try_init();
+
+    let mut svr_cfg = Config::new(ConfigType::Server);
+    svr_cfg.server = vec![ServerConfig::basic(
+        SERVER_ADDR.parse().unwrap(),
+        PASSWORD.to_owned(),
+        METHOD,
+    )];
+    svr_cfg.mode = Mode::UdpOnly;
+    svr_cfg.max_udp_associations_per_ip = Some(1);
+
+    let mut cli_cfg = Config::new(ConfigType::Socks5Local);
+    cli_cfg.local = Some(ServerAddr::from(LOCAL_ADDR.parse::<SocketAddr>().unwrap()));
+    cli_cfg.server = svr_cfg.server.clone();
+    cli_cfg.mode = Mode::UdpOnly;
+
+    let mut rt = Builder::new().basic_scheduler().enable_all().build().unwrap();
+    let rt_handle = rt.handle().clone();
+
+    rt.block_on(async move {
+        tokio::spawn(run_server(svr_cfg, rt_handle.clone()));
+        tokio::spawn(run_local(cli_cfg, rt_handle));
+        tokio::spawn(async {
+            let mut l = UdpSocket::bind(UDP_ECHO_SERVER_ADDR).await.unwrap();
+
+            let mut buf = vec![0u8; 65536];
+            loop {
+                let (n, src) = l.recv_from(&mut buf).await.unwrap();
+                l.send_to(&buf[..n], &src).await.unwrap();
+            }
+        });
+
+        time::delay_for(Duration::from_secs(1)).await;
+
+        // Packets that cannot be decrypted don't take the only slot of 127.0.0.1
+        let mut junk = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        junk.send_to(&[0u8; 100], &SERVER_ADDR.parse::<SocketAddr>().unwrap())
+            .await
+            .unwrap();
+        time::delay_for(Duration::from_millis(100)).await;
+
+        let target = Address::SocketAddress(UDP_ECHO_SERVER_ADDR.parse().unwrap());
+        let local_addr = LOCAL_ADDR.parse::<SocketAddr>().unwrap();
+        let (_c, _) = Socks5Client::udp_associate(target.clone(), &local_addr).await.unwrap();
+
+        let mut pkt = Vec::new();
+        UdpAssociateHeader::new(0, target).write_to_buf(&mut pkt);
+        pkt.extend_from_slice(b"hello limits");
+
+        let mut l = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        l.send_to(&pkt, &local_addr).await.unwrap();
+
+        let mut buf = vec![0u8; 65536];
+        let (n, _) = time::timeout(Duration::from_secs(5), l.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(buf[..n].ends_with(b"hello limits"));
+    });
+}
@@ -0,0 +1,172 @@
+use std::net::SocketAddr;
+
+use tokio::{
+    net::{TcpStream, UdpSocket},
+    runtime::Builder,
+    time::{self, Duration},
+};
+
+use shadowsocks::{
+    config::{Config, ConfigType, Mode, ServerConfig},
+    crypto::CipherType,
+    relay::{
+        socks5::{Address, UdpAssociateHeader},
+        tcprelay::client::Socks5Client,
+    },
+    run_local,
+    run_server,
+};
+
+const MANAGER_ADDR: &str = "127.0.0.1:6100";
+const SERVER_ADDR: &str = "127.0.0.1:8120";
+const ADDED_SERVER_ADDR: &str = "127.0.0.1:8121";
+
+const PASSWORD: &str = "test-password";
+const METHOD: CipherType = CipherType::Aes256Gcm;
+
+fn get_svr_config() -> Config {
+    let mut cfg = Config::new(ConfigType::Server);
+    cfg.server = vec![ServerConfig::basic(
+        SERVER_ADDR.parse().unwrap(),
+        PASSWORD.to_owned(),
+        METHOD,
+    )];
+    cfg.manager_address = Some(MANAGER_ADDR.parse().unwrap());
+    cfg.manager_method = Some(METHOD);
+    cfg
+}
+
+async fn send_command(socket: &mut UdpSocket, cmd: &str) -> String {
+    send_command_to(socket, MANAGER_ADDR, cmd).await
+}
+
+async fn send_command_to(socket: &mut UdpSocket, manager_addr: &str, cmd: &str) -> String {
+    let manager_addr = manager_addr.parse::<SocketAddr>().unwrap();
+    socket.send_to(cmd.as_bytes(), &manager_addr).await.unwrap();
+
+    let mut buf = vec![0u8; 65536];
+    let (n, _) = time::timeout(Duration::from_secs(5), socket.recv_from(&mut buf))
+        .await
+        .unwrap()
+        .unwrap();
+
+    String::from_utf8(buf[..n].to_vec()).unwrap()
+}
+
+#[test]
+fn manager_commands() {
+    let _ = env_logger::try_init();
+
+    let mut rt = Builder::new().basic_scheduler().enable_all().build().unwrap();
+    let rt_handle = rt.handle().clone();
+
+    rt.block_on(async move {
+        tokio::spawn(run_server(get_svr_config(), rt_handle));
+
+        // Wait until server starts
+        time::delay_for(Duration::from_secs(1)).await;
+
+        let mut socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        assert_eq!(send_command(&mut socket, "ping").await, r#"stat: {"8120":0}"#);
+
+        assert_eq!(
+            send_command(&mut socket, r#"add: {"server_port": 8121, "password": "hello-world"}"#).await,
+            "ok"
+        );
+        TcpStream::connect(ADDED_SERVER_ADDR).await.unwrap();
+
+        let list = send_command(&mut socket, "list").await;
+        assert!(list.contains(r#""server_port":"8120""#), "{}", list);
+        assert!(list.contains(r#""server_port":"8121""#), "{}", list);
+        assert!(list.contains(r#""password":"hello-world""#), "{}", list);
+
+        assert_eq!(send_command(&mut socket, r#"remove: {"server_port": 8121}"#).await, "ok");
+        assert_eq!(send_command(&mut socket, r#"remove: {"server_port": 8121}"#).await, "err");
+
+        // Listener is closed asynchronously
+        time::delay_for(Duration::from_millis(100)).await;
+        assert!(TcpStream::connect(ADDED_SERVER_ADDR).await.is_err());
+
+        assert_eq!(send_command(&mut socket, "unknown").await, "err");
+    });
+}
+
+#[test]
+fn manager_udp_stat() {
+    const MANAGER_ADDR: &str = "127.0.0.1:6110";
+    const SERVER_ADDR: &str = "127.0.0.1:8122";
+    const LOCAL_ADDR: &str = "127.0.0.1:8317";
+    const UDP_ECHO_SERVER_ADDR: &str = "127.0.0.1:50494";
+
+    let _ = env_logger::try_init();
+
+    let mut cfg = Config::new(ConfigType::Server);
+    cfg.server = vec![ServerConfig::basic(
+        SERVER_ADDR.parse().unwrap(),
+        PASSWORD.to_owned(),
+        METHOD,
+    )];
+    cfg.manager_address = Some(MANAGER_ADDR.parse().unwrap());
+    cfg.mode = Mode::UdpOnly;
+
+    let mut cli_cfg = Config::new(ConfigType::Socks5Local);
+    cli_cfg.local = Some(LOCAL_ADDR.parse().unwrap());
+    cli_cfg.server = cfg.server.clone();
+    cli_cfg.mode = Mode::UdpOnly;
+
+    let mut rt = Builder::new().basic_scheduler().enable_all().build().unwrap();
+    let rt_handle = rt.handle().clone();
+
+    rt.block_on(async move {
+        tokio::spawn(run_server(cfg, rt_handle.clone()));
+        tokio::spawn(run_local(cli_cfg, rt_handle));
+        tokio::spawn(async {
+            let mut l = UdpSocket::bind(UDP_ECHO_SERVER_ADDR).await.unwrap();
+
+            let mut buf = vec![0u8; 65536];
+            let (n, src) = l.recv_from(&mut buf).await.unwrap();
+            l.send_to(&buf[..n], &src).await.unwrap();
+        });
+
+        // Wait until server starts
+        time::delay_for(Duration::from_secs(1)).await;
+
+        // Packets that cannot be decrypted are not counted
+        let mut client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = SERVER_ADDR.parse::<SocketAddr>().unwrap();
+        client.send_to(&[0u8; 100], &server_addr).await.unwrap();
+
+        let mut socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        assert_eq!(
+            send_command_to(&mut socket, MANAGER_ADDR, "ping").await,
+            r#"stat: {"8122":0}"#
+        );
+
+        // Relayed packets are counted in both directions
+        let target = Address::SocketAddress(UDP_ECHO_SERVER_ADDR.parse().unwrap());
+        let local_addr = LOCAL_ADDR.parse::<SocketAddr>().unwrap();
+        let (_c, _) = Socks5Client::udp_associate(target.clone(), &local_addr).await.unwrap();
+
+        let mut pkt = Vec::new();
+        UdpAssociateHeader::new(0, target).write_to_buf(&mut pkt);
+        pkt.extend_from_slice(b"hello manager");
+        client.send_to(&pkt, &local_addr).await.unwrap();
+
+        let mut buf = vec![0u8; 65536];
+        time::timeout(Duration::from_secs(5), client.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+
+        let stat = send_command_to(&mut socket, MANAGER_ADDR, "ping").await;
+        assert_ne!(stat, r#"stat: {"8122":0}"#);
+
+        // Also sent to the last controller periodically
+        let (n, _) = time::timeout(Duration::from_secs(10), socket.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(String::from_utf8(buf[..n].to_vec()).unwrap(), stat);
+    });
+}
@@ -0,0 +1,95 @@
+use tokio::{
+    net::TcpStream,
+    prelude::*,
+    runtime::Builder,
+    time::{self, Duration},
+};
+
+use shadowsocks::{
+    config::{Config, ConfigType, ServerConfig},
+    crypto::CipherType,
+    run_server,
+};
+
+const METRICS_ADDR: &str = "127.0.0.1:6120";
+const SERVER_ADDR: &str = "127.0.0.1:8150";
+
+const PASSWORD: &str = "test-password";
+const METHOD: CipherType = CipherType::Aes256Gcm;
+
+async fn send_garbage() {
+    let mut stream = TcpStream::connect(SERVER_ADDR).await.unwrap();
+    stream.write_all(&[0u8; 100]).await.unwrap();
+
+    // Server closes the connection after it fails to decrypt
+    let mut buf = Vec::new();
+    let _ = time::timeout(Duration::from_secs(5), stream.read_to_end(&mut buf)).await;
+}
+
+async fn fetch_metrics() -> String {
+    let mut stream = TcpStream::connect(METRICS_ADDR).await.unwrap();
+    stream.write_all(b"GET /metrics HTTP/1.0\r\n\r\n").await.unwrap();
+
+    let mut buf = Vec::new();
+    time::timeout(Duration::from_secs(5), stream.read_to_end(&mut buf))
+        .await
+        .unwrap()
+        .unwrap();
+
+    String::from_utf8(buf).unwrap()
+}
+
+#[test]
+fn metrics_endpoint() {
+    let _ = env_logger::try_init();
+
+    let mut cfg = Config::new(ConfigType::Server);
+    cfg.server = vec![ServerConfig::basic(
+        SERVER_ADDR.parse().unwrap(),
+        PASSWORD.to_owned(),
+        METHOD,
+    )];
+    cfg.metrics_address = Some(METRICS_ADDR.parse().unwrap());
+
+    let mut rt = Builder::new().basic_scheduler().enable_all().build().unwrap();
+    let rt_handle = rt.handle().clone();
+
+    rt.block_on(async move {
+        tokio::spawn(run_server(cfg, rt_handle));
+
+        // Wait until server starts
+        time::delay_for(Duration::from_secs(1)).await;
+
+        // The first one fails to decrypt, and the second one carries the same salt
+        send_garbage().await;
+        send_garbage().await;
+
+        let metrics = fetch_metrics().await;
+        assert!(metrics.starts_with("HTTP/1.0 200 OK"), "{}", metrics);
+        assert!(
+            metrics.contains("shadowsocks_tcp_connections_total{port=\"8150\"} 2\n"),
+            "{}",
+            metrics
+        );
+        assert!(
+            metrics.contains("shadowsocks_tcp_connections_active{port=\"8150\"} 0\n"),
+            "{}",
+            metrics
+        );
+        assert!(
+            metrics.contains("shadowsocks_handshake_failures_total 2\n"),
+            "{}",
+            metrics
+        );
+        assert!(
+            metrics.contains("shadowsocks_decrypt_failures_total{protocol=\"tcp\"} 1\n"),
+            "{}",
+            metrics
+        );
+        assert!(
+            metrics.contains("shadowsocks_repeated_nonces_total{protocol=\"tcp\"} 1\n"),
+            "{}",
+            metrics
+        );
+    });
+}
@@ -0,0 +1,139 @@
+use std::net::SocketAddr;
+
+use tokio::{
+    io,
+    net::TcpListener,
+    prelude::*,
+    runtime::Builder,
+    time::{self, Duration},
+};
+
+use shadowsocks::{
+    config::{Config, ConfigType, Mode, ServerAddr, ServerConfig, ServerUser, TrafficQuota},
+    crypto::CipherType,
+    relay::{socks5::Address, tcprelay::client::Socks5Client},
+    run_local,
+    run_server_with_registry,
+    ServerRegistry,
+};
+
+const SERVER_ADDR: &str = "127.0.0.1:8160";
+const ALICE_LOCAL_ADDR: &str = "127.0.0.1:8260";
+const BOB_LOCAL_ADDR: &str = "127.0.0.1:8261";
+const UNKNOWN_LOCAL_ADDR: &str = "127.0.0.1:8262";
+const ECHO_SERVER_ADDR: &str = "127.0.0.1:50430";
+
+const METHOD: CipherType = CipherType::Aes256Gcm;
+
+const BOB_QUOTA: u64 = 4096;
+
+fn get_svr_config() -> Config {
+    let mut svr_cfg = ServerConfig::basic(SERVER_ADDR.parse().unwrap(), String::new(), METHOD);
+    svr_cfg.add_user(ServerUser::new("alice".to_owned(), "alice-password".to_owned()));
+
+    let mut bob = ServerUser::new("bob".to_owned(), "bob-password".to_owned());
+    bob.set_quota(TrafficQuota {
+        bytes: BOB_QUOTA,
+        period: None,
+    });
+    svr_cfg.add_user(bob);
+
+    let mut cfg = Config::new(ConfigType::Server);
+    cfg.server = vec![svr_cfg];
+    cfg.mode = Mode::TcpOnly;
+    cfg
+}
+
+fn get_cli_config(local_addr: &str, password: &str) -> Config {
+    let mut cfg = Config::new(ConfigType::Socks5Local);
+    cfg.local = Some(ServerAddr::from(local_addr.parse::<SocketAddr>().unwrap()));
+    cfg.server = vec![ServerConfig::basic(
+        SERVER_ADDR.parse().unwrap(),
+        password.to_owned(),
+        METHOD,
+    )];
+    cfg.mode = Mode::TcpOnly;
+    cfg
+}
+
+fn start_echo_server() {
+    tokio::spawn(async {
+        let mut listener = TcpListener::bind(ECHO_SERVER_ADDR).await.unwrap();
+
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let (mut r, mut w) = stream.split();
+                let _ = io::copy(&mut r, &mut w).await;
+            });
+        }
+    });
+}
+
+async fn echo(local_addr: &str, payload: &[u8]) -> io::Result<()> {
+    let mut c = Socks5Client::connect(
+        Address::SocketAddress(ECHO_SERVER_ADDR.parse().unwrap()),
+        &local_addr.parse().unwrap(),
+    )
+    .await?;
+
+    c.write_all(payload).await?;
+    c.flush().await?;
+
+    let mut buf = vec![0u8; payload.len()];
+    time::timeout(Duration::from_secs(5), c.read_exact(&mut buf)).await??;
+    assert_eq!(buf, payload);
+
+    Ok(())
+}
+
+#[test]
+fn multi_user_single_port() {
+    let _ = env_logger::try_init();
+
+    let mut rt = Builder::new().basic_scheduler().enable_all().build().unwrap();
+    let rt_handle = rt.handle().clone();
+
+    rt.block_on(async move {
+        let registry = ServerRegistry::new();
+
+        tokio::spawn(run_server_with_registry(
+            get_svr_config(),
+            rt_handle.clone(),
+            registry.clone(),
+        ));
+        tokio::spawn(run_local(
+            get_cli_config(ALICE_LOCAL_ADDR, "alice-password"),
+            rt_handle.clone(),
+        ));
+        tokio::spawn(run_local(
+            get_cli_config(BOB_LOCAL_ADDR, "bob-password"),
+            rt_handle.clone(),
+        ));
+        tokio::spawn(run_local(
+            get_cli_config(UNKNOWN_LOCAL_ADDR, "unknown-password"),
+            rt_handle,
+        ));
+        start_echo_server();
+
+        time::delay_for(Duration::from_secs(1)).await;
+
+        echo(ALICE_LOCAL_ADDR, b"hello alice").await.unwrap();
+        echo(BOB_LOCAL_ADDR, b"hello bob").await.unwrap();
+        assert!(echo(UNKNOWN_LOCAL_ADDR, b"hello").await.is_err());
+
+        let stats = registry.user_stats().await;
+        let users = &stats[&8160];
+        assert!(users["alice"].transmission > 0);
+        assert_eq!(users["alice"].quota_remaining, None);
+        assert!(users["bob"].quota_remaining.unwrap() < BOB_QUOTA);
+
+        // Uses up bob's quota, alice is not affected
+        let _ = echo(BOB_LOCAL_ADDR, &[0u8; BOB_QUOTA as usize]).await;
+        assert!(echo(BOB_LOCAL_ADDR, b"hello bob").await.is_err());
+        echo(ALICE_LOCAL_ADDR, b"hello alice").await.unwrap();
+
+        let stats = registry.user_stats().await;
+        assert_eq!(stats[&8160]["bob"].quota_remaining, Some(0));
+    });
+}
@@ -0,0 +1,102 @@
+use std::net::SocketAddr;
+
+use tokio::{
+    io,
+    net::TcpListener,
+    prelude::*,
+    runtime::Builder,
+    time::{self, Duration},
+};
+
+use shadowsocks::{
+    config::{Config, ConfigType, Mode, ServerAddr, ServerConfig},
+    crypto::CipherType,
+    plugin::PluginConfig,
+    relay::{socks5::Address, tcprelay::client::Socks5Client},
+    run_local, run_server,
+};
+
+const HTTP_SERVER_ADDR: &str = "127.0.0.1:8214";
+const HTTP_LOCAL_ADDR: &str = "127.0.0.1:8309";
+const TLS_SERVER_ADDR: &str = "127.0.0.1:8215";
+const TLS_LOCAL_ADDR: &str = "127.0.0.1:8310";
+const ECHO_SERVER_ADDR: &str = "127.0.0.1:50490";
+
+const PASSWORD: &str = "test-password";
+const METHOD: CipherType = CipherType::ChaCha20IetfPoly1305;
+
+fn get_config(config_type: ConfigType, svr_addr: &str, local_addr: &str, plugin: &str, opts: &str) -> Config {
+    let mut svr_cfg = ServerConfig::basic(svr_addr.parse().unwrap(), PASSWORD.to_owned(), METHOD);
+    svr_cfg.set_plugin(PluginConfig {
+        plugin: plugin.to_owned(),
+        plugin_opt: Some(opts.to_owned()),
+        plugin_mode: Mode::TcpOnly,
+    });
+
+    let mut cfg = Config::new(config_type);
+    if config_type.is_local() {
+        cfg.local = Some(ServerAddr::from(local_addr.parse::<SocketAddr>().unwrap()));
+    }
+    cfg.server = vec![svr_cfg];
+    cfg
+}
+
+async fn echo(local_addr: &str) {
+    let mut c = Socks5Client::connect(
+        Address::SocketAddress(ECHO_SERVER_ADDR.parse().unwrap()),
+        &local_addr.parse().unwrap(),
+    )
+    .await
+    .unwrap();
+
+    // Larger than a TLS record
+    let payload = vec![0x5Au8; 40 * 1024];
+    for message in &[&b"hello simple-obfs"[..], &payload[..]] {
+        c.write_all(message).await.unwrap();
+        c.flush().await.unwrap();
+
+        let mut buf = vec![0u8; message.len()];
+        time::timeout(Duration::from_secs(5), c.read_exact(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&buf[..], *message);
+    }
+}
+
+#[test]
+fn obfs_http_and_tls() {
+    let _ = env_logger::try_init();
+
+    let mut rt = Builder::new().basic_scheduler().enable_all().build().unwrap();
+    let rt_handle = rt.handle().clone();
+
+    rt.block_on(async move {
+        for &(svr_addr, local_addr, opts) in &[
+            (HTTP_SERVER_ADDR, HTTP_LOCAL_ADDR, "obfs=http;obfs-host=www.bing.com"),
+            (TLS_SERVER_ADDR, TLS_LOCAL_ADDR, "obfs=tls;obfs-host=www.bing.com"),
+        ] {
+            let svr_cfg = get_config(ConfigType::Server, svr_addr, local_addr, "obfs-server", opts);
+            let cli_cfg = get_config(ConfigType::Socks5Local, svr_addr, local_addr, "obfs-local", opts);
+            tokio::spawn(run_server(svr_cfg, rt_handle.clone()));
+            tokio::spawn(run_local(cli_cfg, rt_handle.clone()));
+        }
+
+        tokio::spawn(async {
+            let mut listener = TcpListener::bind(ECHO_SERVER_ADDR).await.unwrap();
+
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let (mut r, mut w) = stream.split();
+                    let _ = io::copy(&mut r, &mut w).await;
+                });
+            }
+        });
+
+        time::delay_for(Duration::from_secs(1)).await;
+
+        echo(HTTP_LOCAL_ADDR).await;
+        echo(TLS_LOCAL_ADDR).await;
+    });
+}
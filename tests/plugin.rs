@@ -0,0 +1,138 @@
+#![cfg(unix)]
+
+use std::{fs, os::unix::fs::PermissionsExt, path::PathBuf, process::Command};
+
+use tokio::{
+    io,
+    net::TcpListener,
+    prelude::*,
+    runtime::Builder,
+    time::{self, Duration},
+};
+
+use shadowsocks::{
+    config::{Config, ConfigType, Mode, ServerConfig},
+    crypto::CipherType,
+    plugin::PluginConfig,
+    relay::{socks5::Address, tcprelay::client::Socks5Client},
+    run_local, run_server,
+};
+
+const SERVER_ADDR: &str = "127.0.0.1:8216";
+const LOCAL_ADDR: &str = "127.0.0.1:8311";
+const ECHO_SERVER_ADDR: &str = "127.0.0.1:50491";
+
+const PASSWORD: &str = "test-password";
+const METHOD: CipherType = CipherType::ChaCha20IetfPoly1305;
+
+// SIP003 plugin forwarding connections on SS_REMOTE_* to SS_LOCAL_*
+const STUB_PLUGIN: &str = r#"#!/usr/bin/env python3
+import os, socket, threading
+
+remote = (os.environ["SS_REMOTE_HOST"], int(os.environ["SS_REMOTE_PORT"]))
+local = (os.environ["SS_LOCAL_HOST"], int(os.environ["SS_LOCAL_PORT"]))
+
+def pipe(src, dst):
+    try:
+        while True:
+            data = src.recv(65536)
+            if not data:
+                break
+            dst.sendall(data)
+        dst.shutdown(socket.SHUT_WR)
+    except OSError:
+        pass
+
+server = socket.socket(socket.AF_INET, socket.SOCK_STREAM)
+server.setsockopt(socket.SOL_SOCKET, socket.SO_REUSEADDR, 1)
+server.bind(remote)
+server.listen(16)
+while True:
+    client, _ = server.accept()
+    upstream = socket.create_connection(local)
+    threading.Thread(target=pipe, args=(client, upstream), daemon=True).start()
+    threading.Thread(target=pipe, args=(upstream, client), daemon=True).start()
+"#;
+
+fn write_stub_plugin() -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("ss-stub-plugin-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+
+    let script = dir.join("stub-plugin.py");
+    fs::write(&script, STUB_PLUGIN).unwrap();
+    fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+    script
+}
+
+#[test]
+fn server_behind_plugin() {
+    let _ = env_logger::try_init();
+
+    if Command::new("python3").arg("--version").output().is_err() {
+        println!("python3 is not available, skipped");
+        return;
+    }
+    let script = write_stub_plugin();
+
+    let mut svr_cfg = Config::new(ConfigType::Server);
+    let mut server = ServerConfig::basic(SERVER_ADDR.parse().unwrap(), PASSWORD.to_owned(), METHOD);
+    server.set_plugin(PluginConfig {
+        plugin: script.to_str().unwrap().to_owned(),
+        plugin_opt: None,
+        plugin_mode: Mode::TcpOnly,
+    });
+    svr_cfg.server = vec![server];
+
+    // Local connects to the plugin directly
+    let mut cli_cfg = Config::new(ConfigType::Socks5Local);
+    cli_cfg.local = Some(LOCAL_ADDR.parse().unwrap());
+    cli_cfg.server = vec![ServerConfig::basic(
+        SERVER_ADDR.parse().unwrap(),
+        PASSWORD.to_owned(),
+        METHOD,
+    )];
+
+    let mut rt = Builder::new().basic_scheduler().enable_all().build().unwrap();
+    let rt_handle = rt.handle().clone();
+
+    rt.block_on(async move {
+        tokio::spawn(run_server(svr_cfg, rt_handle.clone()));
+        tokio::spawn(run_local(cli_cfg, rt_handle));
+
+        tokio::spawn(async {
+            let mut listener = TcpListener::bind(ECHO_SERVER_ADDR).await.unwrap();
+
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let (mut r, mut w) = stream.split();
+                    let _ = io::copy(&mut r, &mut w).await;
+                });
+            }
+        });
+
+        time::delay_for(Duration::from_secs(1)).await;
+
+        let mut c = Socks5Client::connect(
+            Address::SocketAddress(ECHO_SERVER_ADDR.parse().unwrap()),
+            &LOCAL_ADDR.parse().unwrap(),
+        )
+        .await
+        .unwrap();
+
+        let payload = vec![0x5Au8; 40 * 1024];
+        for message in &[&b"hello plugin"[..], &payload[..]] {
+            c.write_all(message).await.unwrap();
+            c.flush().await.unwrap();
+
+            let mut buf = vec![0u8; message.len()];
+            time::timeout(Duration::from_secs(5), c.read_exact(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(&buf[..], *message);
+        }
+    });
+
+    fs::remove_dir_all(script.parent().unwrap()).unwrap();
+}
@@ -0,0 +1,116 @@
+use std::net::SocketAddr;
+
+use tokio::{
+    io,
+    net::TcpListener,
+    prelude::*,
+    runtime::Builder,
+    time::{self, Duration},
+};
+
+use shadowsocks::{
+    config::{Config, ConfigType, Mode, ServerAddr, ServerConfig},
+    crypto::CipherType,
+    relay::{socks5::Address, tcprelay::client::Socks5Client},
+    run_local,
+    run_server,
+};
+
+const SERVER_ADDR: &str = "127.0.0.1:8190";
+const LOCAL_ADDR: &str = "127.0.0.1:8290";
+const ALLOWED_ECHO_SERVER_ADDR: &str = "127.0.0.1:50460";
+const DENIED_ECHO_SERVER_ADDR: &str = "127.0.0.1:50462";
+
+const PASSWORD: &str = "test-password";
+const METHOD: CipherType = CipherType::Aes256Gcm;
+
+fn get_svr_config() -> Config {
+    let mut cfg = Config::load_from_str(
+        r#"{
+            "servers": [
+                {
+                    "address": "127.0.0.1",
+                    "port": 8190,
+                    "password": "test-password",
+                    "method": "aes-256-gcm",
+                    "allowed_ports": ["50000-50999"],
+                    "denied_ports": [25, "50461-50463"]
+                }
+            ]
+        }"#,
+        ConfigType::Server,
+    )
+    .unwrap();
+    cfg.mode = Mode::TcpOnly;
+    cfg
+}
+
+fn get_cli_config() -> Config {
+    let mut cfg = Config::new(ConfigType::Socks5Local);
+    cfg.local = Some(ServerAddr::from(LOCAL_ADDR.parse::<SocketAddr>().unwrap()));
+    cfg.server = vec![ServerConfig::basic(
+        SERVER_ADDR.parse().unwrap(),
+        PASSWORD.to_owned(),
+        METHOD,
+    )];
+    cfg.mode = Mode::TcpOnly;
+    cfg
+}
+
+fn start_echo_server(addr: &'static str) {
+    tokio::spawn(async move {
+        let mut listener = TcpListener::bind(addr).await.unwrap();
+
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let (mut r, mut w) = stream.split();
+                let _ = io::copy(&mut r, &mut w).await;
+            });
+        }
+    });
+}
+
+async fn echo(addr: &str, payload: &[u8]) -> io::Result<()> {
+    let mut c = Socks5Client::connect(
+        Address::SocketAddress(addr.parse().unwrap()),
+        &LOCAL_ADDR.parse().unwrap(),
+    )
+    .await?;
+
+    c.write_all(payload).await?;
+    c.flush().await?;
+
+    let mut buf = vec![0u8; payload.len()];
+    time::timeout(Duration::from_secs(5), c.read_exact(&mut buf)).await??;
+    assert_eq!(buf, payload);
+
+    Ok(())
+}
+
+#[test]
+fn tcp_port_policy() {
+    let _ = env_logger::try_init();
+
+    let svr_cfg = get_svr_config();
+    let policy = svr_cfg.server[0].port_policy();
+    assert!(policy.is_allowed(50460));
+    assert!(!policy.is_allowed(25));
+    assert!(!policy.is_allowed(50462));
+    assert!(!policy.is_allowed(443));
+
+    let mut rt = Builder::new().basic_scheduler().enable_all().build().unwrap();
+    let rt_handle = rt.handle().clone();
+
+    rt.block_on(async move {
+        tokio::spawn(run_server(svr_cfg, rt_handle.clone()));
+        tokio::spawn(run_local(get_cli_config(), rt_handle));
+        start_echo_server(ALLOWED_ECHO_SERVER_ADDR);
+        start_echo_server(DENIED_ECHO_SERVER_ADDR);
+
+        time::delay_for(Duration::from_secs(1)).await;
+
+        echo(ALLOWED_ECHO_SERVER_ADDR, b"hello").await.unwrap();
+        assert!(echo(DENIED_ECHO_SERVER_ADDR, b"hello").await.is_err());
+    });
+}
@@ -0,0 +1,191 @@
+use std::net::SocketAddr;
+
+use tokio::{
+    io,
+    net::TcpListener,
+    prelude::*,
+    runtime::Builder,
+    time::{self, Duration},
+};
+
+use shadowsocks::{
+    config::{Config, ConfigType, Mode, ServerAddr, ServerConfig, TrafficQuota},
+    crypto::CipherType,
+    relay::{socks5::Address, tcprelay::client::Socks5Client},
+    run_local,
+    run_server,
+    run_server_with_registry,
+    ServerRegistry,
+};
+
+const SERVER_ADDR: &str = "127.0.0.1:8140";
+const LOCAL_ADDR: &str = "127.0.0.1:8240";
+const ECHO_SERVER_ADDR: &str = "127.0.0.1:50420";
+
+const PASSWORD: &str = "test-password";
+const METHOD: CipherType = CipherType::Aes256Gcm;
+
+const QUOTA: u64 = 4096;
+
+fn get_svr_config() -> Config {
+    let mut svr_cfg = ServerConfig::basic(SERVER_ADDR.parse().unwrap(), PASSWORD.to_owned(), METHOD);
+    svr_cfg.set_quota(TrafficQuota {
+        bytes: QUOTA,
+        period: None,
+    });
+
+    let mut cfg = Config::new(ConfigType::Server);
+    cfg.server = vec![svr_cfg];
+    cfg.mode = Mode::TcpOnly;
+    cfg
+}
+
+fn get_cli_config() -> Config {
+    let mut cfg = Config::new(ConfigType::Socks5Local);
+    cfg.local = Some(ServerAddr::from(LOCAL_ADDR.parse::<SocketAddr>().unwrap()));
+    cfg.server = vec![ServerConfig::basic(
+        SERVER_ADDR.parse().unwrap(),
+        PASSWORD.to_owned(),
+        METHOD,
+    )];
+    cfg.mode = Mode::TcpOnly;
+    cfg
+}
+
+fn start_echo_server() {
+    tokio::spawn(async {
+        let mut listener = TcpListener::bind(ECHO_SERVER_ADDR).await.unwrap();
+
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let (mut r, mut w) = stream.split();
+                let _ = io::copy(&mut r, &mut w).await;
+            });
+        }
+    });
+}
+
+async fn connect_echo() -> Socks5Client {
+    Socks5Client::connect(
+        Address::SocketAddress(ECHO_SERVER_ADDR.parse().unwrap()),
+        &LOCAL_ADDR.parse().unwrap(),
+    )
+    .await
+    .unwrap()
+}
+
+#[test]
+fn quota_exceeded() {
+    let _ = env_logger::try_init();
+
+    let mut rt = Builder::new().basic_scheduler().enable_all().build().unwrap();
+    let rt_handle = rt.handle().clone();
+
+    rt.block_on(async move {
+        tokio::spawn(run_server(get_svr_config(), rt_handle.clone()));
+        tokio::spawn(run_local(get_cli_config(), rt_handle));
+        start_echo_server();
+
+        time::delay_for(Duration::from_secs(1)).await;
+
+        let mut c = connect_echo().await;
+
+        let payload = [0u8; 64];
+        c.write_all(&payload).await.unwrap();
+        let mut buf = [0u8; 64];
+        c.read_exact(&mut buf).await.unwrap();
+
+        // Uses up the quota, relay will be closed
+        let payload = vec![0u8; QUOTA as usize];
+        let _ = c.write_all(&payload).await;
+        let mut buf = Vec::new();
+        let _ = time::timeout(Duration::from_secs(5), c.read_to_end(&mut buf))
+            .await
+            .unwrap();
+        assert!(buf.len() < QUOTA as usize);
+
+        // New connections are refused
+        let mut c = connect_echo().await;
+        let _ = c.write_all(b"hello").await;
+        let mut buf = Vec::new();
+        let _ = time::timeout(Duration::from_secs(5), c.read_to_end(&mut buf))
+            .await
+            .unwrap();
+        assert!(buf.is_empty());
+    });
+}
+
+#[test]
+fn quota_kept_after_replacing() {
+    const SERVER_ADDR: &str = "127.0.0.1:8141";
+    const LOCAL_ADDR: &str = "127.0.0.1:8241";
+    const ECHO_SERVER_ADDR: &str = "127.0.0.1:50421";
+
+    let _ = env_logger::try_init();
+
+    let quota_server = |bytes| {
+        let mut svr_cfg = ServerConfig::basic(SERVER_ADDR.parse().unwrap(), PASSWORD.to_owned(), METHOD);
+        svr_cfg.set_quota(TrafficQuota { bytes, period: None });
+        svr_cfg
+    };
+
+    let mut svr_cfg = Config::new(ConfigType::Server);
+    svr_cfg.server = vec![quota_server(QUOTA)];
+    svr_cfg.mode = Mode::TcpOnly;
+
+    let mut cli_cfg = Config::new(ConfigType::Socks5Local);
+    cli_cfg.local = Some(ServerAddr::from(LOCAL_ADDR.parse::<SocketAddr>().unwrap()));
+    cli_cfg.server = vec![ServerConfig::basic(
+        SERVER_ADDR.parse().unwrap(),
+        PASSWORD.to_owned(),
+        METHOD,
+    )];
+    cli_cfg.mode = Mode::TcpOnly;
+
+    let mut rt = Builder::new().basic_scheduler().enable_all().build().unwrap();
+    let rt_handle = rt.handle().clone();
+
+    rt.block_on(async move {
+        let registry = ServerRegistry::new();
+
+        tokio::spawn(run_server_with_registry(svr_cfg, rt_handle.clone(), registry.clone()));
+        tokio::spawn(run_local(cli_cfg, rt_handle));
+        tokio::spawn(async {
+            let mut listener = TcpListener::bind(ECHO_SERVER_ADDR).await.unwrap();
+
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let (mut r, mut w) = stream.split();
+                    let _ = io::copy(&mut r, &mut w).await;
+                });
+            }
+        });
+
+        time::delay_for(Duration::from_secs(1)).await;
+
+        let mut c = Socks5Client::connect(
+            Address::SocketAddress(ECHO_SERVER_ADDR.parse().unwrap()),
+            &LOCAL_ADDR.parse().unwrap(),
+        )
+        .await
+        .unwrap();
+
+        let payload = [0u8; 64];
+        c.write_all(&payload).await.unwrap();
+        let mut buf = [0u8; 64];
+        c.read_exact(&mut buf).await.unwrap();
+
+        let before = registry.stats().await[&8141];
+        assert!(before.transmission > 0);
+        assert_eq!(before.quota_remaining, Some(QUOTA - before.transmission as u64));
+
+        // Replacing the server only changes the limit, the used quota is kept
+        registry.add_server(quota_server(QUOTA * 2)).await.unwrap();
+
+        let after = registry.stats().await[&8141];
+        assert_eq!(after.transmission, before.transmission);
+        assert_eq!(after.quota_remaining, Some(QUOTA * 2 - before.transmission as u64));
+    });
+}
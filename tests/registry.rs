@@ -0,0 +1,426 @@
+use std::net::SocketAddr;
+
+use tokio::{
+    io,
+    net::{TcpListener, TcpStream},
+    prelude::*,
+    runtime::Builder,
+    time::{self, Duration},
+};
+
+use shadowsocks::{
+    config::{Config, ConfigType, Mode, ServerAddr, ServerConfig},
+    crypto::CipherType,
+    relay::{socks5::Address, tcprelay::client::Socks5Client},
+    run_local,
+    run_server_with_registry,
+    ServerRegistry,
+};
+
+const SERVER_ADDR: &str = "127.0.0.1:8130";
+const ADDED_SERVER_ADDR: &str = "127.0.0.1:8131";
+const LOCAL_ADDR: &str = "127.0.0.1:8230";
+const ECHO_SERVER_ADDR: &str = "127.0.0.1:50410";
+
+const PASSWORD: &str = "test-password";
+const METHOD: CipherType = CipherType::Aes256Gcm;
+
+fn get_svr_config() -> Config {
+    let mut cfg = Config::new(ConfigType::Server);
+    cfg.server = vec![ServerConfig::basic(
+        SERVER_ADDR.parse().unwrap(),
+        PASSWORD.to_owned(),
+        METHOD,
+    )];
+    cfg.mode = Mode::TcpOnly;
+    cfg
+}
+
+fn get_cli_config() -> Config {
+    let mut cfg = Config::new(ConfigType::Socks5Local);
+    cfg.local = Some(ServerAddr::from(LOCAL_ADDR.parse::<SocketAddr>().unwrap()));
+    cfg.server = vec![ServerConfig::basic(
+        SERVER_ADDR.parse().unwrap(),
+        PASSWORD.to_owned(),
+        METHOD,
+    )];
+    cfg.mode = Mode::TcpOnly;
+    cfg
+}
+
+fn start_echo_server() {
+    tokio::spawn(async {
+        let mut listener = TcpListener::bind(ECHO_SERVER_ADDR).await.unwrap();
+
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let (mut r, mut w) = stream.split();
+                let _ = io::copy(&mut r, &mut w).await;
+            });
+        }
+    });
+}
+
+async fn echo(c: &mut Socks5Client, payload: &[u8]) {
+    c.write_all(payload).await.unwrap();
+    c.flush().await.unwrap();
+
+    let mut buf = vec![0u8; payload.len()];
+    time::timeout(Duration::from_secs(5), c.read_exact(&mut buf))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(buf, payload);
+}
+
+#[test]
+fn registry_add_remove_server() {
+    let _ = env_logger::try_init();
+
+    let mut rt = Builder::new().basic_scheduler().enable_all().build().unwrap();
+    let rt_handle = rt.handle().clone();
+
+    rt.block_on(async move {
+        let registry = ServerRegistry::new();
+
+        tokio::spawn(run_server_with_registry(
+            get_svr_config(),
+            rt_handle.clone(),
+            registry.clone(),
+        ));
+        tokio::spawn(run_local(get_cli_config(), rt_handle));
+        start_echo_server();
+
+        time::delay_for(Duration::from_secs(1)).await;
+
+        let mut c = Socks5Client::connect(
+            Address::SocketAddress(ECHO_SERVER_ADDR.parse().unwrap()),
+            &LOCAL_ADDR.parse().unwrap(),
+        )
+        .await
+        .unwrap();
+        echo(&mut c, b"before").await;
+
+        let svr_cfg = ServerConfig::basic(ADDED_SERVER_ADDR.parse().unwrap(), "hello-world".to_owned(), METHOD);
+        registry.add_server(svr_cfg).await.unwrap();
+        assert_eq!(registry.servers().await.len(), 2);
+        TcpStream::connect(ADDED_SERVER_ADDR).await.unwrap();
+
+        // Replacing on the same address takes over its listener
+        let svr_cfg = ServerConfig::basic(ADDED_SERVER_ADDR.parse().unwrap(), "hello-again".to_owned(), METHOD);
+        registry.add_server(svr_cfg).await.unwrap();
+        TcpStream::connect(ADDED_SERVER_ADDR).await.unwrap();
+
+        // The running one is kept if the replacing one failed to bind
+        let svr_cfg = ServerConfig::basic("192.0.2.1:8131".parse().unwrap(), "hello-world".to_owned(), METHOD);
+        assert!(registry.add_server(svr_cfg).await.is_err());
+        assert_eq!(registry.servers().await.len(), 2);
+        TcpStream::connect(ADDED_SERVER_ADDR).await.unwrap();
+
+        // AEAD-2022 ciphers require base64 encoded keys
+        let svr_cfg = ServerConfig::basic(
+            ADDED_SERVER_ADDR.parse().unwrap(),
+            "hello-world".to_owned(),
+            CipherType::Aead2022Blake3Aes128Gcm,
+        );
+        let err = registry.add_server(svr_cfg).await.err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(registry.servers().await.len(), 2);
+        TcpStream::connect(ADDED_SERVER_ADDR).await.unwrap();
+
+        assert!(registry.remove_server(8131).await);
+        assert!(!registry.remove_server(8131).await);
+        assert_eq!(registry.servers().await.len(), 1);
+
+        time::delay_for(Duration::from_millis(100)).await;
+        assert!(TcpStream::connect(ADDED_SERVER_ADDR).await.is_err());
+
+        // Connections of the other servers are not affected
+        echo(&mut c, b"after").await;
+    });
+}
+
+#[test]
+fn registry_reload() {
+    const SERVER_ADDR: &str = "127.0.0.1:8132";
+    const ADDED_SERVER_ADDR: &str = "127.0.0.1:8133";
+    const REMOVED_SERVER_ADDR: &str = "127.0.0.1:8134";
+    const LOCAL_ADDR: &str = "127.0.0.1:8231";
+    const ECHO_SERVER_ADDR: &str = "127.0.0.1:50411";
+
+    let _ = env_logger::try_init();
+
+    let mut svr_cfg = Config::new(ConfigType::Server);
+    svr_cfg.server = vec![
+        ServerConfig::basic(SERVER_ADDR.parse().unwrap(), PASSWORD.to_owned(), METHOD),
+        ServerConfig::basic(REMOVED_SERVER_ADDR.parse().unwrap(), PASSWORD.to_owned(), METHOD),
+    ];
+
+    let mut cli_cfg = Config::new(ConfigType::Socks5Local);
+    cli_cfg.local = Some(ServerAddr::from(LOCAL_ADDR.parse::<SocketAddr>().unwrap()));
+    cli_cfg.server = vec![ServerConfig::basic(
+        SERVER_ADDR.parse().unwrap(),
+        PASSWORD.to_owned(),
+        METHOD,
+    )];
+
+    let mut rt = Builder::new().basic_scheduler().enable_all().build().unwrap();
+    let rt_handle = rt.handle().clone();
+
+    rt.block_on(async move {
+        let registry = ServerRegistry::new();
+
+        tokio::spawn(run_server_with_registry(svr_cfg, rt_handle.clone(), registry.clone()));
+        tokio::spawn(run_local(cli_cfg, rt_handle));
+        tokio::spawn(async {
+            let mut listener = TcpListener::bind(ECHO_SERVER_ADDR).await.unwrap();
+
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let (mut r, mut w) = stream.split();
+                    let _ = io::copy(&mut r, &mut w).await;
+                });
+            }
+        });
+
+        time::delay_for(Duration::from_secs(1)).await;
+
+        let echo_addr = Address::SocketAddress(ECHO_SERVER_ADDR.parse().unwrap());
+        let mut c = Socks5Client::connect(echo_addr.clone(), &LOCAL_ADDR.parse().unwrap())
+            .await
+            .unwrap();
+        echo(&mut c, b"before").await;
+
+        // Invalid configuration is rejected without touching the running servers
+        let mut invalid_cfg = Config::new(ConfigType::Server);
+        invalid_cfg.server = vec![
+            ServerConfig::basic(SERVER_ADDR.parse().unwrap(), PASSWORD.to_owned(), METHOD),
+            ServerConfig::basic(SERVER_ADDR.parse().unwrap(), "hello-world".to_owned(), METHOD),
+        ];
+        assert!(registry.reload(invalid_cfg).await.is_err());
+        assert_eq!(registry.servers().await.len(), 2);
+
+        // Nothing is changed if any of the servers failed to bind
+        let mut unbindable_cfg = Config::new(ConfigType::Server);
+        unbindable_cfg.server = vec![
+            ServerConfig::basic(SERVER_ADDR.parse().unwrap(), PASSWORD.to_owned(), METHOD),
+            ServerConfig::basic("192.0.2.1:8136".parse().unwrap(), PASSWORD.to_owned(), METHOD),
+        ];
+        unbindable_cfg.forbidden_ip.insert("127.0.0.1".parse().unwrap());
+        assert!(registry.reload(unbindable_cfg).await.is_err());
+        assert_eq!(registry.servers().await.len(), 2);
+        TcpStream::connect(REMOVED_SERVER_ADDR).await.unwrap();
+
+        let mut unchanged = Socks5Client::connect(echo_addr.clone(), &LOCAL_ADDR.parse().unwrap())
+            .await
+            .unwrap();
+        echo(&mut unchanged, b"unchanged").await;
+
+        let mut new_cfg = Config::new(ConfigType::Server);
+        new_cfg.server = vec![
+            ServerConfig::basic(SERVER_ADDR.parse().unwrap(), PASSWORD.to_owned(), METHOD),
+            ServerConfig::basic(ADDED_SERVER_ADDR.parse().unwrap(), "hello-world".to_owned(), METHOD),
+        ];
+        new_cfg.forbidden_ip.insert("127.0.0.1".parse().unwrap());
+        registry.reload(new_cfg).await.unwrap();
+
+        time::delay_for(Duration::from_millis(100)).await;
+        TcpStream::connect(ADDED_SERVER_ADDR).await.unwrap();
+        assert!(TcpStream::connect(REMOVED_SERVER_ADDR).await.is_err());
+
+        // Connections of the unchanged server are kept
+        echo(&mut c, b"after").await;
+
+        // New connections follow the reloaded `forbidden_ip`
+        let mut c = Socks5Client::connect(echo_addr, &LOCAL_ADDR.parse().unwrap())
+            .await
+            .unwrap();
+        c.write_all(b"forbidden").await.unwrap();
+        c.flush().await.unwrap();
+
+        let mut buf = [0u8; 9];
+        let res = time::timeout(Duration::from_secs(5), c.read_exact(&mut buf))
+            .await
+            .unwrap();
+        assert!(res.is_err());
+    });
+}
+
+#[test]
+fn registry_shutdown() {
+    const SERVER_ADDR: &str = "127.0.0.1:8135";
+    const LOCAL_ADDR: &str = "127.0.0.1:8232";
+    const ECHO_SERVER_ADDR: &str = "127.0.0.1:50412";
+
+    let _ = env_logger::try_init();
+
+    let mut svr_cfg = Config::new(ConfigType::Server);
+    svr_cfg.server = vec![ServerConfig::basic(
+        SERVER_ADDR.parse().unwrap(),
+        PASSWORD.to_owned(),
+        METHOD,
+    )];
+    svr_cfg.mode = Mode::TcpAndUdp;
+    svr_cfg.shutdown_grace_period = Some(Duration::from_secs(2));
+
+    let mut cli_cfg = Config::new(ConfigType::Socks5Local);
+    cli_cfg.local = Some(ServerAddr::from(LOCAL_ADDR.parse::<SocketAddr>().unwrap()));
+    cli_cfg.server = vec![ServerConfig::basic(
+        SERVER_ADDR.parse().unwrap(),
+        PASSWORD.to_owned(),
+        METHOD,
+    )];
+    cli_cfg.mode = Mode::TcpOnly;
+
+    let mut rt = Builder::new().basic_scheduler().enable_all().build().unwrap();
+    let rt_handle = rt.handle().clone();
+
+    rt.block_on(async move {
+        let registry = ServerRegistry::new();
+
+        let server = tokio::spawn(run_server_with_registry(svr_cfg, rt_handle.clone(), registry.clone()));
+        tokio::spawn(run_local(cli_cfg, rt_handle));
+        tokio::spawn(async {
+            let mut listener = TcpListener::bind(ECHO_SERVER_ADDR).await.unwrap();
+
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let (mut r, mut w) = stream.split();
+                    let _ = io::copy(&mut r, &mut w).await;
+                });
+            }
+        });
+
+        time::delay_for(Duration::from_secs(1)).await;
+
+        let mut c = Socks5Client::connect(
+            Address::SocketAddress(ECHO_SERVER_ADDR.parse().unwrap()),
+            &LOCAL_ADDR.parse().unwrap(),
+        )
+        .await
+        .unwrap();
+        echo(&mut c, b"before").await;
+
+        let shutdown = tokio::spawn({
+            let registry = registry.clone();
+            async move { registry.shutdown().await }
+        });
+
+        // Listeners are closed at once, active relays are kept
+        time::delay_for(Duration::from_millis(100)).await;
+        assert!(TcpStream::connect(SERVER_ADDR).await.is_err());
+        // UDP server without associations is stopped at once
+        assert!(std::net::UdpSocket::bind(SERVER_ADDR).is_ok());
+        echo(&mut c, b"draining").await;
+
+        // Servers are stopped after the grace period
+        time::timeout(Duration::from_secs(5), shutdown)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        time::timeout(Duration::from_secs(1), server)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert!(registry.servers().await.is_empty());
+
+        let mut buf = [0u8; 1];
+        let res = time::timeout(Duration::from_secs(1), c.read(&mut buf)).await.unwrap();
+        assert!(matches!(res, Ok(0) | Err(..)));
+    });
+}
+
+#[test]
+fn registry_same_port_on_different_ips() {
+    const SERVER_ADDR_V4: &str = "127.0.0.1:8137";
+    const SERVER_ADDR_V6: &str = "[::1]:8137";
+
+    let _ = env_logger::try_init();
+
+    let mut svr_cfg = Config::new(ConfigType::Server);
+    svr_cfg.server = vec![
+        ServerConfig::basic(SERVER_ADDR_V4.parse().unwrap(), PASSWORD.to_owned(), METHOD),
+        ServerConfig::basic(SERVER_ADDR_V6.parse().unwrap(), PASSWORD.to_owned(), METHOD),
+    ];
+    svr_cfg.mode = Mode::TcpOnly;
+
+    let mut rt = Builder::new().basic_scheduler().enable_all().build().unwrap();
+    let rt_handle = rt.handle().clone();
+
+    rt.block_on(async move {
+        // Servers sharing the same address are rejected at startup
+        let mut duplicated_cfg = svr_cfg.clone();
+        duplicated_cfg.server.push(ServerConfig::basic(
+            SERVER_ADDR_V4.parse().unwrap(),
+            "hello-world".to_owned(),
+            METHOD,
+        ));
+        let res = run_server_with_registry(duplicated_cfg, rt_handle.clone(), ServerRegistry::new()).await;
+        assert_eq!(res.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+
+        let registry = ServerRegistry::new();
+        tokio::spawn(run_server_with_registry(svr_cfg, rt_handle, registry.clone()));
+
+        time::delay_for(Duration::from_secs(1)).await;
+
+        assert_eq!(registry.servers().await.len(), 2);
+        TcpStream::connect(SERVER_ADDR_V4).await.unwrap();
+        TcpStream::connect(SERVER_ADDR_V6).await.unwrap();
+
+        // Manager sees them as one port
+        assert_eq!(registry.stats().await.len(), 1);
+
+        assert!(registry.remove_server(8137).await);
+        assert!(registry.servers().await.is_empty());
+    });
+}
+
+#[test]
+fn registry_remove_then_reload() {
+    const SERVER_ADDR: &str = "127.0.0.1:8138";
+    const REMOVED_SERVER_ADDR: &str = "127.0.0.1:8139";
+
+    let _ = env_logger::try_init();
+
+    let mut svr_cfg = Config::new(ConfigType::Server);
+    svr_cfg.server = vec![
+        ServerConfig::basic(SERVER_ADDR.parse().unwrap(), PASSWORD.to_owned(), METHOD),
+        ServerConfig::basic(REMOVED_SERVER_ADDR.parse().unwrap(), PASSWORD.to_owned(), METHOD),
+    ];
+    svr_cfg.mode = Mode::TcpOnly;
+
+    let mut rt = Builder::new().basic_scheduler().enable_all().build().unwrap();
+    let rt_handle = rt.handle().clone();
+
+    rt.block_on(async move {
+        let registry = ServerRegistry::new();
+        tokio::spawn(run_server_with_registry(svr_cfg, rt_handle, registry.clone()));
+
+        time::delay_for(Duration::from_secs(1)).await;
+
+        // The removed server is no longer one from configuration, even if it is added back by the manager
+        assert!(registry.remove_server(8139).await);
+        time::delay_for(Duration::from_millis(100)).await;
+        let svr_cfg = ServerConfig::basic(REMOVED_SERVER_ADDR.parse().unwrap(), "hello-world".to_owned(), METHOD);
+        registry.add_server(svr_cfg).await.unwrap();
+
+        let mut new_cfg = Config::new(ConfigType::Server);
+        new_cfg.server = vec![ServerConfig::basic(
+            SERVER_ADDR.parse().unwrap(),
+            PASSWORD.to_owned(),
+            METHOD,
+        )];
+        new_cfg.mode = Mode::TcpOnly;
+        registry.reload(new_cfg).await.unwrap();
+
+        time::delay_for(Duration::from_millis(100)).await;
+        assert_eq!(registry.servers().await.len(), 2);
+        TcpStream::connect(SERVER_ADDR).await.unwrap();
+        TcpStream::connect(REMOVED_SERVER_ADDR).await.unwrap();
+    });
+}
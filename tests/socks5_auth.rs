@@ -0,0 +1,172 @@
+use std::net::SocketAddr;
+
+use tokio::{
+    io::{self, ErrorKind},
+    net::{TcpListener, UdpSocket},
+    prelude::*,
+    runtime::Builder,
+    time::{self, Duration},
+};
+
+use shadowsocks::{
+    config::{Config, ConfigType, Mode, ServerAddr, ServerConfig, Socks5User},
+    crypto::CipherType,
+    relay::{
+        socks5::{Address, UdpAssociateHeader},
+        tcprelay::client::Socks5Client,
+    },
+    run_local, run_server,
+};
+
+const SERVER_ADDR: &str = "127.0.0.1:8217";
+const LOCAL_ADDR: &str = "127.0.0.1:8312";
+const ECHO_SERVER_ADDR: &str = "127.0.0.1:50492";
+
+const PASSWORD: &str = "test-password";
+const METHOD: CipherType = CipherType::Aes256Gcm;
+
+#[test]
+fn socks5_password_auth() {
+    let _ = env_logger::try_init();
+
+    let mut svr_cfg = Config::new(ConfigType::Server);
+    svr_cfg.server = vec![ServerConfig::basic(
+        SERVER_ADDR.parse().unwrap(),
+        PASSWORD.to_owned(),
+        METHOD,
+    )];
+
+    let mut cli_cfg = Config::new(ConfigType::Socks5Local);
+    cli_cfg.local = Some(ServerAddr::from(LOCAL_ADDR.parse::<SocketAddr>().unwrap()));
+    cli_cfg.server = svr_cfg.server.clone();
+    cli_cfg.socks5_users = vec![
+        Socks5User::new("alice".to_owned(), "alice-password".to_owned()),
+        Socks5User::new("bob".to_owned(), "bob-password".to_owned()),
+    ];
+
+    let mut rt = Builder::new().basic_scheduler().enable_all().build().unwrap();
+    let rt_handle = rt.handle().clone();
+
+    rt.block_on(async move {
+        tokio::spawn(run_server(svr_cfg, rt_handle.clone()));
+        tokio::spawn(run_local(cli_cfg, rt_handle));
+
+        tokio::spawn(async {
+            let mut listener = TcpListener::bind(ECHO_SERVER_ADDR).await.unwrap();
+
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let (mut r, mut w) = stream.split();
+                    let _ = io::copy(&mut r, &mut w).await;
+                });
+            }
+        });
+
+        time::delay_for(Duration::from_secs(1)).await;
+
+        let target = Address::SocketAddress(ECHO_SERVER_ADDR.parse().unwrap());
+        let local_addr = LOCAL_ADDR.parse().unwrap();
+
+        let mut c = Socks5Client::connect_with_password(target.clone(), &local_addr, "bob", "bob-password")
+            .await
+            .unwrap();
+        c.write_all(b"hello authenticated").await.unwrap();
+        c.flush().await.unwrap();
+
+        let mut buf = [0u8; 19];
+        time::timeout(Duration::from_secs(5), c.read_exact(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&buf, b"hello authenticated");
+
+        let err = Socks5Client::connect_with_password(target.clone(), &local_addr, "alice", "bob-password")
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+
+        // Clients without credentials are rejected in handshake
+        assert!(Socks5Client::connect(target, &local_addr).await.is_err());
+    });
+}
+
+#[test]
+fn socks5_udp_requires_auth() {
+    const SERVER_ADDR: &str = "127.0.0.1:8221";
+    const LOCAL_ADDR: &str = "127.0.0.1:8316";
+    const UDP_ECHO_SERVER_ADDR: &str = "127.0.0.1:50493";
+
+    let _ = env_logger::try_init();
+
+    let mut svr_cfg = Config::new(ConfigType::Server);
+    svr_cfg.server = vec![ServerConfig::basic(
+        SERVER_ADDR.parse().unwrap(),
+        PASSWORD.to_owned(),
+        METHOD,
+    )];
+    svr_cfg.mode = Mode::UdpOnly;
+
+    let mut cli_cfg = Config::new(ConfigType::Socks5Local);
+    cli_cfg.local = Some(ServerAddr::from(LOCAL_ADDR.parse::<SocketAddr>().unwrap()));
+    cli_cfg.server = svr_cfg.server.clone();
+    cli_cfg.mode = Mode::UdpOnly;
+    cli_cfg.socks5_users = vec![Socks5User::new("bob".to_owned(), "bob-password".to_owned())];
+
+    let mut rt = Builder::new().basic_scheduler().enable_all().build().unwrap();
+    let rt_handle = rt.handle().clone();
+
+    rt.block_on(async move {
+        tokio::spawn(run_server(svr_cfg, rt_handle.clone()));
+        tokio::spawn(run_local(cli_cfg, rt_handle));
+
+        tokio::spawn(async {
+            let mut l = UdpSocket::bind(UDP_ECHO_SERVER_ADDR).await.unwrap();
+
+            let mut buf = vec![0u8; 65536];
+            loop {
+                let (n, src) = l.recv_from(&mut buf).await.unwrap();
+                l.send_to(&buf[..n], &src).await.unwrap();
+            }
+        });
+
+        time::delay_for(Duration::from_secs(1)).await;
+
+        let target = Address::SocketAddress(UDP_ECHO_SERVER_ADDR.parse().unwrap());
+        let local_addr = LOCAL_ADDR.parse::<SocketAddr>().unwrap();
+
+        let mut pkt = Vec::new();
+        UdpAssociateHeader::new(0, target.clone()).write_to_buf(&mut pkt);
+        pkt.extend_from_slice(b"hello udp");
+
+        let mut l = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut buf = vec![0u8; 65536];
+
+        // Datagrams without an authenticated session are thrown away
+        l.send_to(&pkt, &local_addr).await.unwrap();
+        assert!(time::timeout(Duration::from_secs(2), l.recv_from(&mut buf))
+            .await
+            .is_err());
+
+        let (c, _) = Socks5Client::udp_associate_with_password(target.clone(), &local_addr, "bob", "bob-password")
+            .await
+            .unwrap();
+
+        l.send_to(&pkt, &local_addr).await.unwrap();
+        let (n, _) = time::timeout(Duration::from_secs(5), l.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(buf[..n].ends_with(b"hello udp"));
+
+        // Session ends with its TCP connection
+        drop(c);
+        time::delay_for(Duration::from_millis(100)).await;
+
+        l.send_to(&pkt, &local_addr).await.unwrap();
+        assert!(time::timeout(Duration::from_secs(2), l.recv_from(&mut buf))
+            .await
+            .is_err());
+    });
+}
@@ -0,0 +1,161 @@
+use std::net::SocketAddr;
+
+use tokio::{
+    net::{TcpListener, TcpStream},
+    prelude::*,
+    runtime::Builder,
+    time::{self, Duration},
+};
+
+use shadowsocks::{
+    config::{Config, ConfigType, ServerAddr, ServerConfig},
+    crypto::CipherType,
+    relay::{socks5::Address, tcprelay::client::Socks5Client},
+    run_local,
+    run_server,
+};
+
+const SERVER_ADDR: &str = "127.0.0.1:8218";
+const LOCAL_ADDR: &str = "127.0.0.1:8313";
+const UNSUPPORTED_SERVER_ADDR: &str = "127.0.0.1:8219";
+const UNSUPPORTED_LOCAL_ADDR: &str = "127.0.0.1:8314";
+const REFUSED_SERVER_ADDR: &str = "127.0.0.1:8220";
+const REFUSED_LOCAL_ADDR: &str = "127.0.0.1:8315";
+
+const PASSWORD: &str = "test-password";
+const METHOD: CipherType = CipherType::Aes128Gcm;
+
+fn get_cli_config(svr_addr: &str, local_addr: &str) -> Config {
+    let mut cfg = Config::new(ConfigType::Socks5Local);
+    cfg.local = Some(ServerAddr::from(local_addr.parse::<SocketAddr>().unwrap()));
+    cfg.server = vec![ServerConfig::basic(
+        svr_addr.parse().unwrap(),
+        PASSWORD.to_owned(),
+        METHOD,
+    )];
+    cfg
+}
+
+#[test]
+fn socks5_bind() {
+    let _ = env_logger::try_init();
+
+    let mut server = ServerConfig::basic(SERVER_ADDR.parse().unwrap(), PASSWORD.to_owned(), METHOD);
+    server.set_allow_bind(true);
+
+    // BIND is not allowed by default
+    let refused_server = ServerConfig::basic(REFUSED_SERVER_ADDR.parse().unwrap(), PASSWORD.to_owned(), METHOD);
+
+    let mut svr_cfg = Config::new(ConfigType::Server);
+    svr_cfg.server = vec![server, refused_server];
+
+    let mut rt = Builder::new().basic_scheduler().enable_all().build().unwrap();
+    let rt_handle = rt.handle().clone();
+
+    rt.block_on(async move {
+        tokio::spawn(run_server(svr_cfg, rt_handle.clone()));
+        tokio::spawn(run_local(get_cli_config(SERVER_ADDR, LOCAL_ADDR), rt_handle.clone()));
+        tokio::spawn(run_local(
+            get_cli_config(UNSUPPORTED_SERVER_ADDR, UNSUPPORTED_LOCAL_ADDR),
+            rt_handle.clone(),
+        ));
+        tokio::spawn(run_local(
+            get_cli_config(REFUSED_SERVER_ADDR, REFUSED_LOCAL_ADDR),
+            rt_handle,
+        ));
+
+        // Servers without BIND close connections with unknown requests
+        tokio::spawn(async {
+            let mut listener = TcpListener::bind(UNSUPPORTED_SERVER_ADDR).await.unwrap();
+
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut buf = [0u8; 1024];
+                let _ = stream.read(&mut buf).await;
+            }
+        });
+
+        time::delay_for(Duration::from_secs(1)).await;
+
+        let peer = Address::SocketAddress("127.0.0.1:0".parse().unwrap());
+
+        let (mut c, bound_addr) = Socks5Client::bind(peer.clone(), &LOCAL_ADDR.parse().unwrap())
+            .await
+            .unwrap();
+        let bound_addr = match bound_addr {
+            Address::SocketAddress(addr) => addr,
+            addr => panic!("BIND replied with {}", addr),
+        };
+
+        let mut inbound = TcpStream::connect(bound_addr).await.unwrap();
+        let remote_addr = time::timeout(Duration::from_secs(5), c.read_bind_reply())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(remote_addr, Address::SocketAddress(inbound.local_addr().unwrap()));
+
+        inbound.write_all(b"from inbound").await.unwrap();
+        let mut buf = [0u8; 12];
+        time::timeout(Duration::from_secs(5), c.read_exact(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&buf, b"from inbound");
+
+        c.write_all(b"from client").await.unwrap();
+        c.flush().await.unwrap();
+        let mut buf = [0u8; 11];
+        time::timeout(Duration::from_secs(5), inbound.read_exact(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&buf, b"from client");
+
+        // Inbound connections from other IPs are closed
+        let other_peer = Address::SocketAddress("127.0.0.2:0".parse().unwrap());
+        let (mut c, bound_addr) = Socks5Client::bind(other_peer, &LOCAL_ADDR.parse().unwrap())
+            .await
+            .unwrap();
+        let bound_addr = match bound_addr {
+            Address::SocketAddress(addr) => addr,
+            addr => panic!("BIND replied with {}", addr),
+        };
+
+        let mut inbound = TcpStream::connect(bound_addr).await.unwrap();
+        let mut buf = [0u8; 1];
+        let n = time::timeout(Duration::from_secs(5), inbound.read(&mut buf))
+            .await
+            .unwrap()
+            .unwrap_or(0);
+        assert_eq!(n, 0);
+        assert!(time::timeout(Duration::from_secs(1), c.read_bind_reply()).await.is_err());
+
+        // BIND requires the address of the inbound connection
+        let unspecified_peer = Address::SocketAddress("0.0.0.0:0".parse().unwrap());
+        let err = Socks5Client::bind(unspecified_peer, &LOCAL_ADDR.parse().unwrap())
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(err.to_string(), "Connection not allowed");
+
+        let err = time::timeout(
+            Duration::from_secs(5),
+            Socks5Client::bind(peer.clone(), &UNSUPPORTED_LOCAL_ADDR.parse().unwrap()),
+        )
+        .await
+        .unwrap()
+        .err()
+        .unwrap();
+        assert_eq!(err.to_string(), "Command not supported");
+
+        let err = time::timeout(
+            Duration::from_secs(5),
+            Socks5Client::bind(peer, &REFUSED_LOCAL_ADDR.parse().unwrap()),
+        )
+        .await
+        .unwrap()
+        .err()
+        .unwrap();
+        assert_eq!(err.to_string(), "Connection not allowed");
+    });
+}
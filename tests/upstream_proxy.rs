@@ -0,0 +1,125 @@
+use std::net::SocketAddr;
+
+use tokio::{
+    io,
+    net::TcpListener,
+    prelude::*,
+    runtime::Builder,
+    time::{self, Duration},
+};
+
+use shadowsocks::{
+    config::{Config, ConfigType, ServerAddr, ServerConfig},
+    crypto::CipherType,
+    relay::{socks5::Address, tcprelay::client::Socks5Client},
+    run_local,
+    run_server,
+};
+
+// Clients -> SOCKS5_LOCAL_ADDR / HTTP_LOCAL_ADDR -> SOCKS5_PROXY_ADDR / HTTP_PROXY_ADDR -> SERVER_ADDR -> Echo
+//
+// Proxies are also sslocals, which are connecting to SERVER_ADDR through SERVER_ADDR itself.
+const SERVER_ADDR: &str = "127.0.0.1:8194";
+const SOCKS5_PROXY_ADDR: &str = "127.0.0.1:8296";
+const HTTP_PROXY_ADDR: &str = "127.0.0.1:8297";
+const SOCKS5_LOCAL_ADDR: &str = "127.0.0.1:8298";
+const HTTP_LOCAL_ADDR: &str = "127.0.0.1:8299";
+const ECHO_SERVER_ADDR: &str = "127.0.0.1:50480";
+
+const PASSWORD: &str = "test-password";
+const METHOD: CipherType = CipherType::ChaCha20IetfPoly1305;
+
+fn server_config() -> ServerConfig {
+    ServerConfig::basic(SERVER_ADDR.parse().unwrap(), PASSWORD.to_owned(), METHOD)
+}
+
+fn get_svr_config() -> Config {
+    let mut cfg = Config::new(ConfigType::Server);
+    cfg.server = vec![server_config()];
+    cfg
+}
+
+fn get_cli_config(config_type: ConfigType, local_addr: &str, upstream_proxy: Option<String>) -> Config {
+    let mut svr_cfg = server_config();
+    if let Some(proxy) = upstream_proxy {
+        svr_cfg.set_upstream_proxy(proxy.parse().unwrap());
+    }
+
+    let mut cfg = Config::new(config_type);
+    cfg.local = Some(ServerAddr::from(local_addr.parse::<SocketAddr>().unwrap()));
+    cfg.server = vec![svr_cfg];
+    cfg
+}
+
+fn start_echo_server() {
+    tokio::spawn(async {
+        let mut listener = TcpListener::bind(ECHO_SERVER_ADDR).await.unwrap();
+
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let (mut r, mut w) = stream.split();
+                let _ = io::copy(&mut r, &mut w).await;
+            });
+        }
+    });
+}
+
+async fn echo(local_addr: &str, payload: &[u8]) -> io::Result<()> {
+    let mut c = Socks5Client::connect(
+        Address::SocketAddress(ECHO_SERVER_ADDR.parse().unwrap()),
+        &local_addr.parse().unwrap(),
+    )
+    .await?;
+
+    c.write_all(payload).await?;
+    c.flush().await?;
+
+    let mut buf = vec![0u8; payload.len()];
+    time::timeout(Duration::from_secs(5), c.read_exact(&mut buf)).await??;
+    assert_eq!(buf, payload);
+
+    Ok(())
+}
+
+#[test]
+fn upstream_proxy() {
+    let _ = env_logger::try_init();
+
+    let mut rt = Builder::new().basic_scheduler().enable_all().build().unwrap();
+    let rt_handle = rt.handle().clone();
+
+    rt.block_on(async move {
+        tokio::spawn(run_server(get_svr_config(), rt_handle.clone()));
+        tokio::spawn(run_local(
+            get_cli_config(ConfigType::Socks5Local, SOCKS5_PROXY_ADDR, None),
+            rt_handle.clone(),
+        ));
+        tokio::spawn(run_local(
+            get_cli_config(ConfigType::HttpLocal, HTTP_PROXY_ADDR, None),
+            rt_handle.clone(),
+        ));
+        tokio::spawn(run_local(
+            get_cli_config(
+                ConfigType::Socks5Local,
+                SOCKS5_LOCAL_ADDR,
+                Some(format!("socks5://{}", SOCKS5_PROXY_ADDR)),
+            ),
+            rt_handle.clone(),
+        ));
+        tokio::spawn(run_local(
+            get_cli_config(
+                ConfigType::Socks5Local,
+                HTTP_LOCAL_ADDR,
+                Some(format!("http://{}", HTTP_PROXY_ADDR)),
+            ),
+            rt_handle,
+        ));
+        start_echo_server();
+
+        time::delay_for(Duration::from_secs(1)).await;
+
+        echo(SOCKS5_LOCAL_ADDR, b"hello through socks5").await.unwrap();
+        echo(HTTP_LOCAL_ADDR, b"hello through http").await.unwrap();
+    });
+}